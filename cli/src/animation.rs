@@ -13,7 +13,7 @@ use core::fmt::Display;
 use image::imageops::resize;
 use std::fmt::Debug;
 
-use crossterm::{cursor, terminal, ExecutableCommand, QueueableCommand};
+use crossterm::{cursor, terminal, tty::IsTty, ExecutableCommand, QueueableCommand};
 use drawille::{Canvas, PixelColor};
 use image::codecs::gif::GifDecoder;
 use image::{AnimationDecoder, ImageDecoder};
@@ -24,11 +24,14 @@ use std::sync::{
 };
 use std::{thread, time};
 
-use crate::utils::{PrettyContext, PrettyExit};
+use torb::utils::{color_enabled, PrettyContext, PrettyExit};
 
 const FRAME_HEIGHT: u16 = 16;
+const NO_ANIMATION_ENV: &str = "TORB_NO_ANIMATION";
 
-pub struct BuilderAnimation {}
+pub struct BuilderAnimation {
+    enabled: bool,
+}
 
 pub trait Animation<T, E> {
     fn do_with_animation(&self, f: Box<dyn FnMut() -> Result<T, E>>) -> Result<T, E>
@@ -43,8 +46,20 @@ pub trait Animation<T, E> {
 }
 
 impl BuilderAnimation {
+    /*
+        The animation moves the cursor around and redraws stdout, which only works on a terminal. Anywhere else,
+        like CI logs or a pipe, it turns into garbage so it's off unless stdout is a TTY and TORB_NO_ANIMATION is unset.
+    */
     pub fn new() -> Self {
-        BuilderAnimation {}
+        let enabled = stdout().is_tty() && std::env::var_os(NO_ANIMATION_ENV).is_none();
+
+        BuilderAnimation { enabled }
+    }
+
+    pub fn with_disabled(mut self, disabled: bool) -> Self {
+        self.enabled = self.enabled && !disabled;
+
+        self
     }
 }
 impl<T, E> Animation<T, E> for BuilderAnimation
@@ -74,6 +89,8 @@ where
         );
 
         let kill_flag_clone = kill_flag.clone();
+        // Without color only the brighter pixels are drawn, in the terminal's own foreground color.
+        let colored = color_enabled();
 
         thread::spawn(move || {
             let mut thread_stdout = stdout();
@@ -95,12 +112,17 @@ where
                     for x in 0..width {
                         for y in 0..height {
                             let pixel = img.get_pixel(x, y);
-                            let color = PixelColor::TrueColor {
-                                r: pixel[0],
-                                g: pixel[1],
-                                b: pixel[2],
-                            };
-                            canvas.set_colored(x, y, color);
+
+                            if colored {
+                                let color = PixelColor::TrueColor {
+                                    r: pixel[0],
+                                    g: pixel[1],
+                                    b: pixel[2],
+                                };
+                                canvas.set_colored(x, y, color);
+                            } else if (pixel[0] as u32 + pixel[1] as u32 + pixel[2] as u32) / 3 > 127 {
+                                canvas.set(x, y);
+                            }
                         }
                     }
 
@@ -122,6 +144,10 @@ where
     where
         E: Debug + Display,
     {
+        if !self.enabled {
+            return f();
+        }
+
         let home_dir = dirs::home_dir().unwrap();
         let torb_path = home_dir.join(".torb");
         let repository_path = torb_path.join("repositories");
@@ -9,23 +9,281 @@
 //
 // See LICENSE file at https://github.com/TorbFoundry/torb/blob/main/LICENSE for details.
 
-use crate::artifacts::{ArtifactNodeRepr, ArtifactRepr};
-use crate::utils::{run_command_in_user_shell, CommandConfig, CommandPipeline};
-use indexmap::{IndexSet};
+use crate::artifacts::{ArtifactNodeRepr, ArtifactRepr, BuildStep, TorbInput};
+use crate::composer::InputAddress;
+use crate::config::{RegistryConfig, TORB_CONFIG};
+use crate::resolver::template::is_template;
+use crate::timings::timed;
+use crate::utils::{
+    buildstate_path_or_create, project_root, run_command_in_user_shell_from, torb_path, CommandConfig, CommandPipeline,
+    TorbUtilityErrors,
+};
+use data_encoding::BASE32;
+use indexmap::{IndexMap, IndexSet};
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::fs;
-use std::process::{Command, Output};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output, Stdio};
 use thiserror::Error;
 
+const BUILD_MANIFEST_FILE: &str = "build_manifest.yaml";
+const BUILD_MANIFEST_VERSION: u32 = 1;
+const REGISTRY_ALIAS_PREFIX: &str = "alias:";
+const INLINE_DOCKERFILE_DIR: &str = "dockerfiles";
+pub const TORB_BUILDER: &str = "torb_builder";
+const BUILDKITD_CONFIG_FILE: &str = "buildkitd.toml";
+const BUILD_SCRIPT_INPUT_PREFIX: &str = "TORB_INPUT_";
+
 #[derive(Error, Debug)]
 pub enum TorbBuilderErrors {
     #[error("Unable to build from dockerfile, reason: {response}")]
     UnableToBuildDockerfile { response: String },
     #[error("Unable to build from build script, reason: {response}")]
     UnableToBuildBuildScript { response: String },
-    #[error("Either dockerfile or script_path must be provided.")]
+    #[error("Unable to read the build script {path}, reason: {response}")]
+    UnableToReadBuildScript { path: String, response: String },
+    #[error("One of dockerfile, dockerfile_inline or script_path must be provided.")]
     MustDefineDockerfileOrBuildScript,
+    #[error("Unable to write the inline dockerfile to {path}, reason: {response}")]
+    UnableToWriteInlineDockerfile { path: String, response: String },
     #[error("The node has already been built. This theoretically should never be hit, so please ping the maintainers.")]
     NodeAlreadyBuilt,
+    #[error("Unsupported container builder: {name}, supported builders are docker and podman.")]
+    UnsupportedContainerBuilder { name: String },
+    #[error("Registry alias {alias} is not defined, add it under registries in your config.yaml.")]
+    UnknownRegistryAlias { alias: String },
+    #[error("Unable to log in to registry {registry}, reason: {response}")]
+    UnableToLoginToRegistry { registry: String, response: String },
+    #[error("Unable to start a pool of {jobs} build jobs, reason: {response}")]
+    UnableToCreateBuildPool { jobs: usize, response: String },
+    #[error("Unable to inspect the pushed manifest for {label}, reason: {response}")]
+    UnableToInspectManifest { label: String, response: String },
+    #[error("{fqn} only builds for {node_platforms}, none of which are in the platforms being built, {build_platforms}. Add one of them to -p or change the platforms in the node's build step.")]
+    NoMatchingPlatforms { fqn: String, node_platforms: String, build_platforms: String },
+    #[error("The pushed manifest for {label} is missing the platforms {missing}, found {found}.")]
+    MissingPlatforms { label: String, missing: String, found: String },
+    #[error("Unable to set up the {builder} builder to push to the insecure registries {hosts}, reason: {response}")]
+    UnableToConfigureInsecureBuilder { builder: String, hosts: String, response: String },
+    #[error("Unable to build and push to the insecure registry {host}, reason: {response}\n\nTorb pushes to {host} over plain HTTP without verifying TLS, check the registry is serving HTTP at that address. Images and registry credentials are sent unencrypted, so anyone on the network path can read or replace them, only mark registries on localhost or a network you trust as insecure.")]
+    UnableToPushToInsecureRegistry { host: String, response: String },
+}
+
+pub fn default_build_jobs() -> usize {
+    std::thread::available_parallelism()
+        .map(|jobs| jobs.get())
+        .unwrap_or(1)
+}
+
+fn registry_alias_config(registry: &str) -> Result<Option<(String, RegistryConfig)>, TorbBuilderErrors> {
+    match registry.strip_prefix(REGISTRY_ALIAS_PREFIX) {
+        Some(alias) => {
+            let registry_conf = TORB_CONFIG
+                .registries
+                .as_ref()
+                .and_then(|registries| registries.get(alias))
+                .ok_or(TorbBuilderErrors::UnknownRegistryAlias {
+                    alias: alias.to_string(),
+                })?;
+
+            Ok(Some((alias.to_string(), registry_conf.clone())))
+        }
+        None => Ok(None),
+    }
+}
+
+// Registries can be written as alias:<name> to reference a registry defined in config.yaml, anything else is used as is.
+pub fn resolve_registry(registry: &str) -> Result<String, TorbBuilderErrors> {
+    match registry_alias_config(registry)? {
+        Some((_, registry_conf)) => Ok(registry_conf.url.trim_end_matches('/').to_string()),
+        None => Ok(registry.to_string()),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ContainerBuilder {
+    Docker,
+    Podman,
+}
+
+impl ContainerBuilder {
+    pub fn from_name(name: &str) -> Result<ContainerBuilder, TorbBuilderErrors> {
+        match name {
+            "docker" => Ok(ContainerBuilder::Docker),
+            "podman" => Ok(ContainerBuilder::Podman),
+            _ => Err(TorbBuilderErrors::UnsupportedContainerBuilder {
+                name: name.to_string(),
+            }),
+        }
+    }
+
+    // The CLI flag wins over config.yaml, and docker is used when neither is set.
+    pub fn configured(override_opt: Option<&str>) -> Result<ContainerBuilder, TorbBuilderErrors> {
+        let name = match override_opt {
+            Some(name) => name.to_string(),
+            None => TORB_CONFIG.builder.clone().unwrap_or("docker".to_string()),
+        };
+
+        ContainerBuilder::from_name(&name)
+    }
+}
+
+// The host part of a registry, localhost:5000/team is pushed to localhost:5000.
+fn registry_host(registry: &str) -> &str {
+    let registry = registry
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+
+    registry.split('/').next().unwrap_or(registry)
+}
+
+// Registries passed with --registry-insecure are insecure for that run, on top of the insecure_registries in config.yaml.
+pub fn configured_insecure_registries(flag_hosts: Vec<&str>) -> Vec<String> {
+    let mut hosts = IndexSet::new();
+
+    for host in TORB_CONFIG.insecure_registries().iter().map(|host| host.as_str()).chain(flag_hosts) {
+        let host = registry_host(host.trim());
+
+        if !host.is_empty() {
+            hosts.insert(host.to_string());
+        }
+    }
+
+    hosts.into_iter().collect()
+}
+
+fn buildkitd_config(hosts: &[String]) -> String {
+    hosts
+        .iter()
+        .map(|host| format!("[registry.\"{}\"]\n  http = true\n  insecure = true\n", host))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/*
+    torb_builder is set up for the insecure_registries in config.yaml. A run that adds hosts with --registry-insecure
+    uses a builder named after its set of hosts instead, so a flag passed once doesn't recreate torb_builder and
+    passing the same flags again reuses the builder made for them.
+*/
+fn buildx_builder_name(insecure_hosts: &[String]) -> String {
+    if insecure_hosts == configured_insecure_registries(vec![]).as_slice() {
+        return TORB_BUILDER.to_string();
+    }
+
+    let hash = BASE32.encode(&Sha256::digest(insecure_hosts.join(",").as_bytes()));
+
+    format!("{}_{}", TORB_BUILDER, hash[..8].to_lowercase())
+}
+
+/*
+    Buildx builders using the docker-container driver run their own buildkitd, which only pushes to a registry
+    without TLS when its config says so. The config is written to ~/.torb/buildkitd.toml, or a file named after the
+    builder for a run's own builder, and the builder is recreated with it whenever its set of insecure registries
+    changes, the config can't be changed in place. Returns the name of the builder to push through.
+*/
+pub fn ensure_torb_builder(insecure_hosts: &[String]) -> Result<String, TorbBuilderErrors> {
+    let builder = buildx_builder_name(insecure_hosts);
+    let configure_err = |response: String| TorbBuilderErrors::UnableToConfigureInsecureBuilder {
+        builder: builder.clone(),
+        hosts: insecure_hosts.join(", "),
+        response,
+    };
+
+    let config_file = match builder.strip_prefix(TORB_BUILDER) {
+        Some(suffix) if !suffix.is_empty() => format!("buildkitd{}.toml", suffix),
+        _ => BUILDKITD_CONFIG_FILE.to_string(),
+    };
+    let config_path = torb_path().join(config_file);
+    let config = buildkitd_config(insecure_hosts);
+
+    let exists = CommandPipeline::execute_single(CommandConfig::new(
+        "docker",
+        vec!["buildx", "inspect", &builder],
+        None,
+    ))
+    .is_ok();
+    let config_unchanged = fs::read_to_string(&config_path).map_or(insecure_hosts.is_empty(), |current| current == config);
+
+    if exists && config_unchanged {
+        return Ok(builder);
+    }
+
+    if exists {
+        info!("The insecure registries changed, recreating the {} builder.", builder);
+
+        CommandPipeline::execute_single(CommandConfig::new("docker", vec!["buildx", "rm", &builder], None))
+            .map_err(|err| configure_err(err.to_string()))?;
+    }
+
+    let config_path_str = config_path.to_str().unwrap().to_string();
+    let mut args = vec!["buildx", "create", "--name", &builder, "--driver-opt", "network=host"];
+
+    if insecure_hosts.is_empty() {
+        let _ = fs::remove_file(&config_path);
+    } else {
+        fs::write(&config_path, &config).map_err(|err| configure_err(err.to_string()))?;
+
+        args.push("--config");
+        args.push(&config_path_str);
+    }
+
+    CommandPipeline::execute_single(CommandConfig::new("docker", args, None))
+        .map_err(|err| configure_err(err.to_string()))?;
+
+    info!("Created docker build kit builder, {}.", builder);
+
+    Ok(builder)
+}
+
+/*
+    Records a fingerprint of the sources each node was last built from so unchanged nodes can be skipped.
+    The version is bumped whenever the fingerprint scheme changes, manifests with any other version are thrown away.
+*/
+#[derive(Serialize, Deserialize, Debug)]
+struct BuildManifest {
+    version: u32,
+    nodes: IndexMap<String, String>,
+}
+
+impl BuildManifest {
+    fn path() -> Result<PathBuf, TorbUtilityErrors> {
+        Ok(buildstate_path_or_create()?.join(BUILD_MANIFEST_FILE))
+    }
+
+    fn empty() -> BuildManifest {
+        BuildManifest {
+            version: BUILD_MANIFEST_VERSION,
+            nodes: IndexMap::new(),
+        }
+    }
+
+    fn load() -> BuildManifest {
+        let contents = match BuildManifest::path().map(fs::read_to_string) {
+            Ok(Ok(contents)) => contents,
+            _ => return BuildManifest::empty(),
+        };
+
+        match serde_yaml::from_str::<BuildManifest>(&contents) {
+            Ok(manifest) if manifest.version == BUILD_MANIFEST_VERSION => manifest,
+            _ => {
+                info!("Build manifest is from an older version of Torb, rebuilding all nodes.");
+
+                BuildManifest::empty()
+            }
+        }
+    }
+
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let contents = serde_yaml::to_string(self)?;
+
+        fs::write(BuildManifest::path()?, contents)?;
+
+        Ok(())
+    }
 }
 
 pub struct StackBuilder<'a> {
@@ -35,6 +293,15 @@ pub struct StackBuilder<'a> {
     build_platforms: String,
     separate_local_registry: bool,
     exempt: std::collections::HashSet<String>,
+    container_builder: ContainerBuilder,
+    force: bool,
+    manifest: BuildManifest,
+    build_jobs: usize,
+    verify_platforms: bool,
+    insecure_registries: Vec<String>,
+    // The buildx builder images are built with, one set up for the insecure registries when there are any.
+    buildx_builder: String,
+    queued: Vec<(ArtifactNodeRepr, Option<String>)>,
 }
 
 impl<'a> StackBuilder<'a> {
@@ -43,6 +310,8 @@ impl<'a> StackBuilder<'a> {
         build_platforms: String,
         dryrun: bool,
         separate_local_registry: bool,
+        container_builder: ContainerBuilder,
+        force: bool,
     ) -> StackBuilder<'a> {
         StackBuilder {
             artifact: artifact,
@@ -51,6 +320,14 @@ impl<'a> StackBuilder<'a> {
             build_platforms: build_platforms,
             separate_local_registry,
             exempt: std::collections::HashSet::new(),
+            container_builder,
+            force,
+            manifest: BuildManifest::load(),
+            build_jobs: default_build_jobs(),
+            verify_platforms: false,
+            insecure_registries: Vec::new(),
+            buildx_builder: TORB_BUILDER.to_string(),
+            queued: Vec::new(),
         }
     }
 
@@ -59,7 +336,9 @@ impl<'a> StackBuilder<'a> {
         build_platforms: String,
         dryrun: bool,
         separate_local_registry: bool,
-        exempt: Vec<String>
+        exempt: Vec<String>,
+        container_builder: ContainerBuilder,
+        force: bool,
     ) -> StackBuilder<'a> {
         StackBuilder {
             artifact: artifact,
@@ -68,28 +347,379 @@ impl<'a> StackBuilder<'a> {
             build_platforms: build_platforms,
             separate_local_registry,
             exempt: std::collections::HashSet::from_iter(exempt.iter().cloned()),
+            container_builder,
+            force,
+            manifest: BuildManifest::load(),
+            build_jobs: default_build_jobs(),
+            verify_platforms: false,
+            insecure_registries: Vec::new(),
+            buildx_builder: TORB_BUILDER.to_string(),
+            queued: Vec::new(),
         }
     }
 
+    // Projects are built from the project root, except a meta stack's, which are built from the meta stack's directory.
+    fn root(&self) -> PathBuf {
+        self.artifact.stack_dir.clone().unwrap_or_else(project_root)
+    }
+
+    // Only applies when pushing to a separate local registry, other builds run one node at a time.
+    pub fn with_build_jobs(mut self, build_jobs: usize) -> StackBuilder<'a> {
+        self.build_jobs = build_jobs.max(1);
+
+        self
+    }
+
+    // Checks that each multi-platform image pushed has every platform that was asked for.
+    pub fn with_verify_platforms(mut self, verify_platforms: bool) -> StackBuilder<'a> {
+        self.verify_platforms = verify_platforms;
+
+        self
+    }
+
+    // Registry hosts pushed to over plain HTTP without verifying TLS.
+    pub fn with_insecure_registries(mut self, insecure_registries: Vec<String>) -> StackBuilder<'a> {
+        self.insecure_registries = insecure_registries;
+
+        self
+    }
+
     pub fn build(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.login_to_registries()?;
+        self.prepare_insecure_registries()?;
+
+        // A node whose platforms don't match any being built fails before anything is built.
+        for node in self.artifact.nodes.values() {
+            self.node_platforms(node)?;
+        }
+
         for node in self.artifact.deploys.iter() {
             if self.exempt.get(&node.fqn).is_none() {
                 self.walk_artifact(node)?;
             }
         }
 
+        if !self.queued.is_empty() {
+            self.build_queued()?;
+        }
+
         Ok(())
     }
 
+    /*
+        With a separate local registry every image is built and pushed by the machine running Torb, so the builds
+        queued during the walk are run on a bounded pool instead. Nodes are grouped into levels by dependency depth
+        and a level only starts once the one before it has finished, so dependencies are still built first.
+    */
+    fn build_queued(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let queued = std::mem::take(&mut self.queued);
+
+        let mut depths = IndexMap::<String, usize>::new();
+        let mut levels = BTreeMap::<usize, Vec<&(ArtifactNodeRepr, Option<String>)>>::new();
+
+        // The walk queues dependencies before their dependents, so a node's dependencies already have a depth here.
+        for entry in queued.iter() {
+            let (node, _) = entry;
+            let depth = node
+                .dependencies
+                .iter()
+                .filter_map(|dep| depths.get(&dep.fqn))
+                .max()
+                .map_or(0, |depth| depth + 1);
+
+            depths.insert(node.fqn.clone(), depth);
+            levels.entry(depth).or_default().push(entry);
+        }
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.build_jobs)
+            .build()
+            .map_err(|err| TorbBuilderErrors::UnableToCreateBuildPool {
+                jobs: self.build_jobs,
+                response: err.to_string(),
+            })?;
+
+        info!("Building {} nodes with up to {} jobs...", queued.len(), self.build_jobs);
+
+        for (_, level) in levels.iter() {
+            let results: Vec<Result<(), TorbBuilderErrors>> = pool.install(|| {
+                level
+                    .par_iter()
+                    .map(|(node, _)| timed("build", Some(&node.fqn), || self.build_node(node)))
+                    .collect()
+            });
+
+            let mut first_err = None;
+
+            for ((node, fingerprint), res) in level.iter().zip(results) {
+                match res {
+                    Ok(()) => {
+                        if let (false, Some(fingerprint)) = (self.dryrun, fingerprint) {
+                            self.manifest.nodes.insert(node.fqn.clone(), fingerprint.clone());
+                        }
+                    }
+                    Err(err) => {
+                        first_err.get_or_insert(err);
+                    }
+                }
+            }
+
+            if !self.dryrun {
+                self.manifest.save()?;
+            }
+
+            if let Some(err) = first_err {
+                return Err(Box::new(err));
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+        Resolves every registry alias used by the stack up front so a typo fails before anything is built,
+        and logs in to any aliased registry that has credentials configured.
+    */
+    fn login_to_registries(&self) -> Result<(), TorbBuilderErrors> {
+        let mut seen = IndexSet::new();
+
+        for node in self.artifact.nodes.values() {
+            let registry = match &node.build_step {
+                Some(step) => step.registry.clone(),
+                None => continue,
+            };
+
+            if let Some((alias, registry_conf)) = registry_alias_config(&registry)? {
+                if !seen.insert(alias) {
+                    continue;
+                }
+
+                if let (Some(username), Some(password)) = (&registry_conf.username, &registry_conf.password) {
+                    self.login_to_registry(&registry_conf.url, username, password)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_insecure_registry(&self, registry: &str) -> bool {
+        let host = registry_host(registry);
+
+        self.insecure_registries.iter().any(|insecure| insecure == host)
+    }
+
+    fn prepare_insecure_registries(&mut self) -> Result<(), TorbBuilderErrors> {
+        if self.insecure_registries.is_empty() {
+            return Ok(());
+        }
+
+        warn!(
+            "Pushing to {} without TLS verification. Images and registry credentials are sent unencrypted and can be read or replaced by anyone on the network path, only use this for registries on localhost or a network you trust.",
+            self.insecure_registries.join(", ")
+        );
+
+        // Podman takes --tls-verify=false per push, only buildx needs a builder configured for it.
+        if self.container_builder == ContainerBuilder::Podman {
+            return Ok(());
+        }
+
+        if self.dryrun {
+            println!("{}", buildkitd_config(&self.insecure_registries));
+
+            return Ok(());
+        }
+
+        self.buildx_builder = ensure_torb_builder(&self.insecure_registries)?;
+
+        Ok(())
+    }
+
+    /*
+        The platforms a node's image is built for. Plain entries in the build step's platforms narrow the platforms
+        passed with -p to the ones they share, entries prefixed with + are built in addition even when -p doesn't
+        include them. Without platforms in the build step every platform passed with -p is built, as before.
+    */
+    fn node_platforms(&self, node: &ArtifactNodeRepr) -> Result<String, TorbBuilderErrors> {
+        let requested = node.build_step.as_ref().map(|step| step.platforms.clone()).unwrap_or_default();
+
+        if requested.is_empty() {
+            return Ok(self.build_platforms.clone());
+        }
+
+        let build_platforms = self
+            .build_platforms
+            .split(',')
+            .map(|platform| platform.trim())
+            .filter(|platform| !platform.is_empty())
+            .collect::<Vec<&str>>();
+
+        let (added, narrowed): (Vec<&String>, Vec<&String>) =
+            requested.iter().partition(|platform| platform.starts_with('+'));
+
+        let mut platforms = if narrowed.is_empty() {
+            build_platforms.iter().map(|platform| platform.to_string()).collect::<IndexSet<String>>()
+        } else {
+            build_platforms
+                .iter()
+                .filter(|platform| narrowed.iter().any(|narrow| narrow.trim() == **platform))
+                .map(|platform| platform.to_string())
+                .collect::<IndexSet<String>>()
+        };
+
+        if platforms.is_empty() {
+            return Err(TorbBuilderErrors::NoMatchingPlatforms {
+                fqn: node.fqn.clone(),
+                node_platforms: narrowed.iter().map(|platform| platform.trim()).collect::<Vec<&str>>().join(", "),
+                build_platforms: build_platforms.join(", "),
+            });
+        }
+
+        platforms.extend(added.iter().map(|platform| platform.trim_start_matches('+').trim().to_string()));
+
+        Ok(platforms.into_iter().collect::<Vec<String>>().join(","))
+    }
+
+    fn login_to_registry(&self, url: &str, username: &str, password: &str) -> Result<(), TorbBuilderErrors> {
+        let host = url.split('/').next().unwrap_or(url);
+        let command = match self.container_builder {
+            ContainerBuilder::Docker => "docker",
+            ContainerBuilder::Podman => "podman",
+        };
+
+        if self.dryrun {
+            println!("{} login {} --username {}", command, host, username);
+
+            return Ok(());
+        }
+
+        // The password is passed over stdin so it never shows up in the process list or in error output.
+        let mut child = Command::new(command)
+            .args(["login", host, "--username", username, "--password-stdin"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| TorbBuilderErrors::UnableToLoginToRegistry {
+                registry: host.to_string(),
+                response: err.to_string(),
+            })?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(password.as_bytes()).map_err(|err| {
+                TorbBuilderErrors::UnableToLoginToRegistry {
+                    registry: host.to_string(),
+                    response: err.to_string(),
+                }
+            })?;
+        }
+
+        let output = child
+            .wait_with_output()
+            .map_err(|err| TorbBuilderErrors::UnableToLoginToRegistry {
+                registry: host.to_string(),
+                response: err.to_string(),
+            })?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(TorbBuilderErrors::UnableToLoginToRegistry {
+                registry: host.to_string(),
+                response: String::from_utf8_lossy(&output.stderr).to_string(),
+            })
+        }
+    }
+
+    /*
+        Hashes the build step config along with the path, size and modified time of every file the node builds from.
+        Mtimes are used over file contents so large build contexts don't make the check slower than the build.
+        The environment build scripts get is hashed too, so changing an input or a registry alias rebuilds the node.
+    */
+    fn fingerprint(&self, node: &ArtifactNodeRepr) -> Option<String> {
+        let step = node.build_step.clone()?;
+        let root = self.root();
+
+        let mut sources = vec![];
+
+        if step.dockerfile != "" || step.dockerfile_inline != "" {
+            sources.push(root.join(node.display_name(false)));
+        } else if step.script_path != "" {
+            sources.push(root.join(&step.script_path));
+        }
+
+        for file in node.files.clone().unwrap_or_default() {
+            let file_name = Path::new(&file).file_name()?.to_owned();
+            sources.push(root.join(file_name));
+        }
+
+        let mut entries = vec![];
+        for source in sources.iter() {
+            StackBuilder::collect_file_stamps(source, &mut entries);
+        }
+        entries.sort();
+
+        let mut hasher = Sha256::new();
+        hasher.update(format!(
+            "{:?}{}{}{:?}",
+            step, self.build_platforms, self.separate_local_registry, self.container_builder
+        ));
+
+        for entry in entries.iter() {
+            hasher.update(entry.as_bytes());
+        }
+
+        for (key, value) in StackBuilder::build_script_env(node, &step).ok()?.iter() {
+            hasher.update(format!("{}={}\n", key, value).as_bytes());
+        }
+
+        Some(BASE32.encode(&hasher.finalize()))
+    }
+
+    fn collect_file_stamps(path: &Path, entries: &mut Vec<String>) {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return,
+        };
+
+        if metadata.is_dir() {
+            if path.file_name().map_or(false, |name| name == ".git") {
+                return;
+            }
+
+            if let Ok(dir) = path.read_dir() {
+                for entry in dir.flatten() {
+                    StackBuilder::collect_file_stamps(&entry.path(), entries);
+                }
+            }
+        } else {
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |duration| duration.as_nanos());
+
+            entries.push(format!("{}:{}:{}", path.display(), metadata.len(), modified));
+        }
+    }
+
     fn build_node(&self, node: &ArtifactNodeRepr) -> Result<(), TorbBuilderErrors> {
         if let Some(step) = node.build_step.clone() {
             if step.dockerfile != "" {
                 let name = node.display_name(false);
+                let platforms = self.node_platforms(node)?;
 
-                self.build_docker(&name, step.dockerfile, step.tag, step.registry)
+                self.build_docker(&node.fqn, &name, step.dockerfile, step.tag, step.registry, &platforms)
                     .and_then(|_| Ok(()))
+            } else if step.dockerfile_inline != "" {
+                let name = node.display_name(false);
+                let platforms = self.node_platforms(node)?;
+
+                self.build_inline_docker(&node.fqn, &name, &step, &platforms)
             } else if step.script_path != "" {
-                self.build_script(step.script_path).and_then(|_| Ok(()))
+                let env = StackBuilder::build_script_env(node, &step)?;
+
+                self.build_script(step.script_path, &env).and_then(|_| Ok(()))
             } else {
                 Err(TorbBuilderErrors::MustDefineDockerfileOrBuildScript)
             }
@@ -98,40 +728,247 @@ impl<'a> StackBuilder<'a> {
         }
     }
 
+    /*
+        Inline dockerfiles are written to the buildstate and passed by absolute path, so nothing is added to the project's
+        source directory, where it could clobber a user's file, change the fingerprint or wake up `stack watch`.
+    */
+    fn build_inline_docker(&self, fqn: &str, name: &str, step: &BuildStep, platforms: &str) -> Result<(), TorbBuilderErrors> {
+        let write_error = |path: String, response: String| TorbBuilderErrors::UnableToWriteInlineDockerfile { path, response };
+
+        let dockerfiles_dir = buildstate_path_or_create()
+            .map_err(|err| write_error(INLINE_DOCKERFILE_DIR.to_string(), err.to_string()))?
+            .join(INLINE_DOCKERFILE_DIR);
+        let dockerfile_path = dockerfiles_dir.join(format!("{}.Dockerfile", fqn));
+
+        if !self.dryrun {
+            fs::create_dir_all(&dockerfiles_dir)
+                .and_then(|_| fs::write(&dockerfile_path, &step.dockerfile_inline))
+                .map_err(|err| write_error(dockerfile_path.display().to_string(), err.to_string()))?;
+        }
+
+        self.build_docker(
+            fqn,
+            name,
+            dockerfile_path.display().to_string(),
+            step.tag.clone(),
+            step.registry.clone(),
+            platforms,
+        )
+        .map(|_| ())
+    }
+
     fn build_docker(
         &self,
+        fqn: &str,
         name: &str,
         dockerfile: String,
         tag: String,
         registry: String,
+        platforms: &str,
     ) -> Result<Vec<Output>, TorbBuilderErrors> {
-        let current_dir = std::env::current_dir().unwrap();
-        let dockerfile_dir = current_dir.join(name);
+        let dockerfile_dir = self.root().join(name);
+        let registry = resolve_registry(&registry)?;
 
         let label = if registry != "local" && registry != "" {
             format!("{}/{}:{}", registry, name, tag)
         } else {
             format!("{}:{}", name, tag)
         };
+        let manifest_destination = format!("docker://{}", label);
+        let dockerfile_dir_str = dockerfile_dir.to_str().unwrap();
+
+        let commands = if self.container_builder == ContainerBuilder::Podman {
+            self.podman_commands(&label, &manifest_destination, &dockerfile, &registry, dockerfile_dir_str, platforms)
+        } else {
+            self.docker_commands(&label, &dockerfile, &registry, dockerfile_dir_str, platforms)
+        };
+
+        let commands: Vec<CommandConfig> = commands
+            .into_iter()
+            .map(|conf| conf.with_streamed_output(fqn))
+            .collect();
+
+        if self.dryrun {
+            println!("{:?}", commands);
+
+            Ok(vec![])
+        } else {
+            let mut pipeline = CommandPipeline::new(Some(commands));
+
+            let out = pipeline.execute().map_err(|err| {
+                if self.is_insecure_registry(&registry) {
+                    TorbBuilderErrors::UnableToPushToInsecureRegistry {
+                        host: registry_host(&registry).to_string(),
+                        response: err.to_string(),
+                    }
+                } else {
+                    TorbBuilderErrors::UnableToBuildDockerfile {
+                        response: err.to_string(),
+                    }
+                }
+            })?;
+
+            // Only pushes to a remote registry without a separate local registry build for more than one platform.
+            if self.verify_platforms && registry != "local" && !self.separate_local_registry {
+                self.verify_pushed_platforms(&label, platforms)?;
+            }
+
+            Ok(out)
+        }
+    }
+
+    /*
+        A platform can fail to build without failing the push, leaving a manifest list that's missing it.
+        The pushed manifest is read back and every platform passed with -p has to be in it, a requested platform
+        without a variant matches any variant of it, so linux/arm64 is satisfied by linux/arm64/v8.
+    */
+    fn verify_pushed_platforms(&self, label: &str, platforms: &str) -> Result<(), TorbBuilderErrors> {
+        let conf = if self.container_builder == ContainerBuilder::Podman {
+            CommandConfig::new("podman", vec!["manifest", "inspect", label], None)
+        } else {
+            CommandConfig::new("docker", vec!["buildx", "imagetools", "inspect", "--raw", label], None)
+        };
+
+        let inspect_err = |response: String| TorbBuilderErrors::UnableToInspectManifest {
+            label: label.to_string(),
+            response,
+        };
+
+        let output = CommandPipeline::execute_single(conf).map_err(|err| inspect_err(err.to_string()))?;
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&output.stdout).map_err(|err| inspect_err(err.to_string()))?;
+
+        let found = manifest["manifests"]
+            .as_array()
+            .map(|manifests| {
+                manifests
+                    .iter()
+                    .filter_map(|entry| {
+                        let platform = &entry["platform"];
+                        let os = platform["os"].as_str()?;
+                        let architecture = platform["architecture"].as_str()?;
+
+                        match platform["variant"].as_str() {
+                            Some(variant) => Some(format!("{}/{}/{}", os, architecture, variant)),
+                            None => Some(format!("{}/{}", os, architecture)),
+                        }
+                    })
+                    .filter(|platform| platform != "unknown/unknown")
+                    .collect::<Vec<String>>()
+            })
+            .unwrap_or_default();
+
+        let missing = platforms
+            .split(',')
+            .map(|platform| platform.trim())
+            .filter(|platform| !platform.is_empty())
+            .filter(|requested| {
+                !found
+                    .iter()
+                    .any(|platform| platform == requested || platform.starts_with(&format!("{}/", requested)))
+            })
+            .collect::<Vec<&str>>();
+
+        if missing.is_empty() {
+            info!("{} has every requested platform.", label);
+
+            Ok(())
+        } else {
+            Err(TorbBuilderErrors::MissingPlatforms {
+                label: label.to_string(),
+                missing: missing.join(", "),
+                found: if found.is_empty() { "none".to_string() } else { found.join(", ") },
+            })
+        }
+    }
+
+    // Podman has no buildx builder instances, multi-platform images are assembled as a manifest list and pushed separately.
+    fn podman_commands<'b>(
+        &'b self,
+        label: &'b str,
+        manifest_destination: &'b str,
+        dockerfile: &'b str,
+        registry: &str,
+        dockerfile_dir: &'b str,
+        platforms: &'b str,
+    ) -> Vec<CommandConfig<'b>> {
+        // Only passed for insecure registries, an explicit flag would override podman's own registries.conf.
+        let tls_flags = if self.is_insecure_registry(registry) { vec!["--tls-verify=false"] } else { vec![] };
+
+        if registry == "local" {
+            vec![CommandConfig::new(
+                "podman",
+                vec!["build", "-t", label, ".", "-f", dockerfile],
+                Some(dockerfile_dir),
+            )]
+        } else if self.separate_local_registry {
+            vec![
+                CommandConfig::new(
+                    "podman",
+                    vec!["build", "-t", label, ".", "-f", dockerfile],
+                    Some(dockerfile_dir),
+                ),
+                CommandConfig::new(
+                    "podman",
+                    [vec!["push"], tls_flags, vec![label]].concat(),
+                    Some(dockerfile_dir),
+                ),
+            ]
+        } else {
+            vec![
+                CommandConfig::new(
+                    "podman",
+                    vec![
+                        "build",
+                        "--platform",
+                        platforms,
+                        "--manifest",
+                        label,
+                        ".",
+                        "-f",
+                        dockerfile,
+                    ],
+                    Some(dockerfile_dir),
+                ),
+                CommandConfig::new(
+                    "podman",
+                    [vec!["manifest", "push", "--all"], tls_flags, vec![label, manifest_destination]].concat(),
+                    Some(dockerfile_dir),
+                ),
+            ]
+        }
+    }
+
+    fn docker_commands<'b>(
+        &'b self,
+        label: &'b str,
+        dockerfile: &'b str,
+        registry: &str,
+        dockerfile_dir: &'b str,
+        platforms: &'b str,
+    ) -> Vec<CommandConfig<'b>> {
         // Todo(Ian): Refactor this to not be so ugly when you feel like dealing with the lifetimes. 
-        let commands = if registry != "local" {
+        if registry != "local" {
             if self.separate_local_registry {
+                // The default builder pushes through the docker daemon, which only trusts insecure registries listed in its daemon.json.
+                let builder = if self.is_insecure_registry(registry) { self.buildx_builder.as_str() } else { "default" };
+
                 vec![
                     CommandConfig::new(
                         "docker",
                         vec![
                             "buildx",
                             "--builder",
-                            "default",
+                            builder,
                             "build",
                             "-t",
-                            &label,
+                            label,
                             ".",
                             "-f",
-                            &dockerfile,
+                            dockerfile,
                             "--push"
                         ],
-                        Some(&dockerfile_dir.to_str().unwrap()),
+                        Some(dockerfile_dir),
                     ),
                 ]
             } else {
@@ -141,18 +978,18 @@ impl<'a> StackBuilder<'a> {
                         vec![
                             "buildx",
                             "--builder",
-                            "torb_builder",
+                            &self.buildx_builder,
                             "build",
                             "--platform",
-                            &self.build_platforms,
+                            platforms,
                             "-t",
-                            &label,
+                            label,
                             ".",
                             "-f",
-                            &dockerfile,
+                            dockerfile,
                             "--push"
                         ],
-                        Some(&dockerfile_dir.to_str().unwrap()),
+                        Some(dockerfile_dir),
                     ),
                 ]
             }
@@ -162,53 +999,74 @@ impl<'a> StackBuilder<'a> {
                 vec![
                     "buildx",
                     "--builder",
-                    "torb_builder",
+                    &self.buildx_builder,
                     "build",
                     "-t",
-                    &label,
+                    label,
                     ".",
                     "-f",
-                    &dockerfile,
+                    dockerfile,
                     "--load",
                 ],
-                Some(&dockerfile_dir.to_str().unwrap()),
+                Some(dockerfile_dir),
             )]
-        };
+        }
+    }
 
-        if self.dryrun {
-            println!("{:?}", commands);
+    /*
+        Build scripts get the node's inputs as TORB_INPUT_<KEY>, the input's name uppercased with anything other than
+        letters and digits replaced by _, and the build step's tag and registry as TORB_TAG and TORB_REGISTRY.
+        Inputs that read another node's outputs aren't known until the stack is deployed, so they're left out.
+    */
+    fn build_script_env(node: &ArtifactNodeRepr, step: &BuildStep) -> Result<Vec<(String, String)>, TorbBuilderErrors> {
+        let mut env = vec![
+            ("TORB_TAG".to_string(), step.tag.clone()),
+            ("TORB_REGISTRY".to_string(), resolve_registry(&step.registry)?),
+        ];
 
-            Ok(vec![])
-        } else {
-            let mut pipeline = CommandPipeline::new(Some(commands));
+        for (key, (_, input)) in node.mapped_inputs.iter() {
+            if let TorbInput::String(value) = input {
+                if is_template(value) || InputAddress::try_from(value.as_str()).is_ok() {
+                    continue;
+                }
+            }
 
-            let out = pipeline
-                .execute()
-                .map_err(|err| TorbBuilderErrors::UnableToBuildDockerfile {
-                    response: err.to_string(),
-                });
+            let env_key = key
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+                .collect::<String>();
 
-            out
+            env.push((format!("{}{}", BUILD_SCRIPT_INPUT_PREFIX, env_key), input.env_value()));
         }
+
+        Ok(env)
     }
 
-    fn build_script(&self, script_path: String) -> Result<Output, TorbBuilderErrors> {
-        let contents = fs::read_to_string(script_path).unwrap();
+    fn build_script(&self, script_path: String, env: &[(String, String)]) -> Result<Output, TorbBuilderErrors> {
+        let script_path = self.root().join(&script_path);
+        let contents = fs::read_to_string(&script_path).map_err(|err| TorbBuilderErrors::UnableToReadBuildScript {
+            path: script_path.display().to_string(),
+            response: err.to_string(),
+        })?;
 
         if self.dryrun {
-            println!("{:?}", contents);
+            for (key, value) in env.iter() {
+                println!("{}={}", key, self.artifact.redact(value));
+            }
 
-            let out = Command::new("")
-                .output()
-                .expect("Failed to run nop command for build script dryrun.");
+            println!("{:?}", contents);
 
-            Ok(out)
+            Ok(Output {
+                status: ExitStatus::default(),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            })
         } else {
             let lines: Vec<&str> = contents.split("\n").collect();
 
             let script_string = lines.join("&&");
 
-            run_command_in_user_shell(script_string, None).map_err(|err| {
+            run_command_in_user_shell_from(&self.root(), script_string, None, env, None).map_err(|err| {
                 TorbBuilderErrors::UnableToBuildBuildScript {
                     response: err.to_string(),
                 }
@@ -229,13 +1087,32 @@ impl<'a> StackBuilder<'a> {
         }
 
         if !self.built.contains(&node.fqn) {
-            self.build_node(&node).and_then(|_out| {
-                if self.built.insert(node.fqn.clone()) {
-                    Ok(())
-                } else {
-                    Err(TorbBuilderErrors::NodeAlreadyBuilt)
+            let fingerprint = self.fingerprint(node);
+
+            let unchanged = !self.force
+                && fingerprint.is_some()
+                && self.manifest.nodes.get(&node.fqn) == fingerprint.as_ref();
+
+            let queue = self.separate_local_registry && !unchanged;
+
+            if unchanged {
+                info!("{} is unchanged since the last build, skipping.", node.fqn);
+            } else if queue {
+                self.queued.push((node.clone(), fingerprint.clone()));
+            } else {
+                timed("build", Some(&node.fqn), || self.build_node(&node))?;
+            }
+
+            if !self.built.insert(node.fqn.clone()) {
+                return Err(Box::new(TorbBuilderErrors::NodeAlreadyBuilt));
+            }
+
+            if let Some(fingerprint) = fingerprint {
+                if !self.dryrun && !unchanged && !queue {
+                    self.manifest.nodes.insert(node.fqn.clone(), fingerprint);
+                    self.manifest.save()?;
                 }
-            })?;
+            }
         }
 
         Ok(())
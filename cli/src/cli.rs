@@ -16,10 +16,77 @@ pub fn cli() -> Command<'static> {
         .version("1.0.0")
         .author("Torb Foundry")
         .setting(AppSettings::ArgRequiredElseHelp)
-        .subcommand(SubCommand::with_name("version").about("Get the version of this torb."))
+        .arg(
+            Arg::new("--quiet")
+                .short('q')
+                .long("quiet")
+                .takes_value(false)
+                .global(true)
+                .help("Only print warnings, errors and the output of the command. Progress messages are hidden."),
+        )
+        .arg(
+            Arg::new("--no-color")
+                .long("no-color")
+                .takes_value(false)
+                .global(true)
+                .help("Print without colors, also done when the NO_COLOR environment variable is set."),
+        )
+        .arg(
+            Arg::new("--dir")
+                .short('C')
+                .long("dir")
+                .value_name("PATH")
+                .takes_value(true)
+                .global(true)
+                .help("Run against the project in this directory instead of the current one. Relative paths, including the stack file and the buildstate, are resolved from it."),
+        )
+        .subcommand(
+            SubCommand::with_name("version")
+                .about("Get the version of this torb.")
+                .arg(
+                    Arg::new("--check")
+                        .long("check")
+                        .takes_value(false)
+                        .help("Check that this version of Torb is compatible with the installed torb-artifacts."),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("init").about("Initialize Torb, download artifacts and tools."),
         )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("Check that the tools, ~/.torb and config.yaml Torb needs are in place and that the cluster is reachable."),
+        )
+        .subcommand(
+            SubCommand::with_name("clean")
+                .about("Remove the generated IaC environments and cached stack resolutions from the project's buildstate.")
+                .arg(
+                    Arg::new("--iac")
+                        .long("iac")
+                        .takes_value(false)
+                        .conflicts_with("--all")
+                        .help("Only remove the generated IaC environments."),
+                )
+                .arg(
+                    Arg::new("--all")
+                        .long("all")
+                        .takes_value(false)
+                        .help("Also remove build files, the build manifest and the record of which units have been initialized."),
+                )
+                .arg(
+                    Arg::new("--force")
+                        .short('f')
+                        .long("force")
+                        .takes_value(false)
+                        .help("Don't ask for confirmation before removing anything."),
+                )
+                .arg(
+                    Arg::new("--buildstate-dir")
+                        .long("buildstate-dir")
+                        .takes_value(true)
+                        .help("Directory the buildstate is kept in, instead of .torb_buildstate in the current directory. Overrides TORB_BUILDSTATE_DIR."),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("repo")
                 .about("Verbs for interacting with project repos.")
@@ -41,6 +108,34 @@ pub fn cli() -> Command<'static> {
                                 .takes_value(false)
                                 .help("Only create the repo locally."),
                         ),
+                )
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("List the repositories Torb has created."),
+                )
+                .subcommand(
+                    SubCommand::with_name("delete")
+                        .about("Delete a repository Torb created, locally and on the remote.")
+                        .arg(
+                            Arg::with_name("path")
+                                .takes_value(true)
+                                .required(true)
+                                .index(1)
+                                .help("Path of the repo to delete."),
+                        )
+                        .arg(
+                            Arg::new("--local-only")
+                                .short('l')
+                                .long("local-only")
+                                .takes_value(false)
+                                .help("Only delete the local repo, leave the remote in place."),
+                        )
+                        .arg(
+                            Arg::new("--confirm")
+                                .long("confirm")
+                                .takes_value(false)
+                                .help("Confirm that the repo should be deleted, the remote as well unless --local-only is passed."),
+                        ),
                 ),
         )
         .subcommand(
@@ -68,6 +163,27 @@ pub fn cli() -> Command<'static> {
             SubCommand::with_name("stack")
                 .about("Verbs for interacting with Torb stacks.")
                 .setting(AppSettings::ArgRequiredElseHelp)
+                .arg(
+                    Arg::new("--buildstate-dir")
+                        .long("buildstate-dir")
+                        .takes_value(true)
+                        .global(true)
+                        .help("Directory to keep the buildstate in, instead of .torb_buildstate in the current directory. Overrides TORB_BUILDSTATE_DIR."),
+                )
+                .arg(
+                    Arg::new("--show-secrets")
+                        .long("show-secrets")
+                        .takes_value(false)
+                        .global(true)
+                        .help("Print the values of inputs marked sensitive instead of redacting them."),
+                )
+                .arg(
+                    Arg::new("--allow-shell-inputs")
+                        .long("allow-shell-inputs")
+                        .takes_value(false)
+                        .global(true)
+                        .help("Run the commands in ${shell:...} input values while resolving the stack. They run arbitrary commands from the stack file, so they're off unless allowed here or in config.yaml."),
+                )
                 .subcommand(
                     SubCommand::with_name("checkout")
                         .about("Add a stack definition template to your current directory.")
@@ -76,7 +192,7 @@ pub fn cli() -> Command<'static> {
                                 .takes_value(true)
                                 .required(false)
                                 .index(1)
-                                .help("Name of the stack definition template to checkout."),
+                                .help("Name of the stack definition template to checkout, prefix it with its repository as <repository>:<name> when more than one repository has it."),
                         ),
                 )
                 .subcommand(
@@ -87,12 +203,33 @@ pub fn cli() -> Command<'static> {
                                 .takes_value(true)
                                 .required(true)
                                 .index(1)
-                                .help("File path of the stack definition file."),
+                                .help("File path of the stack definition file, - to read from stdin, an http(s) URL, or a stack in an artifact repository as <repository>:<name>."),
+                        )
+                        .arg(
+                            Arg::new("--dryrun")
+                                .short('d')
+                                .long("dryrun")
+                                .takes_value(false)
+                                .help("Dry run. Print the files each unit would copy and its init script with inputs resolved, without running anything."),
+                        )
+                        .arg(
+                            Arg::new("--reinit")
+                                .long("reinit")
+                                .takes_value(false)
+                                .help("Run the init steps of every unit, including the ones that have already been initialized."),
                         ),
                 )
                 .subcommand(
                     SubCommand::with_name("new")
                         .about("Create a new stack.yaml template.")
+                        .arg(
+                            Arg::new("--template")
+                                .short('t')
+                                .long("template")
+                                .takes_value(true)
+                                .value_name("NAME")
+                                .help("Template to start from, by name or as <repository>:<name>. Lists the available templates when omitted."),
+                        )
                 )
                 .subcommand(
                     SubCommand::with_name("build")
@@ -102,7 +239,7 @@ pub fn cli() -> Command<'static> {
                                 .takes_value(true)
                                 .required(true)
                                 .index(1)
-                                .help("File path of the stack definition file."),
+                                .help("File path of the stack definition file, - to read from stdin, or an http(s) URL."),
                         )
                         .arg(
                             Arg::new("--dryrun")
@@ -111,6 +248,110 @@ pub fn cli() -> Command<'static> {
                                 .takes_value(false)
                                 .help("Dry run. Don't actually build the stack."),
                         )
+                        .arg(
+                            Arg::new("--print-order")
+                                .long("print-order")
+                                .takes_value(false)
+                                .help("Print the order nodes will be built in and exit without building."),
+                        )
+                        .arg(
+                            Arg::new("--no-cache")
+                                .long("no-cache")
+                                .takes_value(false)
+                                .help("Resolve the stack from scratch instead of using the cached resolution."),
+                        )
+                        .arg(
+                            Arg::new("--verify-charts")
+                                .long("verify-charts")
+                                .takes_value(false)
+                                .help("Check that every Helm chart and version can be fetched before going ahead. Needs network access."),
+                        )
+                        .arg(
+                            Arg::new("--verify-mappings")
+                                .long("verify-mappings")
+                                .takes_value(false)
+                                .help("Warn about input mappings that aren't in their chart's values.schema.json. Charts without a schema are skipped."),
+                        )
+                        .arg(
+                            Arg::new("--timings")
+                                .long("timings")
+                                .takes_value(true)
+                                .value_name("PATH")
+                                .help("Write how long each phase and node took to PATH as JSON, a summary is always printed at the end."),
+                        )
+                        .arg(
+                            Arg::new("--build-file-dir")
+                                .long("build-file-dir")
+                                .takes_value(true)
+                                .value_name("PATH")
+                                .help("Write the build file to PATH instead of the buildstate, so it can be shared with a later deploy."),
+                        )
+                        .arg(
+                            Arg::new("--env")
+                                .long("env")
+                                .takes_value(true)
+                                .value_name("NAME")
+                                .help("Environment from the stack's environments section to apply over the base inputs and values."),
+                        )
+                        .arg(
+                            Arg::new("--set")
+                                .long("set")
+                                .takes_value(true)
+                                .multiple_occurrences(true)
+                                .value_name("NODE.INPUT=VALUE")
+                                .help("Override one of a node's inputs, the value is parsed as the type the unit's input spec declares. Can be passed multiple times."),
+                        )
+                        .arg(
+                            Arg::new("--namespace")
+                                .long("namespace")
+                                .takes_value(true)
+                                .value_name("NAMESPACE")
+                                .help("Deploy every node into this namespace, overriding the namespaces set in the stack and units."),
+                        )
+                        .arg(
+                            Arg::new("--context")
+                                .long("context")
+                                .takes_value(true)
+                                .value_name("NAME")
+                                .help("Kubeconfig context to run kubectl, helm and Terraform against, instead of the current context."),
+                        )
+                        .arg(
+                            Arg::new("--kubeconfig")
+                                .long("kubeconfig")
+                                .takes_value(true)
+                                .value_name("PATH")
+                                .help("Path to the kubeconfig to use, instead of the default kubeconfig."),
+                        )
+                        .arg(
+                            Arg::new("--force")
+                                .short('f')
+                                .long("force")
+                                .takes_value(false)
+                                .help("Rebuild every node, even if its sources haven't changed since the last build."),
+                        )
+                        .arg(
+                            Arg::new("--verify-platforms")
+                                .long("verify-platforms")
+                                .takes_value(false)
+                                .help("After pushing a multi-platform image, check that its manifest has every platform passed to -p."),
+                        )
+                        .arg(
+                            Arg::new("--no-animation")
+                                .long("no-animation")
+                                .takes_value(false)
+                                .help("Don't show the build animation. It's already skipped when stdout isn't a terminal or TORB_NO_ANIMATION is set."),
+                        )
+                        .arg(
+                            Arg::new("--build-jobs")
+                                .long("build-jobs")
+                                .takes_value(true)
+                                .value_name("N")
+                                .validator(|jobs| match jobs.parse::<usize>() {
+                                    Ok(jobs) if jobs > 0 => Ok(()),
+                                    _ => Err("must be a whole number greater than 0".to_string()),
+                                })
+                                .help("Maximum number of images to build at once when pushing to a separate local registry. Defaults to the number of CPUs."),
+                        )
                         .arg(
                             Arg::new("--platforms")
                                 .short('p')
@@ -125,6 +366,21 @@ pub fn cli() -> Command<'static> {
                                 .long("local-hosted-registry")
                                 .takes_value(false)
                                 .help("Runs the builder with the docker driver to push to a separate registry hosted on localhost (or an address pointing to localhost)"),
+                        )
+                        .arg(
+                            Arg::new("--registry-insecure")
+                                .long("registry-insecure")
+                                .takes_value(true)
+                                .multiple_occurrences(true)
+                                .value_name("HOST")
+                                .help("Push to this registry over plain HTTP without verifying TLS for this run, on top of insecure_registries in config.yaml. Only use this for registries on localhost or a network you trust. Can be passed multiple times."),
+                        )
+                        .arg(
+                            Arg::new("--builder")
+                                .long("builder")
+                                .takes_value(true)
+                                .possible_values(&["docker", "podman"])
+                                .help("Container build backend to use, overrides the builder set in config.yaml. Defaults to docker."),
                         ),
                 )
                 .subcommand(
@@ -135,7 +391,7 @@ pub fn cli() -> Command<'static> {
                                 .takes_value(true)
                                 .required(true)
                                 .index(1)
-                                .help("File path of the stack definition file."),
+                                .help("File path of the stack definition file, - to read from stdin, or an http(s) URL."),
                         )
                         .arg(
                             Arg::new("--dryrun")
@@ -143,6 +399,140 @@ pub fn cli() -> Command<'static> {
                                 .long("dryrun")
                                 .takes_value(false)
                                 .help("Dry run. Don't actually deploy the stack."),
+                        )
+                        .arg(
+                            Arg::new("--auto-approve")
+                                .long("auto-approve")
+                                .takes_value(false)
+                                .help("Deploy without asking for confirmation. Required when stdin isn't a terminal."),
+                        )
+                        .arg(
+                            Arg::new("--print-order")
+                                .long("print-order")
+                                .takes_value(false)
+                                .help("Print the order nodes will be deployed in and exit without deploying."),
+                        )
+                        .arg(
+                            Arg::new("--no-cache")
+                                .long("no-cache")
+                                .takes_value(false)
+                                .help("Resolve the stack from scratch instead of using the cached resolution."),
+                        )
+                        .arg(
+                            Arg::new("--verify-charts")
+                                .long("verify-charts")
+                                .takes_value(false)
+                                .help("Check that every Helm chart and version can be fetched before going ahead. Needs network access."),
+                        )
+                        .arg(
+                            Arg::new("--verify-mappings")
+                                .long("verify-mappings")
+                                .takes_value(false)
+                                .help("Warn about input mappings that aren't in their chart's values.schema.json. Charts without a schema are skipped."),
+                        )
+                        .arg(
+                            Arg::new("--validate")
+                                .long("validate")
+                                .takes_value(false)
+                                .help("Dry run every node's manifests against the cluster before applying anything, helm charts are rendered with helm template first."),
+                        )
+                        .arg(
+                            Arg::new("--timings")
+                                .long("timings")
+                                .takes_value(true)
+                                .value_name("PATH")
+                                .help("Write how long each phase and node took to PATH as JSON, a summary is always printed at the end."),
+                        )
+                        .arg(
+                            Arg::new("--build-file-dir")
+                                .long("build-file-dir")
+                                .takes_value(true)
+                                .value_name("PATH")
+                                .help("Read the build file from PATH instead of the buildstate, pass the directory the stack was built with."),
+                        )
+                        .arg(
+                            Arg::new("--env")
+                                .long("env")
+                                .takes_value(true)
+                                .value_name("NAME")
+                                .help("Environment from the stack's environments section to apply over the base inputs and values."),
+                        )
+                        .arg(
+                            Arg::new("--set")
+                                .long("set")
+                                .takes_value(true)
+                                .multiple_occurrences(true)
+                                .value_name("NODE.INPUT=VALUE")
+                                .help("Override one of a node's inputs, the value is parsed as the type the unit's input spec declares. Can be passed multiple times."),
+                        )
+                        .arg(
+                            Arg::new("--readiness-timeout")
+                                .long("readiness-timeout")
+                                .takes_value(true)
+                                .value_name("SECONDS")
+                                .validator(|secs| match secs.parse::<u64>() {
+                                    Ok(secs) if secs > 0 => Ok(()),
+                                    _ => Err("must be a whole number of seconds greater than 0".to_string()),
+                                })
+                                .help("How long to wait for each deployed node to become ready before failing. Defaults to 300."),
+                        )
+                        .arg(
+                            Arg::new("--wait-for-stable")
+                                .long("wait-for-stable")
+                                .takes_value(false)
+                                .help("After deploying, wait for every node's workload to be rolled out at the same time and print the status of each."),
+                        )
+                        .arg(
+                            Arg::new("--stable-timeout")
+                                .long("stable-timeout")
+                                .takes_value(true)
+                                .value_name("SECONDS")
+                                .requires("--wait-for-stable")
+                                .validator(|secs| match secs.parse::<u64>() {
+                                    Ok(_) => Ok(()),
+                                    _ => Err("must be a whole number of seconds".to_string()),
+                                })
+                                .help("How long --wait-for-stable waits for the whole stack before failing. Defaults to 600."),
+                        )
+                        .arg(
+                            Arg::new("--tf-parallelism")
+                                .long("tf-parallelism")
+                                .takes_value(true)
+                                .value_name("N")
+                                .validator(|parallelism| match parallelism.parse::<u32>() {
+                                    Ok(parallelism) if parallelism > 0 => Ok(()),
+                                    _ => Err("must be a whole number greater than 0".to_string()),
+                                })
+                                .help("Limit how many resources Terraform applies at once, overriding tf_parallelism in the stack. This doesn't change the order nodes deploy in."),
+                        )
+                        .arg(
+                            Arg::new("--namespace")
+                                .long("namespace")
+                                .takes_value(true)
+                                .value_name("NAMESPACE")
+                                .help("Deploy every node into this namespace, overriding the namespaces set in the stack and units."),
+                        )
+                        .arg(
+                            Arg::new("--context")
+                                .long("context")
+                                .takes_value(true)
+                                .value_name("NAME")
+                                .help("Kubeconfig context to run kubectl, helm and Terraform against, instead of the current context."),
+                        )
+                        .arg(
+                            Arg::new("--kubeconfig")
+                                .long("kubeconfig")
+                                .takes_value(true)
+                                .value_name("PATH")
+                                .help("Path to the kubeconfig to use, instead of the default kubeconfig."),
+                        )
+                        .arg(
+                            Arg::new("--values")
+                                .long("values")
+                                .takes_value(true)
+                                .multiple_occurrences(true)
+                                .value_name("[NODE=]PATH")
+                                .help("YAML file of Helm values to merge over the stack values. Prefix with a node name to only apply to that node. Can be passed multiple times, later files override earlier ones."),
                         ),
                 )
                 .subcommand(
@@ -155,14 +545,228 @@ pub fn cli() -> Command<'static> {
                                 .index(1)
                                 .help("File path of the stack definition file."),
                         )
+                        .arg(
+                            Arg::new("--namespace")
+                                .long("namespace")
+                                .takes_value(true)
+                                .value_name("NAMESPACE")
+                                .help("Deploy every node into this namespace, overriding the namespaces set in the stack and units."),
+                        )
+                        .arg(
+                            Arg::new("--context")
+                                .long("context")
+                                .takes_value(true)
+                                .value_name("NAME")
+                                .help("Kubeconfig context to run kubectl, helm and Terraform against, instead of the current context."),
+                        )
+                        .arg(
+                            Arg::new("--kubeconfig")
+                                .long("kubeconfig")
+                                .takes_value(true)
+                                .value_name("PATH")
+                                .help("Path to the kubeconfig to use, instead of the default kubeconfig."),
+                        )
                         .arg(
                             Arg::new("--local-hosted-registry")
                                 .short('l')
                                 .long("local-hosted-registry")
                                 .takes_value(false)
                                 .help("Runs the builder with the docker driver to push to a separate registry hosted on localhost (or an address pointing to localhost)"),
+                        )
+                        .arg(
+                            Arg::new("--registry-insecure")
+                                .long("registry-insecure")
+                                .takes_value(true)
+                                .multiple_occurrences(true)
+                                .value_name("HOST")
+                                .help("Push to this registry over plain HTTP without verifying TLS for this run, on top of insecure_registries in config.yaml. Only use this for registries on localhost or a network you trust. Can be passed multiple times."),
+                        )
+                        .arg(
+                            Arg::new("--no-build")
+                                .long("no-build")
+                                .takes_value(false)
+                                .conflicts_with("--no-deploy")
+                                .help("Don't build images, on start or on changes. Changes are only rolled out, for images built outside of Torb."),
+                        )
+                        .arg(
+                            Arg::new("--no-deploy")
+                                .long("no-deploy")
+                                .takes_value(false)
+                                .help("Don't run Terraform on start, only build. Changes are rebuilt and rolled out to the stack as it's already deployed."),
+                        )
+                        .arg(
+                            Arg::new("--builder")
+                                .long("builder")
+                                .takes_value(true)
+                                .possible_values(&["docker", "podman"])
+                                .help("Container build backend to use, overrides the builder set in config.yaml. Defaults to docker."),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("List all available stacks.")
+                        .arg(
+                            Arg::new("--format")
+                                .long("format")
+                                .takes_value(true)
+                                .possible_values(&["tree", "flat", "json"])
+                                .default_value("tree")
+                                .help("tree groups stacks under their repository with counts, flat prints one stack per line and json prints them as JSON."),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("compose")
+                        .about("Generate the Terraform for a stack into a directory without deploying it.")
+                        .arg(
+                            Arg::with_name("file")
+                                .takes_value(true)
+                                .required(true)
+                                .index(1)
+                                .help("File path of the stack definition file."),
+                        )
+                        .arg(
+                            Arg::new("--out")
+                                .short('o')
+                                .long("out")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Directory to write the generated IaC environment to."),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("graph")
+                        .about("Export the dependency graph of a stack as Graphviz DOT.")
+                        .arg(
+                            Arg::with_name("file")
+                                .takes_value(true)
+                                .required(true)
+                                .index(1)
+                                .help("File path of the stack definition file."),
+                        )
+                        .arg(
+                            Arg::new("--out")
+                                .short('o')
+                                .long("out")
+                                .takes_value(true)
+                                .help("File to write the DOT output to. Defaults to stdout."),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("lint")
+                        .about("Check a stack for common mistakes.")
+                        .arg(
+                            Arg::with_name("file")
+                                .takes_value(true)
+                                .required(true)
+                                .index(1)
+                                .help("File path of the stack definition file."),
+                        )
+                        .arg(
+                            Arg::new("--deny-warnings")
+                                .long("deny-warnings")
+                                .takes_value(false)
+                                .help("Exit with an error if lint finds anything."),
                         ),
                 )
-                .subcommand(SubCommand::with_name("list").about("List all available stacks.")),
+                .subcommand(
+                    SubCommand::with_name("outputs")
+                        .about("Show the outputs of each node in a stack and which nodes consume them.")
+                        .arg(
+                            Arg::with_name("file")
+                                .takes_value(true)
+                                .required(true)
+                                .index(1)
+                                .help("File path of the stack definition file."),
+                        )
+                        .arg(
+                            Arg::new("--json")
+                                .long("json")
+                                .takes_value(false)
+                                .help("Print the outputs as JSON."),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("render-values")
+                        .about("Print the inputs and Helm values each node would be deployed with, without writing Terraform.")
+                        .arg(
+                            Arg::with_name("file")
+                                .takes_value(true)
+                                .required(true)
+                                .index(1)
+                                .help("File path of the stack definition file."),
+                        )
+                        .arg(
+                            Arg::new("--env")
+                                .long("env")
+                                .takes_value(true)
+                                .value_name("NAME")
+                                .help("Environment from the stack's environments section to apply over the base inputs and values."),
+                        )
+                        .arg(
+                            Arg::new("--values")
+                                .long("values")
+                                .takes_value(true)
+                                .multiple_occurrences(true)
+                                .value_name("[NODE=]PATH")
+                                .help("YAML file of Helm values to merge over the stack values, as it would be passed to deploy. Can be passed multiple times."),
+                        )
+                        .arg(
+                            Arg::new("--json")
+                                .long("json")
+                                .takes_value(false)
+                                .help("Print the rendered values as JSON."),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("prune")
+                        .about("Uninstall Helm releases left in the cluster by nodes that have been removed from the stack.")
+                        .arg(
+                            Arg::with_name("file")
+                                .takes_value(true)
+                                .required(true)
+                                .index(1)
+                                .help("File path of the stack definition file."),
+                        )
+                        .arg(
+                            Arg::new("--env")
+                                .long("env")
+                                .takes_value(true)
+                                .value_name("NAME")
+                                .help("Environment the stack was deployed with, so nodes it disables are treated the same way."),
+                        )
+                        .arg(
+                            Arg::new("--namespace")
+                                .long("namespace")
+                                .takes_value(true)
+                                .value_name("NAMESPACE")
+                                .help("Namespace the stack was deployed into with --namespace."),
+                        )
+                        .arg(
+                            Arg::new("--context")
+                                .long("context")
+                                .takes_value(true)
+                                .value_name("NAME")
+                                .help("Kubeconfig context to run kubectl, helm and Terraform against, instead of the current context."),
+                        )
+                        .arg(
+                            Arg::new("--kubeconfig")
+                                .long("kubeconfig")
+                                .takes_value(true)
+                                .value_name("PATH")
+                                .help("Path to the kubeconfig to use, instead of the default kubeconfig."),
+                        )
+                        .arg(
+                            Arg::new("--auto-approve")
+                                .long("auto-approve")
+                                .takes_value(false)
+                                .help("Uninstall without asking for confirmation. Required when stdin isn't a terminal."),
+                        )
+                        .arg(
+                            Arg::new("--dryrun")
+                                .long("dryrun")
+                                .takes_value(false)
+                                .help("List the orphaned releases without uninstalling them."),
+                        ),
+                ),
         )
 }
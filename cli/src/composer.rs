@@ -9,21 +9,443 @@
 //
 // See LICENSE file at https://github.com/TorbFoundry/torb/blob/main/LICENSE for details.
 
-use crate::artifacts::{ArtifactNodeRepr, ArtifactRepr, TorbInput, TorbNumeric};
+pub mod deploy_tools;
+
+use crate::artifacts::{ArtifactNodeRepr, ArtifactRepr, TorbInput};
+use crate::builder::resolve_registry;
+use crate::composer::deploy_tools::{deploy_tool, deploy_tool_names};
+use crate::config::TORB_CONFIG;
 use crate::resolver::inputs::{InputResolver, NO_INPUTS_FN, NO_VALUES_FN, NO_INITS_FN};
-use crate::utils::{buildstate_path_or_create, for_each_artifact_repository, torb_path, kebab_to_snake_case, snake_case_to_kebab};
+use crate::resolver::template::{is_template, parse_template, referenced_addresses, TemplateValue, SELF_INPUTS_PREFIX};
+use crate::timings::timed;
+use crate::utils::{buildstate_path_or_create, for_each_artifact_repository, project_path, torb_path, kebab_to_snake_case, snake_case_to_kebab, merge_yaml_values, kube_target, secrets_shown, shell_quote, CommandConfig, CommandPipeline, TorbUtilityErrors, REDACTED};
 
-use hcl::{Block, Body, Expression, Object, ObjectKey, RawExpression, Number};
+use hcl::{Block, Body, Expression, Object, ObjectKey, RawExpression};
 use serde::{Deserialize, Serialize};
 use serde_yaml::{Mapping, Value};
 use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use thiserror::Error;
 use indexmap::{IndexSet, IndexMap};
+use log::{debug, info};
 
 #[derive(Error, Debug)]
-pub enum TorbComposerErrors {}
+pub enum TorbComposerErrors {
+    #[error("Unable to read values file {path}, reason: {reason}")]
+    UnableToReadValuesFile { path: String, reason: String },
+    #[error("Values file {path} targets node {node}, which is not in the stack.")]
+    UnknownValuesNode { path: String, node: String },
+    #[error("{fqn} has no deploy step, units need a helm or kubectl entry under deploy in their torb.yaml.")]
+    MissingDeployStep { fqn: String },
+    #[error("{fqn} uses the deploy tool {tool}, which Torb doesn't support. Supported deploy tools are {supported}.")]
+    UnsupportedDeployTool { fqn: String, tool: String, supported: String },
+    #[error("{fqn} deploys with kubectl but doesn't set manifests, this should be the path to a manifest file or directory relative to the unit.")]
+    MissingKubectlManifests { fqn: String },
+    #[error("{fqn} sets {key} to {address}, which looks like an address but isn't one. Addresses are {expected}.")]
+    MalformedInputAddress { fqn: String, key: String, address: String, expected: &'static str },
+    #[error("Some nodes reference outputs that don't exist:\n\n{references}")]
+    InvalidOutputReferences { references: String },
+    #[error("{fqn} sets array_rendering to {value}, supported values are set and yaml.")]
+    UnsupportedArrayRendering { fqn: String, value: String },
+    #[error("{fqn} maps a nested array to {path}, nested arrays can't be rendered into Helm values.")]
+    NestedArrayInput { fqn: String, path: String },
+    #[error("{fqn} mixes an oci:// chart reference with the chart repository {repository}, set either an oci:// repository and a chart name or a full oci:// chart reference without a repository.")]
+    MixedChartSources { fqn: String, repository: String },
+    #[error("{fqn} sets the chart {chart}, oci:// chart references need a registry and a chart name, e.g. oci://registry.example.com/charts/app.")]
+    InvalidOciChart { fqn: String, chart: String },
+    #[error("{fqn} uses the local chart {chart}, which doesn't exist. Relative chart paths are looked up in the unit's directory {unit_dir} and then in ~/.torb, or use an absolute path.")]
+    MissingLocalChart { fqn: String, chart: String, unit_dir: String },
+    #[error("Some charts couldn't be found:\n\n{charts}")]
+    UnreachableCharts { charts: String },
+    #[error("Some nodes' manifests were rejected by the cluster:\n\n{manifests}")]
+    InvalidManifests { manifests: String },
+    #[error("Some nodes would deploy to the same Helm release and overwrite each other:\n\n{collisions}")]
+    ReleaseCollisions { collisions: String },
+}
+
+const ARRAY_RENDERING_KEY: &str = "array_rendering";
+
+/*
+    Mapped inputs reach Helm as individual set values through the inputs object, so arrays are rendered in Helm's
+    brace list syntax, {a,b,c}. Charts that need a real list can set array_rendering: yaml on their helm deploy step,
+    which moves array inputs out of the inputs object and into the values YAML as sequences.
+*/
+#[derive(Debug, Clone, PartialEq)]
+enum ArrayRendering {
+    Set,
+    Yaml,
+}
+
+impl ArrayRendering {
+    fn for_node(node: &ArtifactNodeRepr, helm: &IndexMap<String, String>) -> Result<ArrayRendering, TorbComposerErrors> {
+        match helm.get(ARRAY_RENDERING_KEY).map(|value| value.as_str()) {
+            None | Some("") | Some("set") => Ok(ArrayRendering::Set),
+            Some("yaml") => Ok(ArrayRendering::Yaml),
+            Some(value) => Err(TorbComposerErrors::UnsupportedArrayRendering {
+                fqn: node.fqn.clone(),
+                value: value.to_string(),
+            }),
+        }
+    }
+}
+
+const OCI_PREFIX: &str = "oci://";
+
+/*
+    Charts come from a chart repository, an OCI registry or a local chart path. OCI charts can be given
+    as an oci:// repository with a chart name, or as a full oci:// chart reference which is split into the two,
+    the Helm provider pulls from the registry when the repository is an oci:// url instead of reading a repo index.
+*/
+#[derive(Debug, Clone, PartialEq)]
+enum ChartSource {
+    Repository { repository: String, chart: String },
+    Local { chart: String },
+}
+
+impl ChartSource {
+    fn for_node(node: &ArtifactNodeRepr, helm: &IndexMap<String, String>) -> Result<ChartSource, TorbComposerErrors> {
+        let repository = helm.get("repository").cloned().unwrap_or_default();
+        let chart = helm.get("chart").cloned().unwrap_or_default();
+
+        let chart_is_oci = chart.starts_with(OCI_PREFIX);
+
+        if chart_is_oci && repository != "" {
+            return Err(TorbComposerErrors::MixedChartSources {
+                fqn: node.fqn.clone(),
+                repository,
+            });
+        }
+
+        if chart_is_oci {
+            let reference = chart.trim_start_matches(OCI_PREFIX).trim_end_matches('/');
+
+            let (registry, chart_name) = reference.rsplit_once('/').ok_or(TorbComposerErrors::InvalidOciChart {
+                fqn: node.fqn.clone(),
+                chart: chart.clone(),
+            })?;
+
+            Ok(ChartSource::Repository {
+                repository: format!("{}{}", OCI_PREFIX, registry),
+                chart: chart_name.to_string(),
+            })
+        } else if repository.starts_with(OCI_PREFIX) {
+            Ok(ChartSource::Repository {
+                repository: repository.trim_end_matches('/').to_string(),
+                chart,
+            })
+        } else if repository != "" {
+            Ok(ChartSource::Repository { repository, chart })
+        } else {
+            Ok(ChartSource::Local { chart })
+        }
+    }
+
+    /*
+        Local charts can be an absolute path or relative to the unit's torb.yaml, so charts can live next to a project's
+        source. Relative paths that aren't in the unit are still looked up under ~/.torb, where charts used to be kept.
+    */
+    fn local_chart_path(node: &ArtifactNodeRepr, chart: &str) -> Result<PathBuf, TorbComposerErrors> {
+        let unit_dir = Path::new(&node.file_path).parent().unwrap_or(Path::new(""));
+        let chart_path = Path::new(chart);
+
+        let candidates = if chart_path.is_absolute() {
+            vec![chart_path.to_path_buf()]
+        } else {
+            vec![unit_dir.join(chart_path), torb_path().join(chart_path)]
+        };
+
+        candidates
+            .iter()
+            .find_map(|candidate| fs::canonicalize(candidate).ok())
+            .ok_or_else(|| TorbComposerErrors::MissingLocalChart {
+                fqn: node.fqn.clone(),
+                chart: chart.to_string(),
+                unit_dir: unit_dir.display().to_string(),
+            })
+    }
+}
+
+// The name of the root Terraform output a helm node's declared outputs are exposed under.
+pub fn node_output_name(fqn: &str) -> String {
+    fqn.replace(".", "_")
+}
+
+const VERIFY_CHART_TIMEOUT: Duration = Duration::from_secs(60);
+
+/*
+    Checks that every helm node's chart can be fetched, so a missing chart or version fails before anything is
+    deployed instead of during terraform apply. Charts from a repository are checked with helm show chart, which
+    needs network access, and local charts are checked on disk. Every unreachable chart is reported at once.
+*/
+pub fn verify_charts(artifact: &ArtifactRepr) -> Result<(), TorbComposerErrors> {
+    let mut unreachable = vec![];
+
+    for node in artifact.nodes.values() {
+        let helm = match node.deploy_tool() {
+            Some((tool, conf)) if tool == "helm" => conf,
+            _ => continue,
+        };
+
+        let version = helm.get("version").cloned().unwrap_or_default();
+
+        let result = match ChartSource::for_node(node, &helm)? {
+            ChartSource::Repository { repository, chart } => verify_repository_chart(&repository, &chart, &version),
+            ChartSource::Local { chart } => ChartSource::local_chart_path(node, &chart)
+                .map(|_| ())
+                .map_err(|_| format!("the local chart {} doesn't exist", chart)),
+        };
+
+        if let Err(reason) = result {
+            unreachable.push(format!("{}: {}", node.fqn, reason));
+        }
+    }
+
+    if unreachable.is_empty() {
+        Ok(())
+    } else {
+        Err(TorbComposerErrors::UnreachableCharts {
+            charts: unreachable.join("\n"),
+        })
+    }
+}
+
+fn verify_repository_chart(repository: &str, chart: &str, version: &str) -> Result<(), String> {
+    let oci_chart = format!("{}/{}", repository, chart);
+
+    let mut args = if repository.starts_with(OCI_PREFIX) {
+        vec!["show", "chart", oci_chart.as_str()]
+    } else {
+        vec!["show", "chart", chart, "--repo", repository]
+    };
+
+    if version != "" {
+        args.extend(["--version", version]);
+    }
+
+    let conf = CommandConfig::new("helm", args, None).with_timeout(VERIFY_CHART_TIMEOUT);
+
+    CommandPipeline::execute_single(conf).map(|_| ()).map_err(command_error_reason)
+}
+
+// A failed command's stderr is what's worth showing, the rest of the error only repeats the command.
+fn command_error_reason(err: Box<dyn std::error::Error>) -> String {
+    match err.downcast_ref::<TorbUtilityErrors>() {
+        Some(TorbUtilityErrors::UnableToRunCommand { reason, .. }) => reason.trim().to_string(),
+        _ => err.to_string(),
+    }
+}
+
+const VALUES_SCHEMA_FILE: &str = "values.schema.json";
+
+/*
+    Checks that each helm node's input mappings are paths its chart's values.schema.json knows about, so an input
+    with a typo in its mapping doesn't silently go nowhere. This is best effort, charts without a schema, or that
+    can't be fetched, are skipped. Returns a warning per unknown mapping rather than failing, since a schema can be
+    looser than what its chart accepts.
+*/
+pub fn verify_mappings(artifact: &ArtifactRepr) -> Result<Vec<String>, TorbComposerErrors> {
+    let mut unknown = vec![];
+
+    for node in artifact.nodes.values() {
+        let helm = match node.deploy_tool() {
+            Some((tool, conf)) if tool == "helm" => conf,
+            _ => continue,
+        };
+
+        let version = helm.get("version").cloned().unwrap_or_default();
+
+        let schema = match ChartSource::for_node(node, &helm)? {
+            ChartSource::Repository { repository, chart } => repository_values_schema(&repository, &chart, &version),
+            ChartSource::Local { chart } => ChartSource::local_chart_path(node, &chart)
+                .map_err(|err| err.to_string())
+                .and_then(|path| read_values_schema(&path.join(VALUES_SCHEMA_FILE))),
+        };
+
+        let schema = match schema {
+            Ok(Some(schema)) => schema,
+            Ok(None) => {
+                debug!("{} has no {}, skipping its mappings.", node.fqn, VALUES_SCHEMA_FILE);
+                continue;
+            }
+            Err(reason) => {
+                debug!("Unable to read the values schema for {}, skipping its mappings. Reason: {}", node.fqn, reason);
+                continue;
+            }
+        };
+
+        for (key, (mapping, _)) in node.mapped_inputs.iter() {
+            if mapping != "" && !schema_has_path(&schema, mapping) {
+                unknown.push(format!(
+                    "{}: input {} maps to {}, which isn't in the chart's {}",
+                    node.fqn, key, mapping, VALUES_SCHEMA_FILE
+                ));
+            }
+        }
+    }
+
+    Ok(unknown)
+}
+
+fn read_values_schema(path: &Path) -> Result<Option<serde_json::Value>, String> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let contents = fs::read_to_string(path).map_err(|err| err.to_string())?;
+
+    serde_json::from_str(&contents).map(Some).map_err(|err| err.to_string())
+}
+
+// helm show has no way to print a chart's schema, so the chart is pulled into a temporary directory to read it.
+fn repository_values_schema(repository: &str, chart: &str, version: &str) -> Result<Option<serde_json::Value>, String> {
+    let pull_dir = tempfile::tempdir().map_err(|err| err.to_string())?;
+    let pull_dir_str = pull_dir.path().to_str().unwrap().to_string();
+    let oci_chart = format!("{}/{}", repository, chart);
+
+    let mut args = if repository.starts_with(OCI_PREFIX) {
+        vec!["pull", oci_chart.as_str()]
+    } else {
+        vec!["pull", chart, "--repo", repository]
+    };
+
+    args.extend(["--untar", "--untardir", pull_dir_str.as_str()]);
+
+    if version != "" {
+        args.extend(["--version", version]);
+    }
+
+    let conf = CommandConfig::new("helm", args, None).with_timeout(VERIFY_CHART_TIMEOUT);
+
+    CommandPipeline::execute_single(conf).map_err(command_error_reason)?;
+
+    // The chart is untarred into a directory named after it, which for OCI charts is the last part of the reference.
+    let chart_dir = chart.rsplit('/').next().unwrap_or(chart);
+
+    read_values_schema(&pull_dir.path().join(chart_dir).join(VALUES_SCHEMA_FILE))
+}
+
+/*
+    Walks a dotted mapping through the schema's properties. Anything the schema explicitly leaves open, a schema
+    without properties, additionalProperties or patternProperties, or an array's items, accepts the rest of the
+    path. additionalProperties defaults to true in JSON schema, but few charts set it, so a key that isn't listed
+    is treated as unknown unless the schema says otherwise.
+*/
+fn schema_has_path(schema: &serde_json::Value, mapping: &str) -> bool {
+    let mut current = schema;
+
+    for key in mapping.split('.') {
+        let properties = match current.get("properties").and_then(|props| props.as_object()) {
+            Some(properties) => properties,
+            None => return true,
+        };
+
+        match properties.get(key) {
+            Some(next) => current = next,
+            None => {
+                let additional = current.get("additionalProperties").map_or(false, |additional| additional != false);
+
+                return additional || current.get("patternProperties").is_some();
+            }
+        }
+
+        if current.get("items").is_some() {
+            return true;
+        }
+    }
+
+    true
+}
+
+const VALIDATE_TIMEOUT: Duration = Duration::from_secs(120);
+const VALIDATION_DIR: &str = "validation";
+
+/*
+    A server side dry run needs the namespace to exist, so manifests for a namespace the deploy hasn't created yet
+    are dry run client side. That still validates them against the schemas the cluster serves.
+*/
+fn dry_run_manifests(apply_flag: &str, manifests: &str, namespace: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let namespace_conf = CommandConfig::new("kubectl", vec!["get", "namespace", namespace], None)
+        .with_timeout(VALIDATE_TIMEOUT);
+
+    let dry_run = if CommandPipeline::execute_single(namespace_conf).is_ok() {
+        "--dry-run=server"
+    } else {
+        debug!("Namespace {} doesn't exist yet, validating client side.", namespace);
+        "--dry-run=client"
+    };
+
+    let conf = CommandConfig::new(
+        "kubectl",
+        vec!["apply", dry_run, apply_flag, manifests, "--namespace", namespace],
+        None,
+    )
+    .with_timeout(VALIDATE_TIMEOUT);
+
+    CommandPipeline::execute_single(conf)?;
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct ValuesOverride {
+    pub node: Option<String>,
+    pub path: String,
+    pub values: Value,
+}
+
+impl ValuesOverride {
+    /*
+        Accepts either a bare path, which applies to every node in the stack,
+        or node=path which only applies to the named node. The node can be given
+        by name or by fully qualified name. The text before = is only taken as a node
+        when it names one in the stack, so paths with an = in them still load as paths.
+     */
+    pub fn load(arg: &str, artifact: &ArtifactRepr) -> Result<ValuesOverride, TorbComposerErrors> {
+        let (node, path) = match arg.split_once('=') {
+            Some((node, path)) if artifact.nodes.values().any(|candidate| ValuesOverride::names(node, candidate)) => {
+                (Some(node.to_string()), path.to_string())
+            }
+            _ => (None, arg.to_string()),
+        };
+
+        let contents = fs::read_to_string(project_path(&path)).map_err(|err| {
+            TorbComposerErrors::UnableToReadValuesFile {
+                path: path.clone(),
+                reason: err.to_string(),
+            }
+        })?;
+
+        let values: Value = serde_yaml::from_str(&contents).map_err(|err| {
+            TorbComposerErrors::UnableToReadValuesFile {
+                path: path.clone(),
+                reason: err.to_string(),
+            }
+        })?;
+
+        Ok(ValuesOverride { node, path, values })
+    }
+
+    fn names(name: &str, node: &ArtifactNodeRepr) -> bool {
+        name == node.fqn || name == node.name
+    }
+
+    fn applies_to(&self, node: &ArtifactNodeRepr) -> bool {
+        match &self.node {
+            Some(name) => ValuesOverride::names(name, node),
+            None => true,
+        }
+    }
+}
+
+// The inputs set on a helm release and the values documents passed to it, see Composer::render_values.
+#[derive(Serialize, Debug, Clone)]
+pub struct RenderedValues {
+    pub fqn: String,
+    pub inputs: IndexMap<String, String>,
+    pub values: Vec<String>,
+}
 
 fn reserved_outputs() -> HashMap<&'static str, &'static str> {
     let reserved = vec![("host", "")];
@@ -37,6 +459,16 @@ fn reserved_outputs() -> HashMap<&'static str, &'static str> {
     reserved_hash
 }
 
+const CONFIG_PROPERTY: &str = "config";
+
+// Addresses under meta read a node from the stack's meta stacks, see Composer::address_target.
+pub const META_LOCALITY: &str = "meta";
+
+// Whether a value is meant as an address, strings that aren't still go through the templates check.
+pub fn is_address_like(value: &str) -> bool {
+    value.starts_with("self.") || value.starts_with("meta.")
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InputAddress {
     pub locality: String,
@@ -84,7 +516,7 @@ impl<'a> InputAddress {
     }
 
     fn is_input_address(vals: &Vec<&str>) -> Option<InputAddress> {
-        if vals.len() == 5 && vals[0] == "self" {
+        if vals.len() == 5 && (vals[0] == "self" || vals[0] == META_LOCALITY) {
             let locality = vals[0].to_string();
             let node_type = vals[1].to_string();
             let node_name = vals[2].to_string();
@@ -104,20 +536,20 @@ impl<'a> InputAddress {
     }
 
     fn supported_localities() -> HashSet<&'a str> {
-        let set = vec!["self", "TORB"];
+        let set = vec!["self", META_LOCALITY, "TORB"];
 
         set.into_iter().collect::<HashSet<&'a str>>()
     }
 
-}
-
-impl TryFrom<&str> for InputAddress {
-    type Error = TorbInput;
-
-    fn try_from(input: &str) -> Result<Self, TorbInput> {
+    fn parse(input: &str) -> Result<InputAddress, TorbInput> {
         let vals = input.split(".").collect::<Vec<&str>>();
 
-        if !InputAddress::supported_localities().contains(vals[0]) {
+        let locality = match vals.first() {
+            Some(locality) => *locality,
+            None => return Err(TorbInput::String(input.to_string())),
+        };
+
+        if !InputAddress::supported_localities().contains(locality) || vals.iter().any(|val| val.is_empty()) {
             return Err(TorbInput::String(input.to_string()))
         }
 
@@ -135,32 +567,74 @@ impl TryFrom<&str> for InputAddress {
 
         Err(TorbInput::String(input.to_string()))
     }
-}
 
-impl TryFrom<&TorbInput> for InputAddress {
-    type Error = TorbInput;
+    /*
+        Strings that aren't addresses are passed through as literal values, so a reference with a part missing or
+        one too many would silently become a literal. Anything that starts with self. or TORB. and doesn't parse
+        is reported along with the forms an address can take. self.inputs.<key> is left alone, it's how defaults
+        read another input.
+    */
+    pub fn malformed_format(input: &str) -> Option<&'static str> {
+        let expected = if input.starts_with(SELF_INPUTS_PREFIX) {
+            return None;
+        } else if input.starts_with("self.") {
+            "self.<type>.<name>.<output|inputs|config>.<key>"
+        } else if input.starts_with("meta.") {
+            "meta.<type>.<name>.<output|inputs>.<key>"
+        } else if input.starts_with("TORB.") {
+            "TORB.<property>.<key>"
+        } else {
+            return None;
+        };
 
-    fn try_from(input: &TorbInput) -> Result<Self, TorbInput> {
-        if let TorbInput::String(str_input) = input {
-            let vals = str_input.split(".").collect::<Vec<&str>>();
+        match InputAddress::parse(input) {
+            Ok(_) => None,
+            Err(_) => Some(expected),
+        }
+    }
+}
 
-            if !InputAddress::supported_localities().contains(vals[0]) {
-                return Err(TorbInput::String(str_input.to_string()))
-            }
+// Checks the node's inputs and config for values that look like addresses but can't be parsed as one.
+pub fn validate_node_addresses(node: &ArtifactNodeRepr) -> Result<(), TorbComposerErrors> {
+    let values = node
+        .mapped_inputs
+        .iter()
+        .map(|(key, (_, input))| (key, input))
+        .chain(node.config.iter());
+
+    for (key, input) in values {
+        let address = match input {
+            TorbInput::String(value) if !is_template(value) => value,
+            _ => continue,
+        };
 
-            let init_addr_opt = InputAddress::is_init_address(&vals);
+        if let Some(expected) = InputAddress::malformed_format(address) {
+            return Err(TorbComposerErrors::MalformedInputAddress {
+                fqn: node.fqn.clone(),
+                key: key.clone(),
+                address: address.clone(),
+                expected,
+            });
+        }
+    }
 
-            if init_addr_opt.is_some() {
-                return Ok(init_addr_opt.unwrap())
-            }
+    Ok(())
+}
 
-            let input_addr_opt = InputAddress::is_input_address(&vals);
+impl TryFrom<&str> for InputAddress {
+    type Error = TorbInput;
 
-            if input_addr_opt.is_some() {
-                return Ok(input_addr_opt.unwrap())
-            }
+    fn try_from(input: &str) -> Result<Self, TorbInput> {
+        InputAddress::parse(input)
+    }
+}
 
-            Err(TorbInput::String(str_input.to_string()))
+impl TryFrom<&TorbInput> for InputAddress {
+    type Error = TorbInput;
+
+    fn try_from(input: &TorbInput) -> Result<Self, TorbInput> {
+        if let TorbInput::String(str_input) = input {
+            InputAddress::parse(str_input)
         } else {
             Err(input.clone())
         }
@@ -175,7 +649,9 @@ pub struct Composer<'a> {
     main_struct: hcl::BodyBuilder,
     artifact_repr: &'a ArtifactRepr,
     watcher_patch: bool,
-    dev_mounts: IndexMap<String, IndexMap<String, String>>
+    dev_mounts: IndexMap<String, IndexMap<String, String>>,
+    values_overrides: Vec<ValuesOverride>,
+    output_root: Option<PathBuf>,
 }
 
 impl<'a> Composer<'a> {
@@ -188,7 +664,9 @@ impl<'a> Composer<'a> {
             main_struct: Body::builder(),
             artifact_repr: artifact_repr,
             watcher_patch: watcher_patch,
-            dev_mounts: IndexMap::new()
+            dev_mounts: IndexMap::new(),
+            values_overrides: Vec::new(),
+            output_root: None,
         }
     }
 
@@ -201,33 +679,335 @@ impl<'a> Composer<'a> {
             main_struct: Body::builder(),
             artifact_repr: artifact_repr,
             watcher_patch: watcher_patch,
-            dev_mounts: dev_mounts
+            dev_mounts: dev_mounts,
+            values_overrides: Vec::new(),
+            output_root: None,
+        }
+    }
+
+    pub fn with_values_overrides(mut self, values_overrides: Vec<ValuesOverride>) -> Composer<'a> {
+        self.values_overrides = values_overrides;
+
+        self
+    }
+
+    // Writes the environment somewhere other than the buildstate, used to inspect generated Terraform without deploying.
+    pub fn with_output_root(mut self, output_root: PathBuf) -> Composer<'a> {
+        self.output_root = Some(output_root);
+
+        self
+    }
+
+    fn validate_values_overrides(&self) -> Result<(), TorbComposerErrors> {
+        for values_override in self.values_overrides.iter() {
+            let matched = self
+                .artifact_repr
+                .nodes
+                .values()
+                .any(|node| values_override.applies_to(node));
+
+            if !matched {
+                return Err(TorbComposerErrors::UnknownValuesNode {
+                    path: values_override.path.clone(),
+                    node: values_override.node.clone().unwrap_or_default(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+        Every self.<type>.<name>.<output|inputs|config>.<key> reference in a node's inputs, values or config is checked
+        against the target node here, so wiring mistakes show up together before anything is handed to Terraform.
+        meta.<type>.<name>.<output|inputs>.<key> references are checked against the stack's meta stacks the same way.
+    */
+    fn validate_output_references(&self) -> Result<(), TorbComposerErrors> {
+        let mut bad_references = Vec::<String>::new();
+
+        for node in self.artifact_repr.nodes.values() {
+            let addresses = Composer::node_addresses(node);
+
+            for address in addresses.iter().filter(|address| address.locality != "TORB") {
+                let target = self.address_target(address);
+
+                let target_fqn = match target {
+                    Some((_, target)) => target.fqn.clone(),
+                    None if address.locality == META_LOCALITY => {
+                        format!("{}.{}.{}", META_LOCALITY, address.node_type, address.node_name)
+                    }
+                    None => format!(
+                        "{}.{}.{}",
+                        self.artifact_repr.stack_name, address.node_type, address.node_name
+                    ),
+                };
+
+                let reason = match target.map(|(_, target)| target) {
+                    None if address.locality == META_LOCALITY => {
+                        Some(format!("{} is not in any of the stack's meta stacks", target_fqn))
+                    }
+                    None => Some(format!("{} is not in the stack", target_fqn)),
+                    Some(_) if address.locality == META_LOCALITY && address.node_property == CONFIG_PROPERTY => {
+                        Some("the config of a meta node can't be read, use its output or inputs".to_string())
+                    }
+                    Some(target) if address.node_property == CONFIG_PROPERTY => {
+                        if target.config.contains_key(&address.property_specifier) {
+                            None
+                        } else if target.config.is_empty() {
+                            Some(format!("{} doesn't declare any config", target_fqn))
+                        } else {
+                            Some(format!(
+                                "{} declares no config key named {}, declared keys are {}",
+                                target_fqn,
+                                address.property_specifier,
+                                target.config.keys().cloned().collect::<Vec<String>>().join(", ")
+                            ))
+                        }
+                    }
+                    Some(_) if address.node_property != "output" && address.node_property != "inputs" => Some(format!(
+                        "{} is not a node property, use output, inputs or config",
+                        address.node_property
+                    )),
+                    Some(_) if reserved_outputs().contains_key(address.property_specifier.as_str()) => None,
+                    Some(target) => {
+                        let exists = target.output(&address.property_specifier).is_some()
+                            || target.mapped_inputs.contains_key(&address.property_specifier);
+
+                        if exists {
+                            None
+                        } else {
+                            Some(format!(
+                                "{} has no output or input named {}",
+                                target_fqn, address.property_specifier
+                            ))
+                        }
+                    }
+                };
+
+                if let Some(reason) = reason {
+                    bad_references.push(format!(
+                        "{} -> {}.{}: {}",
+                        node.fqn, target_fqn, address.property_specifier, reason
+                    ));
+                }
+            }
+        }
+
+        for node in self.artifact_repr.nodes.values() {
+            bad_references.extend(self.mistyped_output_references(node));
+        }
+
+        if bad_references.is_empty() {
+            Ok(())
+        } else {
+            Err(TorbComposerErrors::InvalidOutputReferences {
+                references: bad_references.join("\n"),
+            })
+        }
+    }
+
+    /*
+        Inputs set directly to an output address are checked against the output's type when its unit declares one.
+        Reserved outputs like host are always strings. Templates and values aren't checked, they're rendered as strings.
+    */
+    fn mistyped_output_references(&self, node: &ArtifactNodeRepr) -> Vec<String> {
+        let mut mistyped = vec![];
+
+        for (key, (_, input)) in node.mapped_inputs.iter() {
+            let address = match InputAddress::try_from(input) {
+                Ok(address) if address.node_property == "output" => address,
+                _ => continue,
+            };
+
+            let (spec, target) = match (node.input_spec.get(key), self.address_target(&address)) {
+                (Some(spec), Some((_, target))) => (spec, target),
+                _ => continue,
+            };
+
+            let output_typing = if reserved_outputs().contains_key(address.property_specifier.as_str()) {
+                "string"
+            } else {
+                match target.output(&address.property_specifier).and_then(|output| output.typing.as_ref()) {
+                    Some(typing) => typing.as_str(),
+                    None => continue,
+                }
+            };
+
+            if output_typing != spec.typing() {
+                mistyped.push(format!(
+                    "{} -> {}.{}: the output is a {} but the input {} reading it is a {}",
+                    node.fqn,
+                    target.fqn,
+                    address.property_specifier,
+                    output_typing,
+                    key,
+                    spec.typing()
+                ));
+            }
+        }
+
+        mistyped
+    }
+
+    // Every address a node reads from, in its inputs, values and config, including the ones inside templates.
+    fn node_addresses(node: &ArtifactNodeRepr) -> Vec<InputAddress> {
+        let mut addresses: Vec<InputAddress> = node
+            .mapped_inputs
+            .values()
+            .map(|(_, input)| input)
+            .chain(node.config.values())
+            .flat_map(|input| match input {
+                TorbInput::String(value) => referenced_addresses(value),
+                _ => vec![],
+            })
+            .collect();
+
+        let values: Value = serde_yaml::from_str(node.values.as_str()).unwrap_or(Value::Null);
+        Composer::collect_value_addresses(&values, &mut addresses);
+
+        addresses
+    }
+
+    fn collect_value_addresses(value: &Value, addresses: &mut Vec<InputAddress>) {
+        match value {
+            Value::String(s) if is_address_like(s) || is_template(s) => {
+                addresses.extend(referenced_addresses(s));
+            }
+            Value::Mapping(m) => {
+                for (_, v) in m {
+                    Composer::collect_value_addresses(v, addresses);
+                }
+            }
+            Value::Sequence(seq) => {
+                for v in seq {
+                    Composer::collect_value_addresses(v, addresses);
+                }
+            }
+            _ => {}
         }
     }
 
     fn get_node_for_output_value(&self, torb_input_address: &InputAddress) -> &ArtifactNodeRepr {
-        let stack_name = &self.artifact_repr.stack_name;
-        let output_node_fqn = format!(
-            "{}.{}.{}",
-            stack_name, &torb_input_address.node_type, &torb_input_address.node_name
+        let (_, node) = self
+            .address_target(torb_input_address)
+            .expect("Unable to map input address to node, make sure your mapping is correct.");
+
+        node
+    }
+
+    /*
+        The node an address reads from along with the artifact it's deployed from. Meta addresses are looked up through
+        the stack's meta stacks nearest first, so a meta can shadow a node of the same name in its own meta.
+    */
+    fn address_target(&self, address: &InputAddress) -> Option<(&ArtifactRepr, &ArtifactNodeRepr)> {
+        if address.locality != META_LOCALITY {
+            let fqn = format!("{}.{}.{}", self.artifact_repr.stack_name, address.node_type, address.node_name);
+
+            return self.artifact_repr.nodes.get(&fqn).map(|node| (self.artifact_repr, node));
+        }
+
+        let mut meta = self.artifact_repr.meta.as_ref().as_ref();
+
+        while let Some(artifact) = meta {
+            let fqn = format!("{}.{}.{}", artifact.stack_name, address.node_type, address.node_name);
+
+            if let Some(node) = artifact.nodes.get(&fqn) {
+                return Some((artifact, node));
+            }
+
+            meta = artifact.meta.as_ref().as_ref();
+        }
+
+        None
+    }
+
+    // Helm nodes are released as <release>-<node>, the name a node is given in the stack rather than its fqn.
+    fn helm_release_name(&self, node: &ArtifactNodeRepr) -> String {
+        format!("{}-{}", self.release_name, node.display_name(true))
+    }
+
+    /*
+        Release names only use a node's name, so two nodes with the same name from different sources, or a namespace
+        override putting everything in one namespace, can land on the same Helm release. Helm only reports that as
+        the release already existing partway through a deploy, so every collision is reported before composing.
+    */
+    fn validate_release_collisions(&self) -> Result<(), TorbComposerErrors> {
+        let mut releases = IndexMap::<(String, String), Vec<String>>::new();
+
+        for node in self.artifact_repr.nodes.values() {
+            if !matches!(node.deploy_tool(), Some((tool, _)) if tool == "helm") {
+                continue;
+            }
+
+            releases
+                .entry((self.helm_release_name(node), self.artifact_repr.namespace(node)))
+                .or_default()
+                .push(node.fqn.clone());
+        }
+
+        let collisions = releases
+            .iter()
+            .filter(|(_, fqns)| fqns.len() > 1)
+            .map(|((release, namespace), fqns)| {
+                format!("{} in namespace {}: {}", release, namespace, fqns.join(", "))
+            })
+            .collect::<Vec<String>>();
+
+        if collisions.is_empty() {
+            Ok(())
+        } else {
+            Err(TorbComposerErrors::ReleaseCollisions {
+                collisions: collisions.join("\n"),
+            })
+        }
+    }
+
+    // Meta stacks always set their release, the stack's own may have been generated for this compose.
+    fn address_release(&self, address: &InputAddress, artifact: &ArtifactRepr) -> String {
+        if address.locality == META_LOCALITY {
+            artifact.release()
+        } else {
+            self.release_name.clone()
+        }
+    }
+
+    // Meta releases are read with their own data blocks, prefixed so they can't collide with the stack's.
+    fn release_data_block_name(&self, address: &InputAddress, artifact: &ArtifactRepr, node: &ArtifactNodeRepr) -> String {
+        let block_name = format!(
+            "{}_{}",
+            kebab_to_snake_case(&self.address_release(address, artifact)),
+            node.display_name(false)
         );
 
-        self.artifact_repr
-            .nodes
-            .get(&output_node_fqn)
-            .expect("Unable to map input address to node, make sure your mapping is correct.")
+        if address.locality == META_LOCALITY {
+            format!("{}_{}", META_LOCALITY, block_name)
+        } else {
+            block_name
+        }
     }
 
     fn interpolate_inputs_into_helm_values(
         &self,
+        node: &ArtifactNodeRepr,
         torb_input_address: Result<InputAddress, TorbInput>,
     ) -> String {
-        let output_value = self.input_values_from_input_address(torb_input_address.clone());
+        if let Err(TorbInput::String(template)) = &torb_input_address {
+            if is_template(template) {
+                return match self.evaluate_template(node, template) {
+                    TemplateValue::Literal(value) => value,
+                    TemplateValue::Terraform(expression) => format!("${{{}}}", expression),
+                };
+            }
+        }
+
+        let output_value = self.input_values_from_input_address(node, torb_input_address.clone());
         let string_value = hcl::format::to_string(&output_value).unwrap();
         match torb_input_address {
             Ok(input_address) => {
 
-                if reserved_outputs().contains_key(input_address.property_specifier.as_str()) {
+                if input_address.node_property != CONFIG_PROPERTY
+                    && reserved_outputs().contains_key(input_address.property_specifier.as_str())
+                {
                     string_value.replace("\"", "")
                 } else {
                     format!("${{{}}}", string_value.replace("\"", ""))
@@ -237,14 +1017,59 @@ impl<'a> Composer<'a> {
         }
     }
 
+    /*
+        Templates are evaluated when the stack is composed rather than resolved since that's when it's known how each
+        reference is read. Anything that reads an output becomes a Terraform expression, see TemplateFunction::apply.
+    */
+    fn evaluate_template(&self, node: &ArtifactNodeRepr, template: &str) -> TemplateValue {
+        let expr = parse_template(template).expect("Templates are checked when the stack is resolved.");
+
+        expr.evaluate(&mut |reference: &str| {
+            let address = match reference.strip_prefix(SELF_INPUTS_PREFIX) {
+                Some(key) => {
+                    let (_, input) = node.mapped_inputs.get(key).expect("Template inputs are checked when the stack is resolved.");
+
+                    InputAddress::try_from(input)
+                }
+                None => InputAddress::try_from(reference),
+            };
+
+            match self.input_values_from_input_address(node, address) {
+                Expression::String(value) => TemplateValue::Literal(value),
+                expression => TemplateValue::Terraform(hcl::format::to_string(&expression).unwrap()),
+            }
+        })
+    }
+
+    // Config is read back from the manifest kept in the ConfigMap resource's triggers, see add_config_map_to_main_struct.
+    fn config_value_expression(&self, torb_input_address: &InputAddress) -> String {
+        let config_node = self.get_node_for_output_value(torb_input_address);
+
+        format!(
+            "jsondecode(null_resource.{}.triggers.manifest).data[\"{}\"]",
+            Composer::config_map_resource_name(config_node),
+            torb_input_address.property_specifier
+        )
+    }
+
+    fn config_map_resource_name(node: &ArtifactNodeRepr) -> String {
+        format!("{}_config", node.fqn.replace(".", "_"))
+    }
+
     fn k8s_value_from_reserved_input(&self, torb_input_address: InputAddress) -> Expression {
-        let output_node = self.get_node_for_output_value(&torb_input_address);
+        let (artifact, output_node) = self
+            .address_target(&torb_input_address)
+            .expect("Unable to map input address to node, make sure your mapping is correct.");
 
         match torb_input_address.property_specifier.as_str() {
             "host" => {
-                let name = format!("{}-{}", self.release_name, output_node.display_name(true));
+                let name = format!(
+                    "{}-{}",
+                    self.address_release(&torb_input_address, artifact),
+                    output_node.display_name(true)
+                );
 
-                let namespace = self.artifact_repr.namespace(output_node);
+                let namespace = artifact.namespace(output_node);
 
                 Expression::String(format!("{}.{}.svc.cluster.local", name, namespace))
             }
@@ -254,22 +1079,28 @@ impl<'a> Composer<'a> {
         }
     }
 
+    // Where an output or input is found in the release's values, inputs by their mapping and other outputs by name.
+    fn release_values_key<'n>(node: &'n ArtifactNodeRepr, name: &'n str) -> Option<&'n str> {
+        match node.mapped_inputs.get(name) {
+            Some((kube_val, _)) => Some(kube_val.as_str()),
+            None if node.output(name).is_some() => Some(name),
+            None => None,
+        }
+    }
+
     fn k8s_status_values_path_from_torb_input(&self, torb_input_address: InputAddress) -> String {
-        let output_node = self.get_node_for_output_value(&torb_input_address);
+        let (artifact, output_node) = self
+            .address_target(&torb_input_address)
+            .expect("Unable to map input address to node, make sure your mapping is correct.");
 
         let kube_value = if torb_input_address.node_property == "output" || torb_input_address.node_property == "inputs" {
-            let (kube_val, _) = output_node
-                .mapped_inputs
-                .get(&torb_input_address.property_specifier)
-                .expect("Unable to map input from output node. Key does not exist.");
-
-            kube_val
+            Composer::release_values_key(output_node, &torb_input_address.property_specifier)
+                .expect("Unable to map input from output node. Key does not exist.")
         } else {
             panic!("Unable to map node property to output attribute please check your inputs, ex: 'a.b.output.c or a.b.input.c");
         };
 
-        let formatted_name = kebab_to_snake_case(&self.release_name);
-        let block_name = format!("{}_{}", formatted_name, &output_node.display_name(false));
+        let block_name = self.release_data_block_name(&torb_input_address, artifact, output_node);
 
         format!(
             "jsondecode(data.torb_helm_release.{}.values)[\"{}\"]",
@@ -277,21 +1108,29 @@ impl<'a> Composer<'a> {
         )
     }
 
-    fn iac_environment_path(&self) -> std::path::PathBuf {
-        let buildstate_path = buildstate_path_or_create();
+    fn iac_environment_path(&self) -> Result<std::path::PathBuf, TorbUtilityErrors> {
+        if let Some(output_root) = &self.output_root {
+            return Ok(output_root.clone());
+        }
+
+        let buildstate_path = buildstate_path_or_create()?;
         if self.watcher_patch {
-            buildstate_path.join("watcher_iac_environment")
+            Ok(buildstate_path.join("watcher_iac_environment"))
         } else {
-            buildstate_path.join("iac_environment")
+            Ok(buildstate_path.join("iac_environment"))
         }
     }
 
     pub fn compose(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("Composing build environment...");
-        let environment_path = self.iac_environment_path();
+        info!("Composing build environment...");
+        self.validate_values_overrides()?;
+        self.validate_output_references()?;
+        self.validate_release_collisions()?;
+
+        let environment_path = self.iac_environment_path()?;
 
         if !environment_path.exists() {
-            std::fs::create_dir(environment_path)?;
+            std::fs::create_dir_all(environment_path)?;
         }
 
         self.add_required_providers_to_main_struct();
@@ -300,6 +1139,8 @@ impl<'a> Composer<'a> {
             self.walk_artifact(node)?;
         }
 
+        self.add_meta_data_blocks_to_main_struct();
+
         self.copy_supporting_build_files()
             .expect("Failed to write supporting buildfiles to new environment.");
 
@@ -309,11 +1150,187 @@ impl<'a> Composer<'a> {
         Ok(())
     }
 
+    /*
+        Resolves the inputs and values every helm node would be deployed with, in deploy order, without writing
+        the IaC environment. Output addresses stay as the Terraform expressions they are read from at deploy time.
+    */
+    pub fn render_values(&self) -> Result<Vec<RenderedValues>, Box<dyn std::error::Error>> {
+        self.validate_values_overrides()?;
+        self.validate_output_references()?;
+
+        let mut rendered = vec![];
+
+        for fqn in self.artifact_repr.deploy_order()? {
+            let node = &self.artifact_repr.nodes[&fqn];
+
+            let helm = match node.deploy_tool() {
+                Some((tool, conf)) if tool == "helm" => conf,
+                _ => continue,
+            };
+
+            let array_rendering = ArrayRendering::for_node(node, &helm)?;
+
+            // Sensitive inputs are redacted whole, their values can be read from other inputs or built by templates.
+            let sensitive_mappings = node.sensitive_mappings();
+            let inputs = self
+                .rendered_inputs(node, &array_rendering)
+                .into_iter()
+                .map(|(name, value)| {
+                    if sensitive_mappings.contains(name.as_str()) && !secrets_shown() {
+                        (name, REDACTED.to_string())
+                    } else {
+                        (name, self.redact(&value))
+                    }
+                })
+                .collect();
+
+            let values = self
+                .helm_values(node, &array_rendering)?
+                .iter()
+                .map(|document| self.redact(document))
+                .collect();
+
+            rendered.push(RenderedValues {
+                fqn: fqn.clone(),
+                inputs,
+                values,
+            });
+        }
+
+        Ok(rendered)
+    }
+
+    fn redact(&self, text: &str) -> String {
+        self.artifact_repr.redact(text)
+    }
+
+    fn rendered_inputs(&self, node: &ArtifactNodeRepr, array_rendering: &ArrayRendering) -> IndexMap<String, String> {
+        let name_key = ObjectKey::Expression(Expression::String("name".to_string()));
+        let value_key = ObjectKey::Expression(Expression::String("value".to_string()));
+
+        self.create_input_values(node, array_rendering)
+            .iter()
+            .filter_map(|input| match (input.get(&name_key), input.get(&value_key)) {
+                (Some(Expression::String(name)), Some(Expression::String(value))) => Some((name.clone(), value.clone())),
+                (Some(Expression::String(name)), Some(value)) => Some((name.clone(), value.to_string())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /*
+        Dry runs every node's manifests against the cluster without applying them, so schema errors and API versions
+        the cluster doesn't serve fail before Terraform changes anything. Helm nodes are rendered with helm template
+        and the same inputs and values the deploy uses. Output addresses can't be read before the stack is deployed,
+        so values that reference them are validated with the Terraform expression in their place.
+        Every rejected node is reported at once.
+    */
+    pub fn validate_manifests(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.validate_values_overrides()?;
+        self.validate_output_references()?;
+
+        let validation_path = self.iac_environment_path()?.join(VALIDATION_DIR);
+
+        if validation_path.exists() {
+            fs::remove_dir_all(&validation_path)?;
+        }
+
+        fs::create_dir_all(&validation_path)?;
+
+        let mut rejected = vec![];
+
+        for fqn in self.artifact_repr.deploy_order()? {
+            let node = &self.artifact_repr.nodes[&fqn];
+            let namespace = self.artifact_repr.namespace(node);
+
+            info!("Validating {}...", fqn);
+
+            let result = match node.deploy_tool() {
+                Some((tool, conf)) if tool == "helm" => self
+                    .render_helm_manifests(node, &conf, &namespace, &validation_path)
+                    .and_then(|manifests| dry_run_manifests("-f", manifests.to_str().unwrap(), &namespace)),
+                Some((tool, conf)) if tool == "kubectl" => Composer::kubectl_manifests_path(node, &conf)
+                    .map_err(|err| Box::new(err) as Box<dyn std::error::Error>)
+                    .and_then(|(apply_flag, manifests)| {
+                        dry_run_manifests(apply_flag, manifests.to_str().unwrap(), &namespace)
+                    }),
+                _ => continue,
+            };
+
+            if let Err(err) = result {
+                rejected.push(format!("{}: {}", fqn, command_error_reason(err)));
+            }
+        }
+
+        if rejected.is_empty() {
+            Ok(())
+        } else {
+            Err(Box::new(TorbComposerErrors::InvalidManifests {
+                manifests: rejected.join("\n"),
+            }))
+        }
+    }
+
+    // Renders a helm node the way the Helm provider would install it and returns the path of the rendered manifests.
+    fn render_helm_manifests(
+        &self,
+        node: &ArtifactNodeRepr,
+        helm: &IndexMap<String, String>,
+        namespace: &str,
+        validation_path: &Path,
+    ) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let name = node.fqn.replace(".", "_");
+        let release_name = format!("{}-{}", self.release_name, snake_case_to_kebab(&node.display_name(false)));
+        let array_rendering = ArrayRendering::for_node(node, helm)?;
+
+        let mut args = vec!["template".to_string(), release_name];
+
+        match ChartSource::for_node(node, helm)? {
+            ChartSource::Repository { repository, chart } if repository.starts_with(OCI_PREFIX) => {
+                args.push(format!("{}/{}", repository, chart));
+            }
+            ChartSource::Repository { repository, chart } => {
+                args.extend([chart, "--repo".to_string(), repository]);
+            }
+            ChartSource::Local { chart } => {
+                args.push(ChartSource::local_chart_path(node, &chart)?.to_str().unwrap().to_string());
+            }
+        }
+
+        if let Some(version) = helm.get("version").filter(|version| !version.is_empty()) {
+            args.extend(["--version".to_string(), version.clone()]);
+        }
+
+        args.extend(["--namespace".to_string(), namespace.to_string()]);
+
+        for (idx, values) in self.helm_values(node, &array_rendering)?.iter().enumerate() {
+            let values_path = validation_path.join(format!("{}_values_{}.yaml", name, idx));
+            fs::write(&values_path, values)?;
+
+            args.extend(["--values".to_string(), values_path.to_str().unwrap().to_string()]);
+        }
+
+        for (input, value) in self.rendered_inputs(node, &array_rendering) {
+            args.extend(["--set".to_string(), format!("{}={}", input, value)]);
+        }
+
+        let conf = CommandConfig::new("helm", args.iter().map(|arg| arg.as_str()).collect(), None)
+            .with_timeout(VALIDATE_TIMEOUT);
+
+        let out = CommandPipeline::execute_single(conf)?;
+
+        let manifests_path = validation_path.join(format!("{}.yaml", name));
+        fs::write(&manifests_path, out.stdout)?;
+
+        Ok(manifests_path)
+    }
+
     fn copy_supporting_build_files(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let new_environment_path = self.iac_environment_path()?;
+
         for_each_artifact_repository(Box::new(|repos_path, repo| {
             let repo_path = repos_path.join(repo.file_name());
             let source_path = repo_path.join("common");
-            let new_environment_path = self.iac_environment_path();
 
             let repo_name = repo.file_name().into_string().unwrap();
             let namespace_dir = kebab_to_snake_case(&repo_name);
@@ -359,7 +1376,7 @@ impl<'a> Composer<'a> {
 
     fn write_main_buildfile(&mut self) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
         let builder = std::mem::take(&mut self.main_struct);
-        let environment_path = self.iac_environment_path();
+        let environment_path = self.iac_environment_path()?;
 
         let main_tf_path = environment_path.join("main.tf");
 
@@ -367,9 +1384,7 @@ impl<'a> Composer<'a> {
 
         let main_tf_content_hcl_string = hcl::to_string(&built_content)?;
 
-        if std::env::var("TORB_DEBUG").is_ok() {
-            println!("{}", main_tf_content_hcl_string);
-        }
+        debug!("{}", self.redact(&main_tf_content_hcl_string));
 
         fs::write(&main_tf_path, main_tf_content_hcl_string).expect("Failed to write main.tf");
 
@@ -400,7 +1415,11 @@ impl<'a> Composer<'a> {
         }
 
         if !self.fqn_seen.contains(&node.fqn) {
-            self.add_stack_node_to_main_struct(node).and_then(|_out| {
+            timed("compose", Some(&node.fqn), || {
+                self.add_stack_node_to_main_struct(node)?;
+                self.add_config_map_to_main_struct(node)
+            })
+            .and_then(|_out| {
                 if self.fqn_seen.insert(node.fqn.clone()) {
                     Ok(())
                 } else {
@@ -416,7 +1435,7 @@ impl<'a> Composer<'a> {
     }
 
     fn create_output_data_block(
-        &mut self,
+        &self,
         node: &ArtifactNodeRepr,
     ) -> Result<Block, Box<dyn std::error::Error>> {
         let snake_case_release_name = self.release_name.clone().replace("-", "_");
@@ -441,11 +1460,49 @@ impl<'a> Composer<'a> {
         Ok(data_block)
     }
 
+    /*
+        Exposes a helm node's declared outputs as a root Terraform output so they can be read back with terraform
+        output after a deploy. The output is named after the node's fqn rather than the release, which is generated
+        when the stack doesn't set one, so the deployer can map it back to the node.
+    */
+    pub(crate) fn create_output_block(&self, node: &ArtifactNodeRepr) -> Option<Block> {
+        if node.outputs.is_empty() {
+            return None;
+        }
+
+        let snake_case_release_name = self.release_name.clone().replace("-", "_");
+        let data_address = format!("data.torb_helm_release.{}_{}", snake_case_release_name, node.display_name(false));
+
+        // Read from the same values keys dependents get them from, so an output that's also an input is read at its mapping.
+        let keys = node
+            .outputs
+            .iter()
+            .map(|output| {
+                let values_key = Composer::release_values_key(node, &output.name).unwrap_or(&output.name);
+
+                format!("\"{}\" = \"{}\"", output.name, values_key)
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        let value = format!(
+            "{{ for key, values_key in {{ {} }} : key => try(jsondecode({}.values)[values_key], null) }}",
+            keys, data_address
+        );
+
+        let block = Block::builder("output")
+            .add_label(node_output_name(&node.fqn))
+            .add_attribute(("value", RawExpression::new(value)))
+            .build();
+
+        Some(block)
+    }
+
     fn copy_build_files_for_node(
         &mut self,
         node: &ArtifactNodeRepr,
     ) -> Result<bool, Box<dyn std::error::Error>> {
-        let environment_path = self.iac_environment_path();
+        let environment_path = self.iac_environment_path()?;
         let node_source = node.source.clone().unwrap();
         let namespace_dir = kebab_to_snake_case(&node_source);
         let repo_path = environment_path.join(namespace_dir);
@@ -486,10 +1543,13 @@ impl<'a> Composer<'a> {
         Ok(true)
     }
 
-    fn create_input_values(&self, node: &ArtifactNodeRepr) -> Vec<Object<ObjectKey, Expression>> {
+    fn create_input_values(&self, node: &ArtifactNodeRepr, array_rendering: &ArrayRendering) -> Vec<Object<ObjectKey, Expression>> {
         let mut input_vals = Vec::<Object<ObjectKey, Expression>>::new();
 
-        let resolver_fn = |spec: &String, input_address_result| {
+        let resolver_fn = |spec: &String, input_address_result: Result<InputAddress, TorbInput>| {
+            let rendered_as_yaml = *array_rendering == ArrayRendering::Yaml
+                && matches!(input_address_result, Err(TorbInput::Array(_)));
+
             let mut input: Object<ObjectKey, Expression> = Object::new();
 
             input.insert(
@@ -497,14 +1557,14 @@ impl<'a> Composer<'a> {
                 Expression::String(spec.clone()),
             );
 
-            let mapped_expression = self.input_values_from_input_address(input_address_result);
+            let mapped_expression = self.input_values_from_input_address(node, input_address_result);
 
             input.insert(
                 ObjectKey::Expression(Expression::String("value".to_string())),
                 mapped_expression.clone(),
             );
 
-            if spec != "" {
+            if spec != "" && !rendered_as_yaml {
                 input_vals.push(input);
             }
 
@@ -520,9 +1580,13 @@ impl<'a> Composer<'a> {
 
     fn input_values_from_input_address(
         &self,
+        node: &ArtifactNodeRepr,
         input_address: Result<InputAddress, TorbInput>,
     ) -> Expression {
         match input_address {
+            Ok(input_address) if input_address.node_property == CONFIG_PROPERTY => {
+                Expression::Raw(RawExpression::new(self.config_value_expression(&input_address)))
+            }
             Ok(input_address) => {
                 if reserved_outputs().contains_key(input_address.property_specifier.as_str()) {
                     let val = self.k8s_value_from_reserved_input(input_address);
@@ -535,15 +1599,13 @@ impl<'a> Composer<'a> {
             }
             Err(input_result) => {
                 match input_result {
+                    TorbInput::String(val) if is_template(&val) => match self.evaluate_template(node, &val) {
+                        TemplateValue::Literal(value) => Expression::String(value),
+                        TemplateValue::Terraform(expression) => Expression::Raw(RawExpression::new(expression)),
+                    },
                     TorbInput::String(val) => Expression::String(val),
                     TorbInput::Bool(val) => Expression::String(val.to_string()),
-                    TorbInput::Numeric(val) => {
-                        match val {
-                            TorbNumeric::Float(val) => Expression::String(Number::from_f64(val).unwrap().to_string()),
-                            TorbNumeric::Int(val) => Expression::String(Number::from(val).to_string()),
-                            TorbNumeric::NegInt(val) => Expression::String(Number::from(val).to_string())
-                        }
-                    }
+                    TorbInput::Numeric(val) => Expression::String(val.render()),
                     TorbInput::Array(val) => {
                         Expression::String(self.torb_array_to_hcl_helm_array(val))
                     }
@@ -553,19 +1615,48 @@ impl<'a> Composer<'a> {
         }
     }
 
+    // Builds the values YAML for array inputs when they're rendered as sequences, nested under their dotted mapping.
+    fn array_inputs_as_values(node: &ArtifactNodeRepr) -> Result<Value, TorbComposerErrors> {
+        let mut values = Value::Null;
+
+        for (_, (path, input)) in node.mapped_inputs.iter() {
+            let items = match input {
+                TorbInput::Array(items) => items,
+                _ => continue,
+            };
+
+            if path == "" {
+                continue;
+            }
+
+            if items.iter().any(|item| matches!(item, TorbInput::Array(_))) {
+                return Err(TorbComposerErrors::NestedArrayInput {
+                    fqn: node.fqn.clone(),
+                    path: path.clone(),
+                });
+            }
+
+            let mut nested = serde_yaml::to_value(input).unwrap_or(Value::Null);
+
+            for key in path.rsplit('.') {
+                let mut mapping = Mapping::new();
+                mapping.insert(Value::String(key.to_string()), nested);
+                nested = Value::Mapping(mapping);
+            }
+
+            values = merge_yaml_values(values, nested);
+        }
+
+        Ok(values)
+    }
+
     fn torb_array_to_hcl_helm_array(&self, arr: Vec<TorbInput>) -> String {
         let mut new = Vec::<String>::new();
         for input in arr.iter().cloned() {
             let expr = match input {
                 TorbInput::String(val) => Expression::String(val).to_string(),
                 TorbInput::Bool(val) => Expression::Bool(val).to_string(),
-                TorbInput::Numeric(val) => {
-                    match val {
-                        TorbNumeric::Float(val) => Expression::Number(Number::from_f64(val).unwrap()).to_string(),
-                        TorbNumeric::Int(val) => Expression::Number(Number::from(val)).to_string(),
-                        TorbNumeric::NegInt(val) => Expression::Number(Number::from(val)).to_string()
-                    }
-                }
+                TorbInput::Numeric(val) => val.render(),
                 TorbInput::Array(_val) => {
                     panic!("Nested array types are not supported.")
                 }
@@ -577,6 +1668,54 @@ impl<'a> Composer<'a> {
         "{".to_owned() + &new.join(",") + "}"
     }
 
+    /*
+        Meta nodes are deployed from their own state before the stack, so outputs read from them come from data blocks
+        reading their releases directly instead of depending on a module. host is worked out from the release name.
+    */
+    fn add_meta_data_blocks_to_main_struct(&mut self) {
+        let mut data_blocks = IndexMap::<String, Block>::new();
+
+        for node in self.artifact_repr.nodes.values() {
+            let addresses = Composer::node_addresses(node)
+                .into_iter()
+                .filter(|address| address.locality == META_LOCALITY)
+                .filter(|address| !reserved_outputs().contains_key(address.property_specifier.as_str()));
+
+            for address in addresses {
+                let (artifact, meta_node) = match self.address_target(&address) {
+                    Some(target) => target,
+                    None => continue,
+                };
+
+                let block_name = self.release_data_block_name(&address, artifact, meta_node);
+
+                if data_blocks.contains_key(&block_name) {
+                    continue;
+                }
+
+                let data_block = Block::builder("data")
+                    .add_label("torb_helm_release")
+                    .add_label(&block_name)
+                    .add_attribute((
+                        "release_name",
+                        format!("{}-{}", artifact.release(), snake_case_to_kebab(&meta_node.display_name(false))),
+                    ))
+                    .add_attribute(("namespace", artifact.namespace(meta_node)))
+                    .build();
+
+                data_blocks.insert(block_name, data_block);
+            }
+        }
+
+        let mut builder = std::mem::take(&mut self.main_struct);
+
+        for (_, data_block) in data_blocks {
+            builder = builder.add_block(data_block);
+        }
+
+        self.main_struct = builder;
+    }
+
     fn add_required_providers_to_main_struct(&mut self) {
         let required_providers = Block::builder("terraform")
             .add_block(
@@ -584,8 +1723,8 @@ impl<'a> Composer<'a> {
                     .add_attribute((
                         "torb",
                         Expression::from_iter(vec![
-                            ("source", "TorbFoundry/torb"),
-                            ("version", "0.1.2"),
+                            ("source", TORB_CONFIG.provider_source()),
+                            ("version", TORB_CONFIG.provider_version()),
                         ]),
                     ))
                     .build(),
@@ -606,23 +1745,216 @@ impl<'a> Composer<'a> {
         &mut self,
         node: &ArtifactNodeRepr,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let node_source = node.source.clone().unwrap();
-        let namespace_dir = kebab_to_snake_case(&node_source);
+        let (tool, conf) = node.deploy_tool().ok_or(TorbComposerErrors::MissingDeployStep {
+            fqn: node.fqn.clone(),
+        })?;
 
-        let source = format!("./{namespace_dir}/{}_module", node.display_name(false));
-        let name = node.fqn.clone().replace(".", "_");
+        let deploy_tool = deploy_tool(tool).ok_or(TorbComposerErrors::UnsupportedDeployTool {
+            fqn: node.fqn.clone(),
+            tool: tool.clone(),
+            supported: deploy_tool_names().join(", "),
+        })?;
+
+        let blocks = deploy_tool.blocks(self, node, &conf)?;
+
+        let mut builder = std::mem::take(&mut self.main_struct);
+
+        for block in blocks {
+            builder = builder.add_block(block);
+        }
+
+        self.main_struct = builder;
+
+        Ok(())
+    }
+
+    // What another node depends on changes with the tool, helm nodes are modules and kubectl nodes are null resources.
+    pub(crate) fn dependency_address(dep: &ArtifactNodeRepr) -> String {
+        let dep_fqn_name = dep.fqn.clone().replace(".", "_");
+
+        match dep.deploy_tool().and_then(|(tool, _)| deploy_tool(tool)) {
+            Some(deploy_tool) => deploy_tool.dependency_address(dep),
+            None => format!("module.{dep_fqn_name}"),
+        }
+    }
+
+    /*
+        Implicit dependencies are left to Terraform's own reference tracking by default, which only orders the
+        nodes when the referenced output actually shows up in the module's arguments. Nodes that read outputs
+        at deploy time can race the node they depend on, strict_implicit_ordering adds them to depends_on as well.
+    */
+    fn depends_on_expressions(&self, node: &ArtifactNodeRepr) -> Vec<RawExpression> {
+        let mut depends_on_exprs = vec![];
+        let strict_implicit_ordering = TORB_CONFIG.strict_implicit_ordering();
+
+        for dep in node.dependencies.iter() {
+            if strict_implicit_ordering || node.implicit_dependency_fqns.get(&dep.fqn).is_none() {
+                depends_on_exprs.push(RawExpression::from(Composer::dependency_address(dep)))
+            }
+        }
+
+        /*
+            Nodes usually read config by mounting the ConfigMap rather than through an expression Terraform can track,
+            so reading a node's config always waits on its ConfigMap.
+        */
+        let config_nodes = Composer::node_addresses(node)
+            .into_iter()
+            .filter(|address| address.locality == "self" && address.node_property == CONFIG_PROPERTY)
+            .map(|address| self.get_node_for_output_value(&address))
+            .filter(|config_node| config_node.fqn != node.fqn)
+            .map(|config_node| format!("null_resource.{}", Composer::config_map_resource_name(config_node)))
+            .collect::<IndexSet<String>>();
+
+        depends_on_exprs.extend(config_nodes.into_iter().map(RawExpression::from));
+
+        depends_on_exprs
+    }
+
+    // Manifests are relative to the unit, a kustomization is applied with -k and anything else with -f.
+    fn kubectl_manifests_path(
+        node: &ArtifactNodeRepr,
+        conf: &IndexMap<String, String>,
+    ) -> Result<(&'static str, PathBuf), TorbComposerErrors> {
+        let manifests = conf
+            .get("manifests")
+            .filter(|manifests| !manifests.is_empty())
+            .ok_or(TorbComposerErrors::MissingKubectlManifests {
+                fqn: node.fqn.clone(),
+            })?;
+
+        let unit_dir = Path::new(&node.file_path).parent().unwrap();
+
+        let kustomize = conf.get("kustomize").map_or(false, |val| val == "true");
+        let apply_flag = if kustomize { "-k" } else { "-f" };
 
+        Ok((apply_flag, unit_dir.join(manifests)))
+    }
+
+    /*
+        A node's config is applied as a ConfigMap named <release>-<node>-config in the node's namespace. The manifest
+        is kept in the triggers so a change to the config re-applies it, and other nodes read their keys from there.
+    */
+    fn add_config_map_to_main_struct(
+        &mut self,
+        node: &ArtifactNodeRepr,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if node.config.is_empty() {
+            return Ok(());
+        }
+
+        let config_map_name = format!("{}-{}-config", self.release_name, node.display_name(true));
         let namespace = self.artifact_repr.namespace(node);
 
+        let mut data: Object<ObjectKey, Expression> = Object::new();
+
+        for (key, input) in node.config.iter() {
+            data.insert(
+                ObjectKey::Expression(Expression::String(key.clone())),
+                self.input_values_from_input_address(node, InputAddress::try_from(input)),
+            );
+        }
+
+        let mut metadata: Object<ObjectKey, Expression> = Object::new();
+        metadata.insert(
+            ObjectKey::Expression(Expression::String("name".to_string())),
+            Expression::String(config_map_name.clone()),
+        );
+        metadata.insert(
+            ObjectKey::Expression(Expression::String("namespace".to_string())),
+            Expression::String(namespace.clone()),
+        );
+
+        let mut manifest: Object<ObjectKey, Expression> = Object::new();
+        manifest.insert(
+            ObjectKey::Expression(Expression::String("apiVersion".to_string())),
+            Expression::String("v1".to_string()),
+        );
+        manifest.insert(
+            ObjectKey::Expression(Expression::String("kind".to_string())),
+            Expression::String("ConfigMap".to_string()),
+        );
+        manifest.insert(
+            ObjectKey::Expression(Expression::String("metadata".to_string())),
+            Expression::Object(metadata),
+        );
+        manifest.insert(
+            ObjectKey::Expression(Expression::String("data".to_string())),
+            Expression::Object(data),
+        );
+
+        let manifest_expr = format!("jsonencode({})", hcl::format::to_string(&Expression::Object(manifest))?);
+        let kube_args = kube_target().args("kubectl").iter().map(|arg| shell_quote(arg)).collect::<Vec<String>>().join(" ");
+
+        let mut triggers: Object<ObjectKey, Expression> = Object::new();
+        triggers.insert(
+            ObjectKey::Expression(Expression::String("name".to_string())),
+            Expression::String(config_map_name),
+        );
+        triggers.insert(
+            ObjectKey::Expression(Expression::String("namespace".to_string())),
+            Expression::String(namespace.clone()),
+        );
+        triggers.insert(
+            ObjectKey::Expression(Expression::String("kube_args".to_string())),
+            Expression::String(kube_args.clone()),
+        );
+        triggers.insert(
+            ObjectKey::Expression(Expression::String("manifest".to_string())),
+            Expression::Raw(RawExpression::new(manifest_expr)),
+        );
+
+        // The manifest is passed through the environment so config values never have to be quoted for the shell.
+        let mut environment: Object<ObjectKey, Expression> = Object::new();
+        environment.insert(
+            ObjectKey::Expression(Expression::String("CONFIG_MAP".to_string())),
+            Expression::Raw(RawExpression::new("self.triggers.manifest".to_string())),
+        );
+
+        let apply_command = format!(
+            "kubectl {1} create namespace {0} --dry-run=client -o yaml | kubectl {1} apply -f - && printf '%s' \"$CONFIG_MAP\" | kubectl {1} apply -f - --namespace {0}",
+            shell_quote(&namespace), kube_args
+        );
+
+        let delete_command = "kubectl ${self.triggers.kube_args} delete configmap '${self.triggers.name}' --namespace '${self.triggers.namespace}' --ignore-not-found".to_string();
+
+        let block = Block::builder("resource")
+            .add_label("null_resource")
+            .add_label(Composer::config_map_resource_name(node))
+            .add_attribute(("triggers", Expression::Object(triggers)))
+            .add_block(
+                Block::builder("provisioner")
+                    .add_label("local-exec")
+                    .add_attribute(("command", apply_command))
+                    .add_attribute(("environment", Expression::Object(environment)))
+                    .build(),
+            )
+            .add_block(
+                Block::builder("provisioner")
+                    .add_label("local-exec")
+                    .add_attribute(("when", RawExpression::from("destroy".to_string())))
+                    .add_attribute(("command", delete_command))
+                    .build(),
+            );
+
+        let mut builder = std::mem::take(&mut self.main_struct);
+
+        builder = builder.add_block(block.build());
+
+        self.main_struct = builder;
+
+        Ok(())
+    }
+
+    /*
+        The values documents passed to a helm module, in the order Helm merges them. The build step's image comes first,
+        then the node's values with input addresses interpolated and any overrides merged over them, then the watcher patch.
+    */
+    fn helm_values(
+        &self,
+        node: &ArtifactNodeRepr,
+        array_rendering: &ArrayRendering,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
         let mut values = vec![];
-        let mut attributes = vec![
-            ("source", source),
-            (
-                "release_name",
-                format!("{}-{}", self.release_name.clone(), snake_case_to_kebab(&node.display_name(false))),
-            ),
-            ("namespace", namespace),
-        ];
 
         if node.build_step.is_some() {
             let build_step = node.build_step.clone().unwrap();
@@ -636,7 +1968,7 @@ impl<'a> Composer<'a> {
             }
 
             if build_step.registry != "local" {
-                let registry = format!("{}/{}", build_step.registry, node.display_name(false));
+                let registry = format!("{}/{}", resolve_registry(&build_step.registry)?, node.display_name(false));
                 image_key_map.insert("repository".to_string(), registry);
             } else {
                 image_key_map.insert("repository".to_string(), node.display_name(false).clone());
@@ -647,57 +1979,36 @@ impl<'a> Composer<'a> {
             values.push(serde_yaml::to_string(&map)?)
         }
 
-        if node.deploy_steps["helm"].clone().unwrap()["repository"].clone() != "" {
-            attributes.push((
-                "repository",
-                node.deploy_steps["helm"].clone().unwrap()["repository"].clone(),
-            ));
-            attributes.push((
-                "chart_name",
-                node.deploy_steps["helm"].clone().unwrap()["chart"].clone(),
-            ));
-        } else {
-            // If repository is not specified, we assume that the chart is local.
-            let local_path =
-                torb_path().join(node.deploy_steps["helm"].clone().unwrap()["chart"].clone());
-            attributes.push(("chart_name", local_path.to_str().unwrap().to_string()));
-        }
-
-        let mut depends_on_exprs = vec![];
-
-        for dep in node.dependencies.iter() {
-            let dep_fqn = &dep.fqn;
-
-            if node.implicit_dependency_fqns.get(dep_fqn).is_none() {
-                let dep_fqn_name = dep_fqn.clone().replace(".", "_");
-                depends_on_exprs.push(RawExpression::from(format!("module.{dep_fqn_name}")))
-            }
-        }
-
-        let module_version = node.deploy_steps["helm"]
-            .clone()
-            .unwrap()
-            .get("version")
-            .unwrap_or(&"".to_string())
-            .clone();
-
-        if module_version != "" {
-            attributes.push(("version", module_version));
-        }
+        let resolver_fn = &mut |address: Result<InputAddress, TorbInput>| -> String {
+            self.interpolate_inputs_into_helm_values(node, address)
+        };
 
-        let output_block = self.create_output_data_block(node)?;
+        let (mapped_values, _, _) = InputResolver::resolve(node, Some(resolver_fn), NO_INPUTS_FN, NO_INITS_FN)?;
+        let mapped_values = mapped_values.expect("Unable to resolve values field.");
 
-        let inputs = self.create_input_values(node);
+        let node_overrides = self
+            .values_overrides
+            .iter()
+            .filter(|values_override| values_override.applies_to(node))
+            .collect::<Vec<&ValuesOverride>>();
 
-        let resolver_fn = &mut |address: Result<InputAddress, TorbInput>| -> String {
-            self.interpolate_inputs_into_helm_values(address)
+        let array_values = if *array_rendering == ArrayRendering::Yaml {
+            Composer::array_inputs_as_values(node)?
+        } else {
+            Value::Null
         };
 
-        let (mapped_values, _, _) = InputResolver::resolve(node, Some(resolver_fn), NO_INPUTS_FN, NO_INITS_FN)?;
+        if !node_overrides.is_empty() || !array_values.is_null() {
+            let mut merged: Value = serde_yaml::from_str(&mapped_values).unwrap_or(Value::Null);
+            merged = merge_yaml_values(merged, array_values);
 
+            for values_override in node_overrides {
+                merged = merge_yaml_values(merged, values_override.values.clone());
+            }
 
-        if mapped_values.clone().unwrap() != "---\n~\n" {
-            values.push(mapped_values.expect("Unable to resolve values field."));
+            values.push(serde_yaml::to_string(&merged)?);
+        } else if mapped_values != "---\n~\n" {
+            values.push(mapped_values);
         }
 
         if self.watcher_patch {
@@ -712,51 +2023,6 @@ impl<'a> Composer<'a> {
             values.push(patch_yaml);
         }
 
-        let mut builder = std::mem::take(&mut self.main_struct);
-
-        let mut block = Block::builder("module")
-                .add_label(&name)
-                .add_attributes(attributes)
-                .add_attribute(("inputs", inputs));
-
-        if !values.is_empty() {
-            block = block.add_attribute(("values", values));
-        }
-
-        let postrender_conf_opt = self.dev_mounts.get(&node.fqn);
-        if postrender_conf_opt.is_some() {
-            let postrender_conf = postrender_conf_opt.unwrap();
-
-            block = block.add_attribute(
-                ("postrender_path", "./torb_artifacts/common/dev/volume_and_mount/kustomize.sh".to_string())
-            );
-
-            block = block.add_attribute((
-                "postrender_args",
-                Expression::Array(vec![
-                    Expression::String(node.display_name(false)),
-                    Expression::String(postrender_conf.get("container_mount").unwrap().to_string()),
-                    Expression::String(postrender_conf.get("local_mount").unwrap().to_string())
-                ])
-            ))
-
-        }
-
-
-        if !depends_on_exprs.is_empty() {
-            let depends_on = Expression::from(depends_on_exprs);
-
-            block = block.add_attribute(("depends_on", depends_on));
-        }
-
-        builder = builder.add_block(
-            block.build()
-        );
-
-        builder = builder.add_block(output_block);
-
-        self.main_struct = builder;
-
-        Ok(())
+        Ok(values)
     }
 }
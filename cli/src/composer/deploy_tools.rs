@@ -0,0 +1,245 @@
+// Business Source License 1.1
+// Licensor:  Torb Foundry
+// Licensed Work:  Torb v0.3.7-03.23
+// The Licensed Work is © 2023-Present Torb Foundry
+//
+// Change License: GNU Affero General Public License Version 3
+// Additional Use Grant: None
+// Change Date: Feb 22, 2023
+//
+// See LICENSE file at https://github.com/TorbFoundry/torb/blob/main/LICENSE for details.
+
+use super::{ArrayRendering, ChartSource, Composer};
+use crate::artifacts::ArtifactNodeRepr;
+use crate::utils::{kebab_to_snake_case, kube_target, shell_quote};
+
+use hcl::{Block, Expression, Object, ObjectKey, RawExpression};
+use indexmap::IndexMap;
+use std::error::Error;
+
+/*
+    How a node is turned into Terraform, keyed by the tool named under deploy in the unit's torb.yaml.
+    Supporting a new tool means implementing this and adding it to DEPLOY_TOOL_REGISTRY, the composer
+    and resolver only ever go through the registry.
+*/
+pub trait DeployTool: Sync {
+    fn name(&self) -> &'static str;
+
+    // The blocks that deploy the node, conf is the tool's entry under deploy.
+    fn blocks(
+        &self,
+        composer: &Composer,
+        node: &ArtifactNodeRepr,
+        conf: &IndexMap<String, String>,
+    ) -> Result<Vec<Block>, Box<dyn Error>>;
+
+    // The address other nodes put in depends_on to wait for this one.
+    fn dependency_address(&self, node: &ArtifactNodeRepr) -> String;
+}
+
+static DEPLOY_TOOL_REGISTRY: [&dyn DeployTool; 2] = [&Helm, &Kubectl];
+
+pub fn deploy_tool(name: &str) -> Option<&'static dyn DeployTool> {
+    DEPLOY_TOOL_REGISTRY.iter().copied().find(|tool| tool.name() == name)
+}
+
+pub fn deploy_tool_names() -> Vec<&'static str> {
+    DEPLOY_TOOL_REGISTRY.iter().map(|tool| tool.name()).collect()
+}
+
+// Helm nodes are modules wrapping the Torb provider's helm release, with a data block to read their outputs back.
+pub struct Helm;
+
+impl DeployTool for Helm {
+    fn name(&self) -> &'static str {
+        "helm"
+    }
+
+    fn blocks(
+        &self,
+        composer: &Composer,
+        node: &ArtifactNodeRepr,
+        helm: &IndexMap<String, String>,
+    ) -> Result<Vec<Block>, Box<dyn Error>> {
+        let node_source = node.source.clone().unwrap();
+        let namespace_dir = kebab_to_snake_case(&node_source);
+
+        let source = format!("./{namespace_dir}/{}_module", node.display_name(false));
+        let name = node.fqn.clone().replace(".", "_");
+
+        let namespace = composer.artifact_repr.namespace(node);
+
+        let mut attributes = vec![
+            ("source", source),
+            ("release_name", composer.helm_release_name(node)),
+            ("namespace", namespace),
+        ];
+
+        match ChartSource::for_node(node, helm)? {
+            ChartSource::Repository { repository, chart } => {
+                attributes.push(("repository", repository));
+                attributes.push(("chart_name", chart));
+            }
+            ChartSource::Local { chart } => {
+                // If repository is not specified, we assume that the chart is local.
+                let local_path = ChartSource::local_chart_path(node, &chart)?;
+                attributes.push(("chart_name", local_path.to_str().unwrap().to_string()));
+            }
+        }
+
+        let depends_on_exprs = composer.depends_on_expressions(node);
+
+        let module_version = helm.get("version").cloned().unwrap_or_default();
+
+        if module_version != "" {
+            attributes.push(("version", module_version));
+        }
+
+        let output_block = composer.create_output_data_block(node)?;
+
+        let array_rendering = ArrayRendering::for_node(node, helm)?;
+        let inputs = composer.create_input_values(node, &array_rendering);
+        let values = composer.helm_values(node, &array_rendering)?;
+
+        let mut block = Block::builder("module")
+                .add_label(&name)
+                .add_attributes(attributes)
+                .add_attribute(("inputs", inputs));
+
+        if !values.is_empty() {
+            block = block.add_attribute(("values", values));
+        }
+
+        let postrender_conf_opt = composer.dev_mounts.get(&node.fqn);
+        if postrender_conf_opt.is_some() {
+            let postrender_conf = postrender_conf_opt.unwrap();
+
+            block = block.add_attribute(
+                ("postrender_path", "./torb_artifacts/common/dev/volume_and_mount/kustomize.sh".to_string())
+            );
+
+            block = block.add_attribute((
+                "postrender_args",
+                Expression::Array(vec![
+                    Expression::String(node.display_name(false)),
+                    Expression::String(postrender_conf.get("container_mount").unwrap().to_string()),
+                    Expression::String(postrender_conf.get("local_mount").unwrap().to_string())
+                ])
+            ))
+
+        }
+
+
+        if !depends_on_exprs.is_empty() {
+            let depends_on = Expression::from(depends_on_exprs);
+
+            block = block.add_attribute(("depends_on", depends_on));
+        }
+
+        let mut blocks = vec![block.build(), output_block];
+        blocks.extend(composer.create_output_block(node));
+
+        Ok(blocks)
+    }
+
+    fn dependency_address(&self, node: &ArtifactNodeRepr) -> String {
+        format!("module.{}", node.fqn.replace(".", "_"))
+    }
+}
+
+// Kubectl nodes are null resources that apply the unit's manifests when created and delete them when destroyed.
+pub struct Kubectl;
+
+/*
+    Raw manifests are applied with kubectl from a local-exec provisioner, the triggers hash the manifests
+    so Terraform re-applies them when they change and has what it needs to delete them on destroy.
+*/
+impl DeployTool for Kubectl {
+    fn name(&self) -> &'static str {
+        "kubectl"
+    }
+
+    fn blocks(
+        &self,
+        composer: &Composer,
+        node: &ArtifactNodeRepr,
+        conf: &IndexMap<String, String>,
+    ) -> Result<Vec<Block>, Box<dyn Error>> {
+        let (apply_flag, manifests_path) = Composer::kubectl_manifests_path(node, conf)?;
+        let manifests_path_str = manifests_path.to_str().unwrap().to_string();
+
+        let manifests_hash = if manifests_path.is_dir() {
+            format!(
+                "sha1(join(\"\", [for f in fileset(\"{0}\", \"**\") : filesha1(\"{0}/${{f}}\")]))",
+                manifests_path_str
+            )
+        } else {
+            format!("filesha1(\"{}\")", manifests_path_str)
+        };
+
+        let name = node.fqn.clone().replace(".", "_");
+        let namespace = composer.artifact_repr.namespace(node);
+
+        let mut triggers: Object<ObjectKey, Expression> = Object::new();
+        triggers.insert(
+            ObjectKey::Expression(Expression::String("manifests".to_string())),
+            Expression::String(manifests_path_str.clone()),
+        );
+        triggers.insert(
+            ObjectKey::Expression(Expression::String("apply_flag".to_string())),
+            Expression::String(apply_flag.to_string()),
+        );
+        triggers.insert(
+            ObjectKey::Expression(Expression::String("namespace".to_string())),
+            Expression::String(namespace.clone()),
+        );
+        triggers.insert(
+            ObjectKey::Expression(Expression::String("manifests_hash".to_string())),
+            Expression::Raw(RawExpression::new(manifests_hash)),
+        );
+
+        // The cluster is a trigger as well so destroy deletes from the cluster the manifests were applied to.
+        let kube_args = kube_target().args("kubectl").iter().map(|arg| shell_quote(arg)).collect::<Vec<String>>().join(" ");
+        triggers.insert(
+            ObjectKey::Expression(Expression::String("kube_args".to_string())),
+            Expression::String(kube_args.clone()),
+        );
+
+        let apply_command = format!(
+            "kubectl {3} create namespace {0} --dry-run=client -o yaml | kubectl {3} apply -f - && kubectl {3} apply {1} {2} --namespace {0}",
+            shell_quote(&namespace), apply_flag, shell_quote(&manifests_path_str), kube_args
+        );
+
+        let delete_command = "kubectl ${self.triggers.kube_args} delete ${self.triggers.apply_flag} '${self.triggers.manifests}' --namespace '${self.triggers.namespace}' --ignore-not-found".to_string();
+
+        let mut block = Block::builder("resource")
+            .add_label("null_resource")
+            .add_label(&name)
+            .add_attribute(("triggers", Expression::Object(triggers)))
+            .add_block(
+                Block::builder("provisioner")
+                    .add_label("local-exec")
+                    .add_attribute(("command", apply_command))
+                    .build(),
+            )
+            .add_block(
+                Block::builder("provisioner")
+                    .add_label("local-exec")
+                    .add_attribute(("when", RawExpression::from("destroy".to_string())))
+                    .add_attribute(("command", delete_command))
+                    .build(),
+            );
+
+        let depends_on_exprs = composer.depends_on_expressions(node);
+
+        if !depends_on_exprs.is_empty() {
+            block = block.add_attribute(("depends_on", Expression::from(depends_on_exprs)));
+        }
+
+        Ok(vec![block.build()])
+    }
+
+    fn dependency_address(&self, node: &ArtifactNodeRepr) -> String {
+        format!("null_resource.{}", node.fqn.replace(".", "_"))
+    }
+}
@@ -11,29 +11,259 @@
 
 use serde::{Serialize, Deserialize};
 use serde_yaml::{self};
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use std::fs;
 use indexmap::IndexMap;
+use thiserror::Error;
 
 use crate::utils::{torb_path};
 
-#[derive(Serialize, Deserialize)]
+const FILE_REFERENCE_PREFIX: &str = "file:";
+const ENV_REFERENCE_PREFIX: &str = "env:";
+
+#[derive(Error, Debug)]
+pub enum TorbConfigErrors {
+    #[error("{key} references the file {path}, but it couldn't be read, reason: {reason}")]
+    UnreadableSecretFile { key: String, path: String, reason: String },
+    #[error("{key} references the environment variable {var}, but it isn't set.")]
+    MissingSecretEnv { key: String, var: String },
+    #[error("{key} resolved to an empty value.")]
+    EmptySecret { key: String },
+    #[error("Unable to read {path}, reason: {reason}")]
+    UnreadableConfig { path: String, reason: String },
+    #[error("{path} isn't a valid Torb config, reason: {reason}")]
+    InvalidConfig { path: String, reason: String },
+    #[error("vcs.provider is {provider}, supported providers are github and generic.")]
+    UnsupportedVcsProvider { provider: String },
+    #[error("vcs.provider is generic but vcs.remote isn't set, it should be the remote URL with {{name}} where the repo name goes, e.g. git@git.example.com:team/{{name}}.git")]
+    MissingVcsRemote,
+}
+
+/*
+    Secrets in config.yaml can be given directly or as a reference, file:<path> reads the secret from a file
+    and env:<VAR> from an environment variable, so the token doesn't have to sit in the config in plaintext.
+*/
+fn resolve_secret(key: &str, value: &str) -> Result<String, TorbConfigErrors> {
+    let resolved = if let Some(path) = value.strip_prefix(FILE_REFERENCE_PREFIX) {
+        let path = path.trim();
+        let expanded = match path.strip_prefix("~/") {
+            Some(rest) => dirs::home_dir().unwrap_or_default().join(rest),
+            None => std::path::PathBuf::from(path),
+        };
+
+        fs::read_to_string(&expanded).map_err(|err| TorbConfigErrors::UnreadableSecretFile {
+            key: key.to_string(),
+            path: expanded.display().to_string(),
+            reason: err.to_string(),
+        })?
+    } else if let Some(var) = value.strip_prefix(ENV_REFERENCE_PREFIX) {
+        let var = var.trim();
+
+        std::env::var(var).map_err(|_| TorbConfigErrors::MissingSecretEnv {
+            key: key.to_string(),
+            var: var.to_string(),
+        })?
+    } else {
+        value.to_string()
+    };
+
+    let resolved = resolved.trim().to_string();
+
+    if resolved.is_empty() {
+        return Err(TorbConfigErrors::EmptySecret { key: key.to_string() });
+    }
+
+    Ok(resolved)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RegistryConfig {
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>
+}
+
+/*
+    Repositories are configured as url: alias, or as a mapping with an optional alias and a ref
+    (branch, tag or commit) to pin the repository to.
+*/
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum RepositoryConfig {
+    Alias(String),
+    Pinned {
+        #[serde(default)]
+        alias: Option<String>,
+        #[serde(rename = "ref", default)]
+        git_ref: Option<String>,
+    },
+}
+
+impl RepositoryConfig {
+    pub fn alias(&self) -> Option<&str> {
+        match self {
+            RepositoryConfig::Alias(alias) => Some(alias.as_str()),
+            RepositoryConfig::Pinned { alias, .. } => alias.as_deref(),
+        }
+        .filter(|alias| !alias.is_empty())
+    }
+
+    pub fn git_ref(&self) -> Option<&str> {
+        match self {
+            RepositoryConfig::Alias(_) => None,
+            RepositoryConfig::Pinned { git_ref, .. } => git_ref.as_deref().filter(|git_ref| !git_ref.is_empty()),
+        }
+    }
+
+    // The directory the repository is cloned into under ~/.torb/repositories, the alias or the name git gives it.
+    pub fn dir_name(&self, url: &str) -> String {
+        match self.alias() {
+            Some(alias) => alias.to_string(),
+            None => {
+                let name = url.trim_end_matches('/').rsplit(&['/', ':'][..]).next().unwrap_or(url);
+
+                name.trim_end_matches(".git").to_string()
+            }
+        }
+    }
+}
+
+/*
+    Which host repos are created on. github creates the remote through the GitHub API, generic is for Git over SSH
+    without an API, like Gitea or plain bare repos, where the remote already exists or is created on first push.
+    remote is the generic remote URL, {name} is replaced with the repo name and {user} with githubUser.
+*/
+#[derive(Serialize, Deserialize, Clone)]
+pub struct VcsConfig {
+    pub provider: Option<String>,
+    pub remote: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VcsProvider {
+    Github,
+    Generic { remote: String },
+}
+
+const DEFAULT_PROVIDER_SOURCE: &str = "TorbFoundry/torb";
+const DEFAULT_PROVIDER_VERSION: &str = "0.1.2";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProviderConfig {
+    pub source: Option<String>,
+    pub version: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Default)]
 #[allow(non_snake_case)]
 pub struct Config {
+    #[serde(default)]
     pub githubToken: String,
+    #[serde(default)]
     pub githubUser: String,
-    pub repositories: Option<IndexMap<String, String>>
+    pub repositories: Option<IndexMap<String, RepositoryConfig>>,
+    pub builder: Option<String>,
+    pub registries: Option<IndexMap<String, RegistryConfig>>,
+    pub provider: Option<ProviderConfig>,
+    pub strict_implicit_ordering: Option<bool>,
+    pub insecure_registries: Option<Vec<String>>,
+    pub vcs: Option<VcsConfig>,
+    pub allow_shell_inputs: Option<bool>
 }
 
 impl Config {
-    fn new() -> Config {
-        let torb_path = torb_path();
-        let config_path = torb_path.join("config.yaml");
+    // Reads config.yaml without panicking, for checking it's valid before anything uses TORB_CONFIG.
+    pub fn load() -> Result<Config, TorbConfigErrors> {
+        let config_path = torb_path().join("config.yaml");
+        let path = config_path.display().to_string();
+
+        let conf_str = fs::read_to_string(&config_path).map_err(|err| TorbConfigErrors::UnreadableConfig {
+            path: path.clone(),
+            reason: err.to_string(),
+        })?;
+
+        serde_yaml::from_str(conf_str.as_str()).map_err(|err| TorbConfigErrors::InvalidConfig {
+            path,
+            reason: err.to_string(),
+        })
+    }
+
+    pub fn repository_ref(&self, dir_name: &str) -> Option<String> {
+        self.repositories
+            .as_ref()?
+            .iter()
+            .find(|(url, repo)| repo.dir_name(url) == dir_name)
+            .and_then(|(_, repo)| repo.git_ref().map(|git_ref| git_ref.to_string()))
+    }
+
+    pub fn has_repository(&self, dir_name: &str) -> bool {
+        self.repositories
+            .as_ref()
+            .map_or(false, |repositories| repositories.iter().any(|(url, repo)| repo.dir_name(url) == dir_name))
+    }
+
+    // The GitHub token with any file: or env: reference resolved.
+    pub fn github_token(&self) -> Result<String, TorbConfigErrors> {
+        resolve_secret("githubToken", &self.githubToken)
+    }
+
+    pub fn vcs_provider(&self) -> Result<VcsProvider, TorbConfigErrors> {
+        let vcs = match self.vcs.as_ref() {
+            Some(vcs) => vcs,
+            None => return Ok(VcsProvider::Github),
+        };
 
-        let conf_str = fs::read_to_string(config_path).expect("Failed to read config.yaml");
+        match vcs.provider.as_deref().unwrap_or("github") {
+            "github" => Ok(VcsProvider::Github),
+            "generic" => {
+                let remote = vcs.remote.clone().filter(|remote| !remote.is_empty()).ok_or(TorbConfigErrors::MissingVcsRemote)?;
 
-        serde_yaml::from_str(conf_str.as_str()).expect("Failed to parse config.yaml")
+                Ok(VcsProvider::Generic { remote })
+            }
+            provider => Err(TorbConfigErrors::UnsupportedVcsProvider {
+                provider: provider.to_string(),
+            }),
+        }
     }
+
+    pub fn strict_implicit_ordering(&self) -> bool {
+        self.strict_implicit_ordering.unwrap_or(false)
+    }
+
+    pub fn allow_shell_inputs(&self) -> bool {
+        self.allow_shell_inputs.unwrap_or(false)
+    }
+
+    pub fn insecure_registries(&self) -> Vec<String> {
+        self.insecure_registries.clone().unwrap_or_default()
+    }
+
+    pub fn provider_source(&self) -> String {
+        self.provider
+            .as_ref()
+            .and_then(|provider| provider.source.clone())
+            .unwrap_or(DEFAULT_PROVIDER_SOURCE.to_string())
+    }
+
+    pub fn provider_version(&self) -> String {
+        self.provider
+            .as_ref()
+            .and_then(|provider| provider.version.clone())
+            .unwrap_or(DEFAULT_PROVIDER_VERSION.to_string())
+    }
+}
+
+static LOADED_CONFIG: OnceCell<Config> = OnceCell::new();
+
+// Loads config.yaml the first time it's called, later calls return the same config.
+pub fn load_config() -> Result<&'static Config, TorbConfigErrors> {
+    LOADED_CONFIG.get_or_try_init(Config::load)
 }
 
-pub static TORB_CONFIG: Lazy<Config> = Lazy::new(Config::new);
\ No newline at end of file
+/*
+    TORB_CONFIG falls back to an empty config instead of panicking when config.yaml can't be loaded. The library's
+    entry points and the CLI call load_config before anything reads it, so a missing or invalid config is returned
+    as an error there rather than quietly replaced.
+*/
+pub static TORB_CONFIG: Lazy<&'static Config> =
+    Lazy::new(|| load_config().unwrap_or_else(|_| LOADED_CONFIG.get_or_init(Config::default)));
\ No newline at end of file
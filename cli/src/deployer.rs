@@ -10,114 +10,681 @@
 // See LICENSE file at https://github.com/TorbFoundry/torb/blob/main/LICENSE for details.
 
 use crate::{artifacts::{ArtifactRepr}, utils::{CommandConfig, CommandPipeline}};
-use std::process::Command;
-use crate::utils::{torb_path, buildstate_path_or_create};
+use crate::composer::{node_output_name, Composer};
+use crate::resolver::inputs::resolve_smoke_test;
+use crate::timings::timed;
+use crate::utils::{
+    torb_path, buildstate_path_or_create, create_buildstate_dir, get_resource_kind, run_command_in_user_shell, ResourceKind,
+    TorbUtilityErrors,
+};
+use indexmap::{IndexMap, IndexSet};
+use log::{debug, info};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
+pub const DEFAULT_READINESS_TIMEOUT: Duration = Duration::from_secs(300);
+const READINESS_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const READINESS_MAX_BACKOFF: Duration = Duration::from_secs(30);
+const READINESS_CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_STABLE_TIMEOUT: Duration = Duration::from_secs(600);
+const READY_STATUS: &str = "ready";
+const HELM_RELEASE_TIMEOUT: Duration = Duration::from_secs(300);
+const OUTPUTS_FILE: &str = "outputs.json";
+
 #[derive(Error, Debug)]
 pub enum TorbDeployErrors {
     #[error("Failed to deploy stack with reason: {reason}")]
     FailedDeployment {
         reason: String
+    },
+    #[error("{fqn} in namespace {namespace} was not ready after {elapsed:?}, last status: {status}")]
+    ReleaseNotReady {
+        fqn: String,
+        namespace: String,
+        elapsed: Duration,
+        status: String,
+    },
+    #[error("The stack was not stable after {elapsed:?}, these nodes are still rolling out:\n\n{nodes}")]
+    StackNotStable {
+        elapsed: Duration,
+        nodes: String,
+    },
+    #[error("Smoke test for {fqn} failed with reason: {reason}")]
+    SmokeTestFailed {
+        fqn: String,
+        reason: String,
+    },
+    #[error("The stack doesn't set a release, without one release names are generated and orphaned releases can't be told apart.")]
+    ReleaseNotSet,
+    #[error("Unable to list the Helm releases in namespace {namespace}, reason: {reason}")]
+    UnableToListReleases {
+        namespace: String,
+        reason: String,
+    },
+    #[error("Unable to read the stack's Terraform outputs, reason: {reason}")]
+    UnableToReadOutputs {
+        reason: String,
+    },
+    #[error("Failed to uninstall {release} in namespace {namespace}, reason: {reason}")]
+    UninstallFailed {
+        release: String,
+        namespace: String,
+        reason: String,
+    },
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct HelmRelease {
+    pub name: String,
+    pub namespace: String,
+}
+
+/*
+    A release is orphaned when it's in one of the stack's namespaces and named with the stack's release prefix,
+    but no helm node in the stack deploys it any more, usually because the node was removed from the stack.
+    Another stack whose release starts with this one's and a dash matches the prefix as well, so callers should
+    show what was found before uninstalling anything.
+*/
+pub fn orphaned_releases(artifact: &ArtifactRepr) -> Result<Vec<HelmRelease>, TorbDeployErrors> {
+    let release = artifact.release.as_ref().ok_or(TorbDeployErrors::ReleaseNotSet)?;
+    let prefix = format!("{}-", release);
+
+    let helm_nodes = artifact
+        .nodes
+        .values()
+        .filter(|node| matches!(node.deploy_steps.get("helm"), Some(Some(_))));
+
+    let expected = helm_nodes
+        .map(|node| format!("{}-{}", release, node.display_name(true)))
+        .collect::<HashSet<String>>();
+
+    let mut namespaces = artifact
+        .nodes
+        .values()
+        .map(|node| artifact.namespace(node))
+        .collect::<IndexSet<String>>();
+    namespaces.sort();
+
+    let mut orphaned = vec![];
+
+    for namespace in namespaces {
+        for name in list_releases(&namespace)? {
+            if name.starts_with(&prefix) && !expected.contains(&name) {
+                orphaned.push(HelmRelease {
+                    name,
+                    namespace: namespace.clone(),
+                });
+            }
+        }
     }
+
+    Ok(orphaned)
+}
+
+// --all so failed and pending releases, which are just as likely to be left behind, are listed too.
+fn list_releases(namespace: &str) -> Result<Vec<String>, TorbDeployErrors> {
+    let list_error = |reason: String| TorbDeployErrors::UnableToListReleases {
+        namespace: namespace.to_string(),
+        reason,
+    };
+
+    let conf = CommandConfig::new(
+        "helm",
+        vec!["ls", "--all", "--namespace", namespace, "--output", "json"],
+        None,
+    )
+    .with_timeout(READINESS_CHECK_TIMEOUT);
+
+    let out = CommandPipeline::execute_single(conf).map_err(|err| list_error(err.to_string()))?;
+    let releases: Vec<serde_json::Value> =
+        serde_json::from_slice(&out.stdout).map_err(|err| list_error(err.to_string()))?;
+
+    Ok(releases
+        .iter()
+        .filter_map(|release| release.get("name")?.as_str().map(|name| name.to_string()))
+        .collect())
+}
+
+pub fn uninstall_release(release: &HelmRelease) -> Result<(), TorbDeployErrors> {
+    info!("Uninstalling {} in namespace {}...", release.name, release.namespace);
+
+    let conf = CommandConfig::new(
+        "helm",
+        vec!["uninstall", release.name.as_str(), "--namespace", release.namespace.as_str(), "--wait"],
+        None,
+    )
+    .with_timeout(HELM_RELEASE_TIMEOUT);
+
+    CommandPipeline::execute_single(conf).map_err(|err| TorbDeployErrors::UninstallFailed {
+        release: release.name.clone(),
+        namespace: release.namespace.clone(),
+        reason: err.to_string(),
+    })?;
+
+    Ok(())
+}
+
+// A node's workload while waiting for the stack to be stable, status is the last rollout status read for it.
+struct StableCheck {
+    fqn: String,
+    namespace: String,
+    resource: String,
+    status: String,
 }
 
 pub struct StackDeployer {
-    watcher_patch: bool
+    watcher_patch: bool,
+    readiness_timeout: Duration,
+    tf_parallelism: Option<u32>,
+    stable_timeout: Option<Duration>,
+    buildstate_dir: Option<std::path::PathBuf>,
 }
 
 impl StackDeployer {
     pub fn new(watcher_patch: bool) -> StackDeployer {
         StackDeployer {
-            watcher_patch
+            watcher_patch,
+            readiness_timeout: DEFAULT_READINESS_TIMEOUT,
+            tf_parallelism: None,
+            stable_timeout: None,
+            buildstate_dir: None,
         }
     }
 
+    // Deploys from a buildstate other than the project's, meta stacks keep their own under ~/.torb.
+    pub fn with_buildstate_dir(mut self, buildstate_dir: std::path::PathBuf) -> StackDeployer {
+        self.buildstate_dir = Some(buildstate_dir);
+
+        self
+    }
+
+    fn buildstate_path(&self) -> Result<std::path::PathBuf, TorbUtilityErrors> {
+        match &self.buildstate_dir {
+            Some(dir) => create_buildstate_dir(dir.clone()),
+            None => buildstate_path_or_create(),
+        }
+    }
+
+    pub fn with_readiness_timeout(mut self, readiness_timeout: Duration) -> StackDeployer {
+        self.readiness_timeout = readiness_timeout;
+
+        self
+    }
+
+    /*
+        Passed to terraform plan and apply as -parallelism. This only limits how many resources Terraform works on at once
+        while walking its graph, the order nodes deploy in still comes from their dependencies.
+    */
+    pub fn with_tf_parallelism(mut self, tf_parallelism: Option<u32>) -> StackDeployer {
+        self.tf_parallelism = tf_parallelism;
+
+        self
+    }
+
+    // After every node has been verified, waits up to the timeout for all of the stack's workloads to be rolled out at once.
+    pub fn with_stable_timeout(mut self, stable_timeout: Option<Duration>) -> StackDeployer {
+        self.stable_timeout = stable_timeout;
+
+        self
+    }
+
     pub fn deploy(
         &mut self,
         artifact: &ArtifactRepr,
         dryrun: bool,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        println!("Deploying {} stack...", artifact.stack_name.as_str());
+        info!("Deploying {} stack...", artifact.stack_name.as_str());
+
+        timed("terraform init", None, || self.init_tf(&artifact.stack_name))?;
+
+        self.copy_watcher_state()?;
+
+        if dryrun {
+            timed("terraform plan", None, || self.deploy_tf(&artifact.stack_name, &[], true))?;
+
+            return Ok(());
+        }
+
+        if artifact.release.is_none() {
+            info!("Stack has no release name set, skipping readiness checks.");
+        }
+
+        /*
+            The stack is applied a stage at a time in deploy order, and each stage's nodes have to be ready and pass their
+            smoke tests before the nodes depending on them are applied. Earlier stages are applied with -target, the last
+            one without so everything else in the IaC environment, like the data blocks outputs are read from, goes with it.
+        */
+        let mut stages = artifact.deploy_stages()?;
+
+        if stages.is_empty() {
+            stages.push(vec![]);
+        }
+
+        let last_stage = stages.len() - 1;
+
+        for (idx, stage) in stages.iter().enumerate() {
+            let targets = if idx == last_stage {
+                vec![]
+            } else {
+                stage
+                    .iter()
+                    .map(|fqn| format!("-target={}", Composer::dependency_address(&artifact.nodes[fqn])))
+                    .collect::<Vec<String>>()
+            };
+
+            info!("Applying deploy stage {} of {}: {}", idx + 1, stages.len(), stage.join(", "));
+
+            timed("terraform apply", None, || self.deploy_tf(&artifact.stack_name, &targets, false))?;
+            timed("verify", None, || self.verify_nodes(artifact, stage))?;
+        }
+
+        if let Some(stable_timeout) = self.stable_timeout {
+            timed("stable", None, || self.wait_for_stable(artifact, stable_timeout))?;
+        }
+
+        Ok(())
+    }
+
+    /*
+        Nodes are verified one at a time, so by the end an earlier node can have fallen over again, for example
+        crash looping once the nodes it talks to came up. This checks every helm node's workload together until
+        they're all rolled out at the same time or the timeout runs out, then prints where each one ended up.
+    */
+    fn wait_for_stable(&self, artifact: &ArtifactRepr, timeout: Duration) -> Result<(), Box<dyn std::error::Error>> {
+        if artifact.release.is_none() {
+            info!("Stack has no release name set, skipping waiting for it to be stable.");
+
+            return Ok(());
+        }
+
+        let mut checks = vec![];
+
+        for fqn in artifact.deploy_order()? {
+            let node = &artifact.nodes[&fqn];
+
+            if !matches!(node.deploy_steps.get("helm"), Some(Some(_))) {
+                continue;
+            }
+
+            let resource_name = format!("{}-{}", artifact.release(), node.display_name(true));
+            let namespace = artifact.namespace(node);
+
+            if let Some(resource) = StackDeployer::workload_resource(&fqn, &resource_name, &namespace)? {
+                checks.push(StableCheck {
+                    fqn,
+                    namespace,
+                    resource,
+                    status: String::new(),
+                });
+            }
+        }
+
+        info!("Waiting for the stack to be stable...");
+
+        let start = Instant::now();
+        let mut backoff = READINESS_INITIAL_BACKOFF;
+
+        loop {
+            for check in checks.iter_mut() {
+                check.status = match StackDeployer::rollout_status(&check.resource, &check.namespace) {
+                    Ok(status) if status.contains("successfully rolled out") => READY_STATUS.to_string(),
+                    Ok(status) => status,
+                    Err(err) => err.to_string(),
+                };
+            }
+
+            let waiting = checks.iter().filter(|check| check.status != READY_STATUS).collect::<Vec<&StableCheck>>();
+            let elapsed = start.elapsed();
+
+            if waiting.is_empty() {
+                StackDeployer::print_stable_table(&checks);
+                info!("Stack is stable after {:?}.", elapsed);
+
+                return Ok(());
+            }
+
+            if elapsed >= timeout {
+                StackDeployer::print_stable_table(&checks);
+
+                return Err(Box::new(TorbDeployErrors::StackNotStable {
+                    elapsed,
+                    nodes: waiting
+                        .iter()
+                        .map(|check| format!("{} in namespace {}: {}", check.fqn, check.namespace, check.status))
+                        .collect::<Vec<String>>()
+                        .join("\n"),
+                }));
+            }
+
+            let wait = backoff.min(timeout - elapsed);
+
+            info!("Waiting on {} of {} nodes to be ready, checking again in {:?}.", waiting.len(), checks.len(), wait);
+
+            std::thread::sleep(wait);
+
+            backoff = (backoff * 2).min(READINESS_MAX_BACKOFF);
+        }
+    }
+
+    fn print_stable_table(checks: &[StableCheck]) {
+        let fqn_width = checks.iter().map(|check| check.fqn.len()).max().unwrap_or_default().max(4);
+        let namespace_width = checks.iter().map(|check| check.namespace.len()).max().unwrap_or_default().max(9);
+
+        println!("{:<fqn_width$}  {:<namespace_width$}  STATUS", "NODE", "NAMESPACE");
+
+        for check in checks.iter() {
+            let status = check.status.lines().next().unwrap_or_default();
+
+            println!("{:<fqn_width$}  {:<namespace_width$}  {}", check.fqn, check.namespace, status);
+        }
+    }
+
+    /*
+        Helm returning doesn't mean a release's workloads are ready. This runs after each deploy stage is applied, so
+        the nodes depending on the stage aren't applied until its nodes are ready, and a node's smoke test only runs
+        once its release is ready.
+        The workload checked is guessed to be named <release>-<node name>, the name most charts give their Deployment,
+        StatefulSet or DaemonSet, and a chart naming it anything else is skipped. Release names are only stable when the
+        stack sets a release, otherwise they're generated and can't be looked up again.
+    */
+    fn verify_nodes(&self, artifact: &ArtifactRepr, stage: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        for fqn in stage.iter() {
+            let node = &artifact.nodes[fqn];
 
-        self.init_tf()?;
+            if artifact.release.is_some() && matches!(node.deploy_steps.get("helm"), Some(Some(_))) {
+                let resource_name = format!("{}-{}", artifact.release(), node.display_name(true));
+                let namespace = artifact.namespace(node);
 
-        self.deploy_tf(dryrun)?;
+                timed("readiness", Some(fqn), || self.wait_for_release(fqn, &resource_name, &namespace))?;
+            }
+
+            if let Some(smoke_test) = resolve_smoke_test(node, &artifact.nodes)? {
+                timed("smoke test", Some(fqn), || StackDeployer::run_smoke_test(fqn, smoke_test))?;
+            }
+        }
 
         Ok(())
     }
 
-    fn init_tf(&self) -> Result<std::process::Output, Box<dyn std::error::Error>> {
-        println!("Initalizing terraform...");
+    /*
+        Reads the outputs the helm nodes declare back out of Terraform after a deploy and writes them to outputs.json in
+        the buildstate, keyed by node fqn, so tooling can pick up things like generated hostnames. Nodes without
+        declared outputs aren't listed, and a stack with none writes an empty object. Sensitive values are redacted
+        unless --show-secrets is passed.
+    */
+    pub fn capture_outputs(
+        &self,
+        artifact: &ArtifactRepr,
+    ) -> Result<(std::path::PathBuf, IndexMap<String, serde_json::Value>), TorbDeployErrors> {
+        let read_error = |reason: String| TorbDeployErrors::UnableToReadOutputs { reason };
+
+        let torb_path = torb_path();
+        let iac_env_path = self.iac_environment_path().map_err(|err| read_error(err.to_string()))?;
+        let chdir_arg = format!("-chdir={}", iac_env_path.to_str().unwrap());
+
+        let cmd_conf = CommandConfig::new(
+            "./terraform",
+            vec![chdir_arg.as_str(), "output", "-json"],
+            torb_path.to_str()
+        );
+
+        let out = CommandPipeline::execute_single(cmd_conf).map_err(|err| read_error(err.to_string()))?;
+
+        let tf_outputs: IndexMap<String, serde_json::Value> =
+            serde_json::from_slice(&out.stdout).map_err(|err| read_error(err.to_string()))?;
+
+        let mut outputs = IndexMap::<String, serde_json::Value>::new();
+
+        for fqn in artifact.nodes.keys() {
+            if let Some(output) = tf_outputs.get(&node_output_name(fqn)) {
+                let value = output.get("value").unwrap_or(&serde_json::Value::Null);
+
+                outputs.insert(fqn.clone(), StackDeployer::redact_output(artifact, value));
+            }
+        }
+
+        let outputs_path = self
+            .buildstate_path()
+            .map_err(|err| read_error(err.to_string()))?
+            .join(OUTPUTS_FILE);
+
+        let contents = serde_json::to_string_pretty(&outputs).map_err(|err| read_error(err.to_string()))?;
+        std::fs::write(&outputs_path, contents).map_err(|err| read_error(err.to_string()))?;
+
+        Ok((outputs_path, outputs))
+    }
+
+    /*
+        Outputs are often built from sensitive inputs, e.g. a connection string with the password in it, so they're
+        redacted the same way as everything else Torb prints before they're shown or written to outputs.json.
+    */
+    fn redact_output(artifact: &ArtifactRepr, value: &serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::String(text) => serde_json::Value::String(artifact.redact(text)),
+            serde_json::Value::Number(number) => {
+                let text = number.to_string();
+                let redacted = artifact.redact(&text);
+
+                if redacted == text {
+                    value.clone()
+                } else {
+                    serde_json::Value::String(redacted)
+                }
+            }
+            serde_json::Value::Array(values) => {
+                serde_json::Value::Array(values.iter().map(|value| StackDeployer::redact_output(artifact, value)).collect())
+            }
+            serde_json::Value::Object(values) => serde_json::Value::Object(
+                values
+                    .iter()
+                    .map(|(key, value)| (key.clone(), StackDeployer::redact_output(artifact, value)))
+                    .collect(),
+            ),
+            _ => value.clone(),
+        }
+    }
+
+    fn run_smoke_test(fqn: &str, smoke_test: String) -> Result<(), TorbDeployErrors> {
+        info!("Running smoke test for {}...", fqn);
+
+        run_command_in_user_shell(smoke_test, Some("/bin/bash".to_string()), &[], None).map_err(|err| {
+            TorbDeployErrors::SmokeTestFailed {
+                fqn: fqn.to_string(),
+                reason: err.to_string(),
+            }
+        })?;
+
+        info!("Smoke test for {} passed.", fqn);
+
+        Ok(())
+    }
+
+    // The <kind>/<name> kubectl rollout status takes, nodes without a deployment, statefulset or daemonset have nothing to check.
+    fn workload_resource(
+        fqn: &str,
+        resource_name: &String,
+        namespace: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let kind = match get_resource_kind(resource_name, namespace) {
+            Ok(ResourceKind::Deployment) => "deployment",
+            Ok(ResourceKind::StatefulSet) => "statefulset",
+            Ok(ResourceKind::DaemonSet) => "daemonset",
+            Err(err) => match err.downcast_ref::<TorbUtilityErrors>() {
+                Some(TorbUtilityErrors::ResourceNotFound) | Some(TorbUtilityErrors::UnsupportedKind) => {
+                    info!(
+                        "No deployment, statefulset or daemonset named {} in {}, skipping readiness check for {}. Readiness checks expect the chart to name its workload <release>-<node name>.",
+                        resource_name, namespace, fqn
+                    );
+
+                    return Ok(None);
+                }
+                _ => return Err(err),
+            },
+        };
+
+        Ok(Some(format!("{}/{}", kind, resource_name)))
+    }
+
+    fn wait_for_release(
+        &self,
+        fqn: &str,
+        resource_name: &String,
+        namespace: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let resource = match StackDeployer::workload_resource(fqn, resource_name, namespace)? {
+            Some(resource) => resource,
+            None => return Ok(()),
+        };
+
+        let start = Instant::now();
+        let mut backoff = READINESS_INITIAL_BACKOFF;
+
+        loop {
+            let status = StackDeployer::rollout_status(&resource, namespace);
+
+            match status {
+                Ok(ref status) if status.contains("successfully rolled out") => {
+                    info!("{} is ready.", fqn);
+
+                    return Ok(());
+                }
+                _ => {}
+            }
+
+            let status = match status {
+                Ok(status) => status,
+                Err(err) => err.to_string(),
+            };
+
+            let elapsed = start.elapsed();
+
+            if elapsed >= self.readiness_timeout {
+                return Err(Box::new(TorbDeployErrors::ReleaseNotReady {
+                    fqn: fqn.to_string(),
+                    namespace: namespace.to_string(),
+                    elapsed,
+                    status,
+                }));
+            }
+
+            let wait = backoff.min(self.readiness_timeout - elapsed);
+
+            info!("Waiting for {} to be ready, {}, checking again in {:?}.", fqn, status, wait);
+
+            std::thread::sleep(wait);
+
+            backoff = (backoff * 2).min(READINESS_MAX_BACKOFF);
+        }
+    }
+
+    fn rollout_status(resource: &str, namespace: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let cmd_conf = CommandConfig::new(
+            "kubectl",
+            vec![
+                "rollout",
+                "status",
+                resource,
+                "--namespace",
+                namespace,
+                "--watch=false"
+            ],
+            None
+        ).with_timeout(READINESS_CHECK_TIMEOUT);
+
+        let out = CommandPipeline::execute_single(cmd_conf)?;
+
+        Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+    }
+
+    fn init_tf(&self, stack_name: &str) -> Result<std::process::Output, Box<dyn std::error::Error>> {
+        info!("Initalizing terraform...");
         let torb_path = torb_path();
-        let iac_env_path = self.iac_environment_path();
-        let mut cmd = Command::new("./terraform");
-        cmd.arg(format!("-chdir={}", iac_env_path.to_str().unwrap()));
-        cmd.arg("init");
-        cmd.arg("-upgrade");
-        cmd.current_dir(torb_path);
+        let iac_env_path = self.iac_environment_path()?;
+        let chdir_arg = format!("-chdir={}", iac_env_path.to_str().unwrap());
+        let cmd_conf = CommandConfig::new(
+            "./terraform",
+            vec![
+                chdir_arg.as_str(),
+                "init",
+                "-upgrade"
+            ],
+            torb_path.to_str()
+        ).with_streamed_output(stack_name);
 
-        println!("Running command: {:?}", cmd);
-        Ok(cmd.output()?)
+        debug!("Running command: {:?}", cmd_conf);
+        CommandPipeline::execute_single(cmd_conf)
     }
 
-    fn iac_environment_path(&self) -> std::path::PathBuf {
-        let buildstate_path = buildstate_path_or_create();
+    fn iac_environment_path(&self) -> Result<std::path::PathBuf, TorbUtilityErrors> {
+        let buildstate_path = self.buildstate_path()?;
         if self.watcher_patch {
-            buildstate_path.join("watcher_iac_environment")
+            Ok(buildstate_path.join("watcher_iac_environment"))
         } else {
-            buildstate_path.join("iac_environment")
+            Ok(buildstate_path.join("iac_environment"))
+        }
+    }
+
+    // The watcher's IaC environment starts from the state of the last full deploy, copied once before it's applied.
+    fn copy_watcher_state(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.watcher_patch {
+            return Ok(());
         }
+
+        let buildstate_path = self.buildstate_path()?;
+        let tf_state_path = buildstate_path.join("iac_environment").join("terraform.tfstate");
+
+        if tf_state_path.exists() {
+            let new_path = self.iac_environment_path()?.join("terraform.tfstate");
+            std::fs::copy(tf_state_path, new_path).expect("Failed to copy supporting build file.");
+        };
+
+        Ok(())
     }
 
+    // Targets are passed through to plan as -target=<address>, no targets plans the whole stack.
     fn deploy_tf(
         &self,
+        stack_name: &str,
+        targets: &[String],
         dryrun: bool,
     ) -> Result<std::process::Output, Box<dyn std::error::Error>> {
         let torb_path = torb_path();
-        let iac_env_path = self.iac_environment_path();
-
-        if self.watcher_patch {
-            let buildstate_path = buildstate_path_or_create();
-            let non_watcher_iac = buildstate_path.join("iac_environment");
-            let tf_state_path = non_watcher_iac.join("terraform.tfstate");
-
-            if tf_state_path.exists() {
-                let new_path = iac_env_path.join("terraform.tfstate");
-                std::fs::copy(tf_state_path, new_path).expect("Failed to copy supporting build file.");
-            };
-        };
+        let iac_env_path = self.iac_environment_path()?;
 
         let iac_env_str = iac_env_path.to_str().unwrap();
         let chdir_arg = format!("-chdir={}", iac_env_str);
+        let parallelism_arg = self.tf_parallelism.map(|parallelism| format!("-parallelism={}", parallelism));
+
+        let mut plan_args = vec![chdir_arg.as_str(), "plan", "-out=./tfplan"];
+        plan_args.extend(parallelism_arg.as_deref());
+        plan_args.extend(targets.iter().map(|target| target.as_str()));
+
         let cmd_conf = CommandConfig::new(
             "./terraform",
-            vec![
-                chdir_arg.as_str(),
-                "plan",
-                "-out=./tfplan"
-            ],
+            plan_args,
             torb_path.to_str()
-        );
+        ).with_streamed_output(stack_name);
 
         let out = CommandPipeline::execute_single(cmd_conf)?;
 
         if dryrun {
             Ok(out)
         } else {
-            let mut cmd = Command::new("./terraform");
-            cmd.arg(format!("-chdir={}", iac_env_path.to_str().unwrap()))
-            .arg("apply")
-            .arg("./tfplan")
-            .current_dir(&torb_path);
+            let mut apply_args = vec![chdir_arg.as_str(), "apply"];
+            apply_args.extend(parallelism_arg.as_deref());
+            apply_args.push("./tfplan");
 
-            let output = cmd.output()?;
+            let apply_conf = CommandConfig::new(
+                "./terraform",
+                apply_args,
+                torb_path.to_str()
+            ).with_streamed_output(stack_name);
 
-            if output.status.success() {
-                Ok(cmd.output()?)
-            } else {
-                Err(Box::new(TorbDeployErrors::FailedDeployment { reason: String::from_utf8(output.stderr).unwrap() }))
-            }
+            CommandPipeline::execute_single(apply_conf).map_err(|err| {
+                Box::new(TorbDeployErrors::FailedDeployment { reason: err.to_string() }) as Box<dyn std::error::Error>
+            })
         }
     }
 }
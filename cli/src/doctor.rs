@@ -0,0 +1,139 @@
+// Business Source License 1.1
+// Licensor:  Torb Foundry
+// Licensed Work:  Torb v0.3.7-03.23
+// The Licensed Work is © 2023-Present Torb Foundry
+//
+// Change License: GNU Affero General Public License Version 3
+// Additional Use Grant: None
+// Change Date: Feb 22, 2023
+//
+// See LICENSE file at https://github.com/TorbFoundry/torb/blob/main/LICENSE for details.
+
+use crate::config::Config;
+use crate::utils::{check_artifacts_compatibility, check_initialized, torb_path, CommandConfig, CommandPipeline, TorbUtilityErrors};
+
+use serde::Serialize;
+use std::time::Duration;
+
+const DOCTOR_COMMAND_TIMEOUT: Duration = Duration::from_secs(15);
+
+/*
+    The result of one doctor check. Critical checks are things every stack command needs, a failing one means
+    Torb won't work. The others only affect some commands, like reaching a cluster, which building doesn't need.
+*/
+#[derive(Serialize, Debug, Clone)]
+pub struct DoctorCheck {
+    pub name: String,
+    pub passed: bool,
+    pub critical: bool,
+    pub detail: String,
+}
+
+impl DoctorCheck {
+    fn new(name: &str, critical: bool, result: Result<String, String>) -> DoctorCheck {
+        let (passed, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+
+        DoctorCheck {
+            name: name.to_string(),
+            passed,
+            critical,
+            detail,
+        }
+    }
+}
+
+// Runs every check in the order a new install would hit them, each check runs whether or not the ones before it pass.
+pub fn run_checks(torb_version: &str) -> Vec<DoctorCheck> {
+    let mut checks = vec![];
+
+    checks.push(DoctorCheck::new(
+        "~/.torb",
+        true,
+        check_initialized()
+            .map(|_| format!("{} has the repositories, torb-artifacts and terraform torb init sets up", torb_path().display()))
+            .map_err(|err| format!("{} Run `torb init`.", err)),
+    ));
+
+    let config = Config::load();
+
+    checks.push(DoctorCheck::new(
+        "config.yaml",
+        true,
+        config
+            .as_ref()
+            .map(|_| "parsed".to_string())
+            .map_err(|err| err.to_string()),
+    ));
+
+    // Without a readable config the builder falls back to docker, which is the default anyway.
+    let builder = config
+        .as_ref()
+        .ok()
+        .and_then(|config| config.builder.clone())
+        .unwrap_or("docker".to_string());
+
+    checks.push(DoctorCheck::new("git", true, tool_version("git", vec!["--version"], None)));
+
+    if builder == "podman" {
+        checks.push(DoctorCheck::new("podman", true, tool_version("podman", vec!["--version"], None)));
+    } else {
+        checks.push(DoctorCheck::new("docker", true, tool_version("docker", vec!["--version"], None)));
+        checks.push(DoctorCheck::new("docker buildx", true, tool_version("docker", vec!["buildx", "version"], None)));
+    }
+
+    checks.push(DoctorCheck::new("helm", true, tool_version("helm", vec!["version", "--short"], None)));
+    checks.push(DoctorCheck::new("kubectl", true, tool_version("kubectl", vec!["version", "--client"], None)));
+
+    let torb_path = torb_path();
+    checks.push(DoctorCheck::new(
+        "terraform",
+        true,
+        tool_version("./terraform", vec!["version"], torb_path.to_str())
+            .map_err(|_| format!("terraform isn't installed in {}, run `torb init`", torb_path.display())),
+    ));
+
+    checks.push(DoctorCheck::new(
+        "torb-artifacts compatibility",
+        false,
+        check_artifacts_compatibility(torb_version).map_err(|err| err.to_string()),
+    ));
+
+    checks.push(DoctorCheck::new(
+        "cluster",
+        false,
+        tool_version("kubectl", vec!["get", "--raw", "/readyz"], None)
+            .map(|_| "kubectl can reach the cluster and it's ready".to_string())
+            .map_err(|reason| format!("kubectl can't reach the cluster, {}", reason)),
+    ));
+
+    checks
+}
+
+// The first line a tool prints for its version, or why it couldn't be run.
+fn tool_version(command: &str, args: Vec<&str>, working_dir: Option<&str>) -> Result<String, String> {
+    let conf = CommandConfig::new(command, args, working_dir).with_timeout(DOCTOR_COMMAND_TIMEOUT);
+
+    match CommandPipeline::execute_single(conf) {
+        Ok(out) => Ok(String::from_utf8_lossy(&out.stdout).lines().next().unwrap_or("").trim().to_string()),
+        Err(err) => match err.downcast_ref::<TorbUtilityErrors>() {
+            Some(TorbUtilityErrors::UnableToRunCommand { reason, .. }) => {
+                let reason = reason.trim();
+
+                if reason.is_empty() {
+                    Err(format!("{} exited with an error", command))
+                } else {
+                    Err(reason.lines().next().unwrap_or(reason).to_string())
+                }
+            }
+            _ => match err.downcast_ref::<std::io::Error>() {
+                Some(io_err) if io_err.kind() == std::io::ErrorKind::NotFound => {
+                    Err(format!("{} isn't installed or isn't in your PATH", command.trim_start_matches("./")))
+                }
+                _ => Err(err.to_string()),
+            },
+        },
+    }
+}
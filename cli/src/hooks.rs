@@ -0,0 +1,85 @@
+// Business Source License 1.1
+// Licensor:  Torb Foundry
+// Licensed Work:  Torb v0.3.7-03.23
+// The Licensed Work is © 2023-Present Torb Foundry
+//
+// Change License: GNU Affero General Public License Version 3
+// Additional Use Grant: None
+// Change Date: Feb 22, 2023
+//
+// See LICENSE file at https://github.com/TorbFoundry/torb/blob/main/LICENSE for details.
+
+use crate::artifacts::{ArtifactRepr, StackHooks};
+use crate::utils::run_command_in_user_shell;
+
+use log::info;
+use std::fmt;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum TorbHookErrors {
+    #[error("The {stage} hook failed, reason: {reason}")]
+    HookFailed { stage: HookStage, reason: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookStage {
+    PreBuild,
+    PostBuild,
+    PreDeploy,
+    PostDeploy,
+}
+
+impl fmt::Display for HookStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            HookStage::PreBuild => "pre_build",
+            HookStage::PostBuild => "post_build",
+            HookStage::PreDeploy => "pre_deploy",
+            HookStage::PostDeploy => "post_deploy",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+impl HookStage {
+    fn command<'a>(&self, hooks: &'a StackHooks) -> Option<&'a String> {
+        match self {
+            HookStage::PreBuild => hooks.pre_build.as_ref(),
+            HookStage::PostBuild => hooks.post_build.as_ref(),
+            HookStage::PreDeploy => hooks.pre_deploy.as_ref(),
+            HookStage::PostDeploy => hooks.post_deploy.as_ref(),
+        }
+    }
+}
+
+/*
+    Runs the stack's hook for a stage from the current directory, stacks without one are a no-op.
+    Hooks can change things outside of Torb so they're skipped on a dry run.
+*/
+pub fn run_stack_hook(artifact: &ArtifactRepr, stage: HookStage, dryrun: bool) -> Result<(), TorbHookErrors> {
+    let command = match stage.command(&artifact.hooks) {
+        Some(command) if !command.trim().is_empty() => command,
+        _ => return Ok(()),
+    };
+
+    if dryrun {
+        info!("Skipping the {} hook for a dry run: {}", stage, command);
+
+        return Ok(());
+    }
+
+    info!("Running the {} hook...", stage);
+
+    let output = run_command_in_user_shell(command.clone(), Some("/bin/bash".to_string()), &[], None)
+        .map_err(|err| TorbHookErrors::HookFailed { stage, reason: err.to_string() })?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    if !stdout.trim().is_empty() {
+        info!("{}", stdout.trim_end());
+    }
+
+    Ok(())
+}
@@ -10,13 +10,98 @@
 // See LICENSE file at https://github.com/TorbFoundry/torb/blob/main/LICENSE for details.
 
 use crate::{artifacts::{ArtifactRepr, ArtifactNodeRepr}, resolver::inputs::{InputResolver, NO_INPUTS_FN, NO_VALUES_FN}};
-use std::{env::current_dir};
-use crate::utils::{run_command_in_user_shell, buildstate_path_or_create};
+use std::{path::{Path, PathBuf}, time::Duration};
+use crate::utils::{run_command_in_user_shell, buildstate_path_or_create, project_path, CommandConfig, CommandPipeline, TorbUtilityErrors};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use indexmap::IndexSet;
+use log::info;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+const TORB_IGNORE_FILE: &str = ".torbignore";
+const INIT_RECORD_FILE: &str = ".stack_initialized";
+
+#[derive(Error, Debug)]
+pub enum TorbInitializerErrors {
+    #[error("Unable to read {path}, reason: {reason}")]
+    InvalidTorbIgnore { path: String, reason: String },
+    #[error("Unable to copy {path} to {dest}, reason: {reason}")]
+    UnableToCopyFile { path: String, dest: String, reason: String },
+    #[error("The init step for {fqn} was stopped after running for {timeout}s, it may be waiting on a prompt or a service that isn't up. It wasn't marked as initialized, so the next init retries it.")]
+    InitTimedOut { fqn: String, timeout: u64 },
+    #[error("Unable to read the record of initialized nodes at {path}, reason: {reason}. Run torb clean --all to reset it, the next init then runs every node.")]
+    InvalidInitRecord { path: String, reason: String },
+}
+
+/*
+    The fqns of every node initialized in this buildstate, so nodes added to a stack later are initialized by the
+    next init without running the others again. Before nodes were tracked the file was an empty marker for the whole
+    stack, one of those is read as every node in the stack it's first loaded with having been initialized.
+*/
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct InitRecord {
+    nodes: IndexSet<String>,
+}
+
+impl InitRecord {
+    fn path() -> Result<PathBuf, TorbUtilityErrors> {
+        Ok(buildstate_path_or_create()?.join(INIT_RECORD_FILE))
+    }
+
+    /*
+        A legacy marker is migrated by recording the current stack's nodes and saving that straight away, so nodes
+        added to the stack later are seen as new, dry runs leave the marker alone. Anything else that can't be read is an
+        error rather than a guess.
+    */
+    fn load(artifact: &ArtifactRepr, migrate: bool) -> Result<InitRecord, Box<dyn std::error::Error>> {
+        let path = InitRecord::path()?;
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(InitRecord::default()),
+            Err(err) => {
+                return Err(Box::new(TorbInitializerErrors::InvalidInitRecord {
+                    path: path.display().to_string(),
+                    reason: err.to_string(),
+                }))
+            }
+        };
+
+        if contents.trim().is_empty() {
+            let record = InitRecord {
+                nodes: artifact.nodes.keys().cloned().collect(),
+            };
+
+            if migrate {
+                record.save()?;
+            }
+
+            return Ok(record);
+        }
+
+        serde_yaml::from_str::<InitRecord>(&contents).map_err(|err| {
+            Box::new(TorbInitializerErrors::InvalidInitRecord {
+                path: path.display().to_string(),
+                reason: err.to_string(),
+            }) as Box<dyn std::error::Error>
+        })
+    }
+
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let contents = serde_yaml::to_string(self)?;
+
+        std::fs::write(InitRecord::path()?, contents)?;
+
+        Ok(())
+    }
+}
 
 pub struct StackInitializer<'a> {
     artifact: &'a ArtifactRepr,
     initialized: IndexSet<String>,
+    record: InitRecord,
+    dryrun: bool,
+    reinit: bool,
 }
 
 impl<'a> StackInitializer<'a> {
@@ -24,21 +109,35 @@ impl<'a> StackInitializer<'a> {
         StackInitializer {
             artifact: artifact,
             initialized: IndexSet::new(),
+            record: InitRecord::default(),
+            dryrun: false,
+            reinit: false,
         }
     }
 
-    pub fn run_node_init_steps(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let buildstate_path = buildstate_path_or_create();
-        let init_canary_path = buildstate_path.join(".stack_initialized");
+    // Prints the files each node would copy and its resolved init script instead of running anything.
+    pub fn with_dryrun(mut self, dryrun: bool) -> StackInitializer<'a> {
+        self.dryrun = dryrun;
 
-        if !init_canary_path.exists() {
-            for node in self.artifact.deploys.iter() {
-                self.walk_artifact(node)?;
-            }
+        self
+    }
 
-            std::fs::write(init_canary_path, "")?;
-        } else {
-            println!("Stack has already been initialized, skipping.")
+    // Runs the init steps of every node, including the ones that have already been initialized.
+    pub fn with_reinit(mut self, reinit: bool) -> StackInitializer<'a> {
+        self.reinit = reinit;
+
+        self
+    }
+
+    /*
+        Nodes are recorded as initialized one at a time as their init steps finish, so a failed init only retries
+        the nodes that didn't get through on the next run. A dry run shows the nodes an init would run and records nothing.
+    */
+    pub fn run_node_init_steps(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.record = InitRecord::load(self.artifact, !self.dryrun)?;
+
+        for node in self.artifact.deploys.iter() {
+            self.walk_artifact(node)?;
         }
 
         Ok(())
@@ -49,35 +148,134 @@ impl<'a> StackInitializer<'a> {
         let node_dir = node_file_path.parent().unwrap();
 
         let files = node.files.clone().unwrap_or_default();
+        let ignore = StackInitializer::load_torbignore(node_dir)?;
 
         for file in files {
             let file_path = node_dir.join(file);
 
-            if current_dir()?.join(file_path.clone()).exists() {
+            if project_path(file_path.clone()).exists() {
                 let file_name = file_path.file_name().unwrap();
-                let dest_path = current_dir()?.join(file_name);
-                
-                std::fs::copy(file_path, dest_path)?;
+                let dest_path = project_path(file_name);
+
+                if self.dryrun {
+                    println!("  copy {} -> {}", file_path.display(), dest_path.display());
+                } else {
+                    StackInitializer::copy_path(&file_path, &dest_path, &ignore)?;
+                }
             }
         }
 
         Ok(())
     }
 
+    // A .torbignore in the unit's directory uses gitignore syntax, without one nothing is excluded.
+    fn load_torbignore(node_dir: &Path) -> Result<Gitignore, TorbInitializerErrors> {
+        let ignore_path = node_dir.join(TORB_IGNORE_FILE);
+
+        if !ignore_path.exists() {
+            return Ok(Gitignore::empty());
+        }
+
+        let invalid = |reason: String| TorbInitializerErrors::InvalidTorbIgnore {
+            path: ignore_path.display().to_string(),
+            reason,
+        };
+
+        let mut builder = GitignoreBuilder::new(node_dir);
+
+        if let Some(err) = builder.add(&ignore_path) {
+            return Err(invalid(err.to_string()));
+        }
+
+        builder.build().map_err(|err| invalid(err.to_string()))
+    }
+
+    fn copy_path(path: &Path, dest: &Path, ignore: &Gitignore) -> Result<(), TorbInitializerErrors> {
+        let unable_to_copy = |reason: String| TorbInitializerErrors::UnableToCopyFile {
+            path: path.display().to_string(),
+            dest: dest.display().to_string(),
+            reason,
+        };
+
+        let is_dir = path.is_dir();
+
+        if ignore.matched_path_or_any_parents(path, is_dir).is_ignore() {
+            return Ok(());
+        }
+
+        if is_dir {
+            std::fs::create_dir_all(dest).map_err(|err| unable_to_copy(err.to_string()))?;
+
+            let entries = std::fs::read_dir(path).map_err(|err| unable_to_copy(err.to_string()))?;
+
+            for entry in entries {
+                let entry = entry.map_err(|err| unable_to_copy(err.to_string()))?;
+
+                StackInitializer::copy_path(&entry.path(), &dest.join(entry.file_name()), ignore)?;
+            }
+
+            Ok(())
+        } else {
+            std::fs::copy(path, dest)
+                .map(|_| ())
+                .map_err(|err| unable_to_copy(err.to_string()))
+        }
+    }
+
     fn initalize_node(&self, node: &ArtifactNodeRepr) -> Result<(), Box<dyn std::error::Error>> {
+        if self.dryrun {
+            println!("{}:", node.fqn);
+        }
+
         self.copy_required_files(node)?;
 
         if node.init_step.is_some() {
-            let (_, _, resolved_steps) = InputResolver::resolve(node, NO_VALUES_FN, NO_INPUTS_FN, Some(true))?;
+            let (_, _, resolved_steps) = InputResolver::resolve(node, NO_VALUES_FN, NO_INPUTS_FN, Some(&self.artifact.nodes))?;
 
             let script = resolved_steps.unwrap().join("&&");
 
-            run_command_in_user_shell(script, Some("/bin/bash".to_string()))?;
+            if self.dryrun {
+                println!("  /bin/bash -c {}", self.artifact.redact(&script));
+
+                if let Some(timeout) = node.init_timeout.or(self.artifact.init_timeout) {
+                    println!("  stopped after {}s", timeout);
+                }
+
+                return Ok(());
+            }
+
+            match node.init_timeout.or(self.artifact.init_timeout) {
+                Some(timeout) => self.run_init_script_with_timeout(node, &script, timeout)?,
+                None => {
+                    run_command_in_user_shell(script, Some("/bin/bash".to_string()), &[], None)?;
+                }
+            }
         };
 
         Ok(())
     }
 
+    fn run_init_script_with_timeout(
+        &self,
+        node: &ArtifactNodeRepr,
+        script: &str,
+        timeout: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let conf = CommandConfig::new("/bin/bash", vec!["-c", script], None)
+            .with_timeout(Duration::from_secs(timeout));
+
+        match CommandPipeline::execute_single(conf) {
+            Ok(_) => Ok(()),
+            Err(err) => match err.downcast_ref::<TorbUtilityErrors>() {
+                Some(TorbUtilityErrors::CommandTimedOut { .. }) => Err(Box::new(TorbInitializerErrors::InitTimedOut {
+                    fqn: node.fqn.clone(),
+                    timeout,
+                })),
+                _ => Err(err),
+            },
+        }
+    }
+
     fn walk_artifact(
         &mut self,
         node: &ArtifactNodeRepr,
@@ -91,17 +289,32 @@ impl<'a> StackInitializer<'a> {
             self.walk_artifact(child)?
         }
 
-        if !self.initialized.contains(&node.fqn) {
-            self.initalize_node(&node).and_then(|_out| {
-                if self.initialized.insert(node.fqn.clone()) {
-                    Ok(())
-                } else {
-                    Err(Box::new(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "Step already initialized.",
-                    )))
-                }
-            })?;
+        if self.initialized.contains(&node.fqn) {
+            return Ok(());
+        }
+
+        if !self.reinit && self.record.nodes.contains(&node.fqn) {
+            info!("{} has already been initialized, skipping.", node.fqn);
+
+            self.initialized.insert(node.fqn.clone());
+
+            return Ok(());
+        }
+
+        self.initalize_node(&node).and_then(|_out| {
+            if self.initialized.insert(node.fqn.clone()) {
+                Ok(())
+            } else {
+                Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "Step already initialized.",
+                )))
+            }
+        })?;
+
+        if !self.dryrun {
+            self.record.nodes.insert(node.fqn.clone());
+            self.record.save()?;
         }
 
         Ok(())
@@ -0,0 +1,219 @@
+// Business Source License 1.1
+// Licensor:  Torb Foundry
+// Licensed Work:  Torb v0.3.7-03.23
+// The Licensed Work is © 2023-Present Torb Foundry
+//
+// Change License: GNU Affero General Public License Version 3
+// Additional Use Grant: None
+// Change Date: Feb 22, 2023
+//
+// See LICENSE file at https://github.com/TorbFoundry/torb/blob/main/LICENSE for details.
+
+/*
+    Torb as a library. The CLI is a thin wrapper over the functions here, which cover the same
+    resolve -> build -> compose -> deploy pipeline and return errors instead of exiting the process.
+    All of them run relative to the current directory, where the .torb_buildstate folder lives unless TORB_BUILDSTATE_DIR is set.
+    The ones that read ~/.torb/config.yaml load it first and return an error if it's missing or invalid.
+*/
+
+pub mod artifacts;
+pub mod builder;
+pub mod composer;
+pub mod config;
+pub mod deployer;
+pub mod doctor;
+pub mod hooks;
+pub mod initializer;
+pub mod linter;
+pub mod outputs;
+pub mod resolver;
+pub mod timings;
+pub mod utils;
+pub mod vcs;
+
+use indexmap::IndexMap;
+use log::info;
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::artifacts::{get_build_file_info, load_build_file, write_build_file, ArtifactRepr};
+use crate::builder::{default_build_jobs, ContainerBuilder, StackBuilder};
+use crate::composer::{Composer, ValuesOverride};
+use crate::config::load_config;
+use crate::deployer::{StackDeployer, DEFAULT_READINESS_TIMEOUT};
+use crate::resolver::InputOverride;
+use crate::utils::{acquire_buildstate_lock_in, meta_buildstate_path};
+
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    pub platforms: String,
+    pub dryrun: bool,
+    pub separate_local_registry: bool,
+    pub container_builder: ContainerBuilder,
+    pub force: bool,
+    pub build_jobs: usize,
+    pub verify_platforms: bool,
+    // Registry hosts pushed to without TLS verification.
+    pub insecure_registries: Vec<String>,
+}
+
+impl Default for BuildOptions {
+    fn default() -> BuildOptions {
+        BuildOptions {
+            platforms: "linux/amd64,linux/arm64".to_string(),
+            dryrun: false,
+            separate_local_registry: false,
+            container_builder: ContainerBuilder::Docker,
+            force: false,
+            build_jobs: default_build_jobs(),
+            verify_platforms: false,
+            insecure_registries: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeployOptions {
+    pub dryrun: bool,
+    pub readiness_timeout: Duration,
+    // Overrides the stack's tf_parallelism when set.
+    pub tf_parallelism: Option<u32>,
+    // How long to wait for the whole stack to be rolled out after the deploy, it isn't waited for when unset.
+    pub stable_timeout: Option<Duration>,
+}
+
+impl Default for DeployOptions {
+    fn default() -> DeployOptions {
+        DeployOptions {
+            dryrun: false,
+            readiness_timeout: DEFAULT_READINESS_TIMEOUT,
+            tf_parallelism: None,
+            stable_timeout: None,
+        }
+    }
+}
+
+/*
+    Resolves a stack definition and writes its build file, returning the build hash and the resolved stack.
+    The build hash tags the images built for the stack and has to be passed on to compose.
+    Passing an environment applies that entry of the stack's environments section over the base inputs and values,
+    input overrides are applied over both. The build file is written to the buildstate unless build_file_dir is given.
+*/
+pub fn resolve_stack(
+    stack_yaml: String,
+    environment: Option<&str>,
+    input_overrides: &[InputOverride],
+    use_cache: bool,
+    build_file_dir: Option<&PathBuf>,
+) -> Result<(String, ArtifactRepr), Box<dyn Error>> {
+    load_config()?;
+
+    let (_, build_filename, _) = write_build_file(stack_yaml, build_file_dir, environment, input_overrides, use_cache)?;
+    let (build_hash, _, artifact) = load_build_file(build_filename, build_file_dir)?;
+
+    Ok((build_hash, artifact))
+}
+
+pub fn build(artifact: &ArtifactRepr, options: &BuildOptions) -> Result<(), Box<dyn Error>> {
+    load_config()?;
+
+    // The meta stack's images have to be pushed before it can be deployed ahead of this one.
+    if let Some(meta) = artifact.meta.as_ref() {
+        build(meta, options)?;
+    }
+
+    let mut builder = StackBuilder::new(
+        artifact,
+        options.platforms.clone(),
+        options.dryrun,
+        options.separate_local_registry,
+        options.container_builder.clone(),
+        options.force,
+    )
+    .with_build_jobs(options.build_jobs)
+    .with_verify_platforms(options.verify_platforms)
+    .with_insecure_registries(options.insecure_registries.clone());
+
+    builder.build()
+}
+
+// Generates the Terraform IaC environment for the stack in the buildstate's iac_environment folder.
+pub fn compose(
+    build_hash: String,
+    artifact: &ArtifactRepr,
+    values_overrides: Vec<ValuesOverride>,
+) -> Result<(), Box<dyn Error>> {
+    load_config()?;
+
+    let mut composer = Composer::new(build_hash, artifact, false).with_values_overrides(values_overrides);
+
+    composer.compose()
+}
+
+/*
+    Dry runs the stack's manifests against the cluster without changing anything, helm nodes are rendered with helm template first.
+    Pass the same values overrides the stack is composed with so what's validated is what gets deployed.
+*/
+pub fn validate(
+    build_hash: String,
+    artifact: &ArtifactRepr,
+    values_overrides: Vec<ValuesOverride>,
+) -> Result<(), Box<dyn Error>> {
+    load_config()?;
+
+    let composer = Composer::new(build_hash, artifact, false).with_values_overrides(values_overrides);
+
+    composer.validate_manifests()
+}
+
+pub fn deploy(artifact: &ArtifactRepr, options: &DeployOptions) -> Result<(), Box<dyn Error>> {
+    load_config()?;
+
+    if let Some(meta) = artifact.meta.as_ref() {
+        deploy_meta(meta, options)?;
+    }
+
+    stack_deployer(artifact, options).deploy(artifact, options.dryrun)
+}
+
+fn stack_deployer(artifact: &ArtifactRepr, options: &DeployOptions) -> StackDeployer {
+    StackDeployer::new(false)
+        .with_readiness_timeout(options.readiness_timeout)
+        .with_tf_parallelism(options.tf_parallelism.or(artifact.tf_parallelism))
+        .with_stable_timeout(options.stable_timeout)
+}
+
+/*
+    A meta stack is deployed before the stacks using it, composed and applied from its own buildstate under ~/.torb/meta.
+    Every stack sharing the meta applies the same Terraform state, so its resources are created once and left alone
+    by later deploys unless the meta itself changed. Its own meta, if it has one, goes first.
+*/
+fn deploy_meta(meta: &ArtifactRepr, options: &DeployOptions) -> Result<(), Box<dyn Error>> {
+    if let Some(inner_meta) = meta.meta.as_ref() {
+        deploy_meta(inner_meta, options)?;
+    }
+
+    let (build_hash, _, _) = get_build_file_info(meta)?;
+    let buildstate_dir = meta_buildstate_path(&meta.stack_name, meta.stack_file.as_deref());
+    let _lock = acquire_buildstate_lock_in(buildstate_dir.clone(), &format!("deploying the meta stack {}", meta.stack_name))?;
+
+    info!("Deploying meta stack {}...", meta.stack_name);
+
+    Composer::new(build_hash, meta, false)
+        .with_output_root(buildstate_dir.join("iac_environment"))
+        .compose()?;
+
+    stack_deployer(meta, options)
+        .with_buildstate_dir(buildstate_dir)
+        .deploy(meta, options.dryrun)
+}
+
+// Writes the outputs of the last deploy to outputs.json in the buildstate, returning where it was written and the outputs.
+pub fn capture_outputs(
+    artifact: &ArtifactRepr,
+) -> Result<(std::path::PathBuf, IndexMap<String, serde_json::Value>), Box<dyn Error>> {
+    let deployer = StackDeployer::new(false);
+
+    Ok(deployer.capture_outputs(artifact)?)
+}
@@ -0,0 +1,145 @@
+// Business Source License 1.1
+// Licensor:  Torb Foundry
+// Licensed Work:  Torb v0.3.7-03.23
+// The Licensed Work is © 2023-Present Torb Foundry
+//
+// Change License: GNU Affero General Public License Version 3
+// Additional Use Grant: None
+// Change Date: Feb 22, 2023
+//
+// See LICENSE file at https://github.com/TorbFoundry/torb/blob/main/LICENSE for details.
+
+use crate::artifacts::{ArtifactNodeRepr, ArtifactRepr};
+use crate::utils::normalize_name;
+
+use serde_yaml::Value;
+use std::fmt::Display;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LintSeverity {
+    Warning,
+    Error,
+}
+
+impl Display for LintSeverity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LintSeverity::Warning => write!(f, "warning"),
+            LintSeverity::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone)]
+#[error("[{severity}] {fqn}: {message}")]
+pub struct LintWarning {
+    pub fqn: String,
+    pub severity: LintSeverity,
+    pub message: String,
+    pub suggestion: &'static str,
+}
+
+impl LintWarning {
+    fn new(
+        node: &ArtifactNodeRepr,
+        severity: LintSeverity,
+        message: String,
+        suggestion: &'static str,
+    ) -> LintWarning {
+        LintWarning {
+            fqn: node.fqn.clone(),
+            severity,
+            message,
+            suggestion,
+        }
+    }
+}
+
+/*
+    Lints a resolved stack for things that are valid as far as the schema is concerned but are almost always a mistake.
+    The stack yaml is passed alongside the artifact because nodes without an input spec have their inputs dropped during resolution.
+*/
+pub fn lint_stack(artifact: &ArtifactRepr, stack_yaml: &Value) -> Vec<LintWarning> {
+    let mut warnings = Vec::<LintWarning>::new();
+
+    for node in artifact.nodes.values() {
+        lint_inputs(node, stack_yaml, &mut warnings);
+        lint_build_step(node, &mut warnings);
+        lint_helm_deploy(node, &mut warnings);
+    }
+
+    warnings
+}
+
+fn stack_inputs<'a>(node: &ArtifactNodeRepr, stack_yaml: &'a Value) -> Option<&'a Value> {
+    let kind = format!("{}s", node.fqn.split('.').nth(1)?);
+    let name = node.fqn.split('.').nth(2)?;
+
+    stack_yaml
+        .get(kind.as_str())?
+        .as_mapping()?
+        .iter()
+        .find(|(key, _)| key.as_str().map(normalize_name).as_deref() == Some(name))
+        .and_then(|(_, value)| value.get("inputs"))
+}
+
+fn lint_inputs(node: &ArtifactNodeRepr, stack_yaml: &Value, warnings: &mut Vec<LintWarning>) {
+    let has_inputs = stack_inputs(node, stack_yaml)
+        .and_then(|inputs| inputs.as_mapping())
+        .map_or(false, |inputs| !inputs.is_empty());
+
+    if has_inputs && node.input_spec.is_empty() {
+        warnings.push(LintWarning::new(
+            node,
+            LintSeverity::Warning,
+            "has inputs but its unit has no input spec, the inputs are ignored.".to_string(),
+            "Remove the inputs from the stack or add an inputs section to the unit's torb.yaml.",
+        ));
+    }
+}
+
+fn lint_build_step(node: &ArtifactNodeRepr, warnings: &mut Vec<LintWarning>) {
+    let step = match &node.build_step {
+        Some(step) => step,
+        None => return,
+    };
+
+    let builds_image = !step.dockerfile.is_empty() || !step.dockerfile_inline.is_empty();
+
+    if !builds_image && step.script_path.is_empty() {
+        warnings.push(LintWarning::new(
+            node,
+            LintSeverity::Error,
+            "has a build step with no dockerfile, dockerfile_inline or script_path.".to_string(),
+            "Set dockerfile, dockerfile_inline or script_path in the build step, or remove the build step.",
+        ));
+    }
+
+    if builds_image && step.registry.is_empty() {
+        warnings.push(LintWarning::new(
+            node,
+            LintSeverity::Warning,
+            "pushes its image but no registry is set, so the image is pushed under its bare name.".to_string(),
+            "Set registry in the build step, or use registry: local to keep the image local.",
+        ));
+    }
+}
+
+fn lint_helm_deploy(node: &ArtifactNodeRepr, warnings: &mut Vec<LintWarning>) {
+    let helm = match node.deploy_steps.get("helm") {
+        Some(Some(helm)) => helm,
+        _ => return,
+    };
+
+    let is_set = |key: &str| helm.get(key).map_or(false, |val| !val.is_empty());
+
+    if is_set("repository") && !is_set("chart") {
+        warnings.push(LintWarning::new(
+            node,
+            LintSeverity::Error,
+            "sets a helm repository without a chart.".to_string(),
+            "Set chart in the helm deploy step to the name of the chart in the repository.",
+        ));
+    }
+}
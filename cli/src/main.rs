@@ -9,70 +9,147 @@
 //
 // See LICENSE file at https://github.com/TorbFoundry/torb/blob/main/LICENSE for details.
 
-mod artifacts;
-mod builder;
+mod animation;
 mod cli;
-mod composer;
-mod config;
-mod deployer;
-mod initializer;
-mod resolver;
-mod utils;
-mod vcs;
 mod watcher;
-mod animation;
 
+use colored::Colorize;
+use crossterm::tty::IsTty;
 use indexmap::IndexMap;
+use log::{debug, info, LevelFilter};
 use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use serde::Serialize;
 use std::fs;
 use std::fs::File;
-use std::io::{self};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
 use std::process::Command;
+use std::time::{Duration, Instant};
 use thiserror::Error;
-use ureq;
-use utils::{buildstate_path_or_create, torb_path, PrettyExit};
+use torb::utils::{
+    acquire_buildstate_lock, buildstate_path, buildstate_path_or_create, check_artifacts_compatibility, check_initialized, disable_color_if_requested, http_agent, is_dns_label, kube_target, project_path, read_stack_source, set_buildstate_dir, set_kube_target, set_project_root, show_secrets,
+    for_each_artifact_repository, torb_path, BuildstateLock, PrettyExit,
+};
 use animation::{BuilderAnimation, Animation};
 
-use crate::artifacts::{
-    deserialize_stack_yaml_into_artifact, get_build_file_info, load_build_file, write_build_file,
-    ArtifactRepr,
+use torb::artifacts::{
+    deserialize_stack_yaml_into_artifact, get_build_file_info, load_build_file, ArtifactRepr,
 };
-use crate::builder::StackBuilder;
+use torb::builder::{configured_insecure_registries, default_build_jobs, ensure_torb_builder, ContainerBuilder};
 use crate::cli::cli;
-use crate::composer::Composer;
-use crate::config::TORB_CONFIG;
-use crate::deployer::StackDeployer;
-use crate::initializer::StackInitializer;
-use crate::utils::{CommandConfig, CommandPipeline, PrettyContext};
-use crate::vcs::{GitVersionControl, GithubVCS};
-use crate::watcher::Watcher;
+use torb::composer::{verify_charts, verify_mappings, Composer, ValuesOverride};
+use torb::config::{load_config, VcsProvider, TORB_CONFIG};
+use torb::doctor::run_checks;
+use torb::deployer::{orphaned_releases, uninstall_release, HelmRelease, TorbDeployErrors, DEFAULT_READINESS_TIMEOUT, DEFAULT_STABLE_TIMEOUT};
+use torb::hooks::{run_stack_hook, HookStage};
+use torb::initializer::StackInitializer;
+use torb::linter::{lint_stack, LintSeverity};
+use torb::outputs::output_wiring;
+use torb::resolver::{allow_shell_inputs, resolve_stack, InputOverride};
+use torb::timings::{enable_timings, timed, timing_summary};
+use torb::utils::{ExitCategory, PrettyContext};
+use torb::vcs::{
+    load_managed_repos, save_managed_repos, GenericSSHVCS, GitVersionControl, GithubVCS,
+    ManagedRepo,
+};
+use torb::{BuildOptions, DeployOptions};
+use crate::watcher::{WatchPhases, Watcher};
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const TERRAFORM_VERSION: &str = "1.2.5";
+const TERRAFORM_LOCK_FILE: &str = "terraform.lock";
+const TERRAFORM_LOCK_TIMEOUT: Duration = Duration::from_secs(600);
+const DEBUG_ENV: &str = "TORB_DEBUG";
+const SLOWEST_NODES_SHOWN: usize = 5;
 
 #[derive(Error, Debug)]
 pub enum TorbCliErrors {
     #[error("Stack manifest missing or invalid. Please run `torb init`")]
     ManifestInvalid,
+    #[error("Skipping the {repo} repository, its stack manifest {path} {reason}")]
+    RepositoryManifestInvalid { repo: String, path: String, reason: String },
     #[error("Stack meta template missing or invalid. Please run `torb init`")]
     StackMetaNotFound,
-    #[error("The stack name was found in multiple repository manifests please prefix the stack name with the repository you wish to use. i.e. torb-artifacts:flask-app-with-react-frontend")]
-    StackAmbiguous,
+    #[error("The stack {stack} was found in multiple repository manifests, please prefix the stack name with the repository you wish to use: {candidates}")]
+    StackAmbiguous { stack: String, candidates: String },
+    #[error("{selector} isn't a valid stack name, stacks are selected by name or as <repository>:<name>.")]
+    InvalidStackSelector { selector: String },
+    #[error("There's no artifact repository named {repo} with stacks, repositories with stacks are: {available}")]
+    UnknownStackRepository { repo: String, available: String },
+    #[error("The stack {stack} isn't in {searched}, even after refreshing.")]
+    StackNotFound { stack: String, searched: String },
+    #[error("There's no stack template named {template}, available templates are: {available}")]
+    StackTemplateNotFound { template: String, available: String },
+    #[error("The stack template {template} is in multiple artifact repositories, please prefix it with the repository you wish to use: {candidates}")]
+    StackTemplateAmbiguous { template: String, candidates: String },
+    #[error("Unable to copy the stack template {template}, reason: {reason}")]
+    UnableToCopyStackTemplate { template: String, reason: String },
+    #[error("Failed to refresh {repos}, reason: {reason}")]
+    ArtifactRefreshFailed { repos: String, reason: String },
+    #[error("Failed to check out {git_ref} in {repo}, reason: {reason}")]
+    ArtifactCheckoutFailed { repo: String, git_ref: String, reason: String },
+    #[error("{namespace} is not a valid namespace, it must be a DNS-1123 label: at most 63 lowercase letters, digits or '-', starting and ending with a letter or digit.")]
+    InvalidNamespace { namespace: String },
+    #[error("Lint found {errors} errors and {warnings} warnings.")]
+    LintFailed { errors: usize, warnings: usize },
+    #[error("{path} wasn't created by Torb, so Torb won't delete it.")]
+    RepoNotManaged { path: String },
+    #[error("{path} was created by Torb but there is no remote on record to delete, pass --local-only to only remove the local copy.")]
+    RepoWithoutRemote { path: String },
+    #[error("Deleting the remote repository {remote} can't be undone, pass --confirm to go ahead or --local-only to keep it.")]
+    RemoteDeleteNotConfirmed { remote: String },
+    #[error("Deleting the local repo {path} can't be undone, pass --confirm to go ahead.")]
+    LocalDeleteNotConfirmed { path: String },
+    #[error("Deploy needs to be confirmed but stdin isn't a terminal, pass --auto-approve to deploy without confirming.")]
+    DeployConfirmationUnavailable,
+    #[error("Deploy was not approved.")]
+    DeployNotApproved,
+    #[error("Unable to install terraform, reason: {reason}")]
+    TerraformDownloadFailed { reason: String },
+    #[error("The downloaded terraform didn't match HashiCorp's checksum, expected {expected} but got {actual}. The download has been removed.")]
+    TerraformChecksumMismatch { expected: String, actual: String },
+    #[error("Timed out waiting on {path}, another torb init is installing terraform.")]
+    TerraformLocked { path: String },
+    #[error("Refusing to remove {path}, it resolves to somewhere outside of the buildstate directory {buildstate}.")]
+    CleanOutsideBuildstate { path: String, buildstate: String },
+    #[error("Clean needs to be confirmed but stdin isn't a terminal, pass --force to clean without confirming.")]
+    CleanConfirmationUnavailable,
+    #[error("Clean was not approved.")]
+    CleanNotApproved,
+    #[error("Prune needs to be confirmed but stdin isn't a terminal, pass --auto-approve to prune without confirming.")]
+    PruneConfirmationUnavailable,
+    #[error("Prune was not approved.")]
+    PruneNotApproved,
+    #[error("{failed} of Torb's critical checks failed.")]
+    DoctorFailed { failed: usize },
+}
+
+const IAC_ENVIRONMENT_DIRS: [&str; 2] = ["iac_environment", "watcher_iac_environment"];
+const RESOLVED_STACKS_DIR: &str = "resolved_stacks";
+const BUILD_FILE_PATHS: [&str; 4] = ["buildfiles", "build_manifest.yaml", ".stack_initialized", "watcher_releases"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CleanScope {
+    Iac,
+    Generated,
+    All,
 }
 
 fn init() {
-    println!("Initializing...");
+    info!("Initializing...");
     let torb_path_buf = torb_path();
     let torb_path = torb_path_buf.as_path();
     let artifacts_path = &torb_path.join("repositories");
     if !torb_path.is_dir() {
-        println!("Creating {}...", torb_path.display());
+        info!("Creating {}...", torb_path.display());
 
         fs::create_dir(&torb_path).unwrap();
     }
 
-    if !artifacts_path.is_dir() {
-        println!("Cloning build artifacts...");
-        fs::create_dir(artifacts_path).unwrap();
+    if !artifacts_path.join("torb-artifacts").is_dir() {
+        info!("Cloning build artifacts...");
+        fs::create_dir_all(artifacts_path).unwrap();
         let _clone_cmd_out = Command::new("git")
             .arg("clone")
             .arg("git@github.com:TorbFoundry/torb-artifacts.git")
@@ -89,171 +166,1409 @@ fn init() {
         fs::copy(torb_config_template, torb_config_path).expect(&err_msg);
     }
 
-    let tf_path = torb_path.join("terraform.zip");
-    let tf_bin_path = torb_path.join("terraform");
-    if !tf_bin_path.is_file() {
-        println!("Downloading terraform...");
-        let tf_url = match std::env::consts::OS {
-            "linux" => {
-                "https://releases.hashicorp.com/terraform/1.2.5/terraform_1.2.5_linux_amd64.zip"
-            }
-            "macos" => {
-                "https://releases.hashicorp.com/terraform/1.2.5/terraform_1.2.5_darwin_amd64.zip"
-            }
-            _ => panic!("Unsupported OS"),
-        };
-        let resp = ureq::get(tf_url).call().unwrap();
+    install_terraform(torb_path).use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, we were unable to install Terraform!")
+        .context("Torb downloads Terraform from releases.hashicorp.com and checks it against HashiCorp's published SHA256SUMS before unzipping it.")
+        .suggestions(vec![
+            "Check your connection to releases.hashicorp.com and run `torb init` again, partial downloads are removed.",
+            "If another `torb init` is still installing Terraform, wait for it to finish and try again.",
+            "Make sure unzip is installed."
+        ])
+        .pretty()
+    );
+
+    if resolve_container_builder(None) == ContainerBuilder::Podman {
+        info!("Podman selected as the container builder, skipping docker build kit builder creation.");
+    } else {
+        ensure_torb_builder(&configured_insecure_registries(vec![])).use_or_pretty_exit(
+            PrettyContext::default()
+            .error("Oh no, we were unable to create the docker build kit builder!")
+            .context("Torb builds multi-platform images with a buildx builder named torb_builder, set up to push to the insecure_registries in ~/.torb/config.yaml.")
+            .suggestions(vec![
+                "Check that docker is running and buildx is installed with `docker buildx version`.",
+                "Insecure registries are pushed to without TLS, only list registries on localhost or a network you trust."
+            ])
+            .pretty()
+        );
+    }
+
+    info!("Finished!")
+}
+
+// Held for the length of the install, the lock is released when the file is closed, even if init is killed.
+struct TerraformInstallLock {
+    _file: File,
+}
+
+/*
+    The lock file is locked with flock so only one init can hold it, other inits wait for it to be released.
+    A killed init's lock goes with its process, so there's nothing stale to take over.
+*/
+fn acquire_terraform_install_lock(torb_path: &std::path::Path) -> Result<TerraformInstallLock, TorbCliErrors> {
+    let path = torb_path.join(TERRAFORM_LOCK_FILE);
+    let start = Instant::now();
+
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(&path)
+        .map_err(|err| TorbCliErrors::TerraformDownloadFailed {
+            reason: format!("unable to create {}, {}", path.display(), err),
+        })?;
+
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            return Ok(TerraformInstallLock { _file: file });
+        }
 
-        let mut out = File::create(&tf_path).unwrap();
-        io::copy(&mut resp.into_reader(), &mut out).expect("Failed to write terraform zip file.");
+        let err = io::Error::last_os_error();
 
-        let mut unzip_cmd = Command::new("unzip");
+        if err.kind() != io::ErrorKind::WouldBlock {
+            return Err(TorbCliErrors::TerraformDownloadFailed {
+                reason: format!("unable to lock {}, {}", path.display(), err),
+            });
+        }
 
-        unzip_cmd.arg(&tf_path).current_dir(&torb_path);
+        if start.elapsed() > TERRAFORM_LOCK_TIMEOUT {
+            return Err(TorbCliErrors::TerraformLocked {
+                path: path.display().to_string(),
+            });
+        }
 
-        let _unzip_cmd_out = unzip_cmd.output().expect("Failed to unzip terraform.");
+        info!("Waiting for another torb init to finish installing terraform...");
+        std::thread::sleep(Duration::from_secs(2));
     }
+}
 
-    let buildx_cmd_conf = CommandConfig::new(
-        "docker",
-        vec![
-            "buildx",
-            "create",
-            "--name",
-            "torb_builder",
-            "--driver-opt",
-            "network=host",
-        ],
-        None,
+fn download_terraform_checksum(filename: &str) -> Result<String, TorbCliErrors> {
+    let sums_url = format!(
+        "https://releases.hashicorp.com/terraform/{version}/terraform_{version}_SHA256SUMS",
+        version = TERRAFORM_VERSION
     );
 
-    let res = CommandPipeline::execute_single(buildx_cmd_conf);
+    let sums = http_agent(&sums_url)
+        .map_err(|err| err.to_string())
+        .and_then(|agent| agent.get(&sums_url).call().map_err(|err| err.to_string()))
+        .and_then(|resp| resp.into_string().map_err(|err| err.to_string()))
+        .map_err(|reason| TorbCliErrors::TerraformDownloadFailed {
+            reason: format!("unable to fetch {}, {}", sums_url, reason),
+        })?;
+
+    sums.lines()
+        .find_map(|line| match line.split_once("  ") {
+            Some((sum, name)) if name.trim() == filename => Some(sum.to_string()),
+            _ => None,
+        })
+        .ok_or(TorbCliErrors::TerraformDownloadFailed {
+            reason: format!("{} isn't listed in {}", filename, sums_url),
+        })
+}
+
+fn install_terraform(torb_path: &std::path::Path) -> Result<(), TorbCliErrors> {
+    let tf_bin_path = torb_path.join("terraform");
+
+    if tf_bin_path.is_file() {
+        return Ok(());
+    }
+
+    let _lock = acquire_terraform_install_lock(torb_path)?;
+
+    // Another init may have installed it while we waited on the lock.
+    if tf_bin_path.is_file() {
+        return Ok(());
+    }
+
+    let platform = match std::env::consts::OS {
+        "linux" => "linux_amd64",
+        "macos" => "darwin_amd64",
+        _ => panic!("Unsupported OS"),
+    };
+
+    let filename = format!("terraform_{}_{}.zip", TERRAFORM_VERSION, platform);
+    let tf_url = format!("https://releases.hashicorp.com/terraform/{}/{}", TERRAFORM_VERSION, filename);
+    let tf_path = torb_path.join("terraform.zip");
+
+    let expected = download_terraform_checksum(&filename)?;
+
+    info!("Downloading terraform...");
+    let download_failed = |reason: String| {
+        let _ = fs::remove_file(&tf_path);
 
-    match res {
-        Ok(_) => println!("Created docker build kit builder, torb_builder."),
-        Err(err) => panic!("{}", err),
+        TorbCliErrors::TerraformDownloadFailed {
+            reason: format!("unable to download {}, {}", tf_url, reason),
+        }
+    };
+
+    let agent = http_agent(&tf_url).map_err(|err| download_failed(err.to_string()))?;
+    let resp = agent.get(&tf_url).call().map_err(|err| download_failed(err.to_string()))?;
+    let mut out = File::create(&tf_path).map_err(|err| download_failed(err.to_string()))?;
+    io::copy(&mut resp.into_reader(), &mut out).map_err(|err| download_failed(err.to_string()))?;
+
+    let contents = fs::read(&tf_path).map_err(|err| download_failed(err.to_string()))?;
+    let actual = format!("{:x}", Sha256::digest(&contents));
+
+    if actual != expected {
+        let _ = fs::remove_file(&tf_path);
+
+        return Err(TorbCliErrors::TerraformChecksumMismatch { expected, actual });
+    }
+
+    let unzip_out = Command::new("unzip")
+        .arg("-o")
+        .arg(&tf_path)
+        .current_dir(&torb_path)
+        .output()
+        .map_err(|err| TorbCliErrors::TerraformDownloadFailed {
+            reason: format!("unable to unzip terraform, {}", err),
+        })?;
+
+    if !unzip_out.status.success() {
+        let _ = fs::remove_file(&tf_bin_path);
+
+        return Err(TorbCliErrors::TerraformDownloadFailed {
+            reason: format!("unable to unzip terraform, {}", String::from_utf8_lossy(&unzip_out.stderr)),
+        });
     }
 
-    println!("Finished!")
+    Ok(())
+}
+
+fn resolve_container_builder(builder_opt: Option<&str>) -> ContainerBuilder {
+    ContainerBuilder::configured(builder_opt).use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, we don't know how to build with that container builder!")
+        .context("The container builder is read from the --builder flag first and then the builder key in ~/.torb/config.yaml.")
+        .suggestions(vec![
+            "Use either docker or podman as the builder."
+        ])
+        .pretty()
+    )
+}
+
+fn github_token_or_exit() -> String {
+    TORB_CONFIG.github_token().use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, we were unable to read your GitHub token!")
+        .context("The githubToken in ~/.torb/config.yaml can be the token itself, file:<path> to read it from a file or env:<VAR> to read it from an environment variable.")
+        .suggestions(vec![
+            "Check that the file or environment variable githubToken references exists and isn't empty.",
+        ])
+        .pretty()
+    )
+}
+
+fn vcs_provider_or_exit() -> VcsProvider {
+    TORB_CONFIG.vcs_provider().use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, the vcs section of your config is invalid!")
+        .context("vcs in ~/.torb/config.yaml picks where repos are created, github (the default) or generic for Git over SSH with a remote URL template.")
+        .suggestions(vec![
+            "Set vcs.provider to github or generic, and vcs.remote to a URL with {name} in it when using generic."
+        ])
+        .pretty()
+    )
+}
+
+// Generic remotes are created on the server rather than through an API, so only GitHub needs a token.
+fn vcs_or_exit(provider: &VcsProvider, local_only: bool) -> Box<dyn GitVersionControl> {
+    match provider {
+        VcsProvider::Github => {
+            // Local only repos never talk to GitHub, so they don't need a token that resolves.
+            let token = if local_only {
+                TORB_CONFIG.github_token().unwrap_or_default()
+            } else {
+                github_token_or_exit()
+            };
+
+            Box::new(GithubVCS::new(token, TORB_CONFIG.githubUser.clone()))
+        }
+        VcsProvider::Generic { remote } => Box::new(GenericSSHVCS::new(remote.clone(), TORB_CONFIG.githubUser.clone())),
+    }
 }
 
 fn create_repo(path: String, local_only: bool) {
     if !std::path::Path::new(&path).exists() {
-        let mut vcs = GithubVCS::new(
-            TORB_CONFIG.githubToken.clone(),
-            TORB_CONFIG.githubUser.clone(),
-        );
+        let provider = vcs_provider_or_exit();
+        let mut vcs = vcs_or_exit(&provider, local_only);
 
         let mut buf = std::path::PathBuf::new();
         buf.push(path);
 
         vcs.set_cwd(buf);
 
-        vcs.create_repo(local_only).expect("Failed to create repo.");
-    } else {
-        println!("Repo already exists locally. Skipping creation.");
+        let (repo_path, created_remote) = vcs.create_repo(local_only).expect("Failed to create repo.");
+
+        let remote = if local_only {
+            None
+        } else {
+            match provider {
+                VcsProvider::Github => vcs.get_repo_name()
+                    .map(|name| format!("{}/{}", TORB_CONFIG.githubUser, name)),
+                VcsProvider::Generic { .. } => Some(created_remote),
+            }
+        };
+
+        let managed_repo = ManagedRepo {
+            path: fs::canonicalize(&repo_path).unwrap_or(repo_path),
+            remote,
+            created: chrono::Utc::now().to_rfc3339(),
+        };
+
+        load_managed_repos()
+            .and_then(|mut repos| {
+                repos.retain(|repo| repo.path != managed_repo.path);
+                repos.push(managed_repo);
+
+                save_managed_repos(&repos)
+            })
+            .use_or_pretty_warn(
+                PrettyContext::default()
+                .warn("Repo was created but we were unable to record it, it won't show up in `torb repo list`.")
+                .pretty()
+            );
+    } else {
+        info!("Repo already exists locally. Skipping creation.");
+    }
+}
+
+fn list_repos() {
+    let repos = load_managed_repos().use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, we were unable to read the list of repos!")
+        .context("Torb keeps track of the repos it creates in ~/.torb/repos.yaml.")
+        .suggestions(vec![
+            "Check that ~/.torb/repos.yaml is valid YAML, or remove it to start a fresh list."
+        ])
+        .pretty()
+    );
+
+    println!("\nTorb Repos:\n");
+
+    for repo in repos.iter() {
+        let missing = if repo.path.exists() { "" } else { " (missing locally)" };
+        let remote = repo.remote.clone().unwrap_or("local only".to_string());
+
+        println!("- {} [{}] created {}{}", repo.path.display(), remote, repo.created, missing);
+    }
+}
+
+fn delete_repo(path: &str, local_only: bool, confirmed: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let repo_path = fs::canonicalize(path).unwrap_or(std::path::PathBuf::from(path));
+    let mut repos = load_managed_repos()?;
+    // Only repos Torb created are deleted, so a mistyped path can't remove an unrelated directory.
+    let managed_repo = repos
+        .iter()
+        .find(|repo| repo.path == repo_path)
+        .cloned()
+        .ok_or(TorbCliErrors::RepoNotManaged { path: path.to_string() })?;
+
+    if !local_only {
+        let remote = managed_repo
+            .remote
+            .clone()
+            .ok_or(TorbCliErrors::RepoWithoutRemote { path: path.to_string() })?;
+
+        if !confirmed {
+            return Err(Box::new(TorbCliErrors::RemoteDeleteNotConfirmed { remote }));
+        }
+
+        let vcs: Box<dyn GitVersionControl> = match TORB_CONFIG.vcs_provider()? {
+            VcsProvider::Github => Box::new(GithubVCS::new(
+                TORB_CONFIG.github_token()?,
+                TORB_CONFIG.githubUser.clone(),
+            )),
+            VcsProvider::Generic { remote } => Box::new(GenericSSHVCS::new(remote, TORB_CONFIG.githubUser.clone())),
+        };
+
+        info!("Deleting remote repo {}...", remote);
+        vcs.delete_remote_repo(&remote)?;
+    }
+
+    if repo_path.exists() {
+        if !confirmed {
+            return Err(Box::new(TorbCliErrors::LocalDeleteNotConfirmed { path: repo_path.display().to_string() }));
+        }
+
+        info!("Deleting local repo {}...", repo_path.display());
+        fs::remove_dir_all(&repo_path)?;
+    }
+
+    repos.retain(|repo| repo.path != repo_path);
+    save_managed_repos(&repos)?;
+
+    Ok(())
+}
+
+fn pull_stack_or_exit(name: &str) -> (String, String) {
+    pull_stack(name, false).use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, we were unable to pull the stack!")
+        .exit_category(ExitCategory::Resolution)
+        .context("Stacks are looked up by name in the stacks/manifest.yaml of each artifact repository, or in a single repository when given as <repository>:<name>.")
+        .suggestions(vec![
+            "Run `torb stack list` to see the fully qualified name of every stack you have.",
+            "Check that the source is configured correctly in config.yaml and cloned with `torb artifacts clone`."
+        ])
+        .pretty()
+    )
+}
+
+/*
+    Init also takes a stack straight from an artifact repository as <repository>:<name>. Only the prefixed form is
+    accepted here, so a mistyped file name is reported as missing instead of being looked up as a stack.
+*/
+fn read_stack_or_pulled_stack_or_exit(location: &str) -> String {
+    let is_remote = location.starts_with("http://") || location.starts_with("https://");
+    let selector = StackSelector::parse(location).ok().filter(|selector| {
+        selector.repo.as_ref().map_or(false, |repo| !repo.contains('/'))
+    });
+
+    match selector {
+        Some(_) if !is_remote && !project_path(location).exists() => pull_stack_or_exit(location).1,
+        _ => read_stack_or_exit(location),
+    }
+}
+
+fn checkout_stack(name: Option<&str>) {
+    match name {
+        Some(name) => {
+            let (qualified_name, stack_yaml) = pull_stack_or_exit(name);
+
+            fs::write(project_path("stack.yaml"), stack_yaml).expect("Failed to write stack.yaml.");
+
+            info!("Checked out {} to ./stack.yaml", qualified_name);
+        }
+        None => {
+            fs::write(project_path("stack.yaml"), "").expect("Failed to write stack.yaml");
+        }
+    }
+}
+
+const STACK_TEMPLATES_DIR: &str = "templates";
+const STACK_TEMPLATE_FILE: &str = "stack.template.yaml";
+
+// A stack template from the templates directory of an artifact repository, named after its file.
+struct StackTemplate {
+    repo: String,
+    name: String,
+    path: std::path::PathBuf,
+}
+
+fn load_stack_templates() -> Vec<StackTemplate> {
+    let mut templates = vec![];
+
+    let _ = for_each_artifact_repository(Box::new(|_repo_path, repo| {
+        let repo_name = repo.file_name().to_string_lossy().to_string();
+        let templates_dir = repo.path().join(STACK_TEMPLATES_DIR);
+
+        let mut entries = match fs::read_dir(&templates_dir) {
+            Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect::<Vec<_>>(),
+            Err(_) => return,
+        };
+        entries.sort();
+
+        for path in entries {
+            let is_yaml = path.extension().map_or(false, |ext| ext == "yaml" || ext == "yml");
+
+            if let (true, Some(name)) = (is_yaml, path.file_stem()) {
+                templates.push(StackTemplate {
+                    repo: repo_name.clone(),
+                    name: name.to_string_lossy().to_string(),
+                    path: path.clone(),
+                });
+            }
+        }
+    }));
+
+    templates
+}
+
+// Templates are named like stacks, by name or as <repository>:<name> when more than one repository has it.
+fn resolve_stack_template(name: &str, templates: &[StackTemplate]) -> Result<std::path::PathBuf, TorbCliErrors> {
+    let (repo, template) = match name.split_once(':') {
+        Some((repo, template)) => (Some(repo), template),
+        None => (None, name),
+    };
+
+    let candidates: Vec<&StackTemplate> = templates
+        .iter()
+        .filter(|candidate| candidate.name == template && repo.map_or(true, |repo| candidate.repo == repo))
+        .collect();
+
+    match candidates.as_slice() {
+        [] => Err(TorbCliErrors::StackTemplateNotFound {
+            template: name.to_string(),
+            available: templates
+                .iter()
+                .map(|candidate| StackSelector::qualified(&candidate.repo, &candidate.name))
+                .collect::<Vec<String>>()
+                .join(", "),
+        }),
+        [found] => Ok(found.path.clone()),
+        _ => Err(TorbCliErrors::StackTemplateAmbiguous {
+            template: name.to_string(),
+            candidates: candidates
+                .iter()
+                .map(|candidate| StackSelector::qualified(&candidate.repo, &candidate.name))
+                .collect::<Vec<String>>()
+                .join(", "),
+        }),
+    }
+}
+
+/*
+    Without --template the stack.template.yaml at the root of torb-artifacts is copied, as it always has been, and
+    the named templates are listed so they can be found. Either way the template is copied to stack.template.yaml.
+*/
+fn new_stack(template: Option<&str>) {
+    let templates = load_stack_templates();
+
+    let template_path = match template {
+        Some(name) => resolve_stack_template(name, &templates).use_or_pretty_exit(
+            PrettyContext::default()
+            .error("Oh no, we couldn't find that stack template!")
+            .context("Stack templates are the YAML files in the templates directory of each artifact repository, named after the file.")
+            .suggestions(vec![
+                "Pick one of the templates listed above, or run `torb artifacts refresh` to pull the latest templates."
+            ])
+            .pretty()
+        ),
+        None => torb_path().join("repositories").join("torb-artifacts").join(STACK_TEMPLATE_FILE),
+    };
+
+    let dest = project_path(STACK_TEMPLATE_FILE);
+
+    fs::copy(&template_path, dest)
+        .map_err(|err| TorbCliErrors::UnableToCopyStackTemplate {
+            template: template_path.display().to_string(),
+            reason: err.to_string(),
+        })
+        .use_or_pretty_exit(
+            PrettyContext::default()
+            .error("Oh no, we were unable to create the stack template!")
+            .suggestions(vec![
+                "Check that Torb has been initialized with `torb init`.",
+                "Check that the current directory is writable."
+            ])
+            .pretty()
+        );
+
+    if template.is_none() && !templates.is_empty() {
+        println!("\nCopied the default template, pass --template to use one of these instead:\n");
+
+        for candidate in templates.iter() {
+            println!("- {}", StackSelector::qualified(&candidate.repo, &candidate.name));
+        }
+    }
+}
+
+// Failing checks that aren't critical are only warned about, the report exits non-zero for critical ones.
+fn doctor() {
+    let checks = run_checks(VERSION);
+
+    println!("\nTorb Doctor:\n");
+
+    for check in checks.iter() {
+        let line = format!("{}: {}", check.name, check.detail);
+
+        match (check.passed, check.critical) {
+            (true, _) => println!("{} {}", "✔".green(), line),
+            (false, true) => println!("{} {}", "✘".red(), line.red()),
+            (false, false) => println!("{} {}", "!".yellow(), line.yellow()),
+        }
+    }
+
+    let failed = checks.iter().filter(|check| !check.passed && check.critical).count();
+    let warnings = checks.iter().filter(|check| !check.passed && !check.critical).count();
+
+    let success = if warnings == 0 {
+        "Success! Everything Torb needs is in place.".to_string()
+    } else {
+        format!("Torb's critical checks passed, but {} other checks need a look.", warnings)
+    };
+
+    let result = if failed == 0 { Ok(()) } else { Err(TorbCliErrors::DoctorFailed { failed }) };
+
+    result.use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, Torb isn't set up to work here!")
+        .success(&success)
+        .context("Checks marked ✘ are needed by every stack command, checks marked ! only affect some of them.")
+        .suggestions(vec![
+            "Install any tool that's missing and make sure it's in your PATH.",
+            "Run `torb init` if ~/.torb or terraform is missing.",
+            "Check ~/.torb/config.yaml against the Configuring Torb section of the README."
+        ])
+        .pretty()
+    );
+}
+
+fn lock_buildstate_or_exit(operation: &str) -> BuildstateLock {
+    acquire_buildstate_lock(operation).use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, another Torb operation is in progress in this project!")
+        .context("Builds, deploys and watches in the same project share the buildstate and Terraform state, so only one of them runs at a time.")
+        .suggestions(vec![
+            "Wait for the other operation to finish and try again.",
+            "The lock is released when the process holding it exits, if the pid above isn't running anymore try again."
+        ])
+        .pretty()
+    )
+}
+
+fn buildstate_or_exit() -> std::path::PathBuf {
+    buildstate_path_or_create().use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, we were unable to create the buildstate folder!")
+        .context("Torb keeps build files and the generated IaC environment in .torb_buildstate in the current directory, or in TORB_BUILDSTATE_DIR or --buildstate-dir when set.")
+        .suggestions(vec![
+            "Check that the buildstate directory is writable.",
+            "Pass --buildstate-dir to keep the buildstate somewhere else."
+        ])
+        .pretty()
+    )
+}
+
+fn init_stack(file_path: String, dryrun: bool, reinit: bool) {
+    info!("Attempting to read or create buildstate folder...");
+    buildstate_or_exit();
+
+    info!("Attempting to read stack file...");
+    let stack_yaml = read_stack_or_pulled_stack_or_exit(&file_path);
+
+    info!("Reading stack into internal representation...");
+    let artifact = deserialize_stack_yaml_into_artifact(&stack_yaml, None, &[], true)
+        .use_or_pretty_exit(resolution_failed());
+
+    let mut stack_initializer = StackInitializer::new(&artifact).with_dryrun(dryrun).with_reinit(reinit);
+    let success = if dryrun { "Dry run complete, nothing was run." } else { "Success! Stack initialized!" };
+
+    stack_initializer
+        .run_node_init_steps().use_or_pretty_exit(
+            PrettyContext::default()
+            .error("Oh no, we failed to initialize the stack!")
+            .context("Failures here are typically because of missing dependencies for parts of the stack you're looking to initialize.")
+            .suggestions(vec![
+                "Check that all dependencies are installed.",
+                "Check to make sure you're on a compatible operating system."
+            ])
+            .success(success)
+            .pretty()
+        )
+}
+
+fn compose_build_environment(
+    build_hash: String,
+    build_artifact: &ArtifactRepr,
+    values_overrides: Vec<ValuesOverride>,
+) {
+    timed("compose", None, || torb::compose(build_hash, build_artifact, values_overrides)).use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, we failed to generate the IaC build environment!")
+        .exit_category(ExitCategory::Compose)
+        .success("Success! IaC build environment generated!")
+        .context("This typically happens due to failures parsing the stack into HCL for Terraform.")
+        .suggestions(vec![
+            "Check that your inputs are escaped correctly.",
+            "Check that Torb has been initialized correctly, at ~/.torb you should see a Terraform binary appropriate to your system."
+        ])
+        .pretty()
+    );
+}
+
+fn load_namespace_override(namespace_option: Option<&str>) -> Option<String> {
+    namespace_option.map(|namespace| {
+        let res: Result<String, TorbCliErrors> = if is_dns_label(namespace) {
+            Ok(namespace.to_string())
+        } else {
+            Err(TorbCliErrors::InvalidNamespace { namespace: namespace.to_string() })
+        };
+
+        res.use_or_pretty_exit(
+            PrettyContext::default()
+            .error("Oh no, the namespace passed with --namespace isn't valid!")
+            .exit_category(ExitCategory::Validation)
+            .context("Kubernetes namespaces have to be valid DNS-1123 labels.")
+            .suggestions(vec![
+                "Use only lowercase letters, digits and '-', for example preview-1234."
+            ])
+            .pretty()
+        )
+    })
+}
+
+fn load_kube_target(context_option: Option<&str>, kubeconfig_option: Option<&str>) {
+    set_kube_target(
+        context_option.map(|context| context.to_string()),
+        kubeconfig_option.map(|kubeconfig| kubeconfig.to_string()),
+    )
+    .use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, we couldn't find the cluster you asked for!")
+        .context("--context and --kubeconfig are checked before anything is built or deployed so nothing runs against the wrong cluster.")
+        .suggestions(vec![
+            "Run `kubectl config get-contexts` to see the contexts available in your kubeconfig.",
+            "Check that the path passed to --kubeconfig exists."
+        ])
+        .pretty()
+    );
+}
+
+fn load_values_overrides(values_args: Vec<&str>, artifact: &ArtifactRepr) -> Vec<ValuesOverride> {
+    values_args
+        .iter()
+        .map(|arg| {
+            ValuesOverride::load(arg, artifact).use_or_pretty_exit(
+                PrettyContext::default()
+                .error("Oh no, we were unable to load a values file!")
+                .exit_category(ExitCategory::Validation)
+                .context("Values files are passed as --values <path> to apply to every node, or --values <node>=<path> to apply to a single node.")
+                .suggestions(vec![
+                    "Check that the file exists and is valid YAML.",
+                    "If you are targeting a node, check the name before the = matches a node in the stack and there are no spaces around it."
+                ])
+                .pretty()
+            )
+        })
+        .collect()
+}
+
+fn load_input_overrides(set_args: Vec<&str>) -> Vec<InputOverride> {
+    set_args
+        .iter()
+        .map(|arg| {
+            InputOverride::parse(arg).use_or_pretty_exit(
+                PrettyContext::default()
+                .error("Oh no, we were unable to parse an input override!")
+                .exit_category(ExitCategory::Validation)
+                .context("Inputs are overridden with --set <node>.<input>=<value>, where node is the node's name in the stack.")
+                .suggestions(vec![
+                    "Check there's a . between the node and the input and an = before the value.",
+                    "Quote the whole override if the value has spaces or characters your shell expands."
+                ])
+                .pretty()
+            )
+        })
+        .collect()
+}
+
+/*
+    Lists what a deploy will touch and asks for a yes before going ahead, anything other than y or yes is a no.
+    Without a terminal there's nobody to ask so the deploy is refused unless --auto-approve was passed.
+*/
+fn confirm_deploy(artifact: &ArtifactRepr, auto_approve: bool) -> Result<(), TorbCliErrors> {
+    if auto_approve {
+        return Ok(());
+    }
+
+    let release = artifact.release.clone().unwrap_or("<generated>".to_string());
+
+    println!("Deploying will create or update the following releases:");
+
+    for node in artifact.nodes.values() {
+        println!(
+            "  {}-{} ({}) in namespace {}",
+            release,
+            node.display_name(true),
+            node.fqn,
+            artifact.namespace(node)
+        );
+    }
+
+    if !io::stdin().is_tty() {
+        return Err(TorbCliErrors::DeployConfirmationUnavailable);
+    }
+
+    print!("Do you want to deploy? [y/N] ");
+    io::stdout().flush().map_err(|_| TorbCliErrors::DeployConfirmationUnavailable)?;
+
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .map_err(|_| TorbCliErrors::DeployConfirmationUnavailable)?;
+
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(TorbCliErrors::DeployNotApproved),
+    }
+}
+
+/*
+    Only the entries Torb creates in the buildstate are ever removed, never the directory itself, since
+    TORB_BUILDSTATE_DIR can point anywhere. Anything that resolves outside of the buildstate, like a symlink
+    to another directory, is refused before anything is removed.
+*/
+fn clean_targets(buildstate: &std::path::Path, scope: CleanScope) -> Result<Vec<std::path::PathBuf>, Box<dyn std::error::Error>> {
+    let mut names = IAC_ENVIRONMENT_DIRS.to_vec();
+
+    if scope != CleanScope::Iac {
+        names.push(RESOLVED_STACKS_DIR);
+    }
+
+    if scope == CleanScope::All {
+        names.extend(BUILD_FILE_PATHS);
+    }
+
+    let canonical_buildstate = fs::canonicalize(buildstate)?;
+    let mut targets = vec![];
+
+    for name in names {
+        let target = buildstate.join(name);
+
+        if fs::symlink_metadata(&target).is_err() {
+            continue;
+        }
+
+        let resolved = fs::canonicalize(&target)?;
+
+        if !resolved.starts_with(&canonical_buildstate) || resolved == canonical_buildstate {
+            return Err(Box::new(TorbCliErrors::CleanOutsideBuildstate {
+                path: target.display().to_string(),
+                buildstate: canonical_buildstate.display().to_string(),
+            }));
+        }
+
+        targets.push(target);
+    }
+
+    Ok(targets)
+}
+
+fn confirm_clean(targets: &Vec<std::path::PathBuf>) -> Result<(), TorbCliErrors> {
+    println!("Cleaning will remove:");
+
+    for target in targets.iter() {
+        println!("  {}", target.display());
+    }
+
+    if targets.iter().any(|target| target.join("terraform.tfstate").exists()) {
+        println!(
+            "\n{}",
+            "The IaC environment holds the Terraform state of what's deployed, without it Torb can't update or tear down those releases.".yellow()
+        );
+    }
+
+    if !io::stdin().is_tty() {
+        return Err(TorbCliErrors::CleanConfirmationUnavailable);
+    }
+
+    print!("Do you want to clean? [y/N] ");
+    io::stdout().flush().map_err(|_| TorbCliErrors::CleanConfirmationUnavailable)?;
+
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .map_err(|_| TorbCliErrors::CleanConfirmationUnavailable)?;
+
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(TorbCliErrors::CleanNotApproved),
+    }
+}
+
+fn confirm_prune(auto_approve: bool) -> Result<(), TorbCliErrors> {
+    if auto_approve {
+        return Ok(());
+    }
+
+    if !io::stdin().is_tty() {
+        return Err(TorbCliErrors::PruneConfirmationUnavailable);
+    }
+
+    print!("Do you want to uninstall these releases? [y/N] ");
+    io::stdout().flush().map_err(|_| TorbCliErrors::PruneConfirmationUnavailable)?;
+
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .map_err(|_| TorbCliErrors::PruneConfirmationUnavailable)?;
+
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(TorbCliErrors::PruneNotApproved),
+    }
+}
+
+fn stack_prune(
+    file_path: &str,
+    environment: Option<&str>,
+    namespace_override: Option<String>,
+    auto_approve: bool,
+    dryrun: bool,
+) {
+    let contents = read_stack_or_exit(file_path);
+
+    let artifact = deserialize_stack_yaml_into_artifact(&contents, environment, &[], true)
+        .use_or_pretty_exit(resolution_failed())
+        .with_namespace_override(namespace_override);
+
+    let orphaned: Vec<HelmRelease> = orphaned_releases(&artifact).use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, we were unable to look for orphaned releases!")
+        .context("Prune lists the Helm releases in each of the stack's namespaces with `helm ls`.")
+        .suggestions(vec![
+            "Set release in the stack, releases from a stack without one can't be matched to its nodes.",
+            "Check that helm can reach the cluster, and pass the same --context and --kubeconfig you deploy with."
+        ])
+        .pretty()
+    );
+
+    if orphaned.is_empty() {
+        info!("No orphaned releases found.");
+
+        return;
+    }
+
+    println!("These releases match the stack's release but none of its nodes:");
+
+    for release in orphaned.iter() {
+        println!("  {} in namespace {}", release.name, release.namespace);
+    }
+
+    if dryrun {
+        return;
+    }
+
+    confirm_prune(auto_approve).use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Prune cancelled, nothing was uninstalled.")
+        .context("Prune asks for confirmation since any release named with the stack's release prefix is matched, including another stack's.")
+        .suggestions(vec![
+            "Answer y at the prompt to uninstall the releases listed above.",
+            "Pass --auto-approve to prune without a prompt, for example in CI."
+        ])
+        .pretty()
+    );
+
+    for release in orphaned.iter() {
+        uninstall_release(release).use_or_pretty_exit(
+            PrettyContext::default()
+            .error("Oh no, we were unable to uninstall an orphaned release!")
+            .context("Releases are uninstalled one at a time, the ones listed before this one have already been removed.")
+            .suggestions(vec![
+                "Run helm uninstall for the release by hand to see why it fails.",
+                "Run prune again once it's fixed, releases that are already gone won't be listed."
+            ])
+            .pretty()
+        );
+    }
+}
+
+fn clean(scope: CleanScope, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let buildstate = buildstate_path()?;
+
+    if !buildstate.exists() {
+        info!("{} doesn't exist, nothing to clean.", buildstate.display());
+
+        return Ok(());
+    }
+
+    let targets = clean_targets(&buildstate, scope)?;
+
+    if targets.is_empty() {
+        info!("Nothing to clean in {}.", buildstate.display());
+
+        return Ok(());
+    }
+
+    if !force {
+        confirm_clean(&targets)?;
+    }
+
+    for target in targets.iter() {
+        info!("Removing {}...", target.display());
+
+        if fs::symlink_metadata(target)?.is_dir() {
+            fs::remove_dir_all(target)?;
+        } else {
+            fs::remove_file(target)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Outputs are only worth reading once the deploy has succeeded, failing to read them doesn't undo the deploy.
+fn report_outputs(artifact: &ArtifactRepr) {
+    info!("Reading stack outputs...");
+
+    let captured = torb::capture_outputs(artifact).use_or_pretty_warn(
+        PrettyContext::default()
+        .warn("The stack was deployed but we were unable to read its outputs.")
+        .context("Outputs are read with terraform output from the IaC environment in the buildstate.")
+        .pretty()
+    );
+
+    let (outputs_path, outputs) = match captured {
+        Some(captured) => captured,
+        None => return,
+    };
+
+    if outputs.is_empty() {
+        info!("The stack has no outputs.");
+        return;
+    }
+
+    println!("\nStack Outputs:\n");
+
+    for (fqn, values) in outputs.iter() {
+        println!("{}:", fqn);
+
+        for (key, value) in values.as_object().into_iter().flatten() {
+            let value = value.as_str().map(|value| value.to_string()).unwrap_or_else(|| value.to_string());
+
+            println!("  {}: {}", key, value);
+        }
+    }
+
+    info!("Outputs written to {}.", outputs_path.display());
+}
+
+fn report_deploy_result(result: Result<(), Box<dyn std::error::Error>>) {
+    let deploy_error = result.as_ref().err().and_then(|err| err.downcast_ref::<TorbDeployErrors>());
+
+    match deploy_error {
+        Some(TorbDeployErrors::SmokeTestFailed { fqn, .. }) => {
+            let context = format!(
+                "{} was deployed and is ready, but its smoke test exited with an error.",
+                fqn
+            );
+            let smoke_test_suggestion = format!("Run the smoke_test for {} by hand to see why it fails.", fqn);
+
+            result.use_or_pretty_exit(
+                PrettyContext::default()
+                .error("Oh no, a node in the stack failed its smoke test!")
+                .exit_category(ExitCategory::Deploy)
+                .success("Success! Stack has been deployed!")
+                .context(&context)
+                .suggestions(vec![
+                    &smoke_test_suggestion,
+                    "Check the node's logs with `kubectl logs --namespace <namespace> <pod>`.",
+                    "The release is left deployed, fix the node and deploy again."
+                ])
+                .pretty()
+            )
+        }
+        Some(TorbDeployErrors::ReleaseNotReady { fqn, namespace, .. }) => {
+            let (fqn, namespace) = (fqn.clone(), namespace.clone());
+            let context = format!(
+                "{} was deployed to the {} namespace but its workloads didn't finish rolling out in time.",
+                fqn, namespace
+            );
+            let pods_suggestion = format!("Check the pods for {} with `kubectl get pods --namespace {}`.", fqn, namespace);
+
+            result.use_or_pretty_exit(
+                PrettyContext::default()
+                .error("Oh no, a node in the stack didn't become ready!")
+                .exit_category(ExitCategory::Deploy)
+                .success("Success! Stack has been deployed!")
+                .context(&context)
+                .suggestions(vec![
+                    &pods_suggestion,
+                    "Describe any pods that aren't ready with `kubectl describe pod <pod> --namespace <namespace>` to see why.",
+                    "If the node is just slow to start, pass a longer --readiness-timeout."
+                ])
+                .pretty()
+            )
+        }
+        _ => result.use_or_pretty_exit(
+            PrettyContext::default()
+            .error("Oh no, we were unable to deploy the stack!")
+            .exit_category(ExitCategory::Deploy)
+            .success("Success! Stack has been deployed!")
+            .context("Errors here are typically because of failed Terraform deployments or Helm failures.")
+            .suggestions(vec![
+                "Check that your Terraform IaC environment was generated correctly. \nThis can be found in your project folder at, .torb_buildstate/iac_environment, or .torb_buildstate/watcher_iac_environment if you're using the watcher.",
+                "To see if your Helm deployment failed you can do `helm ls --namespace <namespace>` where the namespace is the one you're deploying to.",
+                "After seeing if the deployment has failed in Helm, you can use kubectl to debug further. Take a look at https://kubernetes.io/docs/reference/kubectl/cheatsheet/ if you're less familiar with kubectl."
+            ])
+            .pretty()
+        ),
+    }
+}
+
+fn run_hook_or_exit(artifact: &ArtifactRepr, stage: HookStage, dryrun: bool) {
+    let context = match stage {
+        HookStage::PreBuild => "Nothing was built, the pre_build hook has to succeed before the stack is built.",
+        HookStage::PreDeploy => "Nothing was deployed, the pre_deploy hook has to succeed before the stack is deployed.",
+        HookStage::PostBuild => "The stack was built, only the post_build hook that runs afterwards failed.",
+        HookStage::PostDeploy => "The stack was already deployed, only the post_deploy hook that runs afterwards failed.",
+    };
+    let error = format!("Oh no, the stack's {} hook failed!", stage);
+
+    run_stack_hook(artifact, stage, dryrun).use_or_pretty_exit(
+        PrettyContext::default()
+        .error(&error)
+        .context(&context)
+        .suggestions(vec![
+            "Run the hook from the hooks section of the stack by hand in the same directory to see why it fails.",
+        ])
+        .pretty()
+    );
+}
+
+fn verify_charts_or_exit(artifact: &ArtifactRepr) {
+    info!("Verifying Helm charts...");
+
+    verify_charts(artifact).use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, some of the stack's Helm charts can't be fetched!")
+        .exit_category(ExitCategory::Validation)
+        .context("--verify-charts checks each chart with `helm show chart`, and local charts on disk, before building or deploying.")
+        .suggestions(vec![
+            "Check the repository, chart and version in the deploy section of each unit listed above.",
+            "If the chart is in a private repository or registry, check that helm is logged in to it.",
+            "Charts are fetched over the network, check that the repository or registry is reachable from here."
+        ])
+        .pretty()
+    );
+}
+
+fn verify_mappings_or_warn(artifact: &ArtifactRepr) {
+    info!("Verifying input mappings against chart values schemas...");
+
+    let unknown = verify_mappings(artifact).use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, we were unable to read the stack's Helm charts to verify their input mappings!")
+        .exit_category(ExitCategory::Validation)
+        .pretty()
+    );
+
+    if unknown.is_empty() {
+        return;
+    }
+
+    Err::<(), _>(unknown.join("\n")).use_or_pretty_warn(
+        PrettyContext::default()
+        .warn("Some inputs map to values their chart's values.schema.json doesn't know about.")
+        .context("--verify-mappings checks each input's mapping against the chart's values schema, an unknown path usually means the input is silently ignored.")
+        .suggestions(vec![
+            "Check the mapping of each input listed above in the unit's torb.yaml for typos.",
+            "If the chart accepts the value anyway, the warning can be ignored."
+        ])
+        .pretty()
+    );
+}
+
+fn validate_manifests_or_exit(build_hash: String, artifact: &ArtifactRepr, values_overrides: Vec<ValuesOverride>) {
+    info!("Validating manifests against the cluster...");
+
+    timed("validate", None, || torb::validate(build_hash, artifact, values_overrides)).use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, the cluster rejected some of the stack's manifests!")
+        .exit_category(ExitCategory::Validation)
+        .success("Success! Every manifest passed a dry run against the cluster.")
+        .context("--validate renders each node and dry runs it with `kubectl apply --dry-run`, nothing has been deployed.")
+        .suggestions(vec![
+            "Check the values and manifests of each unit listed above, the errors come straight from the cluster.",
+            "An API version the cluster doesn't serve usually means the chart or manifest is too old or too new for this cluster.",
+            "Values that read another node's outputs are validated with a placeholder, fields that need a number or bool there will fail until deployed."
+        ])
+        .pretty()
+    );
+}
+
+fn write_timings(path: &str, summary: &torb::timings::TimingSummary) -> Result<(), Box<dyn std::error::Error>> {
+    fs::write(project_path(path), serde_json::to_string_pretty(summary)?)?;
+
+    Ok(())
+}
+
+// Summarizes where the time went, the slowest nodes are usually what's worth looking at in a slow pipeline.
+fn report_timings(started: Instant, timings_path: Option<&str>) {
+    let summary = timing_summary(started.elapsed());
+
+    info!("Finished in {:.1}s.", summary.total_seconds);
+
+    for phase in summary.phases.iter() {
+        info!("  {}: {:.1}s", phase.phase, phase.seconds);
+    }
+
+    if !summary.nodes.is_empty() {
+        info!("Slowest nodes:");
+
+        for timing in summary.nodes.iter().take(SLOWEST_NODES_SHOWN) {
+            info!("  {} ({}): {:.1}s", timing.node.as_deref().unwrap_or_default(), timing.phase, timing.seconds);
+        }
+    }
+
+    if let Some(path) = timings_path {
+        let warning = format!("Warning! Unable to write timings to {}.", path);
+
+        write_timings(path, &summary).use_or_pretty_warn(
+            PrettyContext::default()
+            .warn(&warning)
+            .pretty()
+        );
+    }
+}
+
+fn read_stack_or_exit(file_path: &str) -> String {
+    read_stack_source(file_path).use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, we were unable to read the stack definition!")
+        .exit_category(ExitCategory::Resolution)
+        .context("Stacks can be read from a file path, from stdin by passing -, or from an http(s) URL.")
+        .suggestions(vec![
+            "Check that the file exists and is readable.",
+            "If the stack is remote, check that the URL is correct and reachable, and that it returns the raw stack.yaml."
+        ])
+        .pretty()
+    )
+}
+
+fn resolution_failed() -> PrettyContext<'static> {
+    PrettyContext::default()
+    .error("Oh no, we were unable to resolve the stack!")
+    .exit_category(ExitCategory::Resolution)
+    .context("Resolving reads every unit the stack uses from your artifact repositories and checks their inputs, values and dependencies.")
+    .suggestions(vec![
+        "Check that the stack file is valid and that Torb has been initialized with `torb init`.",
+        "Run `torb stack lint` on the stack for more specific findings."
+    ])
+    .pretty()
+}
+
+fn compose_to_directory(file_path: &str, out_path: &str) {
+    let contents = read_stack_or_exit(file_path);
+
+    let artifact = deserialize_stack_yaml_into_artifact(&contents, None, &[], true)
+        .use_or_pretty_exit(resolution_failed());
+
+    let (build_hash, _, _) = get_build_file_info(&artifact)
+        .expect("Unable to get build file info for stack.");
+
+    let mut composer = Composer::new(build_hash, &artifact, false)
+        .with_output_root(project_path(out_path));
+
+    composer.compose().use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, we failed to generate the IaC environment!")
+        .exit_category(ExitCategory::Compose)
+        .success("Success! IaC environment generated, nothing has been deployed.")
+        .context("This typically happens due to failures parsing the stack into HCL for Terraform.")
+        .suggestions(vec![
+            "Check that your inputs are escaped correctly.",
+            "Check that the output directory can be created and written to."
+        ])
+        .pretty()
+    );
+}
+
+fn lint_stack_file(file_path: &str, deny_warnings: bool) {
+    let contents = read_stack_or_exit(file_path);
+
+    let artifact = deserialize_stack_yaml_into_artifact(&contents, None, &[], true)
+        .use_or_pretty_exit(resolution_failed());
+    let stack_yaml: serde_yaml::Value = serde_yaml::from_str(&contents)
+        .expect("Unable to parse stack file.");
+
+    let findings = lint_stack(&artifact, &stack_yaml);
+
+    for finding in findings.iter() {
+        let marquee = match finding.severity {
+            LintSeverity::Error => "Lint error, this will fail at build or deploy time.",
+            LintSeverity::Warning => "Lint warning.",
+        };
+
+        Err::<(), _>(finding.clone()).use_or_pretty_warn(
+            PrettyContext::default()
+            .warn(marquee)
+            .suggestions(vec![finding.suggestion])
+            .pretty()
+        );
+    }
+
+    let errors = findings
+        .iter()
+        .filter(|finding| finding.severity == LintSeverity::Error)
+        .count();
+
+    let res = if deny_warnings && !findings.is_empty() {
+        Err(TorbCliErrors::LintFailed { errors, warnings: findings.len() - errors })
+    } else {
+        Ok(())
+    };
+
+    let summary = format!("Lint finished with {} errors and {} warnings.", errors, findings.len() - errors);
+
+    res.use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, the stack didn't pass lint!")
+        .exit_category(ExitCategory::Validation)
+        .success(&summary)
+        .context("--deny-warnings fails on any lint finding.")
+        .suggestions(vec![
+            "Fix the findings above, or run without --deny-warnings to only report them."
+        ])
+        .pretty()
+    );
+}
+
+fn stack_outputs(file_path: &str, json: bool) {
+    let contents = read_stack_or_exit(file_path);
+
+    let artifact = deserialize_stack_yaml_into_artifact(&contents, None, &[], true)
+        .use_or_pretty_exit(resolution_failed());
+
+    let wiring = output_wiring(&artifact).use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, we were unable to map the stack's outputs!")
+        .context("Outputs are read from the input addresses in each node's inputs and values.")
+        .suggestions(vec![
+            "Check that every self.<type>.<name>.output.<key> address in the stack points at a node in the stack."
+        ])
+        .pretty()
+    );
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&wiring).expect("Unable to serialize outputs to JSON."));
+
+        return;
+    }
+
+    for node in wiring.iter() {
+        println!("{}", node.fqn.bold());
+
+        let consumers_of = |output: &String| {
+            node.consumers
+                .iter()
+                .filter(|consumer| &consumer.output == output)
+                .map(|consumer| format!("{} ({})", consumer.consumer, consumer.via))
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+
+        if node.declared.is_empty() {
+            println!("  declares no outputs");
+        }
+
+        for output in node.declared.iter() {
+            if node.unused.contains(output) {
+                println!("  {} {}", output, "unused".yellow());
+            } else {
+                println!("  {} -> {}", output, consumers_of(output));
+            }
+        }
+
+        for output in node.undeclared.iter() {
+            println!("  {} -> {} {}", output, consumers_of(output), "not declared".red());
+        }
+
+        println!();
+    }
+}
+
+fn stack_render_values(file_path: &str, environment: Option<&str>, values_args: Vec<&str>, json: bool) {
+    let contents = read_stack_or_exit(file_path);
+
+    let artifact = deserialize_stack_yaml_into_artifact(&contents, environment, &[], true)
+        .use_or_pretty_exit(resolution_failed());
+
+    let (build_hash, _, _) = get_build_file_info(&artifact)
+        .expect("Unable to get build file info for stack.");
+
+    let values_overrides = load_values_overrides(values_args, &artifact);
+    let composer = Composer::new(build_hash, &artifact, false).with_values_overrides(values_overrides);
+
+    let rendered = composer.render_values().use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, we were unable to render the stack's values!")
+        .exit_category(ExitCategory::Compose)
+        .context("Values are rendered the same way they are for a deploy, so this fails wherever composing the stack would.")
+        .suggestions(vec![
+            "Check that every input address in the stack's inputs and values points at a node and output that exist.",
+            "If you passed --values, check that any node prefix matches a node in the stack."
+        ])
+        .pretty()
+    );
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&rendered).expect("Unable to serialize values to JSON."));
+
+        return;
     }
-}
 
-fn checkout_stack(name: Option<&str>) {
-    match name {
-        Some(name) => {
-            let stack_yaml: String =
-                pull_stack(name, false).expect("Failed to pull stack from any repository. Check that the source is configured correctly and that the stack exists.");
+    for node in rendered.iter() {
+        println!("{}", node.fqn.bold());
 
-            fs::write("./stack.yaml", stack_yaml).expect("Failed to write stack.yaml.");
-        }
-        None => {
-            fs::write("./stack.yaml", "").expect("Failed to write stack.yaml");
+        for (name, value) in node.inputs.iter() {
+            println!("--set {}={}", name, value);
         }
-    }
-}
-
-fn new_stack() {
-    let torb_path = torb_path();
-    let repositories_path = torb_path.join("repositories");
-    let torb_artifacts = repositories_path.join("torb-artifacts");
-    let template_path = torb_artifacts.join("stack.template.yaml");
 
-    let dest = std::env::current_dir().unwrap().join("stack.template.yaml");
+        for values in node.values.iter() {
+            print!("{}", values);
 
-    let source_string = template_path.to_str().unwrap();
-    let err_msg = format!("Unable to copy config template file from {source_string}. Please check that Torb has been initialized properly.");
+            if !values.ends_with('\n') {
+                println!();
+            }
+        }
 
-    fs::copy(template_path, dest).expect(&err_msg);
+        println!();
+    }
 }
 
-fn init_stack(file_path: String) {
-    println!("Attempting to read or create buildstate folder...");
-    buildstate_path_or_create();
+fn graph_stack(file_path: &str, out_option: Option<&str>) {
+    let contents = read_stack_or_exit(file_path);
 
-    println!("Attempting to read stack file...");
-    let stack_yaml = fs::read_to_string(&file_path).expect("Failed to read stack.yaml.");
-
-    println!("Reading stack into internal representation...");
-    let artifact = deserialize_stack_yaml_into_artifact(&stack_yaml)
-        .expect("Failed to read stack into internal representation.");
+    let graph = resolve_stack(&contents, None, &[]).use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, we were unable to resolve the stack!")
+        .exit_category(ExitCategory::Resolution)
+        .context("The stack has to resolve before its dependency graph can be drawn.")
+        .suggestions(vec![
+            "Check that the stack file is valid and that Torb has been initialized with `torb init`."
+        ])
+        .pretty()
+    );
 
-    let mut stack_initializer = StackInitializer::new(&artifact);
+    let dot = graph.to_dot();
 
-    stack_initializer
-        .run_node_init_steps().use_or_pretty_exit(
-            PrettyContext::default()
-            .error("Oh no, we failed to initialize the stack!")
-            .context("Failures here are typically because of missing dependencies for parts of the stack you're looking to initialize.")
-            .suggestions(vec![
-                "Check that all dependencies are installed.",
-                "Check to make sure you're on a compatible operating system."
-            ])
-            .success("Success! Stack initialized!")
-            .pretty()
-        )
+    match out_option {
+        Some(out_path) => {
+            fs::write(project_path(out_path), dot).use_or_pretty_exit(
+                PrettyContext::default()
+                .error("Oh no, we were unable to write the graph!")
+                .success("Success! Stack graph written!")
+                .suggestions(vec![
+                    "Check that the directory you're writing to exists and that you have permission to write there."
+                ])
+                .pretty()
+            );
+        }
+        None => print!("{}", dot),
+    }
 }
 
-fn compose_build_environment(build_hash: String, build_artifact: &ArtifactRepr) {
-    let mut composer = Composer::new(build_hash, build_artifact, false);
-    composer.compose().use_or_pretty_exit(
+fn print_deploy_order(artifact: &ArtifactRepr) {
+    // Meta stacks are deployed before the stack, so their order comes first.
+    if let Some(meta) = artifact.meta.as_ref() {
+        print_deploy_order(meta);
+    }
+
+    let order = artifact.deploy_order().use_or_pretty_exit(
         PrettyContext::default()
-        .error("Oh no, we failed to generate the IaC build environment!")
-        .success("Success! IaC build environment generated!")
-        .context("This typically happens due to failures parsing the stack into HCL for Terraform.")
+        .error("Oh no, we were unable to work out the order of the stack!")
+        .exit_category(ExitCategory::Resolution)
+        .context("This happens when nodes in the stack depend on each other in a cycle.")
         .suggestions(vec![
-            "Check that your inputs are escaped correctly.",
-            "Check that Torb has been initialized correctly, at ~/.torb you should see a Terraform binary appropriate to your system."
+            "Check the deps and input references of the nodes in the cycle above."
         ])
         .pretty()
     );
-}
 
-fn run_dependency_build_steps(
-    _build_hash: String,
-    build_artifact: &ArtifactRepr,
-    build_platform_string: String,
-    dryrun: bool,
-    separate_local_registry: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut builder = StackBuilder::new(
-        build_artifact,
-        build_platform_string,
-        dryrun,
-        separate_local_registry,
-    );
+    println!("\nOrder for {}:\n", artifact.stack_name);
 
-    builder.build()
+    for (idx, fqn) in order.iter().enumerate() {
+        println!("{}. {}", idx + 1, fqn);
+    }
 }
 
-fn run_deploy_steps(
-    _build_hash: String,
-    build_artifact: &ArtifactRepr,
-    dryrun: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut deployer = StackDeployer::new(false);
-
-    deployer.deploy(build_artifact, dryrun)
-}
+fn watch(fp_opt: Option<&str>, local_registry: bool, container_builder: ContainerBuilder, insecure_registries: Vec<String>, namespace_override: Option<String>, phases: WatchPhases) {
+    let _lock = lock_buildstate_or_exit("torb stack watch");
 
-fn watch(fp_opt: Option<&str>, local_registry: bool) {
-    let watcher = Watcher::configure(fp_opt.unwrap_or("stack.yaml").to_string(), local_registry);
+    let watcher = Watcher::configure(fp_opt.unwrap_or("stack.yaml").to_string(), local_registry, container_builder, insecure_registries, namespace_override, phases);
 
     watcher.start();
 }
@@ -266,17 +1581,8 @@ fn clone_artifacts() {
         repos_to_aliases
             .iter()
             .par_bridge()
-            .for_each(|(repo, alias)| {
-                if alias == "" {
-                    let err_msg = format!("Failed to clone {}.", &repo);
-
-                    let _clone_cmd_out = Command::new("git")
-                        .arg("clone")
-                        .arg(repo)
-                        .current_dir(&artifacts_path)
-                        .output()
-                        .expect(&err_msg);
-                } else {
+            .for_each(|(repo, repo_config)| {
+                if let Some(alias) = repo_config.alias() {
                     let alias_path = artifacts_path.join(&alias);
                     std::fs::create_dir_all(&alias_path)
                         .expect("Unable to create aliased dir for artifact repo.");
@@ -290,147 +1596,469 @@ fn clone_artifacts() {
                         .current_dir(&alias_path)
                         .output()
                         .expect(&err_msg);
+                } else {
+                    let err_msg = format!("Failed to clone {}.", &repo);
+
+                    let _clone_cmd_out = Command::new("git")
+                        .arg("clone")
+                        .arg(repo)
+                        .current_dir(&artifacts_path)
+                        .output()
+                        .expect(&err_msg);
+                }
+
+                /*
+                    Pinned repos are checked out after cloning rather than cloned with --branch,
+                    which only accepts branches and tags and not commits.
+                */
+                if let Some(git_ref) = repo_config.git_ref() {
+                    let repo_path = artifacts_path.join(repo_config.dir_name(repo));
+                    let checkout_failed = |reason: String| TorbCliErrors::ArtifactCheckoutFailed {
+                        repo: repo.to_string(),
+                        git_ref: git_ref.to_string(),
+                        reason,
+                    };
+
+                    let checkout = match Command::new("git").arg("checkout").arg(git_ref).current_dir(&repo_path).output() {
+                        Ok(output) if output.status.success() => Ok(()),
+                        Ok(output) => Err(checkout_failed(String::from_utf8_lossy(&output.stderr).trim().to_string())),
+                        Err(err) => Err(checkout_failed(err.to_string())),
+                    };
+
+                    checkout.use_or_pretty_exit(
+                        PrettyContext::default()
+                        .error("Oh no, we were unable to check out a pinned artifact repository!")
+                        .context("Repositories with a ref in ~/.torb/config.yaml are cloned and then checked out at that ref, leaving them on the default branch would build from the wrong artifacts.")
+                        .suggestions(vec![
+                            "Check that the ref is a branch, tag or commit that exists in the repository.",
+                            "Fix the ref in ~/.torb/config.yaml and run `torb init` again."
+                        ])
+                        .pretty()
+                    );
                 }
             })
     }
 }
 
+struct RepoRefreshResult {
+    repo_name: String,
+    outcome: Result<(), TorbCliErrors>,
+}
+
+fn run_git_in_repo(artifacts_path: &std::path::Path, repo_name: &str, args: &[&str]) -> Result<(), TorbCliErrors> {
+    let cmd_out = Command::new("git")
+        .args(args)
+        .current_dir(artifacts_path)
+        .output();
+
+    match cmd_out {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(TorbCliErrors::ArtifactRefreshFailed {
+            repos: repo_name.to_string(),
+            reason: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        }),
+        Err(err) => Err(TorbCliErrors::ArtifactRefreshFailed {
+            repos: repo_name.to_string(),
+            reason: err.to_string(),
+        }),
+    }
+}
+
+/*
+    A pinned repo is fetched and checked out at its ref, and only pulled when the ref is a branch
+    so tags and commits stay where they are.
+*/
+fn refresh_artifact_repo(repo_path: &std::path::Path, repo_name: String) -> RepoRefreshResult {
+    let artifacts_path = repo_path.join(&repo_name);
+
+    let outcome = match TORB_CONFIG.repository_ref(&repo_name) {
+        Some(git_ref) => run_git_in_repo(&artifacts_path, &repo_name, &["fetch", "--tags", "origin"])
+            .and_then(|_| run_git_in_repo(&artifacts_path, &repo_name, &["checkout", &git_ref]))
+            .and_then(|_| {
+                match run_git_in_repo(&artifacts_path, &repo_name, &["symbolic-ref", "-q", "HEAD"]) {
+                    Ok(_) => run_git_in_repo(&artifacts_path, &repo_name, &["pull", "--rebase"]),
+                    Err(_) => Ok(()),
+                }
+            }),
+        None => run_git_in_repo(&artifacts_path, &repo_name, &["pull", "--rebase"]),
+    };
+
+    RepoRefreshResult { repo_name, outcome }
+}
+
 fn update_artifacts(name: Option<&str>) {
-    let filter_name = name.unwrap();
+    let filter_name = name.unwrap_or("");
     let torb_path = torb_path();
     let repo_path = torb_path.join("repositories");
 
-    let repos = fs::read_dir(&repo_path).unwrap().par_bridge();
+    let repo_names = fs::read_dir(&repo_path)
+        .unwrap()
+        .filter_map(|repo_result| repo_result.ok())
+        .map(|repo| {
+            repo.file_name()
+                .into_string()
+                .expect("Failed to convert OsString to String.")
+        })
+        .filter(|repo_name| filter_name == "" || repo_name == filter_name)
+        .collect::<Vec<String>>();
+
+    info!("Refreshing {} artifact repositories...", repo_names.len());
+
+    // Pulls run in parallel but nothing is printed until they're all done so the output isn't interleaved.
+    let mut results = repo_names
+        .into_par_iter()
+        .map(|repo_name| refresh_artifact_repo(&repo_path, repo_name))
+        .collect::<Vec<RepoRefreshResult>>();
+
+    results.sort_by(|a, b| a.repo_name.cmp(&b.repo_name));
+
+    let mut failed = vec![];
+
+    for result in results {
+        match result.outcome {
+            Ok(_) => info!("{} done refreshing!", result.repo_name),
+            Err(err) => {
+                println!("{}", err.to_string().red());
+                failed.push(result.repo_name);
+            }
+        }
+    }
 
-    repos.for_each(|repo_result| {
-        let repo = repo_result.unwrap();
+    let aggregate: Result<(), TorbCliErrors> = if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(TorbCliErrors::ArtifactRefreshFailed {
+            repos: failed.join(", "),
+            reason: "see the errors above".to_string(),
+        })
+    };
 
-        if filter_name == "" || repo.file_name() == filter_name {
-            let repo_name = repo.file_name()
-                    .into_string()
-                    .expect("Failed to convert OsString to String.");
+    aggregate.use_or_pretty_exit(
+        PrettyContext::default()
+        .error("Oh no, we were unable to refresh some artifact repositories!")
+        .context("This type of error is usually an access or connection issue.")
+        .suggestions(vec![
+            "Check that you have the ability to access the artifact repo you're refreshing.",
+            "Check that you have an active internet connection.",
+            "Check that the repository doesn't have local changes preventing a rebase."
+        ])
+        .success("Success! Artifact repositories refreshed!")
+        .pretty()
+    );
+}
 
-            println!(
-                "Refreshing '{}' artifact repository...",
-                repo_name
-            );
+/*
+    A repository without a readable stack manifest is skipped with a warning, so one broken or third party repository
+    doesn't stop stacks from every other repository from being listed or checked out.
+*/
+fn load_stack_manifests() -> Result<IndexMap<String, serde_yaml::Value>, TorbCliErrors> {
+    let mut manifests = IndexMap::<String, serde_yaml::Value>::new();
 
-            let err_msg = format!("Failed to pull {:?}", repo.file_name());
-            let artifacts_path = repo_path.join(repo.file_name());
-            let pull_cmd_out = Command::new("git")
-                .arg("pull")
-                .arg("--rebase")
-                .current_dir(&artifacts_path)
-                .output();
+    for_each_artifact_repository(Box::new(|_repo_path, repo| {
+        let manifest_name = repo.file_name().to_string_lossy().to_string();
 
-            let success_msg = format!("{repo_name} done refreshing!");
-            pull_cmd_out.use_or_pretty_exit(
-                PrettyContext::default()
-                .error(&err_msg)
-                .context("This type of error is usually an access or connection issue.")
-                .suggestions(vec![
-                    "Check that you have the ability to access the artifact repo you're refreshing.",
-                    "Check that you have an active internet connection."
-                ])
-                .success(&success_msg)
-                .pretty()
-            );
+        let manifest = load_stack_manifest(&manifest_name, &repo.path()).use_or_pretty_warn(
+            PrettyContext::default()
+            .warn("Unable to load a repository's stacks.")
+            .suggestions(vec![
+                "Run `torb artifacts refresh` to pull the latest version of the repository.",
+                "If the repository doesn't provide stacks, it can still be used as a source for units."
+            ])
+            .pretty()
+        );
 
+        if let Some(manifest) = manifest {
+            manifests.insert(manifest_name, manifest);
         }
-    })
+    }))
+    .map_err(|_| TorbCliErrors::ManifestInvalid)?;
+
+    if manifests.is_empty() {
+        return Err(TorbCliErrors::ManifestInvalid);
+    }
+
+    Ok(manifests)
 }
 
-fn load_stack_manifests() -> IndexMap<String, serde_yaml::Value> {
-    let torb_path = torb_path();
-    let artifacts_path = torb_path.join("repositories");
+fn load_stack_manifest(repo: &str, repo_path: &std::path::Path) -> Result<serde_yaml::Value, TorbCliErrors> {
+    let stack_manifest_path = repo_path.join("stacks").join("manifest.yaml");
 
-    let repository_paths = fs::read_dir(&artifacts_path)
-        .expect("Unable to read list of repositories. Please re-initialize Torb.");
+    let manifest_error = |reason: String| TorbCliErrors::RepositoryManifestInvalid {
+        repo: repo.to_string(),
+        path: stack_manifest_path.display().to_string(),
+        reason,
+    };
 
-    let mut manifests = IndexMap::<String, serde_yaml::Value>::new();
+    let stack_manifest_contents = fs::read_to_string(&stack_manifest_path)
+        .map_err(|err| manifest_error(format!("couldn't be read: {}", err)))?;
+    let stack_manifest_yaml: serde_yaml::Value = serde_yaml::from_str(&stack_manifest_contents)
+        .map_err(|err| manifest_error(format!("isn't valid yaml: {}", err)))?;
 
-    for artifact_path_result in repository_paths {
-        let artifact_path =
-            artifact_path_result.expect("Unable to read entry in repositories, try again.");
-        let stack_manifest_path = artifact_path.path().join("stacks").join("manifest.yaml");
-        let stack_manifest_contents = fs::read_to_string(&stack_manifest_path).unwrap();
-        let stack_manifest_yaml: serde_yaml::Value =
-            serde_yaml::from_str(&stack_manifest_contents).unwrap();
+    match stack_manifest_yaml.get("stacks") {
+        Some(stacks) if stacks.is_mapping() => Ok(stacks.clone()),
+        _ => Err(manifest_error("has no stacks mapping".to_string())),
+    }
+}
 
-        let manifest_name = artifact_path.file_name().to_str().unwrap().to_string();
+/*
+    Stacks are selected by name, or as <repository>:<name> to pick between repositories that both have a stack
+    with that name. A bare name is only ambiguous when more than one repository has it.
+*/
+#[derive(Debug, Clone, PartialEq)]
+struct StackSelector {
+    repo: Option<String>,
+    stack: String,
+}
 
-        manifests.insert(
-            manifest_name,
-            stack_manifest_yaml.get("stacks").unwrap().clone(),
-        );
+impl StackSelector {
+    fn parse(selector: &str) -> Result<StackSelector, TorbCliErrors> {
+        let invalid = || TorbCliErrors::InvalidStackSelector { selector: selector.to_string() };
+
+        match selector.split_once(':') {
+            Some((repo, stack)) if !repo.is_empty() && !stack.is_empty() && !stack.contains(':') => Ok(StackSelector {
+                repo: Some(repo.to_string()),
+                stack: stack.to_string(),
+            }),
+            Some(_) => Err(invalid()),
+            None if selector.is_empty() => Err(invalid()),
+            None => Ok(StackSelector { repo: None, stack: selector.to_string() }),
+        }
     }
 
-    manifests
+    fn qualified(repo: &str, stack: &str) -> String {
+        format!("{}:{}", repo, stack)
+    }
 }
 
-fn pull_stack(
-    stack_name: &str,
-    fail_not_found: bool,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let mut repo = "";
-    let mut stack = stack_name;
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StackListFormat {
+    Tree,
+    Flat,
+    Json,
+}
 
-    if stack_name.find(":").is_some() {
-        let stack_parts: Vec<&str> = stack_name.split(":").collect();
-        repo = stack_parts[0];
-        stack = stack_parts[1];
+impl StackListFormat {
+    fn from_arg(format: Option<&str>) -> StackListFormat {
+        match format {
+            Some("flat") => StackListFormat::Flat,
+            Some("json") => StackListFormat::Json,
+            _ => StackListFormat::Tree,
+        }
     }
+}
+
+#[derive(Serialize)]
+struct RepositoryStacks {
+    repository: String,
+    stacks: Vec<String>,
+}
+
+// Flat prints only the qualified names so it can be piped, tree is meant for reading with many repositories.
+fn print_stack_list(manifests: &IndexMap<String, serde_yaml::Value>, format: StackListFormat) {
+    let repositories = manifests
+        .iter()
+        .map(|(repo, manifest)| RepositoryStacks {
+            repository: repo.clone(),
+            stacks: manifest
+                .as_mapping()
+                .map(|stacks| {
+                    stacks
+                        .iter()
+                        .filter_map(|(key, _)| key.as_str())
+                        .map(|stack| StackSelector::qualified(repo, stack))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+        .collect::<Vec<RepositoryStacks>>();
+
+    match format {
+        StackListFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&repositories).expect("Unable to serialize stacks to JSON."));
+        }
+        StackListFormat::Flat => {
+            for stack in repositories.iter().flat_map(|repo| repo.stacks.iter()) {
+                println!("{}", stack);
+            }
+        }
+        StackListFormat::Tree => {
+            let total: usize = repositories.iter().map(|repo| repo.stacks.len()).sum();
+
+            println!("\nTorb Stacks ({} in {} repositories):\n", total, repositories.len());
 
-    let manifests = load_stack_manifests();
+            for repo in repositories.iter() {
+                let noun = if repo.stacks.len() == 1 { "stack" } else { "stacks" };
 
-    let mut count = 0;
+                println!("{} ({} {}):", repo.repository, repo.stacks.len(), noun);
 
-    for (_name, manifest) in manifests.iter() {
-        let stack_entry = manifest.get(stack);
-        if stack_entry.is_some() {
-            count += 1;
+                for stack in repo.stacks.iter() {
+                    println!("  - {}", stack);
+                }
+            }
         }
     }
+}
 
-    if count > 1 && repo == "" {
-        return Err(Box::new(TorbCliErrors::StackAmbiguous));
-    } else if repo == "" {
-        repo = "torb-artifacts"
-    }
+// Pulls the stack the selector names, returning it along with the fully qualified name it was found under.
+fn pull_stack(
+    stack_name: &str,
+    fail_not_found: bool,
+) -> Result<(String, String), Box<dyn std::error::Error>> {
+    let selector = StackSelector::parse(stack_name)?;
+    let stack = selector.stack.as_str();
+
+    let manifests = load_stack_manifests()?;
+
+    let candidates: Vec<&String> = match &selector.repo {
+        Some(repo) => {
+            let (name, _) = manifests.get_key_value(repo).ok_or_else(|| TorbCliErrors::UnknownStackRepository {
+                repo: repo.clone(),
+                available: manifests.keys().cloned().collect::<Vec<String>>().join(", "),
+            })?;
+
+            vec![name].into_iter().filter(|name| manifests[*name].get(stack).is_some()).collect()
+        }
+        None => manifests.iter().filter(|(_, manifest)| manifest.get(stack).is_some()).map(|(name, _)| name).collect(),
+    };
 
-    let err_msg = format!("Unable to find manifest for {repo}. Make sure it was added in config.yaml and pulled with `torb artifacts refresh`");
-    let repo_manifest = manifests.get(repo).expect(&err_msg);
+    match candidates.as_slice() {
+        [] if fail_not_found => Err(Box::new(TorbCliErrors::StackNotFound {
+            stack: stack.to_string(),
+            searched: selector.repo.clone().unwrap_or("any artifact repository".to_string()),
+        })),
+        [] => {
+            update_artifacts(selector.repo.as_deref());
 
-    let stack_entry = repo_manifest.get(stack);
+            pull_stack(stack_name, true)
+        }
+        [repo] => {
+            let stack_entry_str = manifests[*repo][stack].as_str().ok_or(TorbCliErrors::ManifestInvalid)?;
+            let artifacts_path = torb_path().join("repositories").join(repo);
+            let stack_contents = fs::read(artifacts_path.join("stacks").join(stack_entry_str))
+                .map(|s| String::from_utf8(s).unwrap())?;
 
-    if stack_entry.is_none() {
-        if fail_not_found {
-            return Err(Box::new(TorbCliErrors::ManifestInvalid));
+            Ok((StackSelector::qualified(repo, stack), stack_contents))
         }
+        _ => Err(Box::new(TorbCliErrors::StackAmbiguous {
+            stack: stack.to_string(),
+            candidates: candidates
+                .iter()
+                .map(|repo| StackSelector::qualified(repo, stack))
+                .collect::<Vec<String>>()
+                .join(", "),
+        })),
+    }
+}
 
-        update_artifacts(None);
-        return pull_stack(stack_name, true);
+/*
+    Progress and diagnostic messages go through the log crate to stderr so they can be silenced without
+    touching the output of a command. RUST_LOG still works for finer grained filtering, but --quiet always wins.
+*/
+fn init_logging(quiet: bool) {
+    let level = if quiet {
+        LevelFilter::Warn
+    } else if std::env::var(DEBUG_ENV).is_ok() {
+        LevelFilter::Debug
     } else {
-        let torb_path = torb_path();
-        let repo_path = torb_path.join("repositories");
-        let artifacts_path = repo_path.join(repo);
-        let stack_entry_str = stack_entry.unwrap().as_str().unwrap();
-        let stack_contents = fs::read(artifacts_path.join("stacks").join(stack_entry_str))
-            .map(|s| String::from_utf8(s).unwrap())?;
+        LevelFilter::Info
+    };
+
+    let mut builder = env_logger::Builder::new();
 
-        return Ok(stack_contents);
+    builder
+        .filter_level(level)
+        .format(|buf, record| writeln!(buf, "{}", record.args()))
+        .target(env_logger::Target::Stderr);
+
+    if !quiet {
+        builder.parse_env("RUST_LOG");
     }
+
+    builder.init();
 }
 
 fn main() {
     let cli_app = cli();
 
-    let cli_matches = cli_app.get_matches();
+    /*
+        clap exits with 2 on a usage error, which is the code for a resolution failure here. Help and version
+        output still exit the way clap does, anything else is a validation failure.
+    */
+    let cli_matches = cli_app.try_get_matches().unwrap_or_else(|err| {
+        if !err.use_stderr() {
+            err.exit();
+        }
+
+        let _ = err.print();
+        std::process::exit(ExitCategory::Validation.code())
+    });
+    let quiet = cli_matches.is_present("--quiet");
+
+    disable_color_if_requested(cli_matches.is_present("--no-color"));
+    init_logging(quiet);
+
+    if let Some(dir) = cli_matches.value_of("--dir") {
+        set_project_root(dir).use_or_pretty_exit(
+            PrettyContext::default()
+            .error("Oh no, we were unable to use that project directory!")
+            .exit_category(ExitCategory::Validation)
+            .context("--dir runs Torb against the project in another directory, the stack, buildstate and relative paths are all found from it.")
+            .suggestions(vec![
+                "Check that the directory exists and that you can read it."
+            ])
+            .pretty()
+        );
+    }
+
+    // Everything but init, doctor, clean and version reads config.yaml, so it's checked once up front.
+    if matches!(cli_matches.subcommand_name(), Some("repo") | Some("artifacts") | Some("stack")) {
+        load_config().use_or_pretty_exit(
+            PrettyContext::default()
+            .error("Oh no, we were unable to load your Torb config!")
+            .exit_category(ExitCategory::Validation)
+            .context("Torb reads its repositories, registries and credentials from ~/.torb/config.yaml.")
+            .suggestions(vec![
+                "Run `torb init` if you haven't yet, it creates the config.",
+                "Run `torb doctor` to check the config for errors."
+            ])
+            .pretty()
+        );
+    }
 
     match cli_matches.subcommand_name() {
         Some("init") => {
             init();
         }
+        Some("doctor") => {
+            doctor();
+        }
+        Some("clean") => {
+            let subcommand = cli_matches.subcommand_matches("clean").unwrap();
+
+            if let Some(dir) = subcommand.value_of("--buildstate-dir") {
+                set_buildstate_dir(dir);
+            }
+
+            let scope = if subcommand.is_present("--iac") {
+                CleanScope::Iac
+            } else if subcommand.is_present("--all") {
+                CleanScope::All
+            } else {
+                CleanScope::Generated
+            };
+
+            clean(scope, subcommand.is_present("--force")).use_or_pretty_exit(
+                PrettyContext::default()
+                .error("Oh no, we were unable to clean the buildstate!")
+                .success("Success! Buildstate cleaned!")
+                .context("Clean only removes what Torb generates in the buildstate, the IaC environments, cached stack resolutions and with --all the build files.")
+                .suggestions(vec![
+                    "Check that the buildstate directory and everything in it is writable.",
+                    "Pass --force to clean without a prompt, for example in CI."
+                ])
+                .pretty()
+            );
+        }
         Some("repo") => {
             let mut subcommand = cli_matches.subcommand_matches("repo").unwrap();
             match subcommand.subcommand_name() {
@@ -441,6 +2069,28 @@ fn main() {
 
                     create_repo(path_option.unwrap().to_string(), local_option.is_some());
                 }
+                Some("list") => {
+                    list_repos();
+                }
+                Some("delete") => {
+                    subcommand = subcommand.subcommand_matches("delete").unwrap();
+                    let path = subcommand.value_of("path").unwrap();
+                    let local_only = subcommand.is_present("--local-only");
+                    let confirmed = subcommand.is_present("--confirm");
+
+                    delete_repo(path, local_only, confirmed).use_or_pretty_exit(
+                        PrettyContext::default()
+                        .error("Oh no, we were unable to delete the repo!")
+                        .success("Success! Repo deleted!")
+                        .context("Deleting a remote repo needs a GitHub token with the delete_repo scope.")
+                        .suggestions(vec![
+                            "Check that the githubToken in ~/.torb/config.yaml can delete repositories.",
+                            "Pass --local-only to only remove the local copy, --confirm is still needed to delete it.",
+                            "Only repos created with `torb repo create` can be deleted, check the path is one of them."
+                        ])
+                        .pretty()
+                    );
+                }
                 _ => {
                     println!("No subcommand specified.");
                 }
@@ -461,7 +2111,30 @@ fn main() {
             }
         }
         Some("stack") => {
+            check_initialized().use_or_pretty_exit(
+                PrettyContext::default()
+                .error("Oh no, Torb isn't set up yet!")
+                .context("Stack commands need the Terraform binary and artifact repositories that `torb init` installs in ~/.torb.")
+                .suggestions(vec![
+                    "Run `torb init` to set up Torb, running it again restores anything that's missing."
+                ])
+                .pretty()
+            );
+
             let mut subcommand = cli_matches.subcommand_matches("stack").unwrap();
+
+            if let Some(dir) = subcommand.value_of("--buildstate-dir") {
+                set_buildstate_dir(dir);
+            }
+
+            if subcommand.is_present("--show-secrets") {
+                show_secrets();
+            }
+
+            if subcommand.is_present("--allow-shell-inputs") {
+                allow_shell_inputs();
+            }
+
             match subcommand.subcommand_name() {
                 Some("checkout") => {
                     let name_option = subcommand
@@ -471,20 +2144,49 @@ fn main() {
 
                     checkout_stack(name_option);
                 }
-                Some("new") => new_stack(),
+                Some("new") => {
+                    let new_subcommand = subcommand.subcommand_matches("new").unwrap();
+
+                    new_stack(new_subcommand.value_of("--template"))
+                }
                 Some("init") => {
-                    let file_path_option = subcommand
-                        .subcommand_matches("init")
-                        .unwrap()
-                        .value_of("file");
+                    let init_subcommand = subcommand.subcommand_matches("init").unwrap();
+                    let file_path_option = init_subcommand.value_of("file");
+                    let dryrun = init_subcommand.is_present("--dryrun");
+                    let reinit = init_subcommand.is_present("--reinit");
 
-                    init_stack(file_path_option.unwrap().to_string())
+                    init_stack(file_path_option.unwrap().to_string(), dryrun, reinit)
                 }
                 Some("build") => {
                     subcommand = subcommand.subcommand_matches("build").unwrap();
                     let file_path_option = subcommand.value_of("file");
                     let dryrun = subcommand.is_present("--dryrun");
                     let local_registry = subcommand.is_present("--local-hosted-registry");
+                    let container_builder = resolve_container_builder(subcommand.value_of("--builder"));
+                    let insecure_registries = configured_insecure_registries(
+                        subcommand.values_of("--registry-insecure").map(|vals| vals.collect()).unwrap_or_default(),
+                    );
+                    let force = subcommand.is_present("--force");
+                    let no_animation = subcommand.is_present("--no-animation");
+                    let verify_platforms = subcommand.is_present("--verify-platforms");
+                    let no_cache = subcommand.is_present("--no-cache");
+                    let verify = subcommand.is_present("--verify-charts");
+                    let verify_input_mappings = subcommand.is_present("--verify-mappings");
+                    let timings_path = subcommand.value_of("--timings");
+                    let environment = subcommand.value_of("--env");
+                    let input_overrides = load_input_overrides(
+                        subcommand
+                            .values_of("--set")
+                            .map(|vals| vals.collect())
+                            .unwrap_or_default(),
+                    );
+                    load_kube_target(subcommand.value_of("--context"), subcommand.value_of("--kubeconfig"));
+                    let build_jobs = subcommand
+                        .value_of("--build-jobs")
+                        .map(|jobs| jobs.parse::<usize>().unwrap())
+                        .unwrap_or_else(default_build_jobs);
+                    let namespace_override = load_namespace_override(subcommand.value_of("--namespace"));
+                    let build_file_dir = subcommand.value_of("--build-file-dir").map(project_path);
 
                     let build_platforms_string = subcommand
                         .values_of("--platforms")
@@ -493,36 +2195,60 @@ fn main() {
                         .join(",");
 
                     if let Some(file_path) = file_path_option {
-                        println!("Attempting to read or create buildstate folder...");
-                        buildstate_path_or_create();
-                        println!("Attempting to read and build stack: {}", file_path);
-                        let contents = fs::read_to_string(file_path)
-                            .expect("Something went wrong reading the stack file.");
+                        let started = Instant::now();
+                        enable_timings();
+
+                        info!("Attempting to read or create buildstate folder...");
+                        buildstate_or_exit();
+                        info!("Attempting to read and build stack: {}", file_path);
+                        let contents = read_stack_or_exit(file_path);
 
-                        let (build_hash, build_filename, _) = write_build_file(contents, None);
+                        if subcommand.is_present("--print-order") {
+                            let artifact = deserialize_stack_yaml_into_artifact(&contents, environment, &input_overrides, !no_cache)
+                                .use_or_pretty_exit(resolution_failed());
 
-                        let (_, _, build_artifact) =
-                            load_build_file(build_filename).expect("Unable to load build file.");
+                            print_deploy_order(&artifact);
+                            return;
+                        }
 
+                        let _lock = lock_buildstate_or_exit("torb stack build");
 
-                        let animator = BuilderAnimation::new();
+                        let (build_hash, build_artifact) =
+                            timed("resolve", None, || torb::resolve_stack(contents, environment, &input_overrides, !no_cache, build_file_dir.as_ref()))
+                                .use_or_pretty_exit(resolution_failed());
 
-                        let build_hash_clone = build_hash.clone();
-                        let build_artifact_clone = build_artifact.clone();
+                        if verify {
+                            verify_charts_or_exit(&build_artifact);
+                        }
+                        if verify_input_mappings {
+                            verify_mappings_or_warn(&build_artifact);
+                        }
+                        let build_artifact = build_artifact.with_namespace_override(namespace_override);
 
-                        animator.do_with_animation(Box::new(
-                            move || {
-                            run_dependency_build_steps(
-                                build_hash_clone.clone(),
-                                &build_artifact_clone,
-                            build_platforms_string.clone(),
-                                dryrun,
-                                local_registry
-                            )
-                            }
-                        )).use_or_pretty_exit(
+                        run_hook_or_exit(&build_artifact, HookStage::PreBuild, dryrun);
+
+                        let build_artifact_clone = build_artifact.clone();
+                        let build_options = BuildOptions {
+                            platforms: build_platforms_string,
+                            dryrun,
+                            separate_local_registry: local_registry,
+                            container_builder,
+                            force,
+                            build_jobs,
+                            verify_platforms,
+                            insecure_registries,
+                        };
+
+                        let build = move || timed("build", None, || torb::build(&build_artifact_clone, &build_options));
+
+                        // The animation redraws the terminal, which defeats the point of asking for quiet output.
+                        BuilderAnimation::new()
+                            .with_disabled(quiet || no_animation)
+                            .do_with_animation(Box::new(build))
+                            .use_or_pretty_exit(
                                 PrettyContext::default()
                                 .error("Oh no, we were unable to build the stack!")
+                                .exit_category(ExitCategory::Build)
                                 .success("Success! Stack has been built!")
                                 .context("Errors here are typically because of a failed docker build, syntax issue in the dockerfile or a connectivity issue with the docker registry.")
                                 .suggestions(vec![
@@ -532,60 +2258,215 @@ fn main() {
                                 .pretty()
                             );
 
-                        compose_build_environment(build_hash.clone(), &build_artifact);
+                        compose_build_environment(build_hash.clone(), &build_artifact, Vec::new());
+
+                        run_hook_or_exit(&build_artifact, HookStage::PostBuild, dryrun);
+
+                        report_timings(started, timings_path);
                     }
                 }
                 Some("deploy") => {
                     subcommand = subcommand.subcommand_matches("deploy").unwrap();
                     let file_path_option = subcommand.value_of("file");
                     let dryrun = subcommand.is_present("--dryrun");
+                    let no_cache = subcommand.is_present("--no-cache");
+                    let verify = subcommand.is_present("--verify-charts");
+                    let verify_input_mappings = subcommand.is_present("--verify-mappings");
+                    let validate = subcommand.is_present("--validate");
+                    let timings_path = subcommand.value_of("--timings");
+                    let environment = subcommand.value_of("--env");
+                    let input_overrides = load_input_overrides(
+                        subcommand
+                            .values_of("--set")
+                            .map(|vals| vals.collect())
+                            .unwrap_or_default(),
+                    );
+                    let auto_approve = subcommand.is_present("--auto-approve");
+                    let namespace_override = load_namespace_override(subcommand.value_of("--namespace"));
+                    load_kube_target(subcommand.value_of("--context"), subcommand.value_of("--kubeconfig"));
+                    let readiness_timeout = subcommand
+                        .value_of("--readiness-timeout")
+                        .map(|secs| Duration::from_secs(secs.parse().unwrap()))
+                        .unwrap_or(DEFAULT_READINESS_TIMEOUT);
+                    let stable_timeout = subcommand.is_present("--wait-for-stable").then(|| {
+                        subcommand
+                            .value_of("--stable-timeout")
+                            .map(|secs| Duration::from_secs(secs.parse().unwrap()))
+                            .unwrap_or(DEFAULT_STABLE_TIMEOUT)
+                    });
+                    let tf_parallelism = subcommand
+                        .value_of("--tf-parallelism")
+                        .map(|parallelism| parallelism.parse().unwrap());
+                    let build_file_dir = subcommand.value_of("--build-file-dir").map(project_path);
 
                     if let Some(file_path) = file_path_option {
-                        println!("Attempting to read and deploy stack: {}", file_path);
-                        let contents = fs::read_to_string(file_path)
-                            .expect("Something went wrong reading the stack file.");
+                        let started = Instant::now();
+                        enable_timings();
+
+                        info!("Attempting to read and deploy stack: {}", file_path);
+                        let contents = read_stack_or_exit(file_path);
+
+                        let artifact = timed("resolve", None, || deserialize_stack_yaml_into_artifact(&contents, environment, &input_overrides, !no_cache))
+                            .use_or_pretty_exit(resolution_failed());
+
+                        if subcommand.is_present("--print-order") {
+                            print_deploy_order(&artifact);
+                            return;
+                        }
+
+                        let _lock = lock_buildstate_or_exit("torb stack deploy");
+
+                        if verify {
+                            verify_charts_or_exit(&artifact);
+                        }
 
-                        let artifact = deserialize_stack_yaml_into_artifact(&contents)
-                            .expect("Unable to read stack file into internal representation.");
+                        if verify_input_mappings {
+                            verify_mappings_or_warn(&artifact);
+                        }
 
                         let (build_hash, build_filename, _) = get_build_file_info(&artifact)
                             .expect("Unable to get build file info for stack.");
-                        println!("build_filename: {}", build_filename);
-                        let (_, _, build_artifact) =
-                            load_build_file(build_filename).expect("Unable to load build file.");
-
-                        run_deploy_steps(build_hash.clone(), &build_artifact, dryrun)
-                        .use_or_pretty_exit(
-                            PrettyContext::default()
-                            .error("Oh no, we were unable to deploy the stack!")
-                            .success("Success! Stack has been deployed!")
-                            .context("Errors here are typically because of failed Terraform deployments or Helm failures.")
-                            .suggestions(vec![
-                                "Check that your Terraform IaC environment was generated correctly. \nThis can be found in your project folder at, .torb_buildstate/iac_environment, or .torb_buildstate/watcher_iac_environment if you're using the watcher.",
-                                "To see if your Helm deployment failed you can do `helm ls --namespace <namespace>` where the namespace is the one you're deploying to.",
-                                "After seeing if the deployment has failed in Helm, you can use kubectl to debug further. Take a look at https://kubernetes.io/docs/reference/kubectl/cheatsheet/ if you're less familiar with kubectl."
-                            ])
-                            .pretty()
-                        )
+                        debug!("build_filename: {}", build_filename);
+                        let (_, _, build_artifact) = load_build_file(build_filename, build_file_dir.as_ref())
+                            .use_or_pretty_exit(
+                                PrettyContext::default()
+                                .error("Oh no, we were unable to load the stack's build file!")
+                                .exit_category(ExitCategory::Resolution)
+                                .context("Deploys read the build file written when the stack was built, it's named after the build hash so it has to be built with the same stack, environment and inputs.")
+                                .suggestions(vec![
+                                    "Run `torb stack build` on the stack first, with the same --env and --set flags.",
+                                    "If the stack was built with --build-file-dir, pass the same directory to deploy."
+                                ])
+                                .pretty()
+                            );
+                        let build_artifact = build_artifact.with_namespace_override(namespace_override);
+
+                        let values_overrides = load_values_overrides(
+                            subcommand
+                                .values_of("--values")
+                                .map(|vals| vals.collect())
+                                .unwrap_or_default(),
+                            &build_artifact,
+                        );
+
+                        if validate {
+                            validate_manifests_or_exit(build_hash.clone(), &build_artifact, values_overrides.clone());
+                        }
+
+                        if !dryrun {
+                            confirm_deploy(&build_artifact, auto_approve).use_or_pretty_exit(
+                                PrettyContext::default()
+                                .error("Deploy cancelled, nothing was changed.")
+                                .context("Deploys ask for confirmation so changes aren't made to a cluster by accident.")
+                                .suggestions(vec![
+                                    "Answer y at the prompt to deploy.",
+                                    "Pass --auto-approve to deploy without a prompt, for example in CI or when the stack is read from stdin."
+                                ])
+                                .pretty()
+                            );
+                        }
+
+                        run_hook_or_exit(&build_artifact, HookStage::PreDeploy, dryrun);
+
+                        if !values_overrides.is_empty()
+                            || build_artifact.namespace_override.is_some()
+                            || kube_target().is_set()
+                        {
+                            compose_build_environment(build_hash.clone(), &build_artifact, values_overrides);
+                        }
+
+                        report_deploy_result(torb::deploy(
+                            &build_artifact,
+                            &DeployOptions { dryrun, readiness_timeout, tf_parallelism, stable_timeout },
+                        ));
+
+                        if !dryrun {
+                            report_outputs(&build_artifact);
+                        }
+
+                        run_hook_or_exit(&build_artifact, HookStage::PostDeploy, dryrun);
+
+                        report_timings(started, timings_path);
                     }
                 }
                 Some("watch") => {
                     subcommand = subcommand.subcommand_matches("watch").unwrap();
                     let file_path_option = subcommand.value_of("file");
                     let has_local_registry = subcommand.is_present("--local-hosted-registry");
-                    watch(file_path_option, has_local_registry);
+                    let container_builder = resolve_container_builder(subcommand.value_of("--builder"));
+                    let insecure_registries = configured_insecure_registries(
+                        subcommand.values_of("--registry-insecure").map(|vals| vals.collect()).unwrap_or_default(),
+                    );
+                    let namespace_override = load_namespace_override(subcommand.value_of("--namespace"));
+                    let phases = WatchPhases::from_flags(subcommand.is_present("--no-build"), subcommand.is_present("--no-deploy"));
+                    load_kube_target(subcommand.value_of("--context"), subcommand.value_of("--kubeconfig"));
+                    watch(file_path_option, has_local_registry, container_builder, insecure_registries, namespace_override, phases);
                 }
-                Some("list") => {
-                    println!("\nTorb Stacks:\n");
-                    let stack_manifests = load_stack_manifests();
+                Some("compose") => {
+                    subcommand = subcommand.subcommand_matches("compose").unwrap();
+                    let file_path = subcommand.value_of("file").unwrap();
+                    let out_path = subcommand.value_of("--out").unwrap();
 
-                    for (repo, manifest) in stack_manifests.iter() {
-                        println!("{repo}:");
+                    compose_to_directory(file_path, out_path);
+                }
+                Some("lint") => {
+                    subcommand = subcommand.subcommand_matches("lint").unwrap();
+                    let file_path = subcommand.value_of("file").unwrap();
+                    let deny_warnings = subcommand.is_present("--deny-warnings");
 
-                        for (key, _) in manifest.as_mapping().unwrap().iter() {
-                            println!("- {}", key.as_str().unwrap());
-                        }
-                    }
+                    lint_stack_file(file_path, deny_warnings);
+                }
+                Some("outputs") => {
+                    subcommand = subcommand.subcommand_matches("outputs").unwrap();
+                    let file_path = subcommand.value_of("file").unwrap();
+                    let json = subcommand.is_present("--json");
+
+                    stack_outputs(file_path, json);
+                }
+                Some("render-values") => {
+                    subcommand = subcommand.subcommand_matches("render-values").unwrap();
+                    let file_path = subcommand.value_of("file").unwrap();
+                    let environment = subcommand.value_of("--env");
+                    let json = subcommand.is_present("--json");
+                    let values_args = subcommand
+                        .values_of("--values")
+                        .map(|vals| vals.collect())
+                        .unwrap_or_default();
+
+                    stack_render_values(file_path, environment, values_args, json);
+                }
+                Some("prune") => {
+                    subcommand = subcommand.subcommand_matches("prune").unwrap();
+                    let file_path = subcommand.value_of("file").unwrap();
+                    let environment = subcommand.value_of("--env");
+                    let auto_approve = subcommand.is_present("--auto-approve");
+                    let dryrun = subcommand.is_present("--dryrun");
+                    let namespace_override = load_namespace_override(subcommand.value_of("--namespace"));
+                    load_kube_target(subcommand.value_of("--context"), subcommand.value_of("--kubeconfig"));
+
+                    stack_prune(file_path, environment, namespace_override, auto_approve, dryrun);
+                }
+                Some("graph") => {
+                    subcommand = subcommand.subcommand_matches("graph").unwrap();
+                    let file_path = subcommand.value_of("file").unwrap();
+                    let out_option = subcommand.value_of("--out");
+
+                    graph_stack(file_path, out_option);
+                }
+                Some("list") => {
+                    subcommand = subcommand.subcommand_matches("list").unwrap();
+                    let stack_manifests = load_stack_manifests().use_or_pretty_exit(
+                        PrettyContext::default()
+                        .error("Oh no, none of your artifact repositories have stacks Torb could load!")
+                        .suggestions(vec![
+                            "Run `torb init` to clone torb-artifacts, or `torb artifacts refresh` to update your repositories."
+                        ])
+                        .pretty()
+                    );
+
+                    let format = StackListFormat::from_arg(subcommand.value_of("--format"));
+
+                    print_stack_list(&stack_manifests, format);
                 }
                 _ => {
                     println!("No subcommand specified.");
@@ -594,6 +2475,26 @@ fn main() {
         }
         Some("version") => {
             println!("Torb Version: {}", VERSION);
+
+            let subcommand = cli_matches.subcommand_matches("version").unwrap();
+
+            if subcommand.is_present("--check") {
+                let compatible = check_artifacts_compatibility(VERSION).use_or_pretty_warn(
+                    PrettyContext::default()
+                    .warn("Warning! This version of Torb may not work with your artifacts.")
+                    .context("torb-artifacts declares the Torb versions its units support in compatibility.yaml, units built for a different version can fail to resolve or deploy in subtle ways.")
+                    .suggestions(vec![
+                        "If Torb is too old, install a newer release of Torb.",
+                        "If Torb is too new, run `torb artifacts refresh` to update your artifacts."
+                    ])
+                    .pretty()
+                );
+
+                match compatible {
+                    Some(message) => println!("{}", message),
+                    None => std::process::exit(1),
+                }
+            }
         }
         _ => {
             println!("No subcommand specified.");
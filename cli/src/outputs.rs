@@ -0,0 +1,159 @@
+// Business Source License 1.1
+// Licensor:  Torb Foundry
+// Licensed Work:  Torb v0.3.7-03.23
+// The Licensed Work is © 2023-Present Torb Foundry
+//
+// Change License: GNU Affero General Public License Version 3
+// Additional Use Grant: None
+// Change Date: Feb 22, 2023
+//
+// See LICENSE file at https://github.com/TorbFoundry/torb/blob/main/LICENSE for details.
+
+use crate::artifacts::{ArtifactNodeRepr, ArtifactRepr, TorbInput};
+use crate::composer::InputAddress;
+use crate::resolver::inputs::{InputResolver, NO_INITS_FN, NO_INPUTS_FN};
+use crate::resolver::template::{is_template, referenced_addresses};
+
+use indexmap::{IndexMap, IndexSet};
+use serde::Serialize;
+use std::error::Error;
+
+const OUTPUT_PROPERTY: &str = "output";
+
+#[derive(Serialize, Debug, Clone)]
+pub struct OutputConsumer {
+    pub output: String,
+    pub consumer: String,
+    pub via: String,
+}
+
+/*
+    The outputs a node declares in its unit and everything in the stack that reads them.
+    Undeclared outputs are referenced by another node without the unit declaring them, which is usually a typo,
+    unused outputs are declared but nothing in the stack reads them.
+*/
+#[derive(Serialize, Debug, Clone)]
+pub struct NodeOutputs {
+    pub fqn: String,
+    pub declared: Vec<String>,
+    pub consumers: Vec<OutputConsumer>,
+    pub undeclared: Vec<String>,
+    pub unused: Vec<String>,
+}
+
+// Maps how outputs flow between the nodes of a resolved stack, nodes are listed in deploy order.
+pub fn output_wiring(artifact: &ArtifactRepr) -> Result<Vec<NodeOutputs>, Box<dyn Error>> {
+    let mut consumers = IndexMap::<String, Vec<OutputConsumer>>::new();
+
+    for node in artifact.nodes.values() {
+        for (producer, consumer) in consumed_outputs(&artifact.stack_name, node)? {
+            consumers.entry(producer).or_insert_with(Vec::new).push(consumer);
+        }
+    }
+
+    let mut wiring = vec![];
+
+    for fqn in artifact.deploy_order()? {
+        let node = &artifact.nodes[&fqn];
+        let consumers = consumers.remove(&fqn).unwrap_or_default();
+
+        let referenced = consumers
+            .iter()
+            .map(|consumer| consumer.output.clone())
+            .collect::<IndexSet<String>>();
+
+        let undeclared = referenced
+            .iter()
+            .filter(|output| node.output(output).is_none())
+            .cloned()
+            .collect();
+
+        let declared = node.outputs.iter().map(|output| output.name.clone()).collect::<Vec<String>>();
+
+        let unused = declared
+            .iter()
+            .filter(|output| !referenced.contains(*output))
+            .cloned()
+            .collect();
+
+        wiring.push(NodeOutputs {
+            fqn,
+            declared,
+            consumers,
+            undeclared,
+            unused,
+        });
+    }
+
+    Ok(wiring)
+}
+
+// Output addresses in a node's inputs and values, keyed by the fqn of the node they read from.
+fn consumed_outputs(
+    stack_name: &str,
+    node: &ArtifactNodeRepr,
+) -> Result<Vec<(String, OutputConsumer)>, Box<dyn Error>> {
+    let address_to_output = |addr: Result<InputAddress, TorbInput>| -> Option<(String, String)> {
+        let addr = addr.ok().filter(|addr| addr.node_property == OUTPUT_PROPERTY)?;
+        let producer = format!("{}.{}.{}", stack_name, addr.node_type, addr.node_name);
+
+        Some((producer, addr.property_specifier))
+    };
+
+    let from_inputs = node
+        .mapped_inputs
+        .iter()
+        .flat_map(|(key, (_, value))| {
+            let addresses = match value {
+                TorbInput::String(value) => referenced_addresses(value),
+                _ => vec![],
+            };
+
+            addresses
+                .into_iter()
+                .filter_map(|addr| address_to_output(Ok(addr)))
+                .map(|output| (key.clone(), output))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<(String, (String, String))>>();
+
+    let mut from_values = vec![];
+
+    let values_fn = |addr: Result<InputAddress, TorbInput>| -> String {
+        match addr {
+            Err(TorbInput::String(template)) if is_template(&template) => {
+                from_values.extend(
+                    referenced_addresses(&template)
+                        .into_iter()
+                        .filter_map(|addr| address_to_output(Ok(addr))),
+                );
+            }
+            addr => from_values.extend(address_to_output(addr)),
+        }
+
+        "".to_string()
+    };
+
+    let (_, _, _) = InputResolver::resolve(node, Some(values_fn), NO_INPUTS_FN, NO_INITS_FN)?;
+
+    let inputs = from_inputs
+        .into_iter()
+        .map(|(key, output)| (format!("inputs.{}", key), output));
+    let values = from_values
+        .into_iter()
+        .map(|output| ("values".to_string(), output));
+
+    Ok(inputs
+        .chain(values)
+        .map(|(via, (producer, output))| {
+            (
+                producer,
+                OutputConsumer {
+                    output,
+                    consumer: node.fqn.clone(),
+                    via,
+                },
+            )
+        })
+        .collect())
+}
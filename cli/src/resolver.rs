@@ -10,23 +10,104 @@
 // See LICENSE file at https://github.com/TorbFoundry/torb/blob/main/LICENSE for details.
 
 pub mod inputs;
-
-use crate::artifacts::{ArtifactNodeRepr, BuildStep, TorbInput, TorbInputSpec};
-use crate::utils::{for_each_artifact_repository, normalize_name, torb_path};
-use crate::watcher::{WatcherConfig};
-
-use indexmap::IndexMap;
+pub mod template;
+
+use crate::artifacts::{ArtifactNodeRepr, BuildStep, StackHooks, TorbInput, TorbInputSpec, TorbOutput, WatcherConfig};
+use crate::composer::deploy_tools::{deploy_tool, deploy_tool_names};
+use crate::config::TORB_CONFIG;
+use crate::utils::{
+    apply_yaml_merge_keys, for_each_artifact_repository, include_source_line, merge_yaml_values, normalize_name,
+    normalize_name_unique, project_root, read_stack_source, stack_source_dir, run_command_in_user_shell, torb_path, TorbUtilityErrors,
+};
+
+use indexmap::{IndexMap, IndexSet};
+use log::info;
 use serde::{Deserialize, Serialize};
 use serde_yaml::{self, Value};
 use std::collections::HashMap;
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use std::{error::Error, path::PathBuf};
 use thiserror::Error;
 
 // const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-pub fn resolve_stack(stack_yaml: &String) -> Result<StackGraph, Box<dyn std::error::Error>> {
-    let stack_def_yaml: serde_yaml::Value = serde_yaml::from_str(stack_yaml).unwrap();
-    let stack_name = stack_def_yaml.get("name").unwrap().as_str().unwrap();
+const REQUIRED_STACK_KEYS: [&str; 3] = ["name", "version", "kind"];
+const ENABLED_INPUTS_PREFIX: &str = "self.inputs.";
+const ENABLED_FLAGS_PREFIX: &str = "flags.";
+const DEFAULT_SOURCE_REPOSITORY: &str = "torb-artifacts";
+const SHELL_INPUT_START: &str = "${shell:";
+const SHELL_INPUT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/*
+    Shell inputs run commands straight from the stack file, so they're off unless the user opts in, either for one
+    command with --allow-shell-inputs or for every command with allow_shell_inputs in config.yaml.
+*/
+static SHELL_INPUTS_ALLOWED: AtomicBool = AtomicBool::new(false);
+
+pub fn allow_shell_inputs() {
+    SHELL_INPUTS_ALLOWED.store(true, Ordering::SeqCst);
+}
+
+fn shell_inputs_allowed() -> bool {
+    SHELL_INPUTS_ALLOWED.load(Ordering::SeqCst) || TORB_CONFIG.allow_shell_inputs()
+}
+
+// Whether resolving the stack would run commands, a cached resolution would have stale output from them.
+pub fn uses_shell_inputs(stack_yaml: &str) -> bool {
+    stack_yaml.contains(SHELL_INPUT_START)
+}
+
+// The contents of the stack's meta stacks, nearest first, so a cached resolution is dropped when one of them changes.
+pub fn meta_stack_sources(stack_yaml: &str) -> Vec<String> {
+    let mut sources = Vec::<String>::new();
+    let mut current = stack_yaml.to_string();
+
+    loop {
+        let meta_path = serde_yaml::from_str::<Value>(&current)
+            .ok()
+            .and_then(|yaml| yaml["meta"].as_str().map(|path| meta_stack_path(&current, path)));
+
+        let contents = match meta_path.map(|path| read_stack_source(&path.to_string_lossy())) {
+            Some(Ok(contents)) if !sources.contains(&contents) => contents,
+            _ => break,
+        };
+
+        sources.push(contents.clone());
+        current = contents;
+    }
+
+    sources
+}
+
+// Meta paths are relative to the stack file setting them, like includes, or the project root for a stack from stdin or a URL.
+fn meta_stack_path(stack_yaml: &str, path: &str) -> PathBuf {
+    stack_source_dir(stack_yaml).unwrap_or_else(project_root).join(path)
+}
+
+// The index of the brace closing a ${shell:...} command, braces inside the command have to be balanced.
+fn shell_command_end(command: &str) -> Option<usize> {
+    let mut depth = 0;
+
+    for (idx, ch) in command.char_indices() {
+        match ch {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(idx),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+pub fn resolve_stack(
+    stack_yaml: &String,
+    environment: Option<&str>,
+    input_overrides: &[InputOverride],
+) -> Result<StackGraph, Box<dyn std::error::Error>> {
+    let stack_def_yaml = parse_stack_yaml(stack_yaml)?;
+    let stack_name = stack_key(&stack_def_yaml, "name")?;
     // let stack_description = stack_def_yaml.get("description").unwrap().as_str().unwrap();
     let resolver_conf = ResolverConfig::new(
         // false,
@@ -34,19 +115,242 @@ pub fn resolve_stack(stack_yaml: &String) -> Result<StackGraph, Box<dyn std::err
         // stack_description.to_string(),
         stack_def_yaml.clone(),
         // VERSION.to_string(),
-    );
+        environment.map(|env| env.to_string()),
+    )
+    .with_stack_source(stack_yaml.clone())
+    .with_input_overrides(input_overrides.to_vec());
 
     let resolver = Resolver::new(&resolver_conf);
 
     resolver.resolve()
 }
 
+/*
+    Checks the stack is a mapping with the required top level keys before anything indexes into it,
+    an empty stack.yaml from `torb stack checkout` is the usual culprit here.
+*/
+fn parse_stack_yaml(stack_yaml: &str) -> Result<serde_yaml::Value, TorbResolverErrors> {
+    if stack_yaml.lines().all(|line| line.trim().is_empty() || line.trim_start().starts_with('#')) {
+        return Err(TorbResolverErrors::EmptyStackManifest);
+    }
+
+    let stack_def_yaml: serde_yaml::Value = serde_yaml::from_str(stack_yaml).map_err(|err| {
+        TorbResolverErrors::InvalidStackYaml {
+            location: err.location().map(|loc| StackLocation::in_source(stack_yaml, loc.line(), loc.column())),
+            reason: err.to_string(),
+        }
+    })?;
+
+    let stack_def_yaml = apply_yaml_merge_keys(stack_def_yaml);
+
+    match stack_def_yaml.as_mapping() {
+        None => return Err(TorbResolverErrors::CannotParseStackManifest),
+        Some(mapping) if mapping.is_empty() => return Err(TorbResolverErrors::EmptyStackManifest),
+        Some(_) => {}
+    }
+
+    for key in REQUIRED_STACK_KEYS {
+        stack_key(&stack_def_yaml, key)?;
+    }
+
+    Ok(stack_def_yaml)
+}
+
+// One of the stack's required top level keys, parse_stack_yaml has checked them but this keeps the lookups from panicking.
+fn stack_key<'a>(stack_def_yaml: &'a serde_yaml::Value, key: &str) -> Result<&'a str, TorbResolverErrors> {
+    stack_def_yaml[key]
+        .as_str()
+        .ok_or_else(|| TorbResolverErrors::MissingStackKey { key: key.to_string() })
+}
+
 #[derive(Error, Debug)]
 pub enum TorbResolverErrors {
     #[error(
         "Unable to parse stack manifest, please check that it is a valid Torb stack manifest."
     )]
     CannotParseStackManifest,
+    #[error("The stack manifest is empty, a stack needs at least a name, version and kind.")]
+    EmptyStackManifest,
+    #[error("The stack manifest is missing the top level key {key}, it must be set to a string.")]
+    MissingStackKey { key: String },
+    #[error("{fqn} uses the deploy tool {tool}, which Torb doesn't support. Supported deploy tools are {supported}.")]
+    UnsupportedDeployTool { fqn: String, tool: String, supported: String },
+    #[error("{fqn} has no deploy step, units need a helm or kubectl entry under deploy in their torb.yaml.")]
+    MissingDeployStep { fqn: String },
+    #[error("The environment {name} isn't defined in the stack, available environments are: {available}")]
+    UnknownEnvironment { name: String, available: String },
+    #[error("{fqn} sets more than one of dockerfile, dockerfile_inline and script_path in its build step, only one can be set.")]
+    ConflictingBuildSources { fqn: String },
+    #[error("The stack manifest isn't valid yaml{}: {reason}", .location.as_ref().map(|loc| format!(" ({})", loc)).unwrap_or_default())]
+    InvalidStackYaml { location: Option<StackLocation>, reason: String },
+    #[error("{path}{}: {reason}", .location.as_ref().map(|loc| format!(" ({})", loc)).unwrap_or_default())]
+    InvalidNode { path: String, location: Option<StackLocation>, reason: String },
+    #[error("{fqn} depends on {dependency}, which is disabled. Enable {dependency} or disable {fqn} as well.")]
+    DependsOnDisabledNode { fqn: String, dependency: String },
+    #[error("Unable to parse the input override {arg}, overrides are written as <node>.<input>=<value>.")]
+    InvalidInputOverride { arg: String },
+    #[error("The input override {arg} targets the node {node}, which isn't in the stack.")]
+    UnknownOverrideNode { arg: String, node: String },
+    #[error("The input override {arg} sets {input} on {fqn}, which isn't one of its inputs. Valid inputs: {valid}")]
+    UnknownOverrideInput { arg: String, fqn: String, input: String, valid: String },
+    #[error("The input override {arg} sets {input} on {fqn} to {value}, which isn't a valid {typing}.")]
+    InvalidOverrideValue { arg: String, fqn: String, input: String, value: String, typing: String },
+    #[error("The stack sets tf_parallelism to {value}, it must be a whole number greater than 0.")]
+    InvalidTfParallelism { value: String },
+    #[error("The stack sets init_timeout to {value}, it must be a whole number of seconds greater than 0.")]
+    InvalidInitTimeout { value: String },
+    #[error("{path} runs `{command}` to get its value, shell inputs are off by default since they run commands from the stack file. Pass --allow-shell-inputs or set allow_shell_inputs: true in config.yaml to allow them.")]
+    ShellInputsDisabled { path: String, command: String },
+    #[error("The stack sets meta to {value}, it must be the path to a stack file.")]
+    InvalidMetaPath { value: String },
+    #[error("Unable to read the meta stack {path}, reason: {reason}")]
+    UnreadableMetaStack { path: String, reason: String },
+    #[error("Unable to resolve the meta stack {path}: {reason}")]
+    InvalidMetaStack { path: String, reason: String },
+    #[error("The meta stack {path} doesn't set a release. Meta stacks are shared by every stack using them, so their release has to be the same each time they're deployed.")]
+    MetaStackWithoutRelease { path: String },
+    #[error("The meta stacks lead back to one already being resolved: {chain}")]
+    MetaStackCycle { chain: String },
+}
+
+/*
+    An input set from the command line, like helm's --set. The node is matched by name against the stack's services
+    and projects, and the value is kept as a string until the node's input spec is loaded since that decides its type.
+*/
+#[derive(Debug, Clone)]
+pub struct InputOverride {
+    pub node: String,
+    pub input: String,
+    pub value: String,
+}
+
+impl InputOverride {
+    pub fn parse(arg: &str) -> Result<InputOverride, TorbResolverErrors> {
+        let invalid = || TorbResolverErrors::InvalidInputOverride { arg: arg.to_string() };
+
+        let (key, value) = arg.split_once('=').ok_or_else(invalid)?;
+        let (node, input) = key.split_once('.').ok_or_else(invalid)?;
+
+        if node.is_empty() || input.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(InputOverride {
+            node: node.to_string(),
+            input: input.to_string(),
+            value: value.to_string(),
+        })
+    }
+
+    // Arrays are written as YAML flow sequences, e.g. [a, b], anything typed as a string is taken as is.
+    fn to_input(&self, fqn: &str, spec: &TorbInputSpec) -> Result<TorbInput, TorbResolverErrors> {
+        let value = self.value.as_str();
+
+        let input = match spec.typing() {
+            "bool" => value.parse::<bool>().ok().map(TorbInput::from),
+            "numeric" => value
+                .parse::<u64>()
+                .map(TorbInput::from)
+                .or_else(|_| value.parse::<i64>().map(TorbInput::from))
+                .or_else(|_| value.parse::<f64>().map(TorbInput::from))
+                .ok(),
+            "array" => serde_yaml::from_str::<TorbInput>(value)
+                .ok()
+                .filter(|input| matches!(input, TorbInput::Array(_))),
+            _ => Some(TorbInput::from(value)),
+        };
+
+        input.ok_or_else(|| TorbResolverErrors::InvalidOverrideValue {
+            arg: self.to_string(),
+            fqn: fqn.to_string(),
+            input: self.input.clone(),
+            value: self.value.clone(),
+            typing: spec.typing().to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for InputOverride {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}={}", self.node, self.input, self.value)
+    }
+}
+
+/*
+    1-based position in the stack manifest, for pointing errors at the line that caused them.
+    file is set when the line came from an included file rather than the stack itself.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackLocation {
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl StackLocation {
+    // Maps a position in the stack with its includes expanded back to the file the user wrote it in.
+    pub fn in_source(expanded: &str, line: usize, column: usize) -> Self {
+        let (file, line) = include_source_line(expanded, line);
+
+        StackLocation { file, line, column }
+    }
+}
+
+impl std::fmt::Display for StackLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{} line {}, column {}", file, self.line, self.column),
+            None => write!(f, "line {}, column {}", self.line, self.column),
+        }
+    }
+}
+
+/*
+    serde_yaml doesn't keep the position of values, so key paths are found again in the stack source by their indentation.
+    Only block style mappings are followed, a path into a flow style mapping isn't located.
+*/
+pub fn locate_key_path(source: &str, path: &[&str]) -> Option<StackLocation> {
+    let mut lines = source.lines().enumerate();
+    let mut parent_indent: Option<usize> = None;
+    let mut found = None;
+
+    for segment in path {
+        let mut child_indent: Option<usize> = None;
+        found = None;
+
+        for (idx, line) in lines.by_ref() {
+            let trimmed = line.trim_start();
+
+            if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with("---") {
+                continue;
+            }
+
+            let indent = line.len() - trimmed.len();
+
+            if parent_indent.map_or(false, |parent| indent <= parent) {
+                return None;
+            }
+
+            if *child_indent.get_or_insert(indent) != indent {
+                continue;
+            }
+
+            let key_matches = [segment.to_string(), format!("\"{}\"", segment), format!("'{}'", segment)]
+                .iter()
+                .any(|key| trimmed.strip_prefix(key.as_str()).map_or(false, |rest| rest.trim_start().starts_with(':')));
+
+            if key_matches {
+                found = Some(StackLocation::in_source(source, idx + 1, indent + 1));
+                parent_indent = Some(indent);
+
+                break;
+            }
+        }
+
+        found.as_ref()?;
+    }
+
+    found
 }
 
 #[derive(Clone)]
@@ -56,6 +360,11 @@ pub struct ResolverConfig {
     // stack_description: String,
     stack_contents: serde_yaml::Value,
     // torb_version: String,
+    environment: Option<String>,
+    stack_source: String,
+    input_overrides: Vec<InputOverride>,
+    // The meta stack files resolved on the way to this stack, for catching a meta that leads back to itself.
+    meta_chain: Vec<PathBuf>,
 }
 
 impl ResolverConfig {
@@ -65,6 +374,7 @@ impl ResolverConfig {
         // stack_description: String,
         stack_contents: serde_yaml::Value,
         // torb_version: String,
+        environment: Option<String>,
     ) -> ResolverConfig {
         ResolverConfig {
             // autoaccept,
@@ -72,8 +382,30 @@ impl ResolverConfig {
             // stack_description,
             stack_contents,
             // torb_version,
+            environment,
+            stack_source: String::new(),
+            input_overrides: Vec::new(),
+            meta_chain: Vec::new(),
         }
     }
+
+    pub fn with_stack_source(mut self, stack_source: String) -> ResolverConfig {
+        self.stack_source = stack_source;
+
+        self
+    }
+
+    pub fn with_input_overrides(mut self, input_overrides: Vec<InputOverride>) -> ResolverConfig {
+        self.input_overrides = input_overrides;
+
+        self
+    }
+
+    pub fn with_meta_chain(mut self, meta_chain: Vec<PathBuf>) -> ResolverConfig {
+        self.meta_chain = meta_chain;
+
+        self
+    }
 }
 
 // #[derive(Serialize, Deserialize, Clone)]
@@ -104,12 +436,19 @@ pub struct StackGraph {
     pub commits: IndexMap<String, String>,
     pub tf_version: String,
     pub helm_version: String,
-    pub meta: Box<Option<ArtifactNodeRepr>>,
+    pub meta: Box<Option<StackGraph>>,
     pub incoming_edges: HashMap<String, Vec<String>>,
     pub namespace: Option<String>,
     pub release: Option<String>,
     pub repositories: Option<Vec<String>>,
-    pub watcher: WatcherConfig
+    pub watcher: WatcherConfig,
+    pub hooks: StackHooks,
+    pub tf_parallelism: Option<u32>,
+    pub init_timeout: Option<u64>,
+    // Set for meta stacks, whose projects are built from the meta stack's directory rather than the project root.
+    pub stack_dir: Option<PathBuf>,
+    // The meta stack file's canonical path, set alongside stack_dir.
+    pub stack_file: Option<PathBuf>,
 }
 
 impl StackGraph {
@@ -120,7 +459,7 @@ impl StackGraph {
         commits: IndexMap<String, String>,
         tf_version: String,
         helm_version: String,
-        meta: Box<Option<ArtifactNodeRepr>>,
+        meta: Box<Option<StackGraph>>,
         namespace: Option<String>,
         release: Option<String>,
         repositories: Option<Vec<String>>,
@@ -141,7 +480,12 @@ impl StackGraph {
             namespace,
             release,
             repositories,
-            watcher: watcher
+            watcher: watcher,
+            hooks: StackHooks::default(),
+            tf_parallelism: None,
+            init_timeout: None,
+            stack_dir: None,
+            stack_file: None,
         }
     }
 
@@ -188,7 +532,7 @@ impl StackGraph {
             .map_or((), |projects| {
                 projects.iter().for_each(|project| {
                     let s_fqn = format!("{}.{}.{}", stack_name, "service".to_string(), project);
-                    match self.incoming_edges.get_mut(project) {
+                    match self.incoming_edges.get_mut(s_fqn.as_str()) {
                         Some(edges) => {
                             edges.push(node.fqn.clone());
                         }
@@ -207,7 +551,7 @@ impl StackGraph {
             .map_or((), |projects| {
                 projects.iter().for_each(|project| {
                     let s_fqn = format!("{}.{}.{}", stack_name, "stack".to_string(), project);
-                    match self.incoming_edges.get_mut(project) {
+                    match self.incoming_edges.get_mut(s_fqn.as_str()) {
                         Some(edges) => {
                             edges.push(node.fqn.clone());
                         }
@@ -222,6 +566,74 @@ impl StackGraph {
     }
 }
 
+impl StackGraph {
+    fn graph_node(&self, fqn: &str) -> Option<&ArtifactNodeRepr> {
+        self.projects
+            .get(fqn)
+            .or(self.services.get(fqn))
+            .or(self.stacks.get(fqn))
+    }
+
+    /*
+        Renders the graph as Graphviz DOT, edges point from a node to what it depends on.
+        Dependencies declared with deps are solid, ones discovered from input addresses are dashed.
+    */
+    pub fn to_dot(&self) -> String {
+        let mut fqns: Vec<&String> = self.incoming_edges.keys().collect();
+        fqns.sort();
+
+        let mut explicit_edges = IndexSet::<(String, String)>::new();
+        for (dep_fqn, dependents) in self.incoming_edges.iter() {
+            for dependent in dependents.iter() {
+                explicit_edges.insert((dependent.clone(), dep_fqn.clone()));
+            }
+        }
+
+        let mut implicit_edges = IndexSet::<(String, String)>::new();
+        for fqn in fqns.iter() {
+            if let Some(node) = self.graph_node(fqn) {
+                for dep_fqn in node.implicit_dependency_fqns.iter() {
+                    let edge = ((*fqn).clone(), dep_fqn.clone());
+
+                    if !explicit_edges.contains(&edge) {
+                        implicit_edges.insert(edge);
+                    }
+                }
+            }
+        }
+
+        explicit_edges.sort();
+        implicit_edges.sort();
+
+        let mut out = format!("digraph \"{}\" {{\n", self.name);
+        out.push_str("    rankdir=LR;\n");
+
+        for fqn in fqns.iter() {
+            let mut parts = fqn.splitn(3, '.');
+            let kind = parts.nth(1).unwrap_or("unknown");
+            let name = parts.next().unwrap_or(fqn.as_str());
+            let shape = if kind == "project" { "box" } else { "ellipse" };
+
+            out.push_str(&format!(
+                "    \"{}\" [label=\"{}\\n({})\", shape={}];\n",
+                fqn, name, kind, shape
+            ));
+        }
+
+        for (from, to) in explicit_edges.iter() {
+            out.push_str(&format!("    \"{}\" -> \"{}\";\n", from, to));
+        }
+
+        for (from, to) in implicit_edges.iter() {
+            out.push_str(&format!("    \"{}\" -> \"{}\" [style=dashed];\n", from, to));
+        }
+
+        out.push_str("}\n");
+
+        out
+    }
+}
+
 pub struct Resolver {
     config: ResolverConfig,
     stack: Value,
@@ -236,7 +648,7 @@ impl Resolver {
     }
 
     pub fn resolve(&self) -> Result<StackGraph, Box<dyn Error>> {
-        println!("Resolving stack graph...");
+        info!("Resolving stack graph...");
         let yaml = self.stack.clone();
         let graph = self.build_graph(yaml)?;
 
@@ -247,12 +659,10 @@ impl Resolver {
         &self,
         yaml: serde_yaml::Value,
     ) -> Result<StackGraph, Box<dyn std::error::Error>> {
-        let meta = Box::new(None);
-        let mut name = yaml["name"].as_str().unwrap().to_string();
-        name = normalize_name(&name);
-
-        let version = yaml["version"].as_str().unwrap().to_string();
-        let kind = yaml["kind"].as_str().unwrap().to_string();
+        let meta = Box::new(self.resolve_meta(&yaml["meta"])?);
+        let name = normalize_name(stack_key(&yaml, "name")?);
+        let version = stack_key(&yaml, "version")?.to_string();
+        let kind = stack_key(&yaml, "kind")?.to_string();
         let tf_version = self.get_tf_version();
         let helm_version = self.get_helm_version();
         let mut commits = IndexMap::new();
@@ -275,6 +685,15 @@ impl Resolver {
             _ => serde_yaml::from_value(yaml["watcher"].clone())?
         };
 
+        let hooks: StackHooks = match yaml["hooks"] {
+            Value::Null => StackHooks::default(),
+            _ => serde_yaml::from_value(yaml["hooks"].clone())?
+        };
+
+        let tf_parallelism = Resolver::tf_parallelism(&yaml["tf_parallelism"])?;
+        let init_timeout = Resolver::init_timeout(&yaml["init_timeout"])
+            .ok_or_else(|| TorbResolverErrors::InvalidInitTimeout { value: Resolver::display_value(&yaml["init_timeout"]) })?;
+
         let mut graph = StackGraph::new(
             name,
             kind,
@@ -288,12 +707,112 @@ impl Resolver {
             repositories,
             watcher
         );
+        graph.hooks = hooks;
+        graph.tf_parallelism = tf_parallelism;
+        graph.init_timeout = init_timeout;
 
-        self.walk_yaml(&mut graph, &yaml);
+        Resolver::check_name_collisions(&yaml)?;
+        self.check_environment(&yaml)?;
+        self.check_input_overrides(&yaml)?;
+
+        self.walk_yaml(&mut graph, &yaml)?;
 
         Ok(graph)
     }
 
+    /*
+        A stack's meta is another stack file, relative to the stack setting it, holding infrastructure shared between
+        stacks. It's resolved on its own, without the environment or input overrides, and can have a meta of its own.
+        Its projects are built from its own directory.
+    */
+    fn resolve_meta(&self, value: &Value) -> Result<Option<StackGraph>, TorbResolverErrors> {
+        let path = match value {
+            Value::Null => return Ok(None),
+            Value::String(path) => meta_stack_path(&self.config.stack_source, path),
+            _ => return Err(TorbResolverErrors::InvalidMetaPath { value: Resolver::display_value(value) }),
+        };
+        let display_path = path.display().to_string();
+
+        let contents = read_stack_source(&display_path).map_err(|err| TorbResolverErrors::UnreadableMetaStack {
+            path: display_path.clone(),
+            reason: match err {
+                TorbUtilityErrors::UnableToReadStackSource { reason, .. } => reason,
+                err => err.to_string(),
+            },
+        })?;
+
+        let canonical_path = std::fs::canonicalize(&path).unwrap_or(path);
+        let stack_dir = canonical_path.parent().map(|dir| dir.to_path_buf());
+
+        if self.config.meta_chain.contains(&canonical_path) {
+            let chain = self
+                .config
+                .meta_chain
+                .iter()
+                .chain([&canonical_path])
+                .map(|path| path.display().to_string())
+                .collect::<Vec<String>>()
+                .join(" -> ");
+
+            return Err(TorbResolverErrors::MetaStackCycle { chain });
+        }
+
+        let yaml = parse_stack_yaml(&contents).map_err(|err| TorbResolverErrors::InvalidMetaStack {
+            path: display_path.clone(),
+            reason: err.to_string(),
+        })?;
+
+        if yaml["release"].as_str().is_none() {
+            return Err(TorbResolverErrors::MetaStackWithoutRelease { path: display_path });
+        }
+
+        let mut meta_chain = self.config.meta_chain.clone();
+        meta_chain.push(canonical_path.clone());
+
+        let config = ResolverConfig::new(normalize_name(stack_key(&yaml, "name")?), yaml, None)
+            .with_stack_source(contents)
+            .with_meta_chain(meta_chain);
+
+        info!("Resolving meta stack: {}", display_path);
+
+        let mut graph = Resolver::new(&config).resolve().map_err(|err| TorbResolverErrors::InvalidMetaStack {
+            path: display_path,
+            reason: err.to_string(),
+        })?;
+        graph.stack_dir = stack_dir;
+        graph.stack_file = Some(canonical_path);
+
+        Ok(Some(graph))
+    }
+
+    fn tf_parallelism(value: &Value) -> Result<Option<u32>, TorbResolverErrors> {
+        match value {
+            Value::Null => Ok(None),
+            _ => value
+                .as_u64()
+                .filter(|parallelism| *parallelism > 0)
+                .and_then(|parallelism| u32::try_from(parallelism).ok())
+                .map(Some)
+                .ok_or_else(|| TorbResolverErrors::InvalidTfParallelism {
+                    value: Resolver::display_value(value),
+                }),
+        }
+    }
+
+    // None when the value is set but isn't a whole number of seconds greater than 0.
+    fn init_timeout(value: &Value) -> Option<Option<u64>> {
+        match value {
+            Value::Null => Some(None),
+            _ => value.as_u64().filter(|secs| *secs > 0).map(Some),
+        }
+    }
+
+    fn display_value(value: &Value) -> String {
+        serde_yaml::to_string(value)
+            .map(|value| value.trim_start_matches("---").trim().to_string())
+            .unwrap_or_default()
+    }
+
     fn get_helm_version(&self) -> String {
         let cmd_out = Command::new("helm")
             .arg("version")
@@ -341,6 +860,7 @@ impl Resolver {
         service_name: &str,
         artifact_path: PathBuf,
         inputs: IndexMap<String, TorbInput>,
+        config: IndexMap<String, TorbInput>,
         values: serde_yaml::Value,
         source: &str,
         namespace: Option<String>,
@@ -381,7 +901,7 @@ impl Resolver {
                 deploy_steps,
                 IndexMap::<String, (String, TorbInput)>::new(),
                 IndexMap::<String, TorbInputSpec>::new(),
-                Vec::<String>::new(),
+                Vec::<TorbOutput>::new(),
                 node_fp,
                 None,
                 None,
@@ -410,9 +930,11 @@ impl Resolver {
 
         node.source = Some(source.to_string());
         node.namespace = namespace;
+        node.config = config;
 
         node.values =
             serde_yaml::to_string(&values).expect("Unable to convert values yaml to string.");
+        let inputs = self.apply_input_overrides(&node, node_name, inputs)?;
         node.validate_map_and_set_inputs(inputs);
         node.discover_and_set_implicit_dependencies(&stack_name.to_string())?;
 
@@ -426,16 +948,18 @@ impl Resolver {
             build_step.registry
         };
 
-        let dockerfile = if new_build_step.dockerfile != "" {
-            new_build_step.dockerfile
-        } else {
-            build_step.dockerfile
-        };
+        /*
+            What a project is built from is overridden as a whole, otherwise a stack setting a dockerfile
+            on a unit that builds from a script would end up with both.
+        */
+        let overrides_source = new_build_step.dockerfile != ""
+            || new_build_step.dockerfile_inline != ""
+            || new_build_step.script_path != "";
 
-        let script_path = if new_build_step.script_path != "" {
-            new_build_step.script_path
+        let (dockerfile, dockerfile_inline, script_path) = if overrides_source {
+            (new_build_step.dockerfile, new_build_step.dockerfile_inline, new_build_step.script_path)
         } else {
-            build_step.script_path
+            (build_step.dockerfile, build_step.dockerfile_inline, build_step.script_path)
         };
 
         let tag = if new_build_step.tag != "" {
@@ -444,14 +968,39 @@ impl Resolver {
             build_step.tag
         };
 
+        let platforms = if !new_build_step.platforms.is_empty() {
+            new_build_step.platforms
+        } else {
+            build_step.platforms
+        };
+
         BuildStep {
             registry,
             tag,
             dockerfile,
+            dockerfile_inline,
             script_path,
+            platforms,
         }
     }
 
+    fn validate_build_step(node: &ArtifactNodeRepr) -> Result<(), TorbResolverErrors> {
+        if let Some(step) = &node.build_step {
+            let sources = [&step.dockerfile, &step.dockerfile_inline, &step.script_path]
+                .iter()
+                .filter(|source| !source.is_empty())
+                .count();
+
+            if sources > 1 {
+                return Err(TorbResolverErrors::ConflictingBuildSources {
+                    fqn: node.fqn.clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
     fn resolve_project(
         &self,
         stack_name: &str,
@@ -460,6 +1009,7 @@ impl Resolver {
         project_name: &str,
         artifact_path: PathBuf,
         inputs: IndexMap<String, TorbInput>,
+        config: IndexMap<String, TorbInput>,
         build_config: Option<&Value>,
         values: serde_yaml::Value,
         source: &str,
@@ -485,12 +1035,7 @@ impl Resolver {
                 self.reconcile_build_step(build_step, temp)
             }
             None => {
-                let temp = BuildStep {
-                    registry: "".to_string(),
-                    dockerfile: "".to_string(),
-                    script_path: "".to_string(),
-                    tag: "".to_string(),
-                };
+                let temp = BuildStep::default();
 
                 self.reconcile_build_step(build_step, temp)
             }
@@ -498,8 +1043,12 @@ impl Resolver {
 
         node.build_step = Some(new_build_step);
         node.fqn = format!("{}.{}.{}", stack_name, stack_kind_name, node_name);
+
+        Resolver::validate_build_step(&node)?;
         node.file_path = node_fp;
+        let inputs = self.apply_input_overrides(&node, node_name, inputs)?;
         node.validate_map_and_set_inputs(inputs);
+        node.config = config;
         node.values =
             serde_yaml::to_string(&values).expect("Unable to convert values yaml to string.");
         node.discover_and_set_implicit_dependencies(&stack_name.to_string())?;
@@ -507,59 +1056,424 @@ impl Resolver {
         Ok(node)
     }
 
+    fn validate_deploy_steps(node: &ArtifactNodeRepr) -> Result<(), TorbResolverErrors> {
+        if node.deploy_steps.is_empty() {
+            return Err(TorbResolverErrors::MissingDeployStep {
+                fqn: node.fqn.clone(),
+            });
+        }
+
+        for tool in node.deploy_steps.keys() {
+            if deploy_tool(tool).is_none() {
+                return Err(TorbResolverErrors::UnsupportedDeployTool {
+                    fqn: node.fqn.clone(),
+                    tool: tool.clone(),
+                    supported: deploy_tool_names().join(", "),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+        Units are read straight from the repository's checkout, so a missing one is reported here with how to get it
+        rather than as an IO error reading the unit's torb.yaml.
+    */
+    fn check_source_repository(&self, path: &[&str], repo: &str, repo_path: &PathBuf) -> Result<(), TorbResolverErrors> {
+        if repo_path.is_dir() {
+            return Ok(());
+        }
+
+        let reason = if repo == DEFAULT_SOURCE_REPOSITORY {
+            format!("the {} repository hasn't been cloned into {}, run `torb init` to clone it.", repo, repo_path.display())
+        } else if TORB_CONFIG.has_repository(repo) {
+            format!("the {} repository hasn't been cloned into {}, run `torb artifacts clone` to clone it.", repo, repo_path.display())
+        } else {
+            format!(
+                "{} isn't one of the repositories in ~/.torb/config.yaml, add it under repositories and run `torb artifacts clone`.",
+                repo
+            )
+        };
+
+        Err(self.node_error(path, reason))
+    }
+
+    /*
+        Errors about a node name the key path they were found at, located in the stack source when it can be.
+        Missing keys are located at the closest parent that exists.
+    */
+    fn node_error(&self, path: &[&str], reason: String) -> TorbResolverErrors {
+        let location = (1..=path.len())
+            .rev()
+            .find_map(|len| locate_key_path(&self.config.stack_source, &path[..len]));
+
+        TorbResolverErrors::InvalidNode {
+            path: path.join("."),
+            location,
+            reason,
+        }
+    }
+
+    /*
+        Values files are resolved relative to the directory of the node's torb.yaml and deep merged over
+        the inline values in the order they're listed, so later files win.
+    */
+    fn merge_values_files(
+        &self,
+        path: &[&str],
+        node_dir: &std::path::Path,
+        values: serde_yaml::Value,
+        files: Option<&serde_yaml::Value>,
+    ) -> Result<serde_yaml::Value, TorbResolverErrors> {
+        let files: Vec<String> = match files {
+            None | Some(Value::Null) => return Ok(values),
+            Some(files) => serde_yaml::from_value(files.clone())
+                .map_err(|_| self.node_error(path, "must be a list of file paths.".to_string()))?,
+        };
+
+        let mut merged = values;
+
+        for file in files.iter() {
+            let file_path = node_dir.join(file);
+            let file_path = std::fs::canonicalize(&file_path).unwrap_or(file_path);
+
+            let contents = std::fs::read_to_string(&file_path).map_err(|err| {
+                self.node_error(path, format!("unable to read {}, reason: {}", file_path.display(), err))
+            })?;
+
+            let file_values: serde_yaml::Value = serde_yaml::from_str(&contents).map_err(|err| {
+                self.node_error(path, format!("{} isn't valid yaml, reason: {}", file_path.display(), err))
+            })?;
+
+            merged = merge_yaml_values(merged, file_values);
+        }
+
+        Ok(merged)
+    }
+
+    // Inputs are deserialized one at a time so an error can name the input it came from.
     fn deserialize_params(
+        &self,
+        path: &[&str],
         params: Option<&serde_yaml::Value>,
-    ) -> Result<IndexMap<String, TorbInput>, Box<dyn Error>> {
-        match params {
-            Some(params) => {
-                let deserialized_params: IndexMap<String, TorbInput> =
-                    serde_yaml::from_value(params.clone())?;
+    ) -> Result<IndexMap<String, TorbInput>, TorbResolverErrors> {
+        let params = match params {
+            None | Some(Value::Null) => return Ok(IndexMap::new()),
+            Some(Value::Mapping(params)) => params,
+            Some(_) => return Err(self.node_error(path, "must be a mapping of input names to values.".to_string())),
+        };
+
+        let mut deserialized_params = IndexMap::new();
+
+        for (key, value) in params.iter() {
+            let key = key
+                .as_str()
+                .ok_or_else(|| self.node_error(path, "input names must be strings.".to_string()))?;
+
+            let input_path = [path, &[key]].concat();
+            let input: TorbInput = serde_yaml::from_value(value.clone())
+                .map_err(|err| self.node_error(&input_path, err.to_string()))?;
+            let input = self.resolve_shell_inputs(&input_path, input)?;
 
-                Ok(deserialized_params)
+            deserialized_params.insert(key.to_string(), input);
+        }
+
+        Ok(deserialized_params)
+    }
+
+    /*
+        Strings can take part of their value from a command, like ${shell:git rev-parse --short HEAD}, each command
+        is run in the user's shell from the current directory and replaced with its trimmed stdout.
+    */
+    fn resolve_shell_inputs(&self, path: &[&str], input: TorbInput) -> Result<TorbInput, TorbResolverErrors> {
+        match input {
+            TorbInput::String(value) => self.substitute_shell_commands(path, &value).map(TorbInput::String),
+            TorbInput::Array(items) => items
+                .into_iter()
+                .map(|item| self.resolve_shell_inputs(path, item))
+                .collect::<Result<Vec<TorbInput>, TorbResolverErrors>>()
+                .map(TorbInput::Array),
+            input => Ok(input),
+        }
+    }
+
+    fn substitute_shell_commands(&self, path: &[&str], value: &str) -> Result<String, TorbResolverErrors> {
+        let mut resolved = String::new();
+        let mut remaining = value;
+
+        while let Some(start) = remaining.find(SHELL_INPUT_START) {
+            resolved.push_str(&remaining[..start]);
+
+            let rest = &remaining[start + SHELL_INPUT_START.len()..];
+            let end = shell_command_end(rest).ok_or_else(|| {
+                self.node_error(path, format!("has a {}...}} command without a closing brace.", SHELL_INPUT_START))
+            })?;
+
+            resolved.push_str(&self.run_shell_input(path, rest[..end].trim())?);
+            remaining = &rest[end + 1..];
+        }
+
+        resolved.push_str(remaining);
+
+        Ok(resolved)
+    }
+
+    fn run_shell_input(&self, path: &[&str], command: &str) -> Result<String, TorbResolverErrors> {
+        if command.is_empty() {
+            return Err(self.node_error(path, format!("has an empty {}}} command.", SHELL_INPUT_START)));
+        }
+
+        if !shell_inputs_allowed() {
+            return Err(TorbResolverErrors::ShellInputsDisabled {
+                path: path.join("."),
+                command: command.to_string(),
+            });
+        }
+
+        info!("Running {} for {}", command, path.join("."));
+
+        let output = run_command_in_user_shell(command.to_string(), None, &[], Some(SHELL_INPUT_TIMEOUT)).map_err(|err| {
+            let reason = match err.downcast_ref::<TorbUtilityErrors>() {
+                Some(TorbUtilityErrors::UnableToRunCommandInShell { reason, .. }) => reason.trim().to_string(),
+                _ => err.to_string(),
+            };
+
+            self.node_error(path, format!("`{}` failed: {}", command, reason))
+        })?;
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    fn check_environment(&self, yaml: &serde_yaml::Value) -> Result<(), TorbResolverErrors> {
+        let name = match &self.config.environment {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+
+        let environments = yaml.get("environments").and_then(|envs| envs.as_mapping());
+
+        if environments.map_or(false, |envs| envs.get(&Value::from(name.as_str())).is_some()) {
+            return Ok(());
+        }
+
+        let available = environments
+            .map(|envs| {
+                envs.iter()
+                    .filter_map(|(key, _)| key.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            })
+            .filter(|available| !available.is_empty())
+            .unwrap_or("none".to_string());
+
+        Err(TorbResolverErrors::UnknownEnvironment {
+            name: name.clone(),
+            available,
+        })
+    }
+
+    fn check_input_overrides(&self, yaml: &serde_yaml::Value) -> Result<(), TorbResolverErrors> {
+        for input_override in self.config.input_overrides.iter() {
+            let exists = ["services", "projects"]
+                .iter()
+                .any(|kind| yaml.get(*kind).and_then(|nodes| nodes.get(input_override.node.as_str())).is_some());
+
+            if !exists {
+                return Err(TorbResolverErrors::UnknownOverrideNode {
+                    arg: input_override.to_string(),
+                    node: input_override.node.clone(),
+                });
             }
-            None => Ok(IndexMap::new()),
         }
+
+        Ok(())
     }
 
+    // Overrides are applied after the environment so the command line always has the last word.
+    fn apply_input_overrides(
+        &self,
+        node: &ArtifactNodeRepr,
+        node_name: &str,
+        mut inputs: IndexMap<String, TorbInput>,
+    ) -> Result<IndexMap<String, TorbInput>, TorbResolverErrors> {
+        let node_overrides = self
+            .config
+            .input_overrides
+            .iter()
+            .filter(|input_override| input_override.node == node_name);
+
+        for input_override in node_overrides {
+            let spec = node.input_spec.get(&input_override.input).ok_or_else(|| {
+                TorbResolverErrors::UnknownOverrideInput {
+                    arg: input_override.to_string(),
+                    fqn: node.fqn.clone(),
+                    input: input_override.input.clone(),
+                    valid: node.input_spec.keys().cloned().collect::<Vec<String>>().join(", "),
+                }
+            })?;
+
+            inputs.insert(input_override.input.clone(), input_override.to_input(&node.fqn, spec)?);
+        }
+
+        Ok(inputs)
+    }
+
+    /*
+        An environment overrides nodes by kind and name, the same way they're laid out at the top of the stack:
+
+        environments:
+          staging:
+            services:
+              postgres_1:
+                inputs:
+                  ...
+                values:
+                  ...
+
+        Only inputs and values can be overridden, they're deep merged over the node's own.
+    */
+    fn apply_environment(&self, stack_kind_name: &str, node_name: &str, mut yaml: Value) -> Value {
+        let overrides = self.config.environment.as_ref().and_then(|env| {
+            self.stack
+                .get("environments")?
+                .get(env.as_str())?
+                .get(format!("{}s", stack_kind_name).as_str())?
+                .get(node_name)
+                .cloned()
+        });
+
+        let overrides = match overrides {
+            Some(overrides) => overrides,
+            None => return yaml,
+        };
+
+        for key in ["inputs", "values"] {
+            if let Some(override_val) = overrides.get(key) {
+                let base = yaml.get(key).cloned().unwrap_or(Value::Null);
+
+                yaml[key] = merge_yaml_values(base, override_val.clone());
+            }
+        }
+
+        yaml
+    }
+
+    /*
+        enabled is either a bool, one of the node's own inputs as self.inputs.<key> or a stack flag as flags.<name>.
+        Flags are set in a top level flags mapping and an environment can override them the same way.
+    */
+    fn node_enabled(&self, path: &[&str], yaml: &Value) -> Result<bool, TorbResolverErrors> {
+        let reference = match yaml.get("enabled") {
+            None | Some(Value::Null) => return Ok(true),
+            Some(Value::Bool(enabled)) => return Ok(*enabled),
+            Some(Value::String(reference)) => reference,
+            Some(_) => {
+                return Err(self.node_error(path, "must be a bool, self.inputs.<key> or flags.<name>.".to_string()))
+            }
+        };
+
+        let value = if let Some(key) = reference.strip_prefix(ENABLED_INPUTS_PREFIX) {
+            yaml.get("inputs").and_then(|inputs| inputs.get(key)).cloned()
+        } else if let Some(name) = reference.strip_prefix(ENABLED_FLAGS_PREFIX) {
+            self.flag(name)
+        } else {
+            return Err(self.node_error(path, "must be a bool, self.inputs.<key> or flags.<name>.".to_string()));
+        };
+
+        match value {
+            Some(Value::Bool(enabled)) => Ok(enabled),
+            Some(_) => Err(self.node_error(path, format!("{} must be a bool.", reference))),
+            None => Err(self.node_error(path, format!("{} isn't set.", reference))),
+        }
+    }
+
+    fn flag(&self, name: &str) -> Option<Value> {
+        let environment_flag = self.config.environment.as_ref().and_then(|env| {
+            self.stack
+                .get("environments")?
+                .get(env.as_str())?
+                .get("flags")?
+                .get(name)
+                .cloned()
+        });
+
+        environment_flag.or_else(|| self.stack.get("flags")?.get(name).cloned())
+    }
+
+    // Disabled nodes resolve to None and are left out of the graph entirely.
     fn resolve_node(
         &self,
         stack_name: &str,
         stack_kind_name: &str,
         node_name: &str,
         yaml: serde_yaml::Value,
-    ) -> Result<ArtifactNodeRepr, Box<dyn Error>> {
-        println!("Resolving node: {}", node_name);
-        let err = TorbResolverErrors::CannotParseStackManifest;
+    ) -> Result<Option<ArtifactNodeRepr>, Box<dyn Error>> {
+        let yaml = self.apply_environment(stack_kind_name, node_name, yaml);
+        let kind_key = format!("{}s", stack_kind_name);
+        let node_path = [kind_key.as_str(), node_name];
+
+        let enabled_path = [&node_path[..], &["enabled"]].concat();
+        if !self.node_enabled(&enabled_path, &yaml)? {
+            info!("Skipping disabled node: {}", node_name);
+            return Ok(None);
+        }
+
+        info!("Resolving node: {}", node_name);
         let home_dir = dirs::home_dir().unwrap();
         let torb_path = home_dir.join(".torb");
         let repository_path = torb_path.join("repositories");
 
-        let repo = match yaml.get("source") {
-            Some(source) => source.as_str().unwrap(),
-            None => "torb-artifacts",
+        let string_key = |key: &str, required: bool| -> Result<Option<&str>, TorbResolverErrors> {
+            let key_path = [&node_path[..], &[key]].concat();
+
+            match yaml.get(key) {
+                None | Some(Value::Null) if required => {
+                    Err(self.node_error(&key_path, "is missing.".to_string()))
+                }
+                None | Some(Value::Null) => Ok(None),
+                Some(val) => val
+                    .as_str()
+                    .map(Some)
+                    .ok_or_else(|| self.node_error(&key_path, "must be a string.".to_string())),
+            }
         };
 
+        let repo = string_key("source", false)?.unwrap_or(DEFAULT_SOURCE_REPOSITORY);
+
         let artifacts_path = repository_path.join(repo);
 
-        let inputs = Resolver::deserialize_params(yaml.get("inputs"))
-            .expect("Unable to deserialize inputs.");
+        let source_path = [&node_path[..], &["source"]].concat();
+        self.check_source_repository(&source_path, repo, &artifacts_path)?;
+
+        let inputs_path = [&node_path[..], &["inputs"]].concat();
+        let inputs = self.deserialize_params(&inputs_path, yaml.get("inputs"))?;
+
+        // Config is read the same way as inputs, literals or addresses, and becomes a ConfigMap other nodes can read.
+        let config_path = [&node_path[..], &["config"]].concat();
+        let config = self.deserialize_params(&config_path, yaml.get("config"))?;
 
         let config_values = yaml.get("values").unwrap_or(&serde_yaml::Value::Null);
+        let values_files_path = [&node_path[..], &["values_files"]].concat();
 
         let mut node = match stack_kind_name {
             "service" => {
-                let service_name = yaml
-                    .get("service")
-                    .ok_or(err)?
-                    .as_str()
-                    .expect("Unable to parse service name.");
+                let service_name = string_key("service", true)?.unwrap();
 
-                let service_namespace = yaml.get("namespace").map(|x| {
-                    x.as_str().unwrap().to_string()
-                });
+                let service_namespace = string_key("namespace", false)?.map(|ns| ns.to_string());
 
                 let expedient: bool = yaml.get("expedient").is_some();
 
+                let service_dir = artifacts_path
+                    .join("services")
+                    .join(if expedient { "torb-expedient" } else { service_name });
+                let config_values = self.merge_values_files(
+                    &values_files_path,
+                    &service_dir,
+                    config_values.clone(),
+                    yaml.get("values_files"),
+                )?;
+
                 self.resolve_service(
                     stack_name,
                     stack_kind_name,
@@ -567,7 +1481,8 @@ impl Resolver {
                     service_name,
                     artifacts_path,
                     inputs,
-                    config_values.clone(),
+                    config,
+                    config_values,
                     repo,
                     service_namespace,
                     expedient,
@@ -575,16 +1490,18 @@ impl Resolver {
                 )
             }
             "project" => {
-                let project_name = yaml
-                    .get("project")
-                    .ok_or(err)?
-                    .as_str()
-                    .expect("Unable to parse project name.");
+                let project_name = string_key("project", true)?.unwrap();
                 let build_config = yaml.get("build");
 
-                let project_namespace = yaml.get("namespace").map(|x| {
-                    x.as_str().unwrap().to_string()
-                });
+                let project_namespace = string_key("namespace", false)?.map(|ns| ns.to_string());
+
+                let project_dir = artifacts_path.join("projects").join(project_name);
+                let config_values = self.merge_values_files(
+                    &values_files_path,
+                    &project_dir,
+                    config_values.clone(),
+                    yaml.get("values_files"),
+                )?;
 
                 self.resolve_project(
                     stack_name,
@@ -593,16 +1510,34 @@ impl Resolver {
                     project_name,
                     artifacts_path,
                     inputs,
+                    config,
                     build_config,
-                    config_values.clone(),
+                    config_values,
                     repo,
                     project_namespace
                 )
             }
 
-            _ => return Err(Box::new(err)),
+            _ => return Err(Box::new(TorbResolverErrors::CannotParseStackManifest)),
         }?;
 
+        Resolver::validate_deploy_steps(&node)?;
+
+        // A smoke test set on the node in the stack replaces the one from its unit.
+        if let Some(smoke_test) = string_key("smoke_test", false)? {
+            node.smoke_test = Some(smoke_test.to_string());
+        }
+
+        // As does an init timeout.
+        let init_timeout_path = [&node_path[..], &["init_timeout"]].concat();
+        let init_timeout = Resolver::init_timeout(yaml.get("init_timeout").unwrap_or(&Value::Null)).ok_or_else(|| {
+            self.node_error(&init_timeout_path, "must be a whole number of seconds greater than 0.".to_string())
+        })?;
+
+        if init_timeout.is_some() {
+            node.init_timeout = init_timeout;
+        }
+
         let dep_values = yaml.get("deps");
         match dep_values {
             Some(deps) => {
@@ -610,68 +1545,109 @@ impl Resolver {
                 let deps: NodeDependencies = serde_yaml::from_str(yaml_str.as_str()).unwrap();
                 node.dependency_names = deps;
 
-                Ok(node)
+                Ok(Some(node))
+            }
+            None => return Ok(Some(node)),
+        }
+    }
+
+    // Names are compared per kind since services and projects are kept in separate maps in the graph.
+    fn check_name_collisions(yaml: &serde_yaml::Value) -> Result<(), Box<dyn Error>> {
+        for kind in ["services", "projects"] {
+            let mut seen = HashMap::<String, String>::new();
+
+            if let Some(mapping) = yaml.get(kind).and_then(|nodes| nodes.as_mapping()) {
+                for (node_name, _) in mapping.iter() {
+                    if let Some(node_name) = node_name.as_str() {
+                        normalize_name_unique(node_name, &mut seen)?;
+                    }
+                }
             }
-            None => return Ok(node),
         }
+
+        Ok(())
     }
 
-    fn walk_yaml(&self, graph: &mut StackGraph, yaml: &serde_yaml::Value) {
+    fn walk_yaml(&self, graph: &mut StackGraph, yaml: &serde_yaml::Value) -> Result<(), Box<dyn Error>> {
+        let mut disabled = IndexSet::<String>::new();
+
         // Walk yaml and add nodes to graph
         for (key, value) in yaml.as_mapping().unwrap().iter() {
             let key_string = key.as_str().unwrap();
-            match key_string {
-                "services" => {
-                    value.as_mapping().and_then(|mapping| {
-                        for (service_name, service_value) in mapping.iter() {
-                            let stack_service_name = service_name.as_str().unwrap();
-                            let stack_name = self.config.stack_name.clone();
-                            let service_value = service_value.clone();
-                            let service_node = self
-                                .resolve_node(
-                                    stack_name.as_str(),
-                                    "service",
-                                    stack_service_name,
-                                    service_value,
-                                )
-                                .unwrap();
-
-                            graph.add_service(&service_node);
-                            graph.add_all_incoming_edges_downstream(
-                                stack_name.clone(),
-                                &service_node,
-                            );
-                        }
+            let stack_kind_name = match key_string {
+                "services" => "service",
+                "projects" => "project",
+                _ => continue,
+            };
+
+            let mapping = match value.as_mapping() {
+                Some(mapping) => mapping,
+                None => continue,
+            };
+
+            for (node_name, node_value) in mapping.iter() {
+                let node_name = node_name.as_str().unwrap();
+                let stack_name = self.config.stack_name.clone();
+                let node = self
+                    .resolve_node(stack_name.as_str(), stack_kind_name, node_name, node_value.clone())
+                    .map_err(|err| self.locate_node_error(key_string, node_name, err))?;
+
+                let node = match node {
+                    Some(node) => node,
+                    None => {
+                        disabled.insert(format!("{}.{}.{}", stack_name, stack_kind_name, node_name));
+                        continue;
+                    }
+                };
 
-                        Some(())
-                    });
+                if stack_kind_name == "service" {
+                    graph.add_service(&node);
+                } else {
+                    graph.add_project(&node);
                 }
-                "projects" => {
-                    value.as_mapping().and_then(|mapping| {
-                        for (project_name, project_value) in mapping.iter() {
-                            let project_name = project_name.as_str().unwrap();
-                            let stack_name = self.config.stack_name.clone();
-                            let project_value = project_value.clone();
-                            let project_node = self
-                                .resolve_node(
-                                    stack_name.as_str(),
-                                    "project",
-                                    project_name,
-                                    project_value,
-                                )
-                                .expect("Failed to resolve project node.");
-                            graph.add_project(&project_node);
-                            graph.add_all_incoming_edges_downstream(
-                                stack_name.clone(),
-                                &project_node,
-                            );
-                        }
 
-                        Some(())
+                graph.add_all_incoming_edges_downstream(stack_name.clone(), &node);
+            }
+        }
+
+        Resolver::check_disabled_dependencies(graph, &disabled)?;
+
+        Ok(())
+    }
+
+    // Anything still enabled that depends on a disabled node would be missing that node when it's deployed.
+    fn check_disabled_dependencies(graph: &StackGraph, disabled: &IndexSet<String>) -> Result<(), TorbResolverErrors> {
+        let mut nodes: Vec<&ArtifactNodeRepr> = graph.services.values().chain(graph.projects.values()).collect();
+        nodes.sort_by(|a, b| a.fqn.cmp(&b.fqn));
+
+        for node in nodes {
+            let services = node.dependency_names.services.clone().unwrap_or_default();
+            let projects = node.dependency_names.projects.clone().unwrap_or_default();
+
+            let explicit = services
+                .iter()
+                .map(|name| format!("{}.service.{}", graph.name, name))
+                .chain(projects.iter().map(|name| format!("{}.project.{}", graph.name, name)));
+
+            for dependency in node.implicit_dependency_fqns.iter().cloned().chain(explicit) {
+                if disabled.contains(&dependency) {
+                    return Err(TorbResolverErrors::DependsOnDisabledNode {
+                        fqn: node.fqn.clone(),
+                        dependency,
                     });
                 }
-                _ => (),
             }
         }
+
+        Ok(())
+    }
+
+    // Errors that don't already name where in the stack they came from are attributed to the node being resolved.
+    fn locate_node_error(&self, kind_key: &str, node_name: &str, err: Box<dyn Error>) -> Box<dyn Error> {
+        if let Some(TorbResolverErrors::InvalidNode { .. }) = err.downcast_ref::<TorbResolverErrors>() {
+            return err;
+        }
+
+        Box::new(self.node_error(&[kind_key, node_name], err.to_string()))
     }
 }
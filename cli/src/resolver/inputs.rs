@@ -10,15 +10,31 @@
 // See LICENSE file at https://github.com/TorbFoundry/torb/blob/main/LICENSE for details.
 
 use crate::artifacts::{ArtifactNodeRepr, TorbInput};
-use crate::composer::InputAddress;
+use crate::composer::{is_address_like, InputAddress};
+use crate::resolver::template::is_template;
+use indexmap::IndexMap;
 use serde_yaml::Value;
 
 use thiserror::Error;
 
 const INIT_TOKEN: &str = "TORB";
+const INIT_INPUTS_PREFIX: &str = "TORB.inputs.";
+const INIT_DEPS_PREFIX: &str = "TORB.deps.";
+
+const INIT_STEP: &str = "init step";
+const SMOKE_TEST_STEP: &str = "smoke test";
 
 #[derive(Error, Debug)]
-pub enum TorbInputResolverErrors {}
+pub enum TorbInputResolverErrors {
+    #[error("{fqn} {step} references {token}, {step}s can only reference TORB.inputs.<key> or TORB.deps.<fqn>.<key>.")]
+    UnsupportedInitToken { fqn: String, step: &'static str, token: String },
+    #[error("{fqn} {step} references the input {key}, which isn't one of its inputs.")]
+    UnknownInitInput { fqn: String, step: &'static str, key: String },
+    #[error("{fqn} {step} references {dep}, which isn't one of its dependencies.")]
+    UnknownInitDependency { fqn: String, step: &'static str, dep: String },
+    #[error("{fqn} {step} references the input {key} on {dep}, which {dep} doesn't have.")]
+    UnknownInitDependencyInput { fqn: String, step: &'static str, dep: String, key: String },
+}
 
 pub const NO_INPUTS_FN: Option<Box<dyn FnMut(&String, Result<InputAddress, TorbInput>) -> String>> =
     None::<Box<dyn FnMut(&String, Result<InputAddress, TorbInput>) -> String>>;
@@ -26,13 +42,42 @@ pub const NO_INPUTS_FN: Option<Box<dyn FnMut(&String, Result<InputAddress, TorbI
 pub const NO_VALUES_FN: Option<Box<dyn FnMut(Result<InputAddress, TorbInput>) -> String>> =
     None::<Box<dyn FnMut(Result<InputAddress, TorbInput>) -> String>>;
 
-pub const NO_INITS_FN: Option<bool> = None;
+pub const NO_INITS_FN: Option<&IndexMap<String, ArtifactNodeRepr>> = None;
 
+/*
+    Init steps are resolved against the stack's nodes so TORB.deps.<fqn>.<key> can read the mapped inputs of a dependency,
+    passing the nodes is what turns init step resolution on.
+*/
 pub struct InputResolver<'a, F, U> {
     node: &'a ArtifactNodeRepr,
     values_fn: Option<F>,
     inputs_fn: Option<U>,
-    inits_fn: Option<bool>
+    init_nodes: Option<&'a IndexMap<String, ArtifactNodeRepr>>,
+    step: &'static str,
+}
+
+/*
+    Smoke tests are interpolated the same way as init steps, they run after the stack is deployed
+    so everything they can reference is already in place.
+*/
+pub fn resolve_smoke_test(
+    node: &ArtifactNodeRepr,
+    nodes: &IndexMap<String, ArtifactNodeRepr>,
+) -> Result<Option<String>, TorbInputResolverErrors> {
+    let smoke_test = match &node.smoke_test {
+        Some(smoke_test) => smoke_test,
+        None => return Ok(None),
+    };
+
+    let mut resolver = InputResolver {
+        node,
+        values_fn: NO_VALUES_FN,
+        inputs_fn: NO_INPUTS_FN,
+        init_nodes: Some(nodes),
+        step: SMOKE_TEST_STEP,
+    };
+
+    resolver.resolve_torb_value_interpolation(smoke_test).map(Some)
 }
 
 impl<'a, F, U> InputResolver<'a, F, U> {
@@ -40,7 +85,7 @@ impl<'a, F, U> InputResolver<'a, F, U> {
         node: &'a ArtifactNodeRepr,
         values_fn: Option<F>,
         inputs_fn: Option<U>,
-        inits_fn: Option<bool>,
+        init_nodes: Option<&'a IndexMap<String, ArtifactNodeRepr>>,
     ) -> Result<(Option<String>, Option<Vec<(String, String)>>, Option<Vec<String>>), Box<dyn std::error::Error>>
     where
         F: FnMut(Result<InputAddress, TorbInput>) -> String,
@@ -50,7 +95,8 @@ impl<'a, F, U> InputResolver<'a, F, U> {
             node: node,
             values_fn,
             inputs_fn,
-            inits_fn
+            init_nodes,
+            step: INIT_STEP,
         };
 
         let values_fn_out = if resolver.values_fn.is_some() {
@@ -65,8 +111,8 @@ impl<'a, F, U> InputResolver<'a, F, U> {
             None
         };
 
-        let inits_fn_out = if resolver.inits_fn.is_some() {
-            Some(resolver.resolve_node_init_script_inputs())
+        let inits_fn_out = if resolver.init_nodes.is_some() {
+            Some(resolver.resolve_node_init_script_inputs()?)
         } else {
             None
         };
@@ -94,11 +140,11 @@ impl<'a, F, U> InputResolver<'a, F, U> {
     }
 
 
-    pub fn resolve_node_init_script_inputs(&mut self) -> Vec<String> {
+    pub fn resolve_node_init_script_inputs(&mut self) -> Result<Vec<String>, TorbInputResolverErrors> {
         let steps = self.node.init_step.clone().unwrap();
         steps.iter().map(|step| {
             self.resolve_torb_value_interpolation(step)
-        }).collect::<Vec<String>>()
+        }).collect::<Result<Vec<String>, TorbInputResolverErrors>>()
     }
     /*
         Case 1: Token at start
@@ -108,7 +154,7 @@ impl<'a, F, U> InputResolver<'a, F, U> {
         Case 3: Token at end
             Remaining = anything before token
      */
-    fn resolve_torb_value_interpolation(&mut self, script_step: &String) -> String {
+    fn resolve_torb_value_interpolation(&mut self, script_step: &String) -> Result<String, TorbInputResolverErrors> {
         let start_option: Option<usize> = script_step.find(INIT_TOKEN);
         match start_option {
             Some(start) => {
@@ -116,20 +162,20 @@ impl<'a, F, U> InputResolver<'a, F, U> {
                 end = script_step.split_at(start).1.find("/").unwrap_or(end);
 
                 let remaining = if start == 0 && end == script_step.len() {
-                    let resolved_token = self.resolve_inputs_in_init_step(script_step.to_string());
+                    let resolved_token = self.resolve_inputs_in_init_step(script_step.to_string())?;
                     let serialized_token = resolved_token.serialize_for_init();
 
                     serialized_token
                 } else if end == script_step.len() {
                     let parts = script_step.split_at(start);
-                    let resolved_token = self.resolve_inputs_in_init_step(parts.1.to_string());
+                    let resolved_token = self.resolve_inputs_in_init_step(parts.1.to_string())?;
                     let remaining = parts.0.to_string();
                     let serialized_token = resolved_token.serialize_for_init();
 
                     format!("{}{}", remaining, serialized_token)
                 } else if start == 0 {
                     let parts = script_step.split_at(end);
-                    let resolved_token = self.resolve_inputs_in_init_step(parts.0.to_string());
+                    let resolved_token = self.resolve_inputs_in_init_step(parts.0.to_string())?;
                     let serialized_token = resolved_token.serialize_for_init();
                     let remaining = parts.1.to_string();
                     format!("{}{}", serialized_token, remaining)
@@ -140,7 +186,7 @@ impl<'a, F, U> InputResolver<'a, F, U> {
                     let token = parts.0.to_string();
                     let remaining_2 = parts.1.to_string();
 
-                    let resolved_token = self.resolve_inputs_in_init_step(token);
+                    let resolved_token = self.resolve_inputs_in_init_step(token)?;
 
                     let serialized_token = resolved_token.serialize_for_init();
                     format!("{}{}{}", remaining_1, serialized_token, remaining_2)
@@ -149,18 +195,70 @@ impl<'a, F, U> InputResolver<'a, F, U> {
                 self.resolve_torb_value_interpolation(&remaining.to_string())
             },
             None => {
-                script_step.clone()
+                Ok(script_step.clone())
             }
         }
     }
 
-    pub fn resolve_inputs_in_init_step(&mut self, token: String) -> TorbInput
+    pub fn resolve_inputs_in_init_step(&mut self, token: String) -> Result<TorbInput, TorbInputResolverErrors>
     {
-        let input = token.split("TORB.inputs.").collect::<Vec<&str>>()[1];
+        if let Some(input) = token.strip_prefix(INIT_INPUTS_PREFIX) {
+            let (_, val) = self.node.mapped_inputs.get(input).ok_or(
+                TorbInputResolverErrors::UnknownInitInput {
+                    fqn: self.node.fqn.clone(),
+                    step: self.step,
+                    key: input.to_string(),
+                },
+            )?;
+
+            Ok(val.clone())
+        } else if let Some(reference) = token.strip_prefix(INIT_DEPS_PREFIX) {
+            self.resolve_dependency_input_in_init_step(reference)
+        } else {
+            Err(TorbInputResolverErrors::UnsupportedInitToken {
+                fqn: self.node.fqn.clone(),
+                step: self.step,
+                token,
+            })
+        }
+    }
 
-        let (_, val) = self.node.mapped_inputs.get(input).unwrap();
+    /*
+        Fqns contain dots themselves so the key is everything after the last one. Only the node's own dependencies
+        can be referenced, those are initialized before it so anything their init steps set up is already in place.
+    */
+    fn resolve_dependency_input_in_init_step(&self, reference: &str) -> Result<TorbInput, TorbInputResolverErrors> {
+        let (dep, key) = reference.rsplit_once('.').ok_or(
+            TorbInputResolverErrors::UnsupportedInitToken {
+                fqn: self.node.fqn.clone(),
+                step: self.step,
+                token: format!("{}{}", INIT_DEPS_PREFIX, reference),
+            },
+        )?;
+
+        let is_dependency = self.node.dependencies.iter().any(|child| child.fqn == dep)
+            || self.node.implicit_dependency_fqns.contains(dep);
+
+        let dep_node = self
+            .init_nodes
+            .and_then(|nodes| nodes.get(dep))
+            .filter(|_| is_dependency)
+            .ok_or(TorbInputResolverErrors::UnknownInitDependency {
+                fqn: self.node.fqn.clone(),
+                step: self.step,
+                dep: dep.to_string(),
+            })?;
+
+        let (_, val) = dep_node.mapped_inputs.get(key).ok_or(
+            TorbInputResolverErrors::UnknownInitDependencyInput {
+                fqn: self.node.fqn.clone(),
+                step: self.step,
+                dep: dep.to_string(),
+                key: key.to_string(),
+            },
+        )?;
 
-        val.clone()
+        Ok(val.clone())
     }
 
     pub fn resolve_inputs_in_values(&mut self) -> String
@@ -182,7 +280,7 @@ impl<'a, F, U> InputResolver<'a, F, U> {
 
         match value {
             Value::String(s) => {
-                if s.starts_with("self.") {
+                if is_address_like(s) || is_template(s) {
                     let torb_input_address = InputAddress::try_from(s.as_str());
 
                     let string_value = f(torb_input_address);
@@ -0,0 +1,603 @@
+// Business Source License 1.1
+// Licensor:  Torb Foundry
+// Licensed Work:  Torb v0.3.7-03.23
+// The Licensed Work is © 2023-Present Torb Foundry
+//
+// Change License: GNU Affero General Public License Version 3
+// Additional Use Grant: None
+// Change Date: Feb 22, 2023
+//
+// See LICENSE file at https://github.com/TorbFoundry/torb/blob/main/LICENSE for details.
+
+/*
+    A deliberately tiny expression language for deriving a value from inputs and outputs, written as the whole of
+    an input or a values string:
+
+    ${concat(self.service.db.output.host, ":5432")}
+    ${upper(self.inputs.env)}
+
+    An expression is a reference, a double quoted string or a call to one of concat, upper, lower or default.
+    References are the node's own inputs as self.inputs.<key> or self.<type>.<name>.<output|inputs>.<key> addresses.
+*/
+
+use crate::artifacts::{ArtifactNodeRepr, TorbInput};
+use crate::composer::{InputAddress, META_LOCALITY};
+
+use serde_yaml::Value;
+use thiserror::Error;
+
+const TEMPLATE_START: &str = "${";
+const TEMPLATE_END: &str = "}";
+pub const SELF_INPUTS_PREFIX: &str = "self.inputs.";
+
+#[derive(Error, Debug, Clone)]
+pub enum TorbTemplateErrors {
+    #[error("{template} isn't a valid template, {reason}.")]
+    InvalidTemplate { template: String, reason: String },
+    #[error("{template} calls {function}, the supported functions are concat, upper, lower and default.")]
+    UnknownFunction { template: String, function: String },
+    #[error("{template} calls {function} with {given} arguments, it takes {expected}.")]
+    WrongArgumentCount { template: String, function: &'static str, given: usize, expected: &'static str },
+    #[error("{template} references {reference}, references are self.inputs.<key> or self.<type>.<name>.<output|inputs>.<key>.")]
+    InvalidReference { template: String, reference: String },
+    #[error("{template} in {fqn} references the input {key}, which {reason}.")]
+    InvalidInputReference { template: String, fqn: String, key: String, reason: &'static str },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TemplateFunction {
+    Concat,
+    Upper,
+    Lower,
+    Default,
+}
+
+impl TemplateFunction {
+    fn from_name(name: &str) -> Option<TemplateFunction> {
+        match name {
+            "concat" => Some(TemplateFunction::Concat),
+            "upper" => Some(TemplateFunction::Upper),
+            "lower" => Some(TemplateFunction::Lower),
+            "default" => Some(TemplateFunction::Default),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            TemplateFunction::Concat => "concat",
+            TemplateFunction::Upper => "upper",
+            TemplateFunction::Lower => "lower",
+            TemplateFunction::Default => "default",
+        }
+    }
+
+    fn arity(&self) -> (usize, usize, &'static str) {
+        match self {
+            TemplateFunction::Concat => (1, usize::MAX, "at least 1"),
+            TemplateFunction::Upper | TemplateFunction::Lower => (1, 1, "1"),
+            TemplateFunction::Default => (2, 2, "2"),
+        }
+    }
+
+    /*
+        When every argument is known the function is applied right away. Otherwise at least one argument is an
+        output that's only known at deploy time, so the call becomes the Terraform function that does the same thing.
+        default falls back when its first argument is empty, or for an output, when the output isn't set at all.
+    */
+    fn apply(&self, args: Vec<TemplateValue>) -> TemplateValue {
+        let literals = args
+            .iter()
+            .map(|arg| match arg {
+                TemplateValue::Literal(value) => Some(value.clone()),
+                TemplateValue::Terraform(_) => None,
+            })
+            .collect::<Option<Vec<String>>>();
+
+        if let Some(literals) = literals {
+            let value = match self {
+                TemplateFunction::Concat => literals.concat(),
+                TemplateFunction::Upper => literals[0].to_uppercase(),
+                TemplateFunction::Lower => literals[0].to_lowercase(),
+                TemplateFunction::Default if literals[0].is_empty() => literals[1].clone(),
+                TemplateFunction::Default => literals[0].clone(),
+            };
+
+            return TemplateValue::Literal(value);
+        }
+
+        let args = args.iter().map(TemplateValue::to_terraform).collect::<Vec<String>>();
+
+        let expression = match self {
+            TemplateFunction::Concat => format!("join(\"\", [{}])", args.join(", ")),
+            TemplateFunction::Upper => format!("upper({})", args[0]),
+            TemplateFunction::Lower => format!("lower({})", args[0]),
+            TemplateFunction::Default => format!("coalesce(try({}, \"\"), {})", args[0], args[1]),
+        };
+
+        TemplateValue::Terraform(expression)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateExpr {
+    Literal(String),
+    Reference(String),
+    Call(TemplateFunction, Vec<TemplateExpr>),
+}
+
+// What a template evaluates to, a value known when the stack is composed or a Terraform expression read at deploy time.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateValue {
+    Literal(String),
+    Terraform(String),
+}
+
+impl TemplateValue {
+    pub fn to_terraform(&self) -> String {
+        match self {
+            TemplateValue::Literal(value) => {
+                let quoted = serde_json::to_string(value).unwrap();
+
+                quoted.replace("${", "$${").replace("%{", "%%{")
+            }
+            TemplateValue::Terraform(expression) => expression.clone(),
+        }
+    }
+}
+
+impl TemplateExpr {
+    pub fn references(&self) -> Vec<&str> {
+        match self {
+            TemplateExpr::Literal(_) => vec![],
+            TemplateExpr::Reference(reference) => vec![reference.as_str()],
+            TemplateExpr::Call(_, args) => args.iter().flat_map(|arg| arg.references()).collect(),
+        }
+    }
+
+    pub fn evaluate<R>(&self, resolve: &mut R) -> TemplateValue
+    where
+        R: FnMut(&str) -> TemplateValue,
+    {
+        match self {
+            TemplateExpr::Literal(value) => TemplateValue::Literal(value.clone()),
+            TemplateExpr::Reference(reference) => resolve(reference),
+            TemplateExpr::Call(function, args) => {
+                let args = args.iter().map(|arg| arg.evaluate(resolve)).collect();
+
+                function.apply(args)
+            }
+        }
+    }
+}
+
+/*
+    Values like "${HOME}" were passed through as they are before templates existed, so only a body that starts like
+    an expression, a quoted string, a function call or a self. or meta. reference, makes a value a template.
+*/
+pub fn is_template(value: &str) -> bool {
+    let body = match value
+        .strip_prefix(TEMPLATE_START)
+        .and_then(|body| body.strip_suffix(TEMPLATE_END))
+    {
+        Some(body) => body.trim_start(),
+        None => return false,
+    };
+
+    if body.starts_with('"') {
+        return true;
+    }
+
+    let word: String = body.chars().take_while(|c| is_word_char(*c)).collect();
+    let after_word = body[word.len()..].trim_start();
+
+    !word.is_empty()
+        && (after_word.starts_with('(')
+            || word.starts_with("self.")
+            || word.starts_with(&format!("{}.", META_LOCALITY)))
+}
+
+pub fn parse_template(template: &str) -> Result<TemplateExpr, TorbTemplateErrors> {
+    let body = template
+        .strip_prefix(TEMPLATE_START)
+        .and_then(|body| body.strip_suffix(TEMPLATE_END))
+        .ok_or_else(|| TorbTemplateErrors::InvalidTemplate {
+            template: template.to_string(),
+            reason: "templates are written as ${<expression>}".to_string(),
+        })?;
+
+    let mut parser = Parser {
+        template,
+        chars: body.chars().collect(),
+        pos: 0,
+    };
+
+    let expr = parser.parse_expr()?;
+    parser.skip_whitespace();
+
+    match parser.peek() {
+        None => Ok(expr),
+        Some(c) => Err(parser.invalid(format!("unexpected {} after the expression", c))),
+    }
+}
+
+// The addresses of other nodes a value reads, whether it's a plain address or a template.
+pub fn referenced_addresses(value: &str) -> Vec<InputAddress> {
+    if !is_template(value) {
+        return InputAddress::try_from(value).into_iter().collect();
+    }
+
+    parse_template(value)
+        .map(|expr| {
+            expr.references()
+                .into_iter()
+                .filter(|reference| !reference.starts_with(SELF_INPUTS_PREFIX))
+                .filter_map(|reference| InputAddress::try_from(reference).ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/*
+    Every template in a node's inputs, values and config is parsed when the stack is resolved, so a typo fails the resolve
+    instead of the deploy. An input a template reads can't be a template itself, which keeps evaluation one level deep.
+*/
+pub fn validate_node_templates(node: &ArtifactNodeRepr) -> Result<(), TorbTemplateErrors> {
+    let mut templates = node
+        .mapped_inputs
+        .values()
+        .map(|(_, input)| input)
+        .chain(node.config.values())
+        .filter_map(|input| match input {
+            TorbInput::String(value) if is_template(value) => Some(value.clone()),
+            _ => None,
+        })
+        .collect::<Vec<String>>();
+
+    let values: Value = serde_yaml::from_str(node.values.as_str()).unwrap_or(Value::Null);
+    collect_value_templates(&values, &mut templates);
+
+    for template in templates.iter() {
+        let expr = parse_template(template)?;
+
+        for key in expr.references().iter().filter_map(|reference| reference.strip_prefix(SELF_INPUTS_PREFIX)) {
+            let reason = match node.mapped_inputs.get(key) {
+                None => Some("isn't one of its inputs"),
+                Some((_, TorbInput::String(value))) if is_template(value) => Some("is a template itself"),
+                Some(_) => None,
+            };
+
+            if let Some(reason) = reason {
+                return Err(TorbTemplateErrors::InvalidInputReference {
+                    template: template.clone(),
+                    fqn: node.fqn.clone(),
+                    key: key.to_string(),
+                    reason,
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn collect_value_templates(value: &Value, templates: &mut Vec<String>) {
+    match value {
+        Value::String(s) if is_template(s) => templates.push(s.clone()),
+        Value::Mapping(m) => {
+            for (_, v) in m {
+                collect_value_templates(v, templates);
+            }
+        }
+        Value::Sequence(seq) => {
+            for v in seq {
+                collect_value_templates(v, templates);
+            }
+        }
+        _ => {}
+    }
+}
+
+struct Parser<'a> {
+    template: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map_or(false, |c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn invalid(&self, reason: String) -> TorbTemplateErrors {
+        TorbTemplateErrors::InvalidTemplate {
+            template: self.template.to_string(),
+            reason,
+        }
+    }
+
+    fn parse_expr(&mut self) -> Result<TemplateExpr, TorbTemplateErrors> {
+        self.skip_whitespace();
+
+        match self.peek() {
+            Some('"') => self.parse_string(),
+            Some(c) if is_word_char(c) => {
+                let start = self.pos;
+
+                while self.peek().map_or(false, is_word_char) {
+                    self.pos += 1;
+                }
+
+                let word: String = self.chars[start..self.pos].iter().collect();
+                self.skip_whitespace();
+
+                if self.peek() == Some('(') {
+                    self.pos += 1;
+                    self.parse_call(&word)
+                } else {
+                    self.parse_reference(word)
+                }
+            }
+            Some(c) => Err(self.invalid(format!("unexpected {}", c))),
+            None => Err(self.invalid("it's missing an expression".to_string())),
+        }
+    }
+
+    fn parse_call(&mut self, name: &str) -> Result<TemplateExpr, TorbTemplateErrors> {
+        let function = TemplateFunction::from_name(name).ok_or_else(|| TorbTemplateErrors::UnknownFunction {
+            template: self.template.to_string(),
+            function: name.to_string(),
+        })?;
+
+        let mut args = vec![];
+
+        self.skip_whitespace();
+
+        if self.peek() == Some(')') {
+            self.pos += 1;
+        } else {
+            loop {
+                args.push(self.parse_expr()?);
+                self.skip_whitespace();
+
+                match self.peek() {
+                    Some(',') => self.pos += 1,
+                    Some(')') => {
+                        self.pos += 1;
+                        break;
+                    }
+                    Some(c) => return Err(self.invalid(format!("expected , or ) in {} but found {}", name, c))),
+                    None => return Err(self.invalid(format!("{} is missing its closing )", name))),
+                }
+            }
+        }
+
+        let (min, max, expected) = function.arity();
+
+        if args.len() < min || args.len() > max {
+            return Err(TorbTemplateErrors::WrongArgumentCount {
+                template: self.template.to_string(),
+                function: function.name(),
+                given: args.len(),
+                expected,
+            });
+        }
+
+        Ok(TemplateExpr::Call(function, args))
+    }
+
+    fn parse_reference(&self, reference: String) -> Result<TemplateExpr, TorbTemplateErrors> {
+        let is_input = reference
+            .strip_prefix(SELF_INPUTS_PREFIX)
+            .map_or(false, |key| !key.is_empty() && !key.contains('.'));
+
+        let is_address = InputAddress::try_from(reference.as_str())
+            .map_or(false, |addr| addr.locality == "self" || addr.locality == META_LOCALITY);
+
+        if is_input || is_address {
+            Ok(TemplateExpr::Reference(reference))
+        } else {
+            Err(TorbTemplateErrors::InvalidReference {
+                template: self.template.to_string(),
+                reference,
+            })
+        }
+    }
+
+    // Strings are double quoted and a backslash escapes the character after it.
+    fn parse_string(&mut self) -> Result<TemplateExpr, TorbTemplateErrors> {
+        self.pos += 1;
+
+        let mut value = String::new();
+
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(TemplateExpr::Literal(value));
+                }
+                Some('\\') => {
+                    self.pos += 1;
+
+                    match self.peek() {
+                        Some(c) => value.push(c),
+                        None => break,
+                    }
+                }
+                Some(c) => value.push(c),
+                None => break,
+            }
+
+            self.pos += 1;
+        }
+
+        Err(self.invalid("a string is missing its closing quote".to_string()))
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '.' || c == '_' || c == '-'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn literal(value: &str) -> TemplateExpr {
+        TemplateExpr::Literal(value.to_string())
+    }
+
+    fn reference(value: &str) -> TemplateExpr {
+        TemplateExpr::Reference(value.to_string())
+    }
+
+    fn evaluate(template: &str) -> TemplateValue {
+        parse_template(template).unwrap().evaluate(&mut |reference: &str| {
+            match reference.strip_prefix(SELF_INPUTS_PREFIX) {
+                Some(key) => TemplateValue::Literal(format!("<{}>", key)),
+                None => TemplateValue::Terraform(format!("output.{}", reference)),
+            }
+        })
+    }
+
+    #[test]
+    fn parses_concat() {
+        let expr = parse_template(r#"${concat(self.service.db.output.host, ":5432")}"#).unwrap();
+
+        assert_eq!(
+            expr,
+            TemplateExpr::Call(TemplateFunction::Concat, vec![reference("self.service.db.output.host"), literal(":5432")])
+        );
+    }
+
+    #[test]
+    fn parses_upper_and_lower() {
+        assert_eq!(
+            parse_template("${upper(self.inputs.env)}").unwrap(),
+            TemplateExpr::Call(TemplateFunction::Upper, vec![reference("self.inputs.env")])
+        );
+        assert_eq!(
+            parse_template("${ lower( self.inputs.env ) }").unwrap(),
+            TemplateExpr::Call(TemplateFunction::Lower, vec![reference("self.inputs.env")])
+        );
+    }
+
+    #[test]
+    fn parses_default_with_nested_call() {
+        let expr = parse_template(r#"${default(self.inputs.name, upper("fallback"))}"#).unwrap();
+
+        assert_eq!(
+            expr,
+            TemplateExpr::Call(
+                TemplateFunction::Default,
+                vec![
+                    reference("self.inputs.name"),
+                    TemplateExpr::Call(TemplateFunction::Upper, vec![literal("fallback")]),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_function() {
+        let err = parse_template("${trim(self.inputs.env)}").unwrap_err();
+
+        assert!(matches!(err, TorbTemplateErrors::UnknownFunction { ref function, .. } if function == "trim"));
+    }
+
+    #[test]
+    fn rejects_wrong_argument_counts() {
+        let cases = [
+            ("${upper()}", "upper", 0),
+            (r#"${lower("a", "b")}"#, "lower", 2),
+            (r#"${default("a")}"#, "default", 1),
+            ("${concat()}", "concat", 0),
+        ];
+
+        for (template, expected_function, expected_given) in cases {
+            match parse_template(template).unwrap_err() {
+                TorbTemplateErrors::WrongArgumentCount { function, given, .. } => {
+                    assert_eq!(function, expected_function);
+                    assert_eq!(given, expected_given);
+                }
+                err => panic!("expected an arity error for {}, got {}", template, err),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_unterminated_string() {
+        let err = parse_template(r#"${upper("abc)}"#).unwrap_err();
+
+        assert!(matches!(err, TorbTemplateErrors::InvalidTemplate { ref reason, .. } if reason.contains("closing quote")));
+    }
+
+    #[test]
+    fn rejects_trailing_garbage() {
+        let err = parse_template(r#"${upper("a") lower("b")}"#).unwrap_err();
+
+        assert!(matches!(err, TorbTemplateErrors::InvalidTemplate { ref reason, .. } if reason.contains("after the expression")));
+    }
+
+    #[test]
+    fn rejects_invalid_reference() {
+        let err = parse_template("${upper(other.inputs.env)}").unwrap_err();
+
+        assert!(matches!(err, TorbTemplateErrors::InvalidReference { .. }));
+    }
+
+    #[test]
+    fn folds_literal_arguments() {
+        assert_eq!(
+            evaluate(r#"${concat(upper(self.inputs.env), "-", lower("API"))}"#),
+            TemplateValue::Literal("<ENV>-api".to_string())
+        );
+        assert_eq!(
+            evaluate(r#"${default("", "fallback")}"#),
+            TemplateValue::Literal("fallback".to_string())
+        );
+        assert_eq!(
+            evaluate(r#"${default("set", "fallback")}"#),
+            TemplateValue::Literal("set".to_string())
+        );
+    }
+
+    #[test]
+    fn defers_outputs_to_terraform() {
+        assert_eq!(
+            evaluate(r#"${concat(self.service.db.output.host, ":5432")}"#),
+            TemplateValue::Terraform(r#"join("", [output.self.service.db.output.host, ":5432"])"#.to_string())
+        );
+        assert_eq!(
+            evaluate(r#"${default(self.service.db.output.host, "localhost")}"#),
+            TemplateValue::Terraform(r#"coalesce(try(output.self.service.db.output.host, ""), "localhost")"#.to_string())
+        );
+    }
+
+    #[test]
+    fn escapes_literals_for_terraform() {
+        let value = TemplateValue::Literal(r#"say "hi" to ${name} and %{if}"#.to_string());
+
+        assert_eq!(value.to_terraform(), r#""say \"hi\" to $${name} and %%{if}""#);
+    }
+
+    #[test]
+    fn only_expression_bodies_are_templates() {
+        assert!(is_template("${upper(self.inputs.env)}"));
+        assert!(is_template(r#"${"literal"}"#));
+        assert!(is_template("${self.service.db.output.host}"));
+        assert!(is_template("${meta.service.db.output.host}"));
+        assert!(is_template("${typo(self.inputs.env)}"));
+
+        assert!(!is_template("${HOME}"));
+        assert!(!is_template("${VAR:-default}"));
+        assert!(!is_template("plain"));
+        assert!(!is_template("${upper(self.inputs.env)} suffix"));
+    }
+}
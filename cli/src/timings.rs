@@ -0,0 +1,83 @@
+// Business Source License 1.1
+// Licensor:  Torb Foundry
+// Licensed Work:  Torb v0.3.7-03.23
+// The Licensed Work is © 2023-Present Torb Foundry
+//
+// Change License: GNU Affero General Public License Version 3
+// Additional Use Grant: None
+// Change Date: Feb 22, 2023
+//
+// See LICENSE file at https://github.com/TorbFoundry/torb/blob/main/LICENSE for details.
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/*
+    A process wide timeline of how long each phase of a build or deploy took. Recording is off until enabled
+    so long running callers like the watcher don't collect timings nobody reads. Builds run on a thread pool,
+    so the timeline is behind a mutex.
+*/
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TIMELINE: Lazy<Mutex<Vec<PhaseTiming>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PhaseTiming {
+    pub phase: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    pub seconds: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TimingSummary {
+    pub total_seconds: f64,
+    pub phases: Vec<PhaseTiming>,
+    pub nodes: Vec<PhaseTiming>,
+}
+
+pub fn enable_timings() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn record_timing(phase: &str, node: Option<&str>, elapsed: Duration) {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return;
+    }
+
+    if let Ok(mut timeline) = TIMELINE.lock() {
+        timeline.push(PhaseTiming {
+            phase: phase.to_string(),
+            node: node.map(|node| node.to_string()),
+            seconds: elapsed.as_secs_f64(),
+        });
+    }
+}
+
+// Runs f and records how long it took, whether or not it succeeded.
+pub fn timed<T>(phase: &str, node: Option<&str>, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let out = f();
+
+    record_timing(phase, node, start.elapsed());
+
+    out
+}
+
+// Stack wide phases in the order they ran, and per node phases slowest first.
+pub fn timing_summary(total: Duration) -> TimingSummary {
+    let timeline = TIMELINE.lock().map(|timeline| timeline.clone()).unwrap_or_default();
+
+    let (mut nodes, phases): (Vec<PhaseTiming>, Vec<PhaseTiming>) =
+        timeline.into_iter().partition(|timing| timing.node.is_some());
+
+    nodes.sort_by(|a, b| b.seconds.total_cmp(&a.seconds));
+
+    TimingSummary {
+        total_seconds: total.as_secs_f64(),
+        phases,
+        nodes,
+    }
+}
@@ -13,12 +13,20 @@ use colored::Colorize;
 
 use core::fmt::Display;
 use data_encoding::BASE32;
+use log::debug;
+use once_cell::sync::OnceCell;
 use sha2::{Digest, Sha256};
 use std::error::Error;
 use std::{
     fmt::Debug,
     fs::DirEntry,
-    process::{Command, Output},
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::io::AsRawFd,
+    os::unix::process::CommandExt,
+    process::{Command, Output, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 
@@ -43,9 +51,283 @@ pub enum TorbUtilityErrors {
 
     #[error("Resource not found.")]
     ResourceNotFound,
+
+    #[error("This command was stopped after running for {elapsed:?}:\n\n{command}")]
+    CommandTimedOut { command: String, elapsed: Duration },
+
+    #[error("{first} and {second} both normalize to {normalized}, please rename one of them.")]
+    NameCollision {
+        first: String,
+        second: String,
+        normalized: String,
+    },
+
+    #[error("Unable to read stack from {location}, reason: {reason}")]
+    UnableToReadStackSource { location: String, reason: String },
+
+    #[error("The kubeconfig {path} doesn't exist.")]
+    KubeconfigNotFound { path: String },
+
+    #[error("The kube context {context} doesn't exist, reason: {reason}")]
+    UnknownKubeContext { context: String, reason: String },
+
+    #[error("Torb hasn't been initialized, {missing} is missing.")]
+    NotInitialized { missing: String },
+
+    #[error("Unable to create the buildstate directory {path}, reason: {reason}")]
+    UnableToCreateBuildstate { path: String, reason: String },
+
+    #[error("Unable to read the compatibility file {path}, reason: {reason}")]
+    InvalidCompatibilityFile { path: String, reason: String },
+
+    #[error("Torb {version} isn't compatible with the installed torb-artifacts, {reason}")]
+    IncompatibleArtifacts { version: String, reason: String },
+
+    #[error("Unable to include {path} from {included_from}, reason: {reason}")]
+    UnableToReadInclude { path: String, included_from: String, reason: String },
+
+    #[error("Stack includes are circular: {chain}")]
+    CircularInclude { chain: String },
+
+    #[error("The remote stack {location} uses !include, includes are only supported for stacks read from a file or stdin.")]
+    RemoteStackInclude { location: String },
+
+    #[error("Another Torb operation is in progress in this project, {operation} (pid {pid}) holds {path}.")]
+    BuildstateLocked { operation: String, pid: String, path: String },
+
+    #[error("Unable to lock the buildstate at {path}, reason: {reason}")]
+    UnableToLockBuildstate { path: String, reason: String },
+
+    #[error("Unable to use {path} as the project directory, reason: {reason}")]
+    InvalidProjectDir { path: String, reason: String },
+
+    #[error("Unable to use the proxy {proxy} from {var}, reason: {reason}")]
+    InvalidProxy { proxy: String, var: String, reason: String },
+}
+
+static KUBE_TARGET: OnceCell<KubeTarget> = OnceCell::new();
+static PROJECT_ROOT: OnceCell<std::path::PathBuf> = OnceCell::new();
+static SHOW_SECRETS: AtomicBool = AtomicBool::new(false);
+
+pub const REDACTED: &str = "(sensitive)";
+pub const NO_COLOR_ENV: &str = "NO_COLOR";
+
+// Values of sensitive inputs are redacted from everything Torb prints or logs unless --show-secrets is passed.
+pub fn show_secrets() {
+    SHOW_SECRETS.store(true, Ordering::SeqCst);
+}
+
+/*
+    Color is turned off for the whole run by --no-color, or by NO_COLOR set to anything but an empty string as
+    described at no-color.org. Everything Torb prints is colored through colored, so its override covers all of it.
+*/
+pub fn disable_color_if_requested(no_color: bool) {
+    let no_color_env = std::env::var_os(NO_COLOR_ENV).map_or(false, |value| !value.is_empty());
+
+    if no_color || no_color_env {
+        colored::control::set_override(false);
+    }
+}
+
+pub fn color_enabled() -> bool {
+    colored::control::SHOULD_COLORIZE.should_colorize()
+}
+
+pub fn secrets_shown() -> bool {
+    SHOW_SECRETS.load(Ordering::SeqCst)
+}
+
+/*
+    The cluster selected with --context and --kubeconfig. It's passed as flags to every kubectl and helm command Torb runs
+    and exported as KUBECONFIG and HELM_KUBECONTEXT so Terraform and the Torb provider talk to the same cluster.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct KubeTarget {
+    pub context: Option<String>,
+    pub kubeconfig: Option<String>,
+}
+
+impl KubeTarget {
+    pub fn is_set(&self) -> bool {
+        self.context.is_some() || self.kubeconfig.is_some()
+    }
+
+    pub fn args(&self, tool: &str) -> Vec<String> {
+        let mut args = Vec::new();
+
+        if let Some(context) = &self.context {
+            let flag = if tool == "helm" { "--kube-context" } else { "--context" };
+            args.push(flag.to_string());
+            args.push(context.clone());
+        }
+
+        if let Some(kubeconfig) = &self.kubeconfig {
+            args.push("--kubeconfig".to_string());
+            args.push(kubeconfig.clone());
+        }
+
+        args
+    }
+}
+
+pub fn kube_target() -> KubeTarget {
+    KUBE_TARGET.get().cloned().unwrap_or_default()
+}
+
+/*
+    Checks the kubeconfig and context exist before anything runs against the cluster. The kubeconfig is made absolute
+    against the project root, Terraform runs its commands from the IaC environment where a relative path would miss.
+*/
+pub fn set_kube_target(context: Option<String>, kubeconfig: Option<String>) -> Result<(), TorbUtilityErrors> {
+    let kubeconfig = kubeconfig
+        .map(|kubeconfig| {
+            std::fs::canonicalize(project_path(&kubeconfig))
+                .map(|path| path.display().to_string())
+                .map_err(|_| TorbUtilityErrors::KubeconfigNotFound { path: kubeconfig.clone() })
+        })
+        .transpose()?;
+    let target = KubeTarget { context, kubeconfig };
+
+    if let Some(kubeconfig) = &target.kubeconfig {
+        std::env::set_var("KUBECONFIG", kubeconfig);
+    }
+
+    if let Some(context) = &target.context {
+        let mut args = vec!["config".to_string(), "get-contexts".to_string(), context.clone()];
+
+        if let Some(kubeconfig) = &target.kubeconfig {
+            args.push("--kubeconfig".to_string());
+            args.push(kubeconfig.clone());
+        }
+
+        let output = Command::new("kubectl").args(&args).output().map_err(|err| {
+            TorbUtilityErrors::UnknownKubeContext {
+                context: context.clone(),
+                reason: err.to_string(),
+            }
+        })?;
+
+        if !output.status.success() {
+            return Err(TorbUtilityErrors::UnknownKubeContext {
+                context: context.clone(),
+                reason: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+
+        std::env::set_var("HELM_KUBECONTEXT", context);
+    }
+
+    let _ = KUBE_TARGET.set(target);
+
+    Ok(())
+}
+
+/*
+    The project root is the directory Torb works against, the buildstate, unit files and relative paths in a stack
+    are all found from it. It's the current directory unless --dir points Torb at a project somewhere else.
+*/
+pub fn project_root() -> std::path::PathBuf {
+    PROJECT_ROOT
+        .get()
+        .cloned()
+        .unwrap_or_else(|| std::env::current_dir().unwrap_or_default())
+}
+
+// Relative paths are joined onto the project root, absolute paths are left as they are.
+pub fn project_path(path: impl AsRef<std::path::Path>) -> std::path::PathBuf {
+    project_root().join(path)
+}
+
+pub fn set_project_root(dir: &str) -> Result<(), TorbUtilityErrors> {
+    let invalid = |reason: String| TorbUtilityErrors::InvalidProjectDir {
+        path: dir.to_string(),
+        reason,
+    };
+
+    let root = std::fs::canonicalize(dir).map_err(|err| invalid(err.to_string()))?;
+
+    if !root.is_dir() {
+        return Err(invalid("it is not a directory".to_string()));
+    }
+
+    let _ = PROJECT_ROOT.set(root);
+
+    Ok(())
+}
+
+// Lowercase names are checked first, as curl does, since they're the more widely supported spelling.
+fn proxy_env(names: &[&str]) -> Option<(String, String)> {
+    names.iter().find_map(|name| {
+        std::env::var(name)
+            .ok()
+            .filter(|value| !value.trim().is_empty())
+            .map(|value| (name.to_string(), value.trim().to_string()))
+    })
+}
+
+// The host of a URL without its scheme, credentials or port.
+fn url_host(url: &str) -> String {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = without_scheme.split(|c| c == '/' || c == '?' || c == '#').next().unwrap_or_default();
+    let host_and_port = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+
+    let host = if host_and_port.starts_with('[') {
+        host_and_port.split_once(']').map_or(host_and_port, |(host, _)| host).trim_start_matches('[')
+    } else {
+        host_and_port.split(':').next().unwrap_or_default()
+    };
+
+    host.to_lowercase()
+}
+
+/*
+    NO_PROXY is a comma separated list of hosts to connect to directly. An entry matches the host itself and any of
+    its subdomains, with or without a leading dot, and * matches every host.
+*/
+fn bypasses_proxy(host: &str) -> bool {
+    let no_proxy = match proxy_env(&["no_proxy", "NO_PROXY"]) {
+        Some((_, value)) => value,
+        None => return false,
+    };
+
+    no_proxy
+        .split(',')
+        .map(|entry| entry.trim().trim_start_matches('.').to_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| entry == "*" || host == entry || host.ends_with(&format!(".{}", entry)))
+}
+
+/*
+    Every HTTP request Torb makes goes through an agent from here, so HTTPS_PROXY and HTTP_PROXY are honored for
+    https and http URLs respectively and NO_PROXY can exclude hosts. With neither set the agent connects directly.
+*/
+pub fn http_agent(url: &str) -> Result<ureq::Agent, TorbUtilityErrors> {
+    let names: &[&str] = if url.starts_with("https://") {
+        &["https_proxy", "HTTPS_PROXY"]
+    } else {
+        &["http_proxy", "HTTP_PROXY"]
+    };
+
+    let agent = ureq::AgentBuilder::new();
+
+    match proxy_env(names) {
+        Some((var, proxy)) if !bypasses_proxy(&url_host(url)) => {
+            let configured = ureq::Proxy::new(&proxy).map_err(|err| TorbUtilityErrors::InvalidProxy {
+                proxy: proxy.clone(),
+                var,
+                reason: err.to_string(),
+            })?;
+
+            Ok(agent.proxy(configured).build())
+        }
+        _ => Ok(agent.build()),
+    }
 }
 
 const TORB_PATH: &str = ".torb";
+const BUILDSTATE_DIR: &str = ".torb_buildstate";
+pub const BUILDSTATE_DIR_ENV: &str = "TORB_BUILDSTATE_DIR";
+const COMPATIBILITY_FILE: &str = "compatibility.yaml";
 
 pub fn kebab_to_snake_case(input: &str) -> String {
     input.replace("-", "_")
@@ -64,34 +346,622 @@ pub fn normalize_name(name: &str) -> String {
         .replace(" ", "_")
 }
 
+// Single quotes an argument for sh, for commands Terraform runs through a local-exec provisioner.
+pub fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+// A DNS-1123 label, lowercase alphanumerics and '-', starting and ending alphanumeric, at most 63 characters.
+pub fn is_dns_label(name: &str) -> bool {
+    let is_alphanumeric = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+
+    !name.is_empty()
+        && name.len() <= 63
+        && name.chars().all(|c| is_alphanumeric(c) || c == '-')
+        && name.starts_with(is_alphanumeric)
+        && name.ends_with(is_alphanumeric)
+}
+
+/*
+    Normalizes a name and records which original produced it, so two different names that would
+    collapse to the same value (my-app, my_app, my.app) are caught instead of silently overwriting each other.
+*/
+pub fn normalize_name_unique(
+    name: &str,
+    seen: &mut std::collections::HashMap<String, String>,
+) -> Result<String, TorbUtilityErrors> {
+    let normalized = normalize_name(name);
+
+    match seen.get(&normalized) {
+        Some(original) if original != name => Err(TorbUtilityErrors::NameCollision {
+            first: original.clone(),
+            second: name.to_string(),
+            normalized,
+        }),
+        _ => {
+            seen.insert(normalized.clone(), name.to_string());
+
+            Ok(normalized)
+        }
+    }
+}
+
+pub fn merge_yaml_values(base: serde_yaml::Value, overlay: serde_yaml::Value) -> serde_yaml::Value {
+    match (base, overlay) {
+        (serde_yaml::Value::Mapping(mut base_map), serde_yaml::Value::Mapping(overlay_map)) => {
+            for (key, overlay_val) in overlay_map {
+                if let Some(base_val) = base_map.get_mut(&key) {
+                    let existing = std::mem::replace(base_val, serde_yaml::Value::Null);
+                    *base_val = merge_yaml_values(existing, overlay_val);
+                } else {
+                    base_map.insert(key, overlay_val);
+                }
+            }
+
+            serde_yaml::Value::Mapping(base_map)
+        }
+        (base, serde_yaml::Value::Null) => base,
+        (_, overlay) => overlay,
+    }
+}
+
 pub fn torb_path() -> std::path::PathBuf {
     let home_dir = dirs::home_dir().unwrap();
     home_dir.join(TORB_PATH)
 }
 
-pub fn buildstate_path_or_create() -> std::path::PathBuf {
-    let current_dir = std::env::current_dir().unwrap();
-    let current_dir_state_dir = current_dir.join(".torb_buildstate");
+/*
+    Stack definitions can come from a file path, stdin when the path is "-", or a remote http(s) URL.
+*/
+pub fn read_stack_source(location: &str) -> Result<String, TorbUtilityErrors> {
+    let unable_to_read = |reason: String| TorbUtilityErrors::UnableToReadStackSource {
+        location: location.to_string(),
+        reason,
+    };
+
+    if location == "-" {
+        let mut contents = String::new();
+
+        io::stdin()
+            .read_to_string(&mut contents)
+            .map_err(|err| unable_to_read(err.to_string()))?;
+
+        expand_includes(&contents, &project_root(), &mut vec![std::path::PathBuf::from("stdin")])
+    } else if location.starts_with("http://") || location.starts_with("https://") {
+        let agent = http_agent(location).map_err(|err| unable_to_read(err.to_string()))?;
+
+        let contents = match agent.get(location).call() {
+            Ok(resp) if resp.status() == 200 => resp
+                .into_string()
+                .map_err(|err| unable_to_read(err.to_string())),
+            Ok(resp) => Err(unable_to_read(format!(
+                "expected 200, got {} {}",
+                resp.status(),
+                resp.status_text()
+            ))),
+            Err(ureq::Error::Status(code, resp)) => Err(unable_to_read(format!(
+                "expected 200, got {} {}",
+                code,
+                resp.status_text()
+            ))),
+            Err(err) => Err(unable_to_read(err.to_string())),
+        }?;
+
+        if include_directives(&contents).iter().any(Option::is_some) {
+            return Err(TorbUtilityErrors::RemoteStackInclude { location: location.to_string() });
+        }
 
-    if current_dir_state_dir.exists() {
-        current_dir_state_dir
+        Ok(contents)
     } else {
-        std::fs::create_dir_all(&current_dir_state_dir).unwrap();
-        current_dir_state_dir
+        let contents = std::fs::read_to_string(project_path(location)).map_err(|err| unable_to_read(err.to_string()))?;
+        let path = std::fs::canonicalize(project_path(location)).map_err(|err| unable_to_read(err.to_string()))?;
+        let base_dir = path.parent().map(|dir| dir.to_path_buf()).unwrap_or_default();
+        let file = path.display().to_string();
+
+        // The stack's own path goes first, so paths in it can be resolved from its directory later on.
+        let mut source = String::new();
+        push_source_marker(&mut source, 1, &file);
+        source.push_str(&expand_includes(&contents, &base_dir, &mut vec![path])?);
+
+        Ok(source)
+    }
+}
+
+// The directory of the file a stack was read from, None for stacks read from stdin or a URL.
+pub fn stack_source_dir(stack_yaml: &str) -> Option<std::path::PathBuf> {
+    let file = stack_source_file(stack_yaml)?;
+
+    std::path::Path::new(file).parent().map(|dir| dir.to_path_buf())
+}
+
+fn stack_source_file(stack_yaml: &str) -> Option<&str> {
+    let marker = stack_yaml.lines().next()?.strip_prefix(INCLUDE_SOURCE_MARKER)?;
+
+    marker.split_once(' ').map(|(_, file)| file)
+}
+
+const INCLUDE_TAG: &str = "!include ";
+/*
+    Expanded includes are surrounded by comments of the form `# torb:source <line> <file>`, saying which line of which
+    file the line after it came from. YAML ignores them, and they let errors point at the user's files.
+*/
+const INCLUDE_SOURCE_MARKER: &str = "# torb:source ";
+
+// Where an include sits decides how the included document is spliced in.
+enum IncludeDirective<'a> {
+    // `key: !include path`, the document becomes the value of key.
+    Value { key: &'a str, path: &'a str },
+    // `- !include path`, the document becomes a sequence item.
+    Item { path: &'a str },
+    // `!include path` on its own, the document's keys are merged into the surrounding mapping.
+    Splice { path: &'a str },
+}
+
+// A comment starts at a # that begins the line or follows whitespace.
+fn strip_yaml_comment(text: &str) -> &str {
+    if text.starts_with('#') {
+        return "";
+    }
+
+    match text.find(" #") {
+        Some(pos) => text[..pos].trim_end(),
+        None => text,
+    }
+}
+
+fn unquote_include_path(path: &str) -> &str {
+    strip_yaml_comment(path.trim()).trim_matches(|c| c == '"' || c == '\'')
+}
+
+// `key: |`, `- >-` and the like, the more indented lines after one are text rather than YAML.
+fn opens_block_scalar(line: &str) -> bool {
+    let content = strip_yaml_comment(line.trim());
+    let indicator = content.rsplit(' ').next().unwrap_or_default();
+    let preceding = content[..content.len() - indicator.len()].trim_end();
+
+    let is_indicator = (indicator.starts_with('|') || indicator.starts_with('>'))
+        && indicator[1..].chars().all(|c| c == '-' || c == '+' || c.is_ascii_digit());
+
+    is_indicator && (preceding.is_empty() || preceding.ends_with(':') || preceding == "-" || preceding.ends_with(" -"))
+}
+
+/*
+    The include directive on each line of a document, if it has one. Comments and the contents of block scalars
+    are skipped, a tag mentioned there is text and not an include.
+*/
+fn include_directives(contents: &str) -> Vec<Option<IncludeDirective<'_>>> {
+    let mut block_scalar_indent: Option<usize> = None;
+
+    contents
+        .lines()
+        .map(|line| {
+            let trimmed = line.trim();
+            let indent = line.len() - line.trim_start().len();
+
+            if let Some(parent_indent) = block_scalar_indent {
+                if trimmed.is_empty() || indent > parent_indent {
+                    return None;
+                }
+
+                block_scalar_indent = None;
+            }
+
+            if trimmed.starts_with('#') {
+                return None;
+            }
+
+            if opens_block_scalar(line) {
+                block_scalar_indent = Some(indent);
+
+                return None;
+            }
+
+            include_directive(line)
+        })
+        .collect()
+}
+
+fn include_directive(line: &str) -> Option<IncludeDirective<'_>> {
+    let trimmed = line.trim();
+
+    if let Some(path) = trimmed.strip_prefix(INCLUDE_TAG) {
+        Some(IncludeDirective::Splice { path: unquote_include_path(path) })
+    } else if let Some(path) = trimmed.strip_prefix("- ").and_then(|item| item.trim_start().strip_prefix(INCLUDE_TAG)) {
+        Some(IncludeDirective::Item { path: unquote_include_path(path) })
+    } else {
+        let pos = trimmed.find(": ")?;
+        let (key, value) = trimmed.split_at(pos);
+        let path = value[2..].trim_start().strip_prefix(INCLUDE_TAG)?;
+
+        Some(IncludeDirective::Value { key, path: unquote_include_path(path) })
+    }
+}
+
+/*
+    Expands `!include <path>` in a stack before it's parsed, paths are relative to the file doing the including.
+    serde_yaml doesn't let us hook custom tags, so this works on the text. The included document is indented to
+    where the tag was, which keeps anchors and aliases working across files since the result is one document.
+    chain holds the files currently being expanded, the first entry being the stack itself.
+*/
+pub fn expand_includes(
+    contents: &str,
+    base_dir: &std::path::Path,
+    chain: &mut Vec<std::path::PathBuf>,
+) -> Result<String, TorbUtilityErrors> {
+    let mut expanded = String::with_capacity(contents.len());
+    let current_file = chain.last().map(|file| file.display().to_string()).unwrap_or_default();
+
+    for ((idx, line), directive) in contents.lines().enumerate().zip(include_directives(contents)) {
+        let directive = match directive {
+            Some(directive) => directive,
+            None => {
+                expanded.push_str(line);
+                expanded.push('\n');
+                continue;
+            }
+        };
+
+        let indent = line.len() - line.trim_start().len();
+
+        let (header, path, content_indent) = match directive {
+            IncludeDirective::Value { key, path } => {
+                // For `- key: !include path` the value nests under key's column, not the dash's.
+                let key_column = match key.strip_prefix('-') {
+                    Some(rest) if rest.starts_with(' ') => indent + key.len() - rest.trim_start().len(),
+                    _ => indent,
+                };
+
+                (Some(format!("{}:", key)), path, key_column + 2)
+            }
+            IncludeDirective::Item { path } => (Some("-".to_string()), path, indent + 2),
+            IncludeDirective::Splice { path } => (None, path, indent),
+        };
+
+        let unable_to_include = |reason: String| TorbUtilityErrors::UnableToReadInclude {
+            path: path.to_string(),
+            included_from: current_file.clone(),
+            reason,
+        };
+
+        let include_path = std::fs::canonicalize(base_dir.join(path)).map_err(|err| unable_to_include(err.to_string()))?;
+
+        if chain.contains(&include_path) {
+            let mut files: Vec<String> = chain.iter().map(|file| file.display().to_string()).collect();
+            files.push(include_path.display().to_string());
+
+            return Err(TorbUtilityErrors::CircularInclude { chain: files.join(" -> ") });
+        }
+
+        let include_contents =
+            std::fs::read_to_string(&include_path).map_err(|err| unable_to_include(err.to_string()))?;
+        let include_dir = include_path.parent().map(|dir| dir.to_path_buf()).unwrap_or_default();
+
+        let include_file = include_path.display().to_string();
+
+        chain.push(include_path);
+        let included = expand_includes(&include_contents, &include_dir, chain)?;
+        chain.pop();
+
+        if let Some(header) = header {
+            push_source_marker(&mut expanded, idx + 1, &current_file);
+            expanded.push_str(&" ".repeat(indent));
+            expanded.push_str(&header);
+            expanded.push('\n');
+        }
+
+        push_source_marker(&mut expanded, 1, &include_file);
+
+        // Document markers are blanked rather than dropped so every included line stays on its own line.
+        for included_line in included.lines() {
+            if !included_line.trim().is_empty() && included_line.trim_end() != "---" {
+                expanded.push_str(&" ".repeat(content_indent));
+                expanded.push_str(included_line);
+            }
+            expanded.push('\n');
+        }
+
+        push_source_marker(&mut expanded, idx + 2, &current_file);
+    }
+
+    Ok(expanded)
+}
+
+fn push_source_marker(expanded: &mut String, line: usize, file: &str) {
+    expanded.push_str(&format!("{}{} {}\n", INCLUDE_SOURCE_MARKER, line, file));
+}
+
+/*
+    The file and line a 1-based line of an expanded stack came from. The file is None for lines of the stack itself,
+    which is every line when nothing was included.
+*/
+pub fn include_source_line(expanded: &str, line: usize) -> (Option<String>, usize) {
+    let stack_file = stack_source_file(expanded);
+    let mut source = (None, line);
+
+    for (idx, text) in expanded.lines().enumerate().take(line.saturating_sub(1)) {
+        if let Some(marker) = text.trim_start().strip_prefix(INCLUDE_SOURCE_MARKER) {
+            if let Some((source_line, file)) = marker.split_once(' ') {
+                if let Ok(source_line) = source_line.parse::<usize>() {
+                    let file = Some(file).filter(|file| Some(*file) != stack_file).map(|file| file.to_string());
+
+                    source = (file, source_line + (line - idx - 2));
+                }
+            }
+        }
+    }
+
+    source
+}
+
+/*
+    serde_yaml resolves anchors and aliases but leaves YAML merge keys as a literal "<<" entry, so they're applied
+    here. Keys set alongside the merge win over the merged ones, and for a list of merges the earlier entries win.
+*/
+pub fn apply_yaml_merge_keys(value: serde_yaml::Value) -> serde_yaml::Value {
+    match value {
+        serde_yaml::Value::Mapping(map) => {
+            let merge_key = serde_yaml::Value::String("<<".to_string());
+            let mut merged = serde_yaml::Mapping::new();
+            let mut sources = Vec::new();
+
+            for (key, val) in map {
+                if key == merge_key {
+                    match val {
+                        serde_yaml::Value::Sequence(seq) => sources.extend(seq),
+                        other => sources.push(other),
+                    }
+                } else {
+                    merged.insert(key, apply_yaml_merge_keys(val));
+                }
+            }
+
+            for source in sources {
+                if let serde_yaml::Value::Mapping(source_map) = apply_yaml_merge_keys(source) {
+                    for (key, val) in source_map {
+                        if !merged.contains_key(&key) {
+                            merged.insert(key, val);
+                        }
+                    }
+                }
+            }
+
+            serde_yaml::Value::Mapping(merged)
+        }
+        serde_yaml::Value::Sequence(seq) => {
+            serde_yaml::Value::Sequence(seq.into_iter().map(apply_yaml_merge_keys).collect())
+        }
+        other => other,
+    }
+}
+
+// Everything `torb init` sets up that stack commands rely on, checked in the order init creates them.
+pub fn check_initialized() -> Result<(), TorbUtilityErrors> {
+    let torb_path = torb_path();
+    let repositories_path = torb_path.join("repositories");
+
+    let required = [
+        torb_path.clone(),
+        repositories_path.clone(),
+        repositories_path.join("torb-artifacts"),
+        torb_path.join("terraform"),
+    ];
+
+    match required.iter().find(|path| !path.exists()) {
+        Some(path) => Err(TorbUtilityErrors::NotInitialized {
+            missing: path.display().to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/*
+    torb-artifacts declares the Torb versions its units work with in compatibility.yaml, as min_torb_version and
+    an optional max_torb_version. Artifacts without the file predate it and aren't checked.
+*/
+pub fn check_artifacts_compatibility(version: &str) -> Result<String, TorbUtilityErrors> {
+    let path = torb_path()
+        .join("repositories")
+        .join("torb-artifacts")
+        .join(COMPATIBILITY_FILE);
+
+    if !path.exists() {
+        return Ok(format!(
+            "The installed torb-artifacts has no {}, skipping the compatibility check.",
+            COMPATIBILITY_FILE
+        ));
+    }
+
+    let invalid = |reason: String| TorbUtilityErrors::InvalidCompatibilityFile {
+        path: path.display().to_string(),
+        reason,
+    };
+
+    let contents = std::fs::read_to_string(&path).map_err(|err| invalid(err.to_string()))?;
+    let yaml: serde_yaml::Value = serde_yaml::from_str(&contents).map_err(|err| invalid(err.to_string()))?;
+
+    let current = parse_version(version).ok_or_else(|| invalid(format!("{} isn't a valid version.", version)))?;
+
+    let bound = |key: &str| -> Result<Option<(String, Vec<u64>)>, TorbUtilityErrors> {
+        let raw = match yaml.get(key) {
+            None | Some(serde_yaml::Value::Null) => return Ok(None),
+            Some(serde_yaml::Value::String(val)) => val.clone(),
+            Some(serde_yaml::Value::Number(val)) => val.to_string(),
+            Some(_) => return Err(invalid(format!("{} must be a version string.", key))),
+        };
+
+        let parsed = parse_version(&raw)
+            .ok_or_else(|| invalid(format!("{} is set to {}, which isn't a valid version.", key, raw)))?;
+
+        Ok(Some((raw, parsed)))
+    };
+
+    let incompatible = |reason: String| TorbUtilityErrors::IncompatibleArtifacts {
+        version: version.to_string(),
+        reason,
+    };
+
+    if let Some((min, parsed)) = bound("min_torb_version")? {
+        if current < parsed {
+            return Err(incompatible(format!("they need at least Torb {}.", min)));
+        }
+    }
+
+    if let Some((max, parsed)) = bound("max_torb_version")? {
+        if current > parsed {
+            return Err(incompatible(format!("they support Torb up to {}.", max)));
+        }
+    }
+
+    Ok(format!("Torb {} is compatible with the installed torb-artifacts.", version))
+}
+
+// Versions are compared by their major.minor.patch numbers, a leading v and any pre-release or build suffix are ignored.
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim().trim_start_matches('v');
+    let release = version.split(&['-', '+'][..]).next()?;
+
+    let mut parts = release
+        .split('.')
+        .map(|part| part.parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+
+    parts.resize(3, 0);
+
+    Some(parts)
+}
+
+/*
+    The buildstate lives in .torb_buildstate in the project root unless TORB_BUILDSTATE_DIR is set,
+    which --buildstate-dir sets for the rest of the process. A relative directory is taken from the project root.
+*/
+pub fn buildstate_path() -> Result<std::path::PathBuf, TorbUtilityErrors> {
+    match std::env::var_os(BUILDSTATE_DIR_ENV) {
+        Some(dir) if !dir.is_empty() => Ok(absolute_project_path(dir)),
+        _ => Ok(project_path(BUILDSTATE_DIR)),
+    }
+}
+
+/*
+    Exported as an absolute path, since Terraform, hooks and build scripts read the buildstate from their own
+    working directories and a relative TORB_BUILDSTATE_DIR would point somewhere else for them.
+*/
+pub fn set_buildstate_dir(dir: &str) {
+    std::env::set_var(BUILDSTATE_DIR_ENV, absolute_project_path(dir));
+}
+
+// Canonicalized when it exists, so the same buildstate is always named by the same path.
+fn absolute_project_path(path: impl AsRef<std::path::Path>) -> std::path::PathBuf {
+    let path = project_path(path);
+
+    std::fs::canonicalize(&path).unwrap_or(path)
+}
+
+/*
+    Meta stacks are shared between stacks, so they keep one buildstate per meta under ~/.torb rather than next to a stack.
+    It's named after the stack and a hash of the meta stack file's path, so unrelated metas that happen to share a name
+    don't apply each other's Terraform state.
+*/
+pub fn meta_buildstate_path(stack_name: &str, stack_file: Option<&std::path::Path>) -> std::path::PathBuf {
+    let dir_name = match stack_file {
+        Some(stack_file) => {
+            let hash = BASE32.encode(&Sha256::digest(stack_file.display().to_string().as_bytes()));
+
+            format!("{}_{}", stack_name, hash[..8].to_lowercase())
+        }
+        None => stack_name.to_string(),
+    };
+
+    torb_path().join("meta").join(dir_name)
+}
+
+pub fn buildstate_path_or_create() -> Result<std::path::PathBuf, TorbUtilityErrors> {
+    create_buildstate_dir(buildstate_path()?)
+}
+
+// For buildstates other than the project's, like a meta stack's.
+pub fn create_buildstate_dir(buildstate_path: std::path::PathBuf) -> Result<std::path::PathBuf, TorbUtilityErrors> {
+    if !buildstate_path.exists() {
+        std::fs::create_dir_all(&buildstate_path).map_err(|err| {
+            TorbUtilityErrors::UnableToCreateBuildstate {
+                path: buildstate_path.display().to_string(),
+                reason: err.to_string(),
+            }
+        })?;
     }
+
+    Ok(buildstate_path)
+}
+
+const BUILDSTATE_LOCK_FILE: &str = "torb.lock";
+
+// Held for the length of a build, deploy or watch, the lock is released when the file is closed.
+pub struct BuildstateLock {
+    _file: std::fs::File,
 }
 
+/*
+    Builds, deploys and watches in the same project share the buildstate and the Terraform state, so only one of them
+    can run at a time. The lock is an flock on the lock file, which the OS releases when the process holding it exits
+    however it exits, so there's no stale lock to take over. A second operation fails straight away instead of waiting,
+    the pid and operation written to the file say who holds it.
+*/
+pub fn acquire_buildstate_lock(operation: &str) -> Result<BuildstateLock, TorbUtilityErrors> {
+    acquire_buildstate_lock_in(buildstate_path()?, operation)
+}
+
+pub fn acquire_buildstate_lock_in(
+    buildstate_path: std::path::PathBuf,
+    operation: &str,
+) -> Result<BuildstateLock, TorbUtilityErrors> {
+    let path = create_buildstate_dir(buildstate_path)?.join(BUILDSTATE_LOCK_FILE);
+    let lock_error = |reason: String| TorbUtilityErrors::UnableToLockBuildstate {
+        path: path.display().to_string(),
+        reason,
+    };
+
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(&path)
+        .map_err(|err| lock_error(err.to_string()))?;
+
+    // The file is truncated only once the lock is held, so the holder's pid is never wiped by a process that fails to lock.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = io::Error::last_os_error();
+
+        if err.kind() != io::ErrorKind::WouldBlock {
+            return Err(lock_error(err.to_string()));
+        }
+
+        let contents = std::fs::read_to_string(&path).unwrap_or_default();
+        let mut lines = contents.lines();
+
+        return Err(TorbUtilityErrors::BuildstateLocked {
+            pid: lines.next().map(|pid| pid.trim().to_string()).unwrap_or_else(|| "unknown".to_string()),
+            operation: lines.next().unwrap_or("unknown").to_string(),
+            path: path.display().to_string(),
+        });
+    }
+
+    file.set_len(0)
+        .and_then(|_| file.write_all(format!("{}\n{}\n", std::process::id(), operation).as_bytes()))
+        .map_err(|err| lock_error(err.to_string()))?;
+
+    Ok(BuildstateLock { _file: file })
+}
+
+// Repositories are visited in name order, read_dir's order depends on the filesystem and would leak into build files.
 pub fn for_each_artifact_repository(
     mut closure: Box<dyn FnMut(std::path::PathBuf, DirEntry) -> () + '_>,
 ) -> Result<(), Box<dyn Error>> {
     let path = torb_path();
     let repo_path = path.join("repositories");
 
-    let repos = std::fs::read_dir(&repo_path)?;
-
-    for repo_res in repos {
-        let repo = repo_res?;
+    let mut repos = std::fs::read_dir(&repo_path)?.collect::<Result<Vec<DirEntry>, std::io::Error>>()?;
+    repos.sort_by_key(|repo| repo.file_name());
 
+    for repo in repos {
         closure(repo_path.clone(), repo);
     }
 
@@ -101,18 +971,37 @@ pub fn for_each_artifact_repository(
 pub fn run_command_in_user_shell(
     command_str: String,
     shell_override: Option<String>,
+    env: &[(String, String)],
+    timeout: Option<Duration>,
 ) -> Result<std::process::Output, Box<dyn std::error::Error>> {
+    run_command_in_user_shell_from(&project_root(), command_str, shell_override, env, timeout)
+}
+
+// Like run_command_in_user_shell, from a directory other than the project root.
+pub fn run_command_in_user_shell_from(
+    dir: &std::path::Path,
+    command_str: String,
+    shell_override: Option<String>,
+    env: &[(String, String)],
+    timeout: Option<Duration>,
+) -> Result<std::process::Output, Box<dyn std::error::Error>> {
+    // SHELL isn't set in most containers and CI runners, /bin/sh is always there.
     let shell = match shell_override {
         Some(sh) => sh,
-        None => std::env::var("SHELL").unwrap(),
+        None => std::env::var("SHELL").ok().filter(|sh| !sh.is_empty()).unwrap_or_else(|| "/bin/sh".to_string()),
     };
 
     let shell_args = vec!["-c".to_string(), command_str.to_string()];
 
     let mut command = std::process::Command::new(shell.clone());
     command.args(shell_args);
+    command.envs(env.iter().map(|(key, value)| (key, value)));
+    command.current_dir(dir);
 
-    let output = command.output()?;
+    let output = match timeout {
+        Some(_) => CommandPipeline::spawn_and_collect_output(&mut command, timeout, &OutputMode::Buffered)?,
+        None => command.output()?,
+    };
 
     if output.status.success() {
         Ok(output)
@@ -129,14 +1018,56 @@ pub fn checksum(data: String, original_hash: String) -> bool {
     let hash = Sha256::digest(data.as_bytes());
     let hash_base32 = BASE32.encode(&hash);
 
-    println!("hash: {}", hash_base32);
-    println!("original_hash: {}", original_hash);
+    debug!("hash: {}", hash_base32);
+    debug!("original_hash: {}", original_hash);
 
     hash_base32 == original_hash
 }
 
+const RETRY_MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+/*
+    Runs f until it succeeds or it has been tried attempts times, waiting initial_backoff after the first failure and
+    doubling the wait after each one after that, up to RETRY_MAX_BACKOFF. The error from the last attempt is returned.
+*/
+pub fn retry_with_backoff<T, E: std::fmt::Display>(
+    attempts: u32,
+    initial_backoff: Duration,
+    mut f: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut backoff = initial_backoff;
+    let mut attempt = 1;
+
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt >= attempts => return Err(err),
+            Err(err) => {
+                debug!("Attempt {} of {} failed, retrying in {:?}: {}", attempt, attempts, backoff, err);
+
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(RETRY_MAX_BACKOFF);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 pub struct CommandPipeline {
-    commands: Vec<Command>,
+    commands: Vec<(Command, Option<Duration>, OutputMode)>,
+}
+
+/*
+    Buffered commands are silent until they finish, which is what callers parsing the output need.
+    Streamed commands forward each line to the terminal as it's written, prefixed so interleaved output can be told apart,
+    and still collect it so errors can report stderr.
+*/
+#[derive(Debug, Clone)]
+pub enum OutputMode {
+    Buffered,
+    Streamed(String),
 }
 
 #[derive(Debug, Clone)]
@@ -144,6 +1075,8 @@ pub struct CommandConfig<'a> {
     command: &'a str,
     args: Vec<&'a str>,
     working_dir: Option<&'a str>,
+    timeout: Option<Duration>,
+    output_mode: OutputMode,
 }
 
 impl<'a> CommandConfig<'a> {
@@ -156,8 +1089,30 @@ impl<'a> CommandConfig<'a> {
             command: command,
             args: args,
             working_dir: working_dir,
+            timeout: None,
+            output_mode: OutputMode::Buffered,
+        }
+    }
+
+    fn kube_args(&self) -> Vec<String> {
+        match self.command {
+            "kubectl" | "helm" => kube_target().args(self.command),
+            _ => Vec::new(),
         }
     }
+
+    pub fn with_streamed_output(mut self, prefix: &str) -> CommandConfig<'a> {
+        self.output_mode = OutputMode::Streamed(prefix.to_string());
+
+        self
+    }
+
+    // Commands with a timeout are killed once it passes instead of being waited on forever.
+    pub fn with_timeout(mut self, timeout: Duration) -> CommandConfig<'a> {
+        self.timeout = Some(timeout);
+
+        self
+    }
 }
 
 impl CommandPipeline {
@@ -172,11 +1127,13 @@ impl CommandPipeline {
                     command.arg(arg);
                 });
 
+                command.args(conf.kube_args());
+
                 if conf.working_dir.is_some() {
                     command.current_dir(conf.working_dir.unwrap());
                 };
 
-                command
+                (command, conf.timeout, conf.output_mode.clone())
             })
             .collect();
 
@@ -192,25 +1149,145 @@ impl CommandPipeline {
             command.arg(arg);
         });
 
+        command.args(conf.kube_args());
+
         if conf.working_dir.is_some() {
             command.current_dir(conf.working_dir.unwrap());
         };
 
-        CommandPipeline::run_command(&mut command)
+        CommandPipeline::run_command(&mut command, conf.timeout, &conf.output_mode)
     }
 
     pub fn execute(&mut self) -> Result<Vec<std::process::Output>, Box<dyn Error>> {
         let outputs: Result<Vec<Output>, Box<dyn std::error::Error>> = self
             .commands
             .iter_mut()
-            .map(CommandPipeline::run_command)
+            .map(|(command, timeout, output_mode)| {
+                CommandPipeline::run_command(command, *timeout, output_mode)
+            })
             .collect();
 
         outputs
     }
 
-    fn run_command(command: &mut Command) -> Result<std::process::Output, Box<dyn Error>> {
-        let output = command.output()?;
+    fn read_pipe_in_background<R: Read + Send + 'static>(
+        pipe: Option<R>,
+        prefix: Option<String>,
+        to_stderr: bool,
+    ) -> JoinHandle<Vec<u8>> {
+        std::thread::spawn(move || {
+            let mut buf = Vec::new();
+
+            match (pipe, prefix) {
+                (Some(mut pipe), None) => {
+                    let _ = pipe.read_to_end(&mut buf);
+                }
+                (Some(pipe), Some(prefix)) => {
+                    let mut reader = BufReader::new(pipe);
+                    let mut line = Vec::new();
+                    let tag = format!("[{}]", prefix).cyan();
+
+                    while let Ok(read) = reader.read_until(b'\n', &mut line) {
+                        if read == 0 {
+                            break;
+                        }
+
+                        let text = String::from_utf8_lossy(&line);
+                        let text = text.trim_end_matches(&['\r', '\n'][..]);
+
+                        if to_stderr {
+                            let _ = writeln!(io::stderr(), "{} {}", tag, text);
+                        } else {
+                            let _ = writeln!(io::stdout(), "{} {}", tag, text);
+                        }
+
+                        buf.append(&mut line);
+                    }
+                }
+                (None, _) => {}
+            }
+
+            buf
+        })
+    }
+
+    /*
+        Spawns the command and waits on it, polling when there's a timeout so it can be killed once the timeout passes.
+        The pipes are drained on separate threads so a chatty command can't block on a full pipe while we wait,
+        for streamed commands those threads also forward each line to the terminal as it arrives.
+    */
+    fn spawn_and_collect_output(
+        command: &mut Command,
+        timeout: Option<Duration>,
+        output_mode: &OutputMode,
+    ) -> Result<Output, Box<dyn Error>> {
+        let start = Instant::now();
+
+        let prefix = match output_mode {
+            OutputMode::Streamed(prefix) => Some(prefix.clone()),
+            OutputMode::Buffered => None,
+        };
+
+        /*
+            Commands with a timeout get their own process group, so when it passes everything they started is killed
+            along with them, rather than leaving e.g. the command a bash -c was running behind as an orphan.
+        */
+        if timeout.is_some() {
+            command.process_group(0);
+        }
+
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let stdout_handle =
+            CommandPipeline::read_pipe_in_background(child.stdout.take(), prefix.clone(), false);
+        let stderr_handle =
+            CommandPipeline::read_pipe_in_background(child.stderr.take(), prefix, true);
+
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+
+            let timeout = match timeout {
+                Some(timeout) => timeout,
+                None => break child.wait()?,
+            };
+
+            let elapsed = start.elapsed();
+
+            if elapsed >= timeout {
+                unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) };
+                let _ = child.wait();
+
+                return Err(Box::new(TorbUtilityErrors::CommandTimedOut {
+                    command: format!("{:?}", command),
+                    elapsed,
+                }));
+            }
+
+            std::thread::sleep(COMMAND_POLL_INTERVAL.min(timeout - elapsed));
+        };
+
+        Ok(Output {
+            status,
+            stdout: stdout_handle.join().unwrap_or_default(),
+            stderr: stderr_handle.join().unwrap_or_default(),
+        })
+    }
+
+    fn run_command(
+        command: &mut Command,
+        timeout: Option<Duration>,
+        output_mode: &OutputMode,
+    ) -> Result<std::process::Output, Box<dyn Error>> {
+        let output = match (timeout, output_mode) {
+            (None, OutputMode::Buffered) => command.output()?,
+            _ => CommandPipeline::spawn_and_collect_output(command, timeout, output_mode)?,
+        };
 
         if output.status.success() {
             Ok(output)
@@ -277,6 +1354,33 @@ pub fn get_resource_kind(
     res
 }
 
+/*
+    What use_or_pretty_exit exits with, so scripts can branch on why Torb failed. Anything that isn't
+    tagged with a category exits with General, and panics keep Rust's exit code of 101.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitCategory {
+    General,
+    Resolution,
+    Build,
+    Compose,
+    Deploy,
+    Validation,
+}
+
+impl ExitCategory {
+    pub fn code(&self) -> i32 {
+        match self {
+            ExitCategory::General => 1,
+            ExitCategory::Resolution => 2,
+            ExitCategory::Build => 3,
+            ExitCategory::Compose => 4,
+            ExitCategory::Deploy => 5,
+            ExitCategory::Validation => 6,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct PrettyContext<'a> {
     success_marquee_msg: Option<&'a str>,
@@ -284,6 +1388,7 @@ pub struct PrettyContext<'a> {
     warning: Option<&'a str>,
     error_context: &'a str,
     suggestions: Vec<&'a str>,
+    exit_category: ExitCategory,
 }
 
 impl<'a> Default for PrettyContext<'a> {
@@ -294,6 +1399,7 @@ impl<'a> Default for PrettyContext<'a> {
             warning: None,
             error_context: "",
             suggestions: Vec::new(),
+            exit_category: ExitCategory::General,
         }
     }
 }
@@ -326,6 +1432,12 @@ impl<'a> PrettyContext<'a> {
         self
     }
 
+    pub fn exit_category(&mut self, category: ExitCategory) -> &mut Self {
+        self.exit_category = category;
+
+        self
+    }
+
     pub fn pretty(&mut self) -> Self {
         self.clone()
     }
@@ -420,7 +1532,7 @@ impl<T, E> PrettyExit<T, E> for Result<T, E> {
                 self.display_error_call_to_action(&context);
 
                 if exit {
-                    std::process::exit(1);
+                    std::process::exit(context.exit_category.code());
                 } else {
                     None
                 }
@@ -464,3 +1576,73 @@ impl<T, E> PrettyExit<T, E> for Result<T, E> {
         println!("\n https://github.com/TorbFoundry/torb/issues/new \n");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timed_out_commands_take_what_they_started_with_them() {
+        let pid_file = std::env::temp_dir().join(format!("torb_timeout_test_{}", std::process::id()));
+        let script = format!("sleep 30 & echo $! > {}; wait", pid_file.display());
+        let conf = CommandConfig::new("/bin/sh", vec!["-c", &script], None).with_timeout(Duration::from_millis(500));
+
+        assert!(CommandPipeline::execute_single(conf).is_err());
+        std::thread::sleep(Duration::from_millis(100));
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let _ = std::fs::remove_file(&pid_file);
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())).unwrap_or_default();
+        let running = stat.rsplit(") ").next().map_or(false, |rest| !rest.starts_with('Z'));
+
+        assert!(!running, "sleep {} outlived the timed out command", pid.trim());
+    }
+
+    #[test]
+    fn include_directives_skip_comments_and_block_scalars() {
+        let stack = "name: test\n# db: !include ./db.yaml\nnotes: |\n  db: !include ./db.yaml\n\n  more text\ndb: !include ./db.yaml # the database\n";
+        let directives = include_directives(stack);
+
+        assert_eq!(directives.iter().filter(|directive| directive.is_some()).count(), 1);
+        assert!(directives[6].is_some());
+        assert_eq!(unquote_include_path("./db.yaml # the database"), "./db.yaml");
+    }
+
+    #[test]
+    fn shell_quote_escapes_single_quotes() {
+        assert_eq!(shell_quote("my ns"), "'my ns'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn expanded_lines_map_back_to_their_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let stack_path = dir.path().join("stack.yaml");
+        let include_path = dir.path().join("db.yaml");
+        std::fs::write(&include_path, "---\nchart: postgres\nbad: value\n").unwrap();
+
+        let stack = "name: test\ndb: !include ./db.yaml\nafter: true\n";
+        let mut chain = vec![stack_path];
+        let expanded = expand_includes(stack, dir.path(), &mut chain).unwrap();
+        let line_of = |text: &str| expanded.lines().position(|line| line.contains(text)).unwrap() + 1;
+
+        let include_file = std::fs::canonicalize(&include_path).unwrap().display().to_string();
+        assert_eq!(include_source_line(&expanded, line_of("bad: value")), (Some(include_file), 3));
+        assert_eq!(include_source_line(&expanded, line_of("after: true")).1, 3);
+        assert_eq!(include_source_line(&expanded, 1), (None, 1));
+    }
+
+    #[test]
+    fn includes_under_a_sequence_items_key_nest_under_the_key() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("db.yaml"), "chart: postgres\n").unwrap();
+
+        let stack = "deploys:\n  - db: !include ./db.yaml\n    name: primary\n";
+        let mut chain = vec![dir.path().join("stack.yaml")];
+        let expanded = expand_includes(stack, dir.path(), &mut chain).unwrap();
+        let parsed: serde_yaml::Value = serde_yaml::from_str(&expanded).unwrap();
+
+        assert_eq!(parsed["deploys"][0]["db"]["chart"].as_str(), Some("postgres"));
+        assert_eq!(parsed["deploys"][0]["name"].as_str(), Some("primary"));
+    }
+}
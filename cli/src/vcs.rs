@@ -9,6 +9,10 @@
 //
 // See LICENSE file at https://github.com/TorbFoundry/torb/blob/main/LICENSE for details.
 
+use crate::utils::{http_agent, torb_path, PrettyContext, PrettyExit};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::fs;
 use std::path::PathBuf;
@@ -16,6 +20,9 @@ use std::process::Command;
 use thiserror::Error;
 use ureq::{AgentBuilder};
 
+const MANAGED_REPOS_FILE: &str = "repos.yaml";
+const GITHUB_API_URL: &str = "https://api.github.com";
+
 #[derive(Error, Debug)]
 pub enum TorbVCSErrors {
     #[error("Cannot create repo directory at: {path:?}, reason: {response:?}")]
@@ -28,6 +35,40 @@ pub enum TorbVCSErrors {
     UnableToPushToRemoteRepo { response: String },
     #[error("Unable to push to init readme, reason: {response:?}")]
     UnableToInitReadme { response: String },
+    #[error("Unable to delete remote repo, reason: {response:?}")]
+    UnableToDeleteRemoteRepo { response: String },
+    #[error("{remote} is a generic SSH remote, which Torb has no API to delete. Delete it on the Git server instead and run torb repo delete with --local-only.")]
+    UnsupportedRemoteDelete { remote: String },
+}
+
+// Repos created by Torb are tracked in ~/.torb/repos.yaml so they can be listed and cleaned up later.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ManagedRepo {
+    pub path: PathBuf,
+    pub remote: Option<String>,
+    pub created: String,
+}
+
+pub fn load_managed_repos() -> Result<Vec<ManagedRepo>, Box<dyn Error>> {
+    let state_path = torb_path().join(MANAGED_REPOS_FILE);
+
+    if !state_path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let contents = fs::read_to_string(state_path)?;
+    let repos: Option<Vec<ManagedRepo>> = serde_yaml::from_str(&contents)?;
+
+    Ok(repos.unwrap_or_default())
+}
+
+pub fn save_managed_repos(repos: &Vec<ManagedRepo>) -> Result<(), Box<dyn Error>> {
+    let state_path = torb_path().join(MANAGED_REPOS_FILE);
+    let contents = serde_yaml::to_string(repos)?;
+
+    fs::write(state_path, contents)?;
+
+    Ok(())
 }
 trait Or: Sized {
     fn or(self, other: Self) -> Self;
@@ -39,10 +80,11 @@ impl<'a> Or for &'a str {
     }
 }
 mod private {
-    use super::GithubVCS;
+    use super::{GenericSSHVCS, GithubVCS};
 
     pub trait Sealed {}
     impl Sealed for GithubVCS {}
+    impl Sealed for GenericSSHVCS {}
 }
 
 pub trait GitVersionControlHelpers: private::Sealed {
@@ -94,8 +136,8 @@ pub trait GitVersionControlHelpers: private::Sealed {
     fn add_remote_origin(&self) -> Result<(), TorbVCSErrors> {
         let repo_name = self.get_repo_name().unwrap().to_string();
         let error_msg_remote = format!("Failed to add remote: {:?}", repo_name);
-        let remote_repo = format!("{}:{}/{}", self.get_address(), self.get_user(), repo_name);
-        println!("remote: {:?}", remote_repo.clone());
+        let remote_repo = self.get_remote_url(&repo_name);
+        debug!("remote: {:?}", remote_repo.clone());
 
         let git_remote_command = Command::new("git")
             .arg("remote")
@@ -162,6 +204,10 @@ pub trait GitVersionControlHelpers: private::Sealed {
     fn get_address(&self) -> String;
     fn get_user(&self) -> String;
 
+    fn get_remote_url(&self, repo_name: &str) -> String {
+        format!("{}:{}/{}", self.get_address(), self.get_user(), repo_name)
+    }
+
     fn get_repo_name(&self) -> Option<String> {
         let cwd = self.get_cwd();
 
@@ -181,6 +227,8 @@ pub trait GitVersionControlHelpers: private::Sealed {
 pub trait GitVersionControl: GitVersionControlHelpers {
     fn create_remote_repo(&self) -> Result<String, Box<dyn std::error::Error>>;
 
+    fn delete_remote_repo(&self, remote: &str) -> Result<(), Box<dyn std::error::Error>>;
+
     fn create_local_repo(
         &self
     ) -> Result<PathBuf, Box<dyn std::error::Error>> {
@@ -297,7 +345,7 @@ impl GitVersionControl for GithubVCS {
         and an async client with the changes to a rust project needed to typically support async does not
         seem like the right move to me. - Ian
         */
-        let req_string = format!("https://api.github.com/user/repos");
+        let req_string = format!("{}/user/repos", GITHUB_API_URL);
         let req = self
             .agent
             .post(&req_string)
@@ -314,6 +362,30 @@ impl GitVersionControl for GithubVCS {
         Ok(resp)
     }
 
+    fn delete_remote_repo(&self, remote: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let token = self.get_api_token();
+
+        let req_string = format!("{}/repos/{}", GITHUB_API_URL, remote);
+        let req = self
+            .agent
+            .delete(&req_string)
+            .set("Authorization", &format!("Bearer {}", token));
+
+        match req.call() {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(code, resp)) => {
+                let body = resp.into_string().unwrap_or_default();
+
+                Err(Box::new(TorbVCSErrors::UnableToDeleteRemoteRepo {
+                    response: format!("{} {}", code, body),
+                }))
+            }
+            Err(err) => Err(Box::new(TorbVCSErrors::UnableToDeleteRemoteRepo {
+                response: err.to_string(),
+            })),
+        }
+    }
+
     fn _get_api_token(&self) -> String {
         self.api_token.clone()
     }
@@ -339,7 +411,13 @@ impl GitVersionControl for GithubVCS {
 
 impl GithubVCS {
     pub fn new(api_token: String, user: String) -> GithubVCS {
-        let agent = AgentBuilder::new().build();
+        let agent = http_agent(GITHUB_API_URL)
+            .use_or_pretty_warn(
+                PrettyContext::default()
+                .warn("Warning! Unable to use the configured proxy, connecting to GitHub directly.")
+                .pretty()
+            )
+            .unwrap_or_else(|| AgentBuilder::new().build());
 
         GithubVCS {
             api_token: api_token,
@@ -350,3 +428,84 @@ impl GithubVCS {
         }
     }
 }
+
+/*
+    For Git over SSH without an API to create repos with, like Gitea or plain bare repos on a server. The remote
+    URL comes from a template in config.yaml, creating the remote is left to the server, either it already exists
+    or it's created by the first push.
+*/
+pub struct GenericSSHVCS {
+    remote_template: String,
+    user: String,
+    cwd: PathBuf,
+}
+
+impl GitVersionControlHelpers for GenericSSHVCS {
+    fn get_user(&self) -> String {
+        self._get_user()
+    }
+
+    fn get_address(&self) -> String {
+        self._get_address()
+    }
+
+    fn get_cwd(&self) -> PathBuf {
+        self._get_cwd()
+    }
+
+    fn get_remote_url(&self, repo_name: &str) -> String {
+        self.remote_template
+            .replace("{name}", repo_name)
+            .replace("{user}", &self.user)
+    }
+}
+
+impl GitVersionControl for GenericSSHVCS {
+    fn create_remote_repo(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let name = self.get_repo_name().unwrap();
+        let remote = self.get_remote_url(&name);
+
+        debug!("Not creating {}, generic remotes are expected to exist or be created on push.", remote);
+
+        Ok(remote)
+    }
+
+    fn delete_remote_repo(&self, remote: &str) -> Result<(), Box<dyn std::error::Error>> {
+        Err(Box::new(TorbVCSErrors::UnsupportedRemoteDelete {
+            remote: remote.to_string(),
+        }))
+    }
+
+    fn _get_api_token(&self) -> String {
+        "".to_string()
+    }
+
+    fn _get_user(&self) -> String {
+        self.user.clone()
+    }
+
+    // The whole remote comes from the template, so there's no address of its own.
+    fn _get_address(&self) -> String {
+        "".to_string()
+    }
+
+    fn _get_cwd(&self) -> PathBuf {
+        self.cwd.clone()
+    }
+
+    fn _set_cwd(&mut self, directory: PathBuf) -> PathBuf {
+        self.cwd = directory;
+
+        self.cwd.clone()
+    }
+}
+
+impl GenericSSHVCS {
+    pub fn new(remote_template: String, user: String) -> GenericSSHVCS {
+        GenericSSHVCS {
+            remote_template,
+            user,
+            cwd: PathBuf::new(),
+        }
+    }
+}
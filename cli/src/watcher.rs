@@ -9,19 +9,21 @@
 //
 // See LICENSE file at https://github.com/TorbFoundry/torb/blob/main/LICENSE for details.
 
-use crate::artifacts::{write_build_file, ArtifactRepr};
-use crate::builder::StackBuilder;
-// use crate::deployer::StackDeployer;
-use crate::composer::Composer;
-use crate::deployer::StackDeployer;
-use crate::utils::buildstate_path_or_create;
-use crate::utils::{
-    get_resource_kind, CommandConfig, CommandPipeline, PrettyContext, PrettyExit, ResourceKind,
+use torb::artifacts::{write_build_file, ArtifactRepr, UnmappedChanges, WatchPath};
+use torb::builder::{ContainerBuilder, StackBuilder};
+// use torb::deployer::StackDeployer;
+use torb::composer::Composer;
+use torb::deployer::StackDeployer;
+use torb::utils::{buildstate_path_or_create, project_path, read_stack_source};
+use torb::utils::{
+    get_resource_kind, retry_with_backoff, CommandConfig, CommandPipeline, ExitCategory, PrettyContext, PrettyExit,
+    ResourceKind,
 };
 
+use log::{error, info};
 use std::collections::HashSet;
 use std::sync::{Arc, Mutex, MutexGuard};
-use std::{sync::PoisonError, time::Duration};
+use std::{sync::PoisonError, time::Duration, time::Instant};
 use indexmap::IndexMap;
 use tokio::{
     runtime::Runtime,
@@ -29,117 +31,235 @@ use tokio::{
     time,
 };
 
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use thiserror::Error;
 use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher as NotifyWatcher};
-use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct WatcherConfig {
-    paths: Vec<String>,
-    interval: u64,
-    patch: bool,
-    exempt: Vec<String>,
-    dev_mounts: IndexMap<String, IndexMap<String, String>>
+const ROLLOUT_RESTART_ATTEMPTS: u32 = 4;
+const ROLLOUT_RESTART_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+// Which part of the stack a change should redeploy.
+#[derive(Clone, Debug)]
+enum ChangeTarget {
+    Stack,
+    Nodes(Vec<String>),
+}
+
+type QueuedChange = (Event, ChangeTarget);
+
+#[derive(Error, Debug)]
+pub enum TorbWatcherErrors {
+    #[error("Watcher path {path} is mapped to {fqn}, which isn't a node in the stack. Nodes are referred to by fqn, e.g. {example}")]
+    UnknownWatchedNode { path: String, fqn: String, example: String },
+    #[error("The watcher ignore glob {pattern} is invalid, reason: {reason}")]
+    InvalidIgnoreGlob { pattern: String, reason: String },
 }
 
-impl Default for WatcherConfig {
-    fn default() -> WatcherConfig {
-        WatcherConfig {
-            paths: vec!["./".to_string()],
-            interval: 3000,
-            patch: true,
-            exempt: vec![],
-            dev_mounts: IndexMap::new()
+/*
+    Which phases the watcher runs. BuildOnly skips Terraform when starting and relies on the rollout restart
+    to pick up rebuilt images, so it only makes sense for a stack that's already deployed. DeployOnly never
+    builds images, for when they're built and pushed by something else.
+*/
+#[derive(Clone, Copy, PartialEq)]
+pub enum WatchPhases {
+    All,
+    BuildOnly,
+    DeployOnly,
+}
+
+impl WatchPhases {
+    pub fn from_flags(no_build: bool, no_deploy: bool) -> Self {
+        match (no_build, no_deploy) {
+            (true, _) => WatchPhases::DeployOnly,
+            (false, true) => WatchPhases::BuildOnly,
+            (false, false) => WatchPhases::All,
         }
     }
+
+    fn builds(&self) -> bool {
+        *self != WatchPhases::DeployOnly
+    }
+
+    fn deploys(&self) -> bool {
+        *self != WatchPhases::BuildOnly
+    }
 }
 
+const WATCHER_RELEASES_DIR: &str = "watcher_releases";
+
 pub struct Watcher {
     pub paths: Vec<PathBuf>,
+    pub node_paths: Vec<(PathBuf, Vec<String>)>,
+    pub unmapped: UnmappedChanges,
     pub interval: u64,
     pub patch: bool,
     pub artifact: Arc<ArtifactRepr>,
     pub build_hash: String,
     pub build_filename: String,
     pub dev_mounts: IndexMap<String, IndexMap<String, String>>,
+    pub ignore: GlobSet,
     internal: Arc<WatcherInternal>,
 }
 
 struct WatcherInternal {
-    pub queue: Mutex<Vec<Event>>,
+    pub queue: Mutex<Vec<QueuedChange>>,
+    pub last_event: Mutex<Option<Instant>>,
+    pub quiet_period: Duration,
     pub separate_local_registry: bool,
     pub exempt: Vec<String>,
     pub exempt_set: HashSet<String>,
+    pub container_builder: ContainerBuilder,
+    pub insecure_registries: Vec<String>,
+    pub phases: WatchPhases,
 }
 
 impl WatcherInternal {
-    fn new(separate_local_registry: bool, exempt: Vec<String>) -> Self {
+    fn new(
+        separate_local_registry: bool,
+        exempt: Vec<String>,
+        quiet_period: u64,
+        container_builder: ContainerBuilder,
+        insecure_registries: Vec<String>,
+        phases: WatchPhases,
+    ) -> Self {
         WatcherInternal {
-            queue: Mutex::new(Vec::<Event>::new()),
+            queue: Mutex::new(Vec::<QueuedChange>::new()),
+            last_event: Mutex::new(None),
+            quiet_period: Duration::from_millis(quiet_period),
             separate_local_registry,
             exempt_set: HashSet::from_iter(exempt.iter().cloned()),
             exempt: exempt,
+            container_builder,
+            insecure_registries,
+            phases,
+        }
+    }
+
+    fn push_event(&self, event: Event, target: ChangeTarget) -> Result<(), PoisonError<MutexGuard<Vec<QueuedChange>>>> {
+        self.queue.lock()?.push((event, target));
+
+        if let Ok(mut last_event) = self.last_event.lock() {
+            *last_event = Some(Instant::now());
         }
+
+        Ok(())
+    }
+
+    // Returns how much longer we need to wait before the most recent burst of events is considered settled.
+    fn time_until_quiet(&self) -> Option<Duration> {
+        let last_event = self.last_event.lock().ok()?.clone()?;
+        let elapsed = last_event.elapsed();
+
+        if elapsed < self.quiet_period {
+            Some(self.quiet_period - elapsed)
+        } else {
+            None
+        }
+    }
+
+    // None when any queued change needs the whole stack redeployed.
+    fn targeted_nodes(queue: &Vec<QueuedChange>) -> Option<HashSet<String>> {
+        let mut targeted = HashSet::<String>::new();
+
+        for (_, target) in queue.iter() {
+            match target {
+                ChangeTarget::Stack => return None,
+                ChangeTarget::Nodes(nodes) => targeted.extend(nodes.iter().cloned()),
+            }
+        }
+
+        Some(targeted)
     }
+
     fn redeploy(
         &self,
         artifact: Arc<ArtifactRepr>,
-    ) -> Result<(), PoisonError<MutexGuard<Vec<Event>>>> {
+    ) -> Result<(), PoisonError<MutexGuard<Vec<QueuedChange>>>> {
         self.queue.lock().map(|mut queue| {
             if !queue.is_empty() {
-                println!("Changes found during watcher interval, redeploying!");
+                let targeted = WatcherInternal::targeted_nodes(&queue);
+
+                let mut exempt = self.exempt.clone();
+
+                match &targeted {
+                    None => info!("Changes found during watcher interval, redeploying!"),
+                    Some(targeted) => {
+                        let mut nodes = targeted.iter().cloned().collect::<Vec<String>>();
+                        nodes.sort();
+
+                        info!("Changes found during watcher interval, redeploying {}!", nodes.join(", "));
+
+                        exempt.extend(artifact.nodes.keys().filter(|fqn| !targeted.contains(*fqn)).cloned());
+                    }
+                }
 
                 queue.clear();
                 queue.shrink_to(10);
 
-                let build_platforms = "".to_string();
+                if self.phases.builds() {
+                    let build_platforms = "".to_string();
 
-                let mut builder = StackBuilder::new_with_exempt_list(&artifact, build_platforms, false, self.separate_local_registry.clone(), self.exempt.clone());
+                    let mut builder = StackBuilder::new_with_exempt_list(&artifact, build_platforms, false, self.separate_local_registry.clone(), exempt, self.container_builder.clone(), true)
+                        .with_insecure_registries(self.insecure_registries.clone());
 
-                builder.build().use_or_pretty_error(
-                    false,
-                    PrettyContext::default()
-                    .success("Success! Watcher rebuilt stack.")
-                    .error("Oh no! The Watcher failed to rebuild the stack. Continuing to watch, please fix your errors.")
-                    .pretty()
-                );
+                    builder.build().use_or_pretty_error(
+                        false,
+                        PrettyContext::default()
+                        .success("Success! Watcher rebuilt stack.")
+                        .error("Oh no! The Watcher failed to rebuild the stack. Continuing to watch, please fix your errors.")
+                        .pretty()
+                    );
+                } else {
+                    info!("Skipping the rebuild, the watcher was started with --no-build.");
+                }
 
                 for (_, node) in artifact.nodes.iter() {
                     if self.exempt_set.get(&node.fqn).is_some() {
                         continue
                     };
 
+                    if targeted.as_ref().map_or(false, |targeted| !targeted.contains(&node.fqn)) {
+                        continue
+                    };
+
                     let resource_name = format!("{}-{}", artifact.release(), node.display_name(true));
 
                     let namespace = artifact.namespace(node);
-                    let kind_res = get_resource_kind(&resource_name, &namespace);
-
-                    let kind = match kind_res {
-                        Err(err) => {
-                            panic!("{}", err)
-                        }
-                        Ok(_enum) => {
-                            match _enum {
-                                ResourceKind::DaemonSet => "daemonset",
-                                ResourceKind::Deployment => "deployment",
-                                ResourceKind::StatefulSet => "statefulset"
-                            }
-                        }
-                    };
 
-                    let cmd = CommandConfig::new("kubectl",
-                    vec![
-                            "rollout",
-                            "restart",
-                            kind,
-                            resource_name.as_str(),
-                            "--namespace",
-                            &namespace
-                        ],
-                        None
+                    // A restart can conflict with a resource that's still being updated, so it's retried before giving up on the node.
+                    let restarted = retry_with_backoff(ROLLOUT_RESTART_ATTEMPTS, ROLLOUT_RESTART_INITIAL_BACKOFF, || {
+                        let kind = match get_resource_kind(&resource_name, &namespace)? {
+                            ResourceKind::DaemonSet => "daemonset",
+                            ResourceKind::Deployment => "deployment",
+                            ResourceKind::StatefulSet => "statefulset"
+                        };
+
+                        let cmd = CommandConfig::new("kubectl",
+                        vec![
+                                "rollout",
+                                "restart",
+                                kind,
+                                resource_name.as_str(),
+                                "--namespace",
+                                &namespace
+                            ],
+                            None
+                        );
+
+                        CommandPipeline::execute_single(cmd).map(|_| ())
+                    });
+
+                    restarted.use_or_pretty_warn(
+                        PrettyContext::default()
+                        .warn(&format!("Unable to restart {} in {}, it may still be running the old image. Continuing to watch.", resource_name, namespace))
+                        .context("The watcher restarts each node's Deployment, StatefulSet or DaemonSet with kubectl rollout restart after rebuilding it.")
+                        .suggestions(vec![
+                            "Check that the cluster is reachable and the resource exists with kubectl get all --namespace <namespace>.",
+                            "Save a watched file again to retry the restart."
+                        ])
+                        .pretty()
                     );
-                    let err_msg = format!("Unable to execute rollout redeploy for {} {}", kind, resource_name);
-                    CommandPipeline::execute_single(cmd).expect(&err_msg);
                 }
 
             }
@@ -148,15 +268,55 @@ impl WatcherInternal {
 }
 
 impl Watcher {
-    pub fn configure(file_path: String, local_registry: bool) -> Self {
-        let contents = std::fs::read_to_string(file_path)
-            .expect("Something went wrong reading the stack file.");
+    pub fn configure(file_path: String, local_registry: bool, container_builder: ContainerBuilder, insecure_registries: Vec<String>, namespace_override: Option<String>, phases: WatchPhases) -> Self {
+        let contents = read_stack_source(&file_path).use_or_pretty_exit(
+            PrettyContext::default()
+            .error("Oh no, we were unable to read the stack definition for the watcher!")
+            .exit_category(ExitCategory::Resolution)
+            .context("The watcher reads the stack once when it starts and rebuilds it from the same file as you make changes.")
+            .suggestions(vec![
+                "Check that the file exists and is readable.",
+                "If the stack includes other files, check that they exist relative to the file including them."
+            ])
+            .pretty()
+        );
 
         let location = std::path::Path::new("/tmp").to_path_buf();
 
-        let (build_hash, build_filename, artifact) = write_build_file(contents, Some(&location));
+        let (build_hash, build_filename, artifact) = write_build_file(contents, Some(&location), None, &[], true).use_or_pretty_exit(
+            PrettyContext::default()
+            .error("Oh no, we were unable to resolve the stack for the watcher!")
+            .exit_category(ExitCategory::Resolution)
+            .context("The watcher resolves the stack against your artifact repositories and writes its build file to /tmp before the first build.")
+            .suggestions(vec![
+                "Run `torb stack lint` on the stack to find invalid nodes or inputs.",
+                "Check that your artifact repositories are up to date with `torb artifacts refresh`.",
+                "Check that /tmp can be written to."
+            ])
+            .pretty()
+        );
+        let artifact = artifact.with_namespace_override(namespace_override);
+        let artifact = match artifact.release.clone() {
+            Some(_) => artifact,
+            None => {
+                let release = Watcher::persisted_release(&artifact.stack_name);
+                artifact.with_release(release)
+            }
+        };
         let watcher = artifact.watcher.clone();
 
+        Watcher::check_watched_nodes(&artifact).use_or_pretty_exit(
+            PrettyContext::default()
+            .error("Oh no, a watcher path is mapped to a node that isn't in the stack!")
+            .exit_category(ExitCategory::Validation)
+            .context("Paths under `watcher.paths` can list the nodes a change should redeploy, every one of them has to name a node in the stack.")
+            .suggestions(vec![
+                "Refer to nodes by their fully qualified name, like the example in the error above.",
+                "Remove the mapping to redeploy the whole stack when the path changes."
+            ])
+            .pretty()
+        );
+
         Watcher::new(
             watcher.paths,
             artifact,
@@ -166,12 +326,83 @@ impl Watcher {
             build_hash,
             build_filename,
             watcher.exempt,
-            watcher.dev_mounts
+            watcher.dev_mounts,
+            watcher.ignore,
+            watcher.quiet_period,
+            watcher.unmapped,
+            container_builder,
+            insecure_registries,
+            phases
         )
     }
 
+    /*
+        Stacks without a release get a random name each time they're resolved, so the one the watcher picks is kept
+        in the buildstate per stack. Restarting the watcher then patches and rolls out the resources it deployed last
+        time instead of orphaning them under a new release.
+    */
+    fn persisted_release(stack_name: &str) -> String {
+        let releases_path = buildstate_path_or_create()
+            .expect("Unable to create buildstate directory.")
+            .join(WATCHER_RELEASES_DIR);
+        let release_path = releases_path.join(stack_name);
+
+        if let Ok(release) = std::fs::read_to_string(&release_path) {
+            let release = release.trim().to_string();
+
+            if !release.is_empty() {
+                info!("Reusing watcher release {} from {}.", release, release_path.display());
+
+                return release;
+            }
+        }
+
+        let release = memorable_wordlist::kebab_case(16);
+
+        std::fs::create_dir_all(&releases_path).expect("Unable to create the watcher releases directory.");
+        std::fs::write(&release_path, &release).expect("Unable to persist the watcher release name.");
+
+        info!("Persisted watcher release {} to {}.", release, release_path.display());
+
+        release
+    }
+
+    fn ignore_set(ignore: &[String]) -> Result<GlobSet, TorbWatcherErrors> {
+        let mut ignore_builder = GlobSetBuilder::new();
+
+        for pattern in ignore.iter() {
+            let glob = Glob::new(pattern).map_err(|err| TorbWatcherErrors::InvalidIgnoreGlob {
+                pattern: pattern.clone(),
+                reason: err.to_string(),
+            })?;
+
+            ignore_builder.add(glob);
+        }
+
+        ignore_builder.build().map_err(|err| TorbWatcherErrors::InvalidIgnoreGlob {
+            pattern: ignore.join(", "),
+            reason: err.to_string(),
+        })
+    }
+
+    fn check_watched_nodes(artifact: &ArtifactRepr) -> Result<(), TorbWatcherErrors> {
+        for watch_path in artifact.watcher.paths.iter() {
+            if let WatchPath::Nodes { path, nodes } = watch_path {
+                if let Some(fqn) = nodes.iter().find(|fqn| !artifact.nodes.contains_key(*fqn)) {
+                    return Err(TorbWatcherErrors::UnknownWatchedNode {
+                        path: path.clone(),
+                        fqn: fqn.clone(),
+                        example: artifact.nodes.keys().next().cloned().unwrap_or_default(),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     fn new(
-        paths: Vec<String>,
+        paths: Vec<WatchPath>,
         artifact: ArtifactRepr,
         interval: Option<u64>,
         patch: Option<bool>,
@@ -179,32 +410,75 @@ impl Watcher {
         build_hash: String,
         build_filename: String,
         exempt: Vec<String>,
-        mounts: IndexMap<String, IndexMap<String, String>>
+        mounts: IndexMap<String, IndexMap<String, String>>,
+        ignore: Vec<String>,
+        quiet_period: u64,
+        unmapped: UnmappedChanges,
+        container_builder: ContainerBuilder,
+        insecure_registries: Vec<String>,
+        phases: WatchPhases
     ) -> Self {
         let interval = interval.unwrap_or(3000);
         let patch = patch.unwrap_or(true);
         let mut bufs = Vec::new();
+        let mut node_paths = Vec::new();
+
+        // Paths are watched canonicalized so event paths can be matched against them by prefix.
+        for watch_path in paths.iter() {
+            let p = project_path(watch_path.path());
+            let p = std::fs::canonicalize(&p).unwrap_or(p);
+
+            if let WatchPath::Nodes { nodes, .. } = watch_path {
+                node_paths.push((p.clone(), nodes.clone()));
+            }
 
-        for str in paths.iter() {
-            let p = PathBuf::from(str);
             bufs.push(p);
         }
 
-        let internal = Arc::new(WatcherInternal::new(local_registry, exempt));
+        let ignore_set = Watcher::ignore_set(&ignore).use_or_pretty_exit(
+            PrettyContext::default()
+            .error("Oh no, one of the watcher's ignore globs is invalid!")
+            .exit_category(ExitCategory::Validation)
+            .context("Changes under paths matching `watcher.ignore` don't trigger a redeploy, each entry has to be a valid glob.")
+            .suggestions(vec![
+                "Check the glob for unbalanced brackets or braces, e.g. **/*.{log,tmp}.",
+                "Quote globs that start with * in the stack file so YAML doesn't read them as aliases."
+            ])
+            .pretty()
+        );
+
+        let internal = Arc::new(WatcherInternal::new(local_registry, exempt, quiet_period, container_builder, insecure_registries, phases));
 
         Watcher {
             paths: bufs,
+            node_paths,
+            unmapped,
             interval,
             patch,
             artifact: Arc::new(artifact),
             build_hash,
             build_filename,
             dev_mounts: mounts,
+            ignore: ignore_set,
             internal,
         }
     }
 
     fn setup_stack(&mut self) {
+        if self.internal.phases.builds() {
+            self.build_stack();
+        } else {
+            info!("Skipping the initial build, the watcher was started with --no-build.");
+        }
+
+        if self.internal.phases.deploys() {
+            self.deploy_stack();
+        } else {
+            info!("Skipping the initial deploy, the watcher was started with --no-deploy. Changes will be rebuilt and rolled out to the stack as it's currently deployed.");
+        }
+    }
+
+    fn build_stack(&self) {
         let build_platforms = "".to_string();
 
         let mut builder = StackBuilder::new(
@@ -212,11 +486,15 @@ impl Watcher {
             build_platforms,
             false,
             self.internal.separate_local_registry.clone(),
-        );
+            self.internal.container_builder.clone(),
+            false,
+        )
+        .with_insecure_registries(self.internal.insecure_registries.clone());
 
         builder.build().use_or_pretty_exit(
             PrettyContext::default()
             .error("Oh no, we were unable to build the stack when starting the watcher!")
+            .exit_category(ExitCategory::Build)
             .success("Success! Stack has been built!")
             .context("Errors here are typically because of a failed docker build, syntax issue in the dockerfile or a connectivity issue with the docker registry.")
             .suggestions(vec![
@@ -225,18 +503,31 @@ impl Watcher {
             ])
             .pretty()
         );
+    }
 
+    fn deploy_stack(&self) {
         let mut composer =
             Composer::new_with_dev_mounts(self.build_hash.clone(), &self.artifact, self.patch.clone(), self.dev_mounts.clone());
-        composer.compose().unwrap();
+        composer.compose().use_or_pretty_exit(
+            PrettyContext::default()
+            .error("Oh no, we failed to generate the IaC environment for the watcher!")
+            .exit_category(ExitCategory::Compose)
+            .context("This typically happens due to failures parsing the stack into HCL for Terraform.")
+            .suggestions(vec![
+                "Check that your inputs are escaped correctly.",
+                "Check that the buildstate directory can be written to."
+            ])
+            .pretty()
+        );
 
-        let mut deployer = StackDeployer::new(self.patch.clone());
+        let mut deployer = StackDeployer::new(self.patch.clone()).with_tf_parallelism(self.artifact.tf_parallelism);
 
         deployer
             .deploy(&self.artifact, false)
             .use_or_pretty_exit(
                 PrettyContext::default()
                 .error("Oh no, we were unable to deploy the stack when starting the watcher!")
+                .exit_category(ExitCategory::Deploy)
                 .success("Success! Stack has been deployed!")
                 .context("Errors here are typically because of failed Terraform deployments or Helm failures.")
                 .suggestions(vec![
@@ -247,7 +538,7 @@ impl Watcher {
                 .pretty()
             );
 
-        let buildstate_path = buildstate_path_or_create();
+        let buildstate_path = buildstate_path_or_create().expect("Unable to create buildstate directory.");
         let non_watcher_iac = buildstate_path.join("iac_environment");
         let watcher_iac = buildstate_path.join("watcher_iac_environment");
         let tf_state_path = watcher_iac.join("terraform.tfstate");
@@ -270,6 +561,11 @@ impl Watcher {
             let mut interval = time::interval(Duration::from_millis(interval.to_owned()));
             loop {
                 interval.tick().await;
+
+                while let Some(remaining) = internal_ref.time_until_quiet() {
+                    time::sleep(remaining).await;
+                }
+
                 internal_ref
                     .redeploy(artifact_ref.clone())
                     .expect("Unable to complete redeploy!");
@@ -278,7 +574,7 @@ impl Watcher {
 
         rt.block_on(async {
             if let Err(e) = self.watch().await {
-                println!("error: {:?}", e)
+                error!("error: {:?}", e)
             }
         });
 
@@ -289,13 +585,21 @@ impl Watcher {
         let (mut watcher, mut rx) = self.async_watcher()?;
 
         for path in self.paths.iter() {
-            println!("Watching: {}", path.to_str().unwrap());
+            info!("Watching: {}", path.to_str().unwrap());
             watcher.watch(&path, RecursiveMode::Recursive)?;
         }
 
         while let Some(res) = rx.recv().await {
             match res {
-                Ok(event) => self.internal.queue.lock()?.push(event),
+                Ok(event) => {
+                    if self.is_ignored(&event) {
+                        continue;
+                    }
+
+                    if let Some(target) = self.change_target(&event) {
+                        self.internal.push_event(event, target)?
+                    }
+                }
                 Err(e) => panic!("{}", e),
             }
         }
@@ -303,6 +607,40 @@ impl Watcher {
         Ok(())
     }
 
+    fn is_ignored(&self, event: &Event) -> bool {
+        !event.paths.is_empty() && event.paths.iter().all(|path| self.ignore.is_match(path))
+    }
+
+    /*
+        A changed path belongs to the most specific mapped path containing it. Changes outside every mapped path
+        redeploy the whole stack, or are dropped when unmapped is set to ignore.
+    */
+    fn change_target(&self, event: &Event) -> Option<ChangeTarget> {
+        let mut nodes = Vec::<String>::new();
+        let mut unmapped_change = event.paths.is_empty();
+
+        for path in event.paths.iter() {
+            let mapped = self
+                .node_paths
+                .iter()
+                .filter(|(node_path, _)| path.starts_with(node_path))
+                .max_by_key(|(node_path, _)| node_path.components().count());
+
+            match mapped {
+                Some((_, mapped_nodes)) => nodes.extend(mapped_nodes.iter().cloned()),
+                None => unmapped_change = true,
+            }
+        }
+
+        if unmapped_change && self.unmapped == UnmappedChanges::Redeploy {
+            Some(ChangeTarget::Stack)
+        } else if nodes.is_empty() {
+            None
+        } else {
+            Some(ChangeTarget::Nodes(nodes))
+        }
+    }
+
     fn async_watcher(
         &self,
     ) -> notify::Result<(RecommendedWatcher, Receiver<notify::Result<Event>>)> {